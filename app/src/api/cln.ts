@@ -1,5 +1,6 @@
 import { send_cmd } from "./cmd";
 import type { Cmd } from "./cmd";
+import type { FeeUpdate } from "./lightning";
 
 // test route hint
 declare global {
@@ -69,6 +70,21 @@ export async function close_channel(
   return await clnCmd("CloseChannel", tag, { id, destination });
 }
 
+export async function rebalance(
+  tag: string,
+  from_channel: string,
+  to_channel: string,
+  amount_msat: number,
+  max_fee_ppm: number
+) {
+  return await clnCmd("Rebalance", tag, {
+    from_channel,
+    to_channel,
+    amount_msat,
+    max_fee_ppm,
+  });
+}
+
 export async function list_invoices(tag: string, payment_hash?: string) {
   return await clnCmd("ListInvoices", tag, payment_hash && { payment_hash });
 }
@@ -94,3 +110,55 @@ export async function add_peer(
 ) {
   return await clnCmd("AddPeer", tag, { pubkey, host, alias });
 }
+
+export async function list_fee_policies(tag: string) {
+  return await clnCmd("ListFeePolicies", tag);
+}
+
+// every channel when channel is not given
+export async function set_fee_policy(
+  tag: string,
+  update: FeeUpdate,
+  channel?: string
+) {
+  return await clnCmd("SetFeePolicy", tag, { channel, ...update });
+}
+
+export interface NewOffer {
+  label: string;
+  // any amount when not given
+  amount_msat?: number;
+  description?: string;
+  single_use?: boolean;
+  absolute_expiry?: number;
+}
+
+export async function create_offer(tag: string, offer: NewOffer) {
+  return await clnCmd("CreateOffer", tag, offer);
+}
+
+export async function list_offers(tag: string) {
+  return await clnCmd("ListOffers", tag);
+}
+
+export async function disable_offer(tag: string, offer_id: string) {
+  return await clnCmd("DisableOffer", tag, { offer_id });
+}
+
+export async function fetch_offer_invoice(
+  tag: string,
+  offer: string,
+  amount_msat?: number,
+  payer_note?: string
+) {
+  return await clnCmd("FetchInvoice", tag, { offer, amount_msat, payer_note });
+}
+
+export async function pay_offer(
+  tag: string,
+  offer: string,
+  amount_msat?: number,
+  payer_note?: string
+) {
+  return await clnCmd("PayOffer", tag, { offer, amount_msat, payer_note });
+}
@@ -22,7 +22,8 @@ type CmdType =
   | "Lnd"
   | "Cln"
   | "Proxy"
-  | "Hsmd";
+  | "Hsmd"
+  | "Lightning";
 
 export type Cmd =
   | "GetConfig"
@@ -30,6 +31,12 @@ export type Cmd =
   | "AddUser"
   | "GetInfo"
   | "GetContainerLogs"
+  | "SearchLogs"
+  | "QueryQuickwitLogs"
+  | "GetQuickwitErrorCounts"
+  | "ListSavedLogQueries"
+  | "SaveLogQuery"
+  | "DeleteSavedLogQuery"
   | "TestMine"
   | "ListChannels"
   | "AddPeer"
@@ -54,11 +61,60 @@ export type Cmd =
   | "UpdateNode"
   | "ListFunds"
   | "CloseChannel"
+  | "OpenChannel"
   | "ListInvoices"
   | "ListPays"
   | "ListPayments"
   | "GetStatistics"
+  | "GetStatisticsHistory"
+  | "ListAlerts"
+  | "CreateAlertSilence"
+  | "DeleteAlertSilence"
+  | "GetDiskUsage"
+  | "UpdateCleanupPolicy"
+  | "RunCleanup"
+  | "ExportBackupKey"
+  | "ListBackups"
+  | "BackupNow"
+  | "RestoreBackup"
+  | "UpdateBackupRetention"
+  | "PruneBackups"
+  | "ExportSwarm"
+  | "GetLiquidityPolicies"
+  | "UpdateLiquidityPolicy"
+  | "EvaluateLiquidity"
+  | "ListLiquidityDecisions"
+  | "GetRebalancePolicies"
+  | "UpdateRebalancePolicy"
+  | "ListRebalances"
+  | "GetFeeSchedules"
+  | "UpdateFeeSchedule"
+  | "ListFeeChanges"
+  | "GetWebhooks"
+  | "UpdateWebhook"
+  | "ListWebhookDeliveries"
+  | "GetLightningAddresses"
+  | "UpdateLightningAddress"
+  | "ListLightningAddressInvoices"
+  | "GetFeeEstimates"
+  | "ListOnchainSpends"
   | "ListPendingChannels"
+  | "Rebalance"
+  | "ListFeePolicies"
+  | "SetFeePolicy"
+  | "CreateOffer"
+  | "ListOffers"
+  | "DisableOffer"
+  | "FetchInvoice"
+  | "PayOffer"
+  | "ListUtxos"
+  | "FreezeUtxos"
+  | "UnfreezeUtxos"
+  | "SendOnchain"
+  | "PublishPsbt"
+  | "ConfirmSpend"
+  | "CreatePsbt"
+  | "SignPsbt"
   | "GetClients"
   | "AddBoltwallAdminPubkey"
   | "GetBoltwallSuperAdmin"
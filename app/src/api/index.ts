@@ -4,5 +4,6 @@ import * as btc from "./btc";
 import * as tribes from "./tribes";
 import * as lnd from "./lnd";
 import * as cln from "./cln";
+import * as lightning from "./lightning";
 
-export { swarm, relay, btc, lnd, tribes, cln };
+export { swarm, relay, btc, lnd, tribes, cln, lightning };
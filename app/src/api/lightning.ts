@@ -0,0 +1,170 @@
+import { send_cmd } from "./cmd";
+import type { Cmd } from "./cmd";
+
+// the same commands on CLN and LND nodes, amounts in msat unless named
+
+export interface LightningChannel {
+  id: string;
+  short_channel_id?: string;
+  peer: string;
+  capacity_sat: number;
+  local_msat: number;
+  remote_msat: number;
+  state: "pending" | "active" | "inactive" | "closing";
+  private: boolean;
+}
+
+export interface LightningBalance {
+  onchain_confirmed_sat: number;
+  onchain_unconfirmed_sat: number;
+  channels_local_msat: number;
+}
+
+async function lightningCmd(cmd: Cmd, tag: string, content?: any) {
+  return await send_cmd("Lightning", { cmd, content }, tag);
+}
+
+export async function get_info(tag: string) {
+  return await lightningCmd("GetInfo", tag);
+}
+
+export async function list_peers(tag: string) {
+  return await lightningCmd("ListPeers", tag);
+}
+
+export async function add_peer(
+  tag: string,
+  pubkey: string,
+  host: string,
+  alias?: string
+) {
+  return await lightningCmd("AddPeer", tag, { pubkey, host, alias });
+}
+
+export async function list_channels(
+  tag: string
+): Promise<LightningChannel[]> {
+  return await lightningCmd("ListChannels", tag);
+}
+
+export async function get_balance(tag: string): Promise<LightningBalance> {
+  return await lightningCmd("GetBalance", tag);
+}
+
+export async function new_address(tag: string) {
+  return await lightningCmd("NewAddress", tag);
+}
+
+export async function add_invoice(tag: string, amt_msat: number) {
+  return await lightningCmd("AddInvoice", tag, { amt_msat });
+}
+
+export async function list_invoices(tag: string, payment_hash?: string) {
+  return await lightningCmd(
+    "ListInvoices",
+    tag,
+    payment_hash && { payment_hash }
+  );
+}
+
+export async function pay_invoice(tag: string, payment_request: string) {
+  return await lightningCmd("PayInvoice", tag, { payment_request });
+}
+
+export async function list_payments(tag: string, payment_hash?: string) {
+  return await lightningCmd(
+    "ListPayments",
+    tag,
+    payment_hash && { payment_hash }
+  );
+}
+
+export async function keysend(tag: string, dest: string, amt_msat: number) {
+  return await lightningCmd("PayKeysend", tag, { dest, amt_msat });
+}
+
+export async function open_channel(
+  tag: string,
+  pubkey: string,
+  amount_sat: number,
+  sat_per_vbyte?: number
+) {
+  return await lightningCmd("OpenChannel", tag, {
+    pubkey,
+    amount_sat,
+    sat_per_vbyte,
+  });
+}
+
+export async function close_channel(
+  tag: string,
+  id: string,
+  destination?: string
+) {
+  return await lightningCmd("CloseChannel", tag, { id, destination });
+}
+
+export interface FeeUpdate {
+  base_fee_msat?: number;
+  fee_ppm?: number;
+  min_htlc_msat?: number;
+  max_htlc_msat?: number;
+  cltv_delta?: number;
+}
+
+export async function list_fee_policies(tag: string) {
+  return await lightningCmd("ListFeePolicies", tag);
+}
+
+// every channel when channel is not given
+export async function set_fee_policy(
+  tag: string,
+  update: FeeUpdate,
+  channel?: string
+) {
+  return await lightningCmd("SetFeePolicy", tag, { channel, ...update });
+}
+
+export interface OnchainSend {
+  address: string;
+  // everything in the wallet, or in utxos, when not given
+  amount_sat?: number;
+  sat_per_vbyte?: number;
+  target_blocks?: number;
+  // "txid:vout"
+  utxos?: string[];
+}
+
+export async function list_utxos(tag: string) {
+  return await lightningCmd("ListUtxos", tag);
+}
+
+export async function freeze_utxos(tag: string, utxos: string[]) {
+  return await lightningCmd("FreezeUtxos", tag, { utxos });
+}
+
+export async function unfreeze_utxos(tag: string, utxos: string[]) {
+  return await lightningCmd("UnfreezeUtxos", tag, { utxos });
+}
+
+// returns a pending spend, nothing is sent until confirm_spend
+export async function send_onchain(tag: string, send: OnchainSend) {
+  return await lightningCmd("SendOnchain", tag, send);
+}
+
+// returns a pending spend, nothing is broadcast until confirm_spend
+export async function publish_psbt(tag: string, psbt: string) {
+  return await lightningCmd("PublishPsbt", tag, { psbt });
+}
+
+export async function confirm_spend(tag: string, id: string) {
+  return await lightningCmd("ConfirmSpend", tag, { id });
+}
+
+export async function create_psbt(tag: string, send: OnchainSend) {
+  return await lightningCmd("CreatePsbt", tag, send);
+}
+
+export async function sign_psbt(tag: string, psbt: string) {
+  return await lightningCmd("SignPsbt", tag, { psbt });
+}
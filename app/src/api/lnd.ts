@@ -1,5 +1,6 @@
 import { send_cmd } from "./cmd";
 import type { Cmd } from "./cmd";
+import type { FeeUpdate } from "./lightning";
 
 export interface LndInfo {
   identity_pubkey: string;
@@ -120,3 +121,16 @@ export async function list_invoices(tag: string) {
 export async function list_payments(tag: string) {
   return await lndCmd("ListPayments", tag);
 }
+
+export async function list_fee_policies(tag: string) {
+  return await lndCmd("ListFeePolicies", tag);
+}
+
+// every channel when channel is not given
+export async function set_fee_policy(
+  tag: string,
+  update: FeeUpdate,
+  channel?: string
+) {
+  return await lndCmd("SetFeePolicy", tag, { channel, ...update });
+}
@@ -37,6 +37,80 @@ export async function get_logs(
   });
 }
 
+export async function search_logs(params: {
+  containers: string[];
+  since?: string;
+  until?: string;
+  filter?: string;
+  regex?: boolean;
+  stream?: "stdout" | "stderr" | "both";
+  limit?: number;
+}) {
+  return await swarmCmd("SearchLogs", {
+    containers: params.containers,
+    since: params.since || null,
+    until: params.until || null,
+    filter: params.filter || null,
+    regex: params.regex || null,
+    stream: params.stream || null,
+    limit: params.limit || null,
+  });
+}
+
+export async function query_quickwit_logs(params: {
+  query?: string;
+  service?: string;
+  start_timestamp?: number;
+  end_timestamp?: number;
+  max_hits?: number;
+  start_offset?: number;
+  saved?: string;
+}) {
+  return await swarmCmd("QueryQuickwitLogs", {
+    query: params.query || null,
+    service: params.service || null,
+    start_timestamp: params.start_timestamp || null,
+    end_timestamp: params.end_timestamp || null,
+    max_hits: params.max_hits || null,
+    start_offset: params.start_offset || null,
+    saved: params.saved || null,
+  });
+}
+
+export async function get_quickwit_error_counts(params: {
+  start_timestamp?: number;
+  end_timestamp?: number;
+  service?: string;
+  service_field?: string;
+}) {
+  return await swarmCmd("GetQuickwitErrorCounts", {
+    start_timestamp: params.start_timestamp || null,
+    end_timestamp: params.end_timestamp || null,
+    service: params.service || null,
+    service_field: params.service_field || null,
+  });
+}
+
+export async function list_saved_log_queries() {
+  return await swarmCmd("ListSavedLogQueries");
+}
+
+export async function save_log_query(params: {
+  name: string;
+  query?: string;
+  service?: string;
+}) {
+  return await swarmCmd("SaveLogQuery", {
+    name: params.name,
+    query: params.query || null,
+    service: params.service || null,
+  });
+}
+
+export async function delete_saved_log_query(name: string) {
+  return await swarmCmd("DeleteSavedLogQuery", name);
+}
+
 export async function get_node_images(name, page) {
   return await swarmCmd("ListVersions", { name, page });
 }
@@ -69,6 +143,20 @@ export async function get_container_stat(name?: string) {
   return await swarmCmd("GetStatistics", name);
 }
 
+export async function get_container_stat_history(
+  container?: string,
+  from?: number,
+  to?: number,
+  resolution?: "raw" | "hour" | "day"
+) {
+  return await swarmCmd("GetStatisticsHistory", {
+    container: container || null,
+    from: from || null,
+    to: to || null,
+    resolution: resolution || null,
+  });
+}
+
 export async function add_boltwall_admin_pubkey(pubkey: string, name?: string) {
   return await swarmCmd("AddBoltwallAdminPubkey", { pubkey, name });
 }
@@ -691,3 +779,255 @@ export async function get_boltwall_users() {
 export async function get_boltwall_db_table(table: string) {
   return await swarmCmd("GetBoltwallDbTable", table);
 }
+
+export async function list_alerts() {
+  return await swarmCmd("ListAlerts");
+}
+
+export async function create_alert_silence(params: {
+  rule?: string;
+  subject?: string;
+  duration_secs: number;
+  comment?: string;
+}) {
+  return await swarmCmd("CreateAlertSilence", {
+    rule: params.rule || null,
+    subject: params.subject || null,
+    duration_secs: params.duration_secs,
+    comment: params.comment || null,
+  });
+}
+
+export async function delete_alert_silence(id: string) {
+  return await swarmCmd("DeleteAlertSilence", id);
+}
+
+export async function get_disk_usage() {
+  return await swarmCmd("GetDiskUsage");
+}
+
+export interface CleanupPolicy {
+  images_older_than_days?: number;
+  orphaned_volumes?: boolean;
+  container_logs_older_than_days?: number;
+  cron?: string;
+}
+
+export async function update_cleanup_policy(policy: CleanupPolicy) {
+  return await swarmCmd("UpdateCleanupPolicy", policy);
+}
+
+export async function run_cleanup(dry_run: boolean, policy?: CleanupPolicy) {
+  return await swarmCmd("RunCleanup", { dry_run, policy: policy || null });
+}
+
+export async function export_backup_key() {
+  return await swarmCmd("ExportBackupKey");
+}
+
+export async function list_backups(node: string) {
+  return await swarmCmd("ListBackups", { node });
+}
+
+export async function backup_now(nodes: string[]) {
+  return await swarmCmd("BackupNow", { nodes });
+}
+
+export async function restore_backup(node: string, snapshot: string) {
+  return await swarmCmd("RestoreBackup", { node, snapshot });
+}
+
+export interface GfsRule {
+  daily?: number;
+  weekly?: number;
+  monthly?: number;
+  yearly?: number;
+}
+
+export interface BackupRetention extends GfsRule {
+  services?: { [service: string]: GfsRule };
+}
+
+export async function update_backup_retention(retention: BackupRetention) {
+  return await swarmCmd("UpdateBackupRetention", retention);
+}
+
+export async function prune_backups(dry_run: boolean) {
+  return await swarmCmd("PruneBackups", { dry_run });
+}
+
+export async function export_swarm(nodes?: string[]) {
+  return await swarmCmd("ExportSwarm", { nodes });
+}
+
+export interface LiquidityTargetPeer {
+  pubkey: string;
+  host?: string;
+  alias?: string;
+}
+
+export interface LiquidityPolicy {
+  auto_open: boolean;
+  peers: LiquidityTargetPeer[];
+  use_lightning_peers: boolean;
+  min_channel_sat: number;
+  channel_sat?: number;
+  max_daily_spend_sat: number;
+  min_outbound_ratio?: number;
+  min_inbound_ratio?: number;
+  sat_per_vbyte?: number;
+  max_sat_per_vbyte?: number;
+  onchain_reserve_sat?: number;
+}
+
+export interface LiquidityDecision {
+  node: string;
+  at: number;
+  action: "open" | "propose" | "hold" | "need_inbound" | "ok";
+  peer?: string;
+  alias?: string;
+  amount_sat?: number;
+  sat_per_vbyte?: number;
+  reason: string;
+  txid?: string;
+  error?: string;
+}
+
+export async function get_liquidity_policies() {
+  return await swarmCmd("GetLiquidityPolicies");
+}
+
+// a null policy removes it
+export async function update_liquidity_policy(
+  node: string,
+  policy: LiquidityPolicy | null
+) {
+  return await swarmCmd("UpdateLiquidityPolicy", { node, policy });
+}
+
+export async function evaluate_liquidity(
+  node: string,
+  dry_run: boolean
+) {
+  return await swarmCmd("EvaluateLiquidity", { node, dry_run });
+}
+
+export async function list_liquidity_decisions(
+  node?: string,
+  limit?: number
+) {
+  return await swarmCmd("ListLiquidityDecisions", { node, limit });
+}
+
+export interface RebalancePolicy {
+  min_local_ratio: number;
+  max_local_ratio: number;
+  max_fee_ppm: number;
+  daily_fee_budget_msat: number;
+  max_amount_msat?: number;
+}
+
+export async function get_rebalance_policies() {
+  return await swarmCmd("GetRebalancePolicies");
+}
+
+// a null policy turns the auto-rebalancer off for the node
+export async function update_rebalance_policy(
+  node: string,
+  policy: RebalancePolicy | null
+) {
+  return await swarmCmd("UpdateRebalancePolicy", { node, policy });
+}
+
+export async function list_rebalances(node?: string, limit?: number) {
+  return await swarmCmd("ListRebalances", { node, limit });
+}
+
+export interface FeeStep {
+  below_local_ratio: number;
+  fee_ppm: number;
+}
+
+export interface FeeSchedule {
+  steps: FeeStep[];
+  default_ppm: number;
+  base_fee_msat?: number;
+  min_change_ppm?: number;
+}
+
+export async function get_fee_schedules() {
+  return await swarmCmd("GetFeeSchedules");
+}
+
+// a null schedule stops it for the node
+export async function update_fee_schedule(
+  node: string,
+  schedule: FeeSchedule | null
+) {
+  return await swarmCmd("UpdateFeeSchedule", { node, schedule });
+}
+
+export async function list_fee_changes(node?: string, limit?: number) {
+  return await swarmCmd("ListFeeChanges", { node, limit });
+}
+
+export type LightningEventKind =
+  | "invoice_paid"
+  | "payment_succeeded"
+  | "payment_failed";
+
+export interface Webhook {
+  url: string;
+  // empty keeps the current secret, or generates one
+  secret?: string;
+  events?: LightningEventKind[];
+  nodes?: string[];
+}
+
+export async function get_webhooks() {
+  return await swarmCmd("GetWebhooks");
+}
+
+// a null webhook removes it. returns the saved webhook with its secret
+export async function update_webhook(name: string, webhook: Webhook | null) {
+  return await swarmCmd("UpdateWebhook", { name, webhook });
+}
+
+export async function list_webhook_deliveries(webhook?: string, limit?: number) {
+  return await swarmCmd("ListWebhookDeliveries", { webhook, limit });
+}
+
+export interface LightningAddress {
+  node: string;
+  min_sendable_msat: number;
+  max_sendable_msat: number;
+  description: string;
+  comment_allowed?: number;
+}
+
+export async function get_lightning_addresses() {
+  return await swarmCmd("GetLightningAddresses");
+}
+
+// a null address removes it
+export async function update_lightning_address(
+  username: string,
+  address: LightningAddress | null
+) {
+  return await swarmCmd("UpdateLightningAddress", { username, address });
+}
+
+export async function list_lightning_address_invoices(
+  username?: string,
+  limit?: number
+) {
+  return await swarmCmd("ListLightningAddressInvoices", { username, limit });
+}
+
+export async function get_fee_estimates() {
+  return await swarmCmd("GetFeeEstimates");
+}
+
+export async function list_onchain_spends(node?: string, limit?: number) {
+  return await swarmCmd("ListOnchainSpends", { node, limit });
+}
@@ -0,0 +1,441 @@
+pub mod notify;
+pub mod rules;
+
+use crate::config;
+use crate::metrics;
+use crate::utils::getenv;
+use anyhow::{anyhow, Result};
+use bollard::Docker;
+use notify::{Notification, Notifier};
+use once_cell::sync::Lazy;
+use rules::{Condition, Evaluator};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio_cron_scheduler::{Job, JobScheduler};
+
+pub static EVALUATE_ALERTS: AtomicBool = AtomicBool::new(false);
+
+/// Current alerts keyed by "rule/subject". Resolved alerts are kept for a
+/// day so they still show up in ListAlerts.
+pub static ALERTS: Lazy<Mutex<BTreeMap<String, Alert>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+const KEEP_RESOLVED_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AlertsConfig {
+    pub rules: Vec<AlertRule>,
+    pub notifiers: Vec<Notifier>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub silences: Vec<Silence>,
+    // re-notify a still-firing alert after this many seconds (never if unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_secs: Option<u64>,
+}
+
+impl AlertsConfig {
+    pub fn remove_secrets(&self) -> Self {
+        Self {
+            notifiers: self.notifiers.iter().map(|n| n.remove_secrets()).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    #[serde(flatten)]
+    pub condition: Condition,
+    // how long the condition must hold before the alert fires
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub for_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Pending,
+    Firing,
+    Resolved,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    // what the rule matched, e.g. a container name
+    pub subject: String,
+    pub status: AlertStatus,
+    pub message: String,
+    // when the condition started holding
+    pub since: u64,
+    pub fired_at: Option<u64>,
+    pub resolved_at: Option<u64>,
+    pub last_notified: Option<u64>,
+    pub silenced: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Silence {
+    pub id: String,
+    // unset matches every rule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    // unset matches every subject
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    // unix seconds
+    pub until: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<u32>,
+}
+
+impl Silence {
+    pub fn matches(&self, rule: &str, subject: &str, now: u64) -> bool {
+        now < self.until
+            && self.rule.as_deref().is_none_or(|r| r == rule)
+            && self.subject.as_deref().is_none_or(|s| s == subject)
+    }
+}
+
+/// One rule result for one subject.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub rule: String,
+    pub subject: String,
+    pub active: bool,
+    pub message: String,
+}
+
+/// Advance alert state with a round of observations and return the
+/// notifications to send. Pure, so the state machine can be tested.
+pub fn step(
+    alerts: &mut BTreeMap<String, Alert>,
+    rules: &[AlertRule],
+    observations: Vec<Observation>,
+    silences: &[Silence],
+    repeat_secs: Option<u64>,
+    now: u64,
+) -> Vec<Notification> {
+    let mut out = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for ob in observations {
+        let key = format!("{}/{}", ob.rule, ob.subject);
+        seen.insert(key.clone());
+        let for_secs = rules
+            .iter()
+            .find(|r| r.name == ob.rule)
+            .and_then(|r| r.for_secs)
+            .unwrap_or(0);
+        let silenced = silences.iter().any(|s| s.matches(&ob.rule, &ob.subject, now));
+        let mut drop_pending = false;
+        match (alerts.get_mut(&key), ob.active) {
+            (None, true) => {
+                alerts.insert(
+                    key.clone(),
+                    Alert {
+                        rule: ob.rule.clone(),
+                        subject: ob.subject.clone(),
+                        status: AlertStatus::Pending,
+                        message: ob.message.clone(),
+                        since: now,
+                        fired_at: None,
+                        resolved_at: None,
+                        last_notified: None,
+                        silenced,
+                    },
+                );
+            }
+            (Some(a), true) if a.status == AlertStatus::Resolved => {
+                a.status = AlertStatus::Pending;
+                a.since = now;
+                a.fired_at = None;
+                a.resolved_at = None;
+                a.last_notified = None;
+            }
+            (Some(_), true) => (),
+            (Some(a), false) => {
+                match a.status {
+                    AlertStatus::Firing => {
+                        a.status = AlertStatus::Resolved;
+                        a.resolved_at = Some(now);
+                        a.message = ob.message.clone();
+                        // only tell people about a resolve if they heard it fire
+                        if a.last_notified.is_some() && !silenced {
+                            out.push(Notification::from_alert(a));
+                        }
+                    }
+                    // never fired, so there is nothing to resolve
+                    AlertStatus::Pending => drop_pending = true,
+                    AlertStatus::Resolved => (),
+                }
+            }
+            (None, false) => continue,
+        }
+        if !ob.active {
+            if drop_pending {
+                alerts.remove(&key);
+            }
+            continue;
+        }
+        // the condition holds: maybe promote or repeat
+        let a = alerts.get_mut(&key).unwrap();
+        a.message = ob.message;
+        a.silenced = silenced;
+        if a.status == AlertStatus::Pending && now.saturating_sub(a.since) >= for_secs {
+            a.status = AlertStatus::Firing;
+            a.fired_at = Some(now);
+        }
+        if a.status != AlertStatus::Firing || silenced {
+            continue;
+        }
+        let due = match (a.last_notified, repeat_secs) {
+            (None, _) => true,
+            (Some(last), Some(rep)) => now.saturating_sub(last) >= rep,
+            (Some(_), None) => false,
+        };
+        if due {
+            a.last_notified = Some(now);
+            out.push(Notification::from_alert(a));
+        }
+    }
+    // rules that were removed from the config, or subjects that went away
+    alerts.retain(|k, a| {
+        if a.status == AlertStatus::Resolved {
+            return now.saturating_sub(a.resolved_at.unwrap_or(now)) < KEEP_RESOLVED_SECS;
+        }
+        seen.contains(k) || rules.iter().any(|r| r.name == a.rule)
+    });
+    out
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+async fn evaluate(docker: &Docker, evaluator: &mut Evaluator) -> Result<()> {
+    let (cfg, host) = config::stack_read(|s| (s.alerts.clone(), s.host.clone())).await;
+    let cfg = match cfg {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    let now = now_secs();
+    let observations = evaluator.observe(docker, &cfg.rules, now).await?;
+    let notifications = {
+        let mut alerts = ALERTS.lock().await;
+        step(
+            &mut alerts,
+            &cfg.rules,
+            observations,
+            &cfg.silences,
+            cfg.repeat_secs,
+            now,
+        )
+    };
+    let host = host.unwrap_or_default();
+    let mut errs = Vec::new();
+    for n in notifications.iter() {
+        for notifier in cfg.notifiers.iter() {
+            if let Err(e) = notifier.send(n, &host).await {
+                errs.push(format!("{}: {}", notifier.kind(), e));
+            }
+        }
+    }
+    if errs.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(errs.join("\n")))
+    }
+}
+
+pub async fn alerts_cron(docker: Docker) -> Result<JobScheduler> {
+    log::info!(":alerts");
+    let sched = JobScheduler::new().await?;
+
+    let cron_time = match getenv("ALERTS_EVAL_CRON") {
+        Ok(env) => env,
+        Err(_) => "*/30 * * * * *".to_string(),
+    };
+
+    sched
+        .add(Job::new_async(cron_time.as_str(), |_uuid, _l| {
+            Box::pin(async move {
+                if !EVALUATE_ALERTS.load(Ordering::Relaxed) {
+                    EVALUATE_ALERTS.store(true, Ordering::Relaxed);
+                }
+            })
+        })?)
+        .await?;
+
+    sched.start().await?;
+
+    tokio::spawn(async move {
+        let mut evaluator = Evaluator::default();
+        loop {
+            let go = EVALUATE_ALERTS.load(Ordering::Relaxed);
+            if go {
+                let res = evaluate(&docker, &mut evaluator).await;
+                metrics::record_job("alerts", res.is_ok());
+                if let Err(e) = res {
+                    log::error!("Alerts: {:?}", e);
+                }
+                EVALUATE_ALERTS.store(false, Ordering::Relaxed);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
+    Ok(sched)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListAlertsResponse {
+    pub alerts: Vec<Alert>,
+    pub silences: Vec<Silence>,
+}
+
+pub async fn list_alerts() -> ListAlertsResponse {
+    let now = now_secs();
+    let silences = config::stack_read(|s| {
+        s.alerts
+            .as_ref()
+            .map(|a| a.silences.clone())
+            .unwrap_or_default()
+    })
+    .await
+    .into_iter()
+    .filter(|s| s.until > now)
+    .collect();
+    let alerts = ALERTS.lock().await.values().cloned().collect();
+    ListAlertsResponse { alerts, silences }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateSilenceRequest {
+    pub rule: Option<String>,
+    pub subject: Option<String>,
+    pub duration_secs: u64,
+    pub comment: Option<String>,
+}
+
+/// Add a silence to the stack config (expired ones are dropped on the way).
+pub async fn create_silence(
+    proj: &str,
+    req: CreateSilenceRequest,
+    user_id: &Option<u32>,
+) -> Result<Silence> {
+    if req.duration_secs == 0 {
+        return Err(anyhow!("duration_secs must be greater than 0"));
+    }
+    let now = now_secs();
+    let silence = Silence {
+        id: crate::secrets::hex_secret_32()[..16].to_string(),
+        rule: req.rule,
+        subject: req.subject,
+        until: now + req.duration_secs,
+        comment: req.comment,
+        created_by: *user_id,
+    };
+    let s = silence.clone();
+    config::stack_write(proj, |stack| {
+        let alerts = stack.alerts.get_or_insert_with(Default::default);
+        alerts.silences.retain(|s| s.until > now);
+        alerts.silences.push(s);
+    })
+    .await;
+    Ok(silence)
+}
+
+pub async fn delete_silence(proj: &str, id: &str) -> Result<()> {
+    let found = config::stack_write(proj, |stack| match stack.alerts.as_mut() {
+        Some(alerts) => {
+            let before = alerts.silences.len();
+            alerts.silences.retain(|s| s.id != id);
+            before != alerts.silences.len()
+        }
+        None => false,
+    })
+    .await;
+    if !found {
+        return Err(anyhow!("no silence with id {}", id));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, for_secs: u64) -> AlertRule {
+        AlertRule {
+            name: name.to_string(),
+            condition: Condition::ContainerDown {
+                container: "neo4j".to_string(),
+            },
+            for_secs: Some(for_secs),
+        }
+    }
+
+    fn ob(active: bool) -> Vec<Observation> {
+        vec![Observation {
+            rule: "neo4j-down".to_string(),
+            subject: "neo4j.sphinx".to_string(),
+            active,
+            message: "neo4j.sphinx is down".to_string(),
+        }]
+    }
+
+    #[test]
+    fn test_pending_then_firing_then_resolved() {
+        let rules = vec![rule("neo4j-down", 300)];
+        let mut alerts = BTreeMap::new();
+        assert!(step(&mut alerts, &rules, ob(true), &[], None, 1000).is_empty());
+        assert_eq!(alerts.values().next().unwrap().status, AlertStatus::Pending);
+        assert!(step(&mut alerts, &rules, ob(true), &[], None, 1200).is_empty());
+        let n = step(&mut alerts, &rules, ob(true), &[], None, 1300);
+        assert_eq!(n.len(), 1);
+        assert_eq!(n[0].status, AlertStatus::Firing);
+        // deduplicated while still firing
+        assert!(step(&mut alerts, &rules, ob(true), &[], None, 1400).is_empty());
+        let n = step(&mut alerts, &rules, ob(false), &[], None, 1500);
+        assert_eq!(n.len(), 1);
+        assert_eq!(n[0].status, AlertStatus::Resolved);
+    }
+
+    #[test]
+    fn test_pending_that_clears_never_notifies() {
+        let rules = vec![rule("neo4j-down", 300)];
+        let mut alerts = BTreeMap::new();
+        step(&mut alerts, &rules, ob(true), &[], None, 1000);
+        assert!(step(&mut alerts, &rules, ob(false), &[], None, 1100).is_empty());
+        assert!(alerts.is_empty());
+    }
+
+    #[test]
+    fn test_repeat_and_silence() {
+        let rules = vec![rule("neo4j-down", 0)];
+        let mut alerts = BTreeMap::new();
+        assert_eq!(step(&mut alerts, &rules, ob(true), &[], Some(600), 0).len(), 1);
+        assert!(step(&mut alerts, &rules, ob(true), &[], Some(600), 300).is_empty());
+        assert_eq!(step(&mut alerts, &rules, ob(true), &[], Some(600), 600).len(), 1);
+        let silence = Silence {
+            id: "s".to_string(),
+            rule: Some("neo4j-down".to_string()),
+            subject: None,
+            until: 5000,
+            comment: None,
+            created_by: None,
+        };
+        assert!(step(&mut alerts, &rules, ob(true), std::slice::from_ref(&silence), Some(600), 1200).is_empty());
+        assert!(alerts.values().next().unwrap().silenced);
+        assert!(step(&mut alerts, &rules, ob(false), &[silence], Some(600), 1300).is_empty());
+    }
+}
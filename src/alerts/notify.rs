@@ -0,0 +1,183 @@
+use super::{Alert, AlertStatus};
+use crate::utils::make_reqwest_client;
+use anyhow::{anyhow, Result};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notifier {
+    // POSTs the Notification as JSON
+    Webhook { url: String },
+    Smtp {
+        host: String,
+        // 587 with STARTTLS by default
+        port: Option<u16>,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+    // broadcast through a Sphinx tribe bot, like the super swarm checker
+    Tribe {
+        bot_url: String,
+        bot_id: String,
+        bot_secret: String,
+        chat_pubkey: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub status: AlertStatus,
+    pub rule: String,
+    pub subject: String,
+    pub message: String,
+    pub since: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_at: Option<u64>,
+}
+
+impl Notification {
+    pub fn from_alert(a: &Alert) -> Self {
+        Self {
+            status: a.status,
+            rule: a.rule.clone(),
+            subject: a.subject.clone(),
+            message: a.message.clone(),
+            since: a.since,
+            resolved_at: a.resolved_at,
+        }
+    }
+
+    fn title(&self, host: &str) -> String {
+        let status = match self.status {
+            AlertStatus::Resolved => "RESOLVED",
+            _ => "FIRING",
+        };
+        format!("[{}] {} on {}", status, self.rule, host)
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+    host: &'a str,
+    #[serde(flatten)]
+    notification: &'a Notification,
+}
+
+#[derive(Serialize)]
+struct BotMsgBody {
+    action: String,
+    bot_id: String,
+    bot_secret: String,
+    chat_uuid: String,
+    chat_pubkey: String,
+    content: String,
+}
+
+impl Notifier {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Notifier::Webhook { .. } => "webhook",
+            Notifier::Smtp { .. } => "smtp",
+            Notifier::Tribe { .. } => "tribe",
+        }
+    }
+
+    pub fn remove_secrets(&self) -> Self {
+        match self.clone() {
+            Notifier::Smtp {
+                host,
+                port,
+                username,
+                from,
+                to,
+                ..
+            } => Notifier::Smtp {
+                host,
+                port,
+                username,
+                password: None,
+                from,
+                to,
+            },
+            Notifier::Tribe {
+                bot_url,
+                bot_id,
+                chat_pubkey,
+                ..
+            } => Notifier::Tribe {
+                bot_url,
+                bot_id,
+                bot_secret: "".to_string(),
+                chat_pubkey,
+            },
+            n => n,
+        }
+    }
+
+    pub async fn send(&self, n: &Notification, host: &str) -> Result<()> {
+        match self {
+            Notifier::Webhook { url } => {
+                let res = make_reqwest_client()
+                    .post(url)
+                    .json(&WebhookBody {
+                        host,
+                        notification: n,
+                    })
+                    .send()
+                    .await?;
+                if !res.status().is_success() {
+                    return Err(anyhow!("webhook returned {}", res.status()));
+                }
+            }
+            Notifier::Smtp {
+                host: smtp_host,
+                port,
+                username,
+                password,
+                from,
+                to,
+            } => {
+                let mut builder = Message::builder()
+                    .from(from.parse::<Mailbox>()?)
+                    .subject(n.title(host));
+                for t in to {
+                    builder = builder.to(t.parse::<Mailbox>()?);
+                }
+                let email = builder.body(n.message.clone())?;
+                let mut transport =
+                    AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?
+                        .port(port.unwrap_or(587));
+                if let (Some(u), Some(p)) = (username, password) {
+                    transport = transport.credentials(Credentials::new(u.clone(), p.clone()));
+                }
+                transport.build().send(email).await?;
+            }
+            Notifier::Tribe {
+                bot_url,
+                bot_id,
+                bot_secret,
+                chat_pubkey,
+            } => {
+                let body = BotMsgBody {
+                    action: "broadcast".to_string(),
+                    bot_id: bot_id.clone(),
+                    bot_secret: bot_secret.clone(),
+                    chat_uuid: chat_pubkey.clone(),
+                    chat_pubkey: chat_pubkey.clone(),
+                    content: format!("{}\n{}", n.title(host), n.message),
+                };
+                make_reqwest_client()
+                    .post(bot_url)
+                    .json(&body)
+                    .send()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
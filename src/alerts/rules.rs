@@ -0,0 +1,254 @@
+use super::{AlertRule, Observation};
+use crate::config;
+use crate::disk;
+use crate::dock::{get_container_statistics, list_containers, sphinx_container, ContainerStat};
+use crate::metrics;
+use crate::utils::container_name;
+use anyhow::{Context, Result};
+use bollard::Docker;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+const HOUR: u64 = 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    // the container is not running
+    ContainerDown { container: String },
+    // memory usage over percent of the container limit (all containers if unset)
+    MemoryAbove {
+        container: Option<String>,
+        percent: f64,
+    },
+    // free space on the filesystem holding path (default "/")
+    DiskFreeBelow { path: Option<String>, percent: f64 },
+    // more than max restarts within the last hour
+    RestartsAbove { container: Option<String>, max: i64 },
+    // confirmed on-chain balance of a CLN node (all if unset)
+    ClnBalanceBelow { tag: Option<String>, sats: u64 },
+    // the last run of a scheduled job failed, e.g. "backup_volumes"
+    JobFailed { job: String },
+}
+
+struct ContainerInfo {
+    name: String,
+    running: bool,
+    restarts: i64,
+}
+
+/// Gathers what the rules need and keeps the history some rules compare
+/// against (restart counts over the last hour).
+#[derive(Default)]
+pub struct Evaluator {
+    restarts: HashMap<String, VecDeque<(u64, i64)>>,
+}
+
+impl Evaluator {
+    /// Fails rather than guessing when docker can't list the containers,
+    /// so a blip doesn't look like every container going down.
+    pub async fn observe(&mut self, docker: &Docker, rules: &[AlertRule], now: u64) -> Result<Vec<Observation>> {
+        let needs_containers = rules.iter().any(|r| {
+            matches!(
+                r.condition,
+                Condition::ContainerDown { .. } | Condition::RestartsAbove { .. }
+            )
+        });
+        let needs_stats = rules
+            .iter()
+            .any(|r| matches!(r.condition, Condition::MemoryAbove { .. }));
+
+        let containers = if needs_containers {
+            container_infos(docker).await?
+        } else {
+            Vec::new()
+        };
+        for c in containers.iter() {
+            let hist = self.restarts.entry(c.name.clone()).or_default();
+            hist.push_back((now, c.restarts));
+            while hist.len() > 1 && now.saturating_sub(hist[1].0) >= HOUR {
+                hist.pop_front();
+            }
+        }
+        let stats = if needs_stats {
+            get_container_statistics(docker, None).await.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        let mut out = Vec::new();
+        for rule in rules {
+            let mut push = |subject: &str, active: bool, message: String| {
+                out.push(Observation {
+                    rule: rule.name.clone(),
+                    subject: subject.to_string(),
+                    active,
+                    message,
+                })
+            };
+            match &rule.condition {
+                Condition::ContainerDown { container } => {
+                    let name = container_name(container);
+                    let running = containers
+                        .iter()
+                        .any(|c| c.name == name && c.running);
+                    let msg = if running {
+                        format!("{} is running", name)
+                    } else {
+                        format!("{} is down", name)
+                    };
+                    push(&name, !running, msg);
+                }
+                Condition::MemoryAbove { container, percent } => {
+                    for s in filter_stats(&stats, container) {
+                        if s.memory_limit == 0 {
+                            continue;
+                        }
+                        let used = memory_percent(s);
+                        push(
+                            &s.container_name,
+                            used > *percent,
+                            format!(
+                                "{} memory at {:.1}% of limit (threshold {}%)",
+                                s.container_name, used, percent
+                            ),
+                        );
+                    }
+                }
+                Condition::DiskFreeBelow { path, percent } => {
+                    let path = path.clone().unwrap_or("/".to_string());
+                    let free = disk::fs_usage(&path)
+                        .filter(|f| f.total_bytes > 0)
+                        .map(|f| f.free_percent());
+                    match free {
+                        Some(free) => push(
+                            &path,
+                            free < *percent,
+                            format!("{:.1}% disk free on {} (threshold {}%)", free, path, percent),
+                        ),
+                        None => log::warn!("alerts: could not read disk usage of {}", path),
+                    }
+                }
+                Condition::RestartsAbove { container, max } => {
+                    let wanted = container.as_ref().map(|c| container_name(c));
+                    for c in containers.iter() {
+                        if wanted.as_ref().is_some_and(|w| w != &c.name) {
+                            continue;
+                        }
+                        let n = self.restarts_in_last_hour(&c.name);
+                        push(
+                            &c.name,
+                            n > *max,
+                            format!("{} restarted {} times in the last hour", c.name, n),
+                        );
+                    }
+                }
+                Condition::ClnBalanceBelow { tag, sats } => {
+                    for (t, bal) in cln_balances(tag).await {
+                        push(
+                            &t,
+                            bal < *sats,
+                            format!("{} on-chain balance {} sats (threshold {})", t, bal, sats),
+                        );
+                    }
+                }
+                Condition::JobFailed { job } => {
+                    if let Some(ok) = metrics::last_job_ok(job) {
+                        let msg = if ok {
+                            format!("{} succeeded", job)
+                        } else {
+                            format!("{} failed", job)
+                        };
+                        push(job, !ok, msg);
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn restarts_in_last_hour(&self, name: &str) -> i64 {
+        match self.restarts.get(name) {
+            Some(h) if !h.is_empty() => h[h.len() - 1].1 - h[0].1,
+            _ => 0,
+        }
+    }
+}
+
+fn filter_stats<'a>(
+    stats: &'a [ContainerStat],
+    container: &Option<String>,
+) -> impl Iterator<Item = &'a ContainerStat> {
+    let wanted = container.as_ref().map(|c| container_name(c));
+    stats
+        .iter()
+        .filter(move |s| wanted.as_ref().is_none_or(|w| w == &s.container_name))
+}
+
+pub fn memory_percent(s: &ContainerStat) -> f64 {
+    if s.memory_limit == 0 {
+        return 0.0;
+    }
+    s.memory_usage as f64 / s.memory_limit as f64 * 100.0
+}
+
+async fn container_infos(docker: &Docker) -> Result<Vec<ContainerInfo>> {
+    let containers = list_containers(docker).await.context("list containers")?;
+    let mut out = Vec::new();
+    for c in containers.iter() {
+        let name = match sphinx_container(&c.names) {
+            Some(n) => n,
+            None => continue,
+        };
+        let restarts = match docker.inspect_container(&name, None).await {
+            Ok(info) => info.restart_count.unwrap_or(0),
+            Err(_) => 0,
+        };
+        out.push(ContainerInfo {
+            running: c.state.as_deref() == Some("running"),
+            name,
+            restarts,
+        });
+    }
+    Ok(out)
+}
+
+async fn cln_balances(tag: &Option<String>) -> Vec<(String, u64)> {
+    let clns = config::clients_read(|c| c.cln.clone()).await;
+    let mut out = Vec::new();
+    for (t, client) in clns.iter() {
+        if tag.as_ref().is_some_and(|w| w != t) {
+            continue;
+        }
+        if let Ok(funds) = client.list_funds().await {
+            // ListfundsOutputsStatus: 1 is confirmed
+            let sats: u64 = funds
+                .outputs
+                .iter()
+                .filter(|o| o.status == 1)
+                .map(|o| o.amount_msat.as_ref().map(|a| a.msat).unwrap_or(0) / 1000)
+                .sum();
+            out.push((t.clone(), sats));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_condition_yaml() {
+        let y = "name: neo4j-memory\nkind: memory_above\ncontainer: neo4j\npercent: 90\nfor_secs: 600\n";
+        let rule: AlertRule = serde_yaml::from_str(y).unwrap();
+        assert_eq!(rule.for_secs, Some(600));
+        assert_eq!(
+            rule.condition,
+            Condition::MemoryAbove {
+                container: Some("neo4j".to_string()),
+                percent: 90.0
+            }
+        );
+    }
+}
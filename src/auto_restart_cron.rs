@@ -4,7 +4,7 @@ use anyhow::{anyhow, Error, Result};
 use bollard::Docker;
 use tokio_cron_scheduler::{Job, JobScheduler};
 
-use crate::{dock::restart_node_container_global, utils::getenv};
+use crate::{dock::restart_node_container_global, metrics, utils::getenv};
 
 pub static RESTART_SERVICES: AtomicBool = AtomicBool::new(false);
 
@@ -37,10 +37,11 @@ pub async fn auto_restart_cron(
         loop {
             let go = RESTART_SERVICES.load(Ordering::Relaxed);
             if go {
-                if let Err(e) =
+                let res =
                     auto_restart_services_handler(&proj, &docker, auto_restart_services.clone())
-                        .await
-                {
+                        .await;
+                metrics::record_job("auto_restart", res.is_ok());
+                if let Err(e) = res {
                     log::error!("Error auto restarting services: {:?}", e);
                 }
 
@@ -1,23 +1,23 @@
+use crate::backup_catalog::{self, BackupRecord, BackupStatus, BACKUP_RUNNING};
+use crate::backup_chunks::{self, incremental_enabled};
+use crate::backup_hooks::{self, hooks_for, BackupHooks};
+use crate::backup_retention::{last_verified, prune_backups, SnapshotRef};
+use crate::backup_store::{apply_retention, backup_store, BackupStore};
 use crate::config;
 use crate::images::DockerHubImage;
+use crate::metrics;
 use crate::utils::{domain, getenv};
-use anyhow::{Context, Result};
-use aws_config::meta::region::RegionProviderChain;
-use aws_config::Region;
-use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier};
-use aws_sdk_s3::Client;
-use aws_smithy_types::byte_stream::{ByteStream, Length};
-use aws_smithy_types::retry::RetryConfig;
+use anyhow::{anyhow, Context, Result};
 use bollard::container::DownloadFromContainerOptions;
 use bollard::Docker;
-use chrono::{DateTime, Duration, Local, NaiveDateTime, Utc};
+use chrono::Local;
 use futures_util::stream::TryStreamExt;
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::fs::remove_dir_all;
-use tokio::io::BufWriter;
+use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio_cron_scheduler::{Job, JobScheduler};
 use tokio_util::io::StreamReader;
 use walkdir::WalkDir;
@@ -52,7 +52,7 @@ fn backup_retention_days() -> i64 {
 pub async fn backup_containers(backup_services: Vec<String>) -> Result<()> {
     let nodes = config::stack_read(|s| s.nodes.clone()).await;
 
-    let mut containers: Vec<(String, String, String)> = Vec::new();
+    let mut containers: Vec<(String, String, String, Option<BackupHooks>)> = Vec::new();
 
     log::info!("About to start get backup containers");
 
@@ -62,7 +62,12 @@ pub async fn backup_containers(backup_services: Vec<String>) -> Result<()> {
         match node.as_internal() {
             Ok(img) => {
                 if backup_services.contains(&node_name) {
-                    containers.push((hostname.clone(), img.repo().root_volume, node_name.clone()))
+                    containers.push((
+                        hostname.clone(),
+                        img.repo().root_volume,
+                        node_name.clone(),
+                        hooks_for(&img),
+                    ))
                 }
             }
             Err(_) => (),
@@ -71,13 +76,15 @@ pub async fn backup_containers(backup_services: Vec<String>) -> Result<()> {
 
     log::info!("Containers to be backed up: {:?}", containers);
 
-    download_and_zip_from_container(containers).await?;
+    let store = backup_store().await?;
+    download_and_zip_from_container(store.as_ref(), containers).await?;
 
     Ok(())
 }
 
 pub async fn download_and_zip_from_container(
-    containers: Vec<(String, String, String)>,
+    store: &dyn BackupStore,
+    containers: Vec<(String, String, String, Option<BackupHooks>)>,
 ) -> Result<()> {
     // Initialize the Docker client
     let docker = Docker::connect_with_local_defaults()?;
@@ -94,68 +101,156 @@ pub async fn download_and_zip_from_container(
 
     log::info!("Directory was created!!!");
 
-    // Iterate over each container and download its volume
-    for (container_id, volume_path, sub_directory) in containers {
-        // Options for downloading the volume
-        let options = DownloadFromContainerOptions { path: &volume_path };
-
-        // Stream the tar content from the container
-        let stream = docker.download_from_container(&container_id, Some(options));
-
-        let body_with_io_error =
-            stream.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
-
-        let body_reader = StreamReader::new(body_with_io_error);
+    let mut failed = Vec::new();
 
-        futures::pin_mut!(body_reader);
+    // chunks already in the store are not uploaded again
+    let incremental = incremental_enabled();
+    // gc can't delete a known chunk before this backup's manifest lands
+    let _chunks = if incremental {
+        Some(backup_chunks::CHUNKS_LOCK.lock().await)
+    } else {
+        None
+    };
+    let mut known = if incremental {
+        backup_chunks::known_chunks(store, &parent_directory).await?
+    } else {
+        Default::default()
+    };
 
+    // Iterate over each container and download its volume
+    for (container_id, volume_path, sub_directory, hooks) in containers {
         let subdirectory = format!("{}/{}", &s3_parent_directory, &sub_directory);
 
         fs::create_dir_all(&subdirectory)?;
 
         let tar_file_name = format!("{}/{}.tar", subdirectory, &sub_directory);
 
-        let mut file = BufWriter::new(tokio::fs::File::create(tar_file_name).await?);
-
-        tokio::io::copy(&mut body_reader, &mut file).await?;
-
-        upload_final_zip_to_s3(
-            format!(
-                "{}/{}/{}.tar",
-                &s3_parent_directory, &sub_directory, &sub_directory
-            ),
-            format!(
-                "{}/{}/{}/{}.tar",
-                &parent_directory, &current_date, &sub_directory, &sub_directory
-            ),
-        )
-        .await?;
+        // quiesce the service so the tar is consistent
+        let pre = match &hooks {
+            Some(h) => backup_hooks::run_pre(&docker, &container_id, h).await,
+            None => Ok(()),
+        };
+        let tarred = match pre {
+            Ok(()) => download_tar(&docker, &container_id, &volume_path, &tar_file_name).await,
+            Err(e) => Err(e.context("pre-hook failed")),
+        };
+        if let Some(h) = &hooks {
+            if let Err(e) = backup_hooks::run_post(&docker, &container_id, h).await {
+                log::error!("{:?}", e);
+            }
+        }
+        if let Err(err) = tarred {
+            log::error!("Error backing up {}: {:?}", container_id, err);
+            record_backup(&sub_directory, &current_date, None, 0, None, Some(err.to_string())).await;
+            failed.push(sub_directory);
+            continue;
+        }
 
-        log::info!(
-            "Volume from container {} downloaded, saved as a TAR file in directory {} and pushed to AWS S3 Buckey",
-            container_id,
-            subdirectory
+        let tar_path = format!(
+            "{}/{}/{}.tar",
+            &s3_parent_directory, &sub_directory, &sub_directory
+        );
+        let key = format!(
+            "{}/{}/{}/{}.{}",
+            &parent_directory,
+            &current_date,
+            &sub_directory,
+            &sub_directory,
+            if incremental { backup_chunks::MANIFEST_EXT } else { "tar" }
         );
+        let size = fs::metadata(&tar_path).map(|m| m.len()).unwrap_or(0);
+        let sum_path = tar_path.clone();
+        let checksum = tokio::task::spawn_blocking(move || backup_catalog::sha256_file(Path::new(&sum_path)))
+            .await
+            .ok()
+            .and_then(|r| r.ok());
+        let uploaded = if incremental {
+            backup_chunks::put_snapshot(
+                store,
+                &parent_directory,
+                &sub_directory,
+                Path::new(&tar_path),
+                &key,
+                &mut known,
+            )
+            .await
+            .map(|(manifest, bytes)| {
+                log::info!("{}: uploaded {} of {} bytes", sub_directory, bytes, manifest.size);
+            })
+        } else {
+            store.put(Path::new(&tar_path), &key).await
+        };
+        let err = uploaded.as_ref().err().map(|e| e.to_string());
+        record_backup(&sub_directory, &current_date, Some(key.clone()), size, checksum, err).await;
+        match uploaded {
+            Ok(()) => {
+                let _ = fs::remove_file(&tar_path);
+                log::info!(
+                    "Volume from container {} downloaded, saved as a TAR file in directory {} and pushed to {}",
+                    container_id,
+                    subdirectory,
+                    store.describe()
+                );
+            }
+            Err(err) => {
+                log::error!("Error uploading {} to {}: {:?}", key, store.describe(), err);
+                failed.push(sub_directory);
+            }
+        }
     }
 
     // delete folder
     let _ = remove_dir_all(&s3_parent_directory).await;
 
+    if !failed.is_empty() {
+        return Err(anyhow!("backup failed for {:?}", failed));
+    }
     Ok(())
 }
 
-async fn upload_final_zip_to_s3(parent_zip: String, key: String) -> Result<()> {
-    match upload_to_s3_multi(&bucket_name(), &parent_zip.clone(), &key.clone()).await {
-        Ok(status) => {
-            if status == true {
-                let _ = fs::remove_file(parent_zip);
-            }
-        }
-        Err(err) => {
-            log::error!("We are getting somewhere: {}", err)
-        }
-    }
+async fn record_backup(
+    node: &str,
+    snapshot: &str,
+    key: Option<String>,
+    size: u64,
+    checksum: Option<String>,
+    error: Option<String>,
+) {
+    let status = match error {
+        Some(_) => BackupStatus::Failed,
+        None => BackupStatus::Ok,
+    };
+    backup_catalog::record(BackupRecord {
+        node: node.to_string(),
+        snapshot: snapshot.to_string(),
+        key: if error.is_some() { None } else { key },
+        size,
+        checksum,
+        created: chrono::Utc::now().timestamp(),
+        status,
+        error,
+        verification: None,
+    })
+    .await;
+}
+
+pub async fn download_tar(docker: &Docker, container_id: &str, volume_path: &str, tar_file_name: &str) -> Result<()> {
+    // Options for downloading the volume
+    let options = DownloadFromContainerOptions { path: volume_path };
+
+    // Stream the tar content from the container
+    let stream = docker.download_from_container(container_id, Some(options));
+
+    let body_with_io_error = stream.map_err(std::io::Error::other);
+
+    let body_reader = StreamReader::new(body_with_io_error);
 
+    futures::pin_mut!(body_reader);
+
+    let mut file = BufWriter::new(tokio::fs::File::create(tar_file_name).await?);
+
+    tokio::io::copy(&mut body_reader, &mut file).await?;
+    file.flush().await?;
     Ok(())
 }
 
@@ -187,247 +282,32 @@ pub fn zip_directory(src_dir: &str, zip_file: &str) -> Result<()> {
     Ok(())
 }
 
-async fn upload_to_s3_multi(bucket: &str, file_path: &str, key: &str) -> Result<bool> {
-    //In bytes, minimum chunk size of 150MB.
-    const CHUNK_SIZE: u64 = 1024 * 1024 * 150;
-    const MAX_CHUNKS: u64 = 10000;
-
-    // Read the custom region environment variable
-    let region = match getenv("AWS_REGION") {
-        Ok(value) => value,
-        Err(_msg) => {
-            log::error!("AWS_REGION is not provided in environment variable");
-            return Ok(false);
-        }
-    };
-
-    // Create a region provider chain
-    let region_provider = RegionProviderChain::first_try(Some(Region::new(region)));
-
-    // Load the AWS configuration
-    let config = aws_config::from_env()
-        .region(region_provider)
-        .retry_config(RetryConfig::standard().with_max_attempts(10))
-        .load()
-        .await;
-    let client = Client::new(&config);
-
-    let result = client
-        .create_multipart_upload()
-        .bucket(bucket)
-        .key(key)
-        .send()
-        .await;
-
-    // CreateMultipartUploadOutput
-
-    let multipart_upload_res = match result {
-        Ok(response) => response,
-        Err(err) => {
-            log::error!("Error creating multipart: {:?}", err);
-            return Ok(false);
-        }
-    };
-
-    let upload_id = match multipart_upload_res.upload_id() {
-        Some(id) => id,
-        None => {
-            log::error!("Upload ID not found");
-            return Ok(false);
-        }
-    };
-
-    let path = Path::new(&file_path);
-    let file_size = tokio::fs::metadata(path)
-        .await
-        .expect(&format!(
-            "unable to find file to upload in this path: {}",
-            file_path
-        ))
-        .len();
-
-    let mut chunk_count = (file_size / CHUNK_SIZE) + 1;
-    let mut size_of_last_chunk = file_size % CHUNK_SIZE;
-    if size_of_last_chunk == 0 {
-        size_of_last_chunk = CHUNK_SIZE;
-        chunk_count -= 1;
-    }
-
-    if file_size == 0 {
-        log::error!("Invalid file, file size is 0");
-        return Ok(false);
-    }
-    if chunk_count > MAX_CHUNKS {
-        log::error!("Too many chunks! Try increasing your chunk size.");
-        return Ok(false);
-    }
-
-    let file_size_mb = file_size / (1024 * 1024);
-    log::info!(
-        "S3 upload: {} ({}MB, {} chunks)",
-        key,
-        file_size_mb,
-        chunk_count
-    );
-
-    let mut upload_parts: Vec<CompletedPart> = Vec::new();
-
-    for chunk_index in 0..chunk_count {
-        let this_chunk = if chunk_count - 1 == chunk_index {
-            size_of_last_chunk
-        } else {
-            CHUNK_SIZE
-        };
-        let stream = ByteStream::read_from()
-            .path(path)
-            .offset(chunk_index * CHUNK_SIZE)
-            .length(Length::Exact(this_chunk))
-            .build()
-            .await?;
-
-        //Chunk index needs to start at 0, but part numbers start at 1.
-        let part_number = (chunk_index as i32) + 1;
-
-        let upload_part_res = match client
-            .upload_part()
-            .key(key)
-            .bucket(bucket)
-            .upload_id(upload_id)
-            .body(stream)
-            .part_number(part_number)
-            .send()
-            .await
-        {
-            Ok(res) => res,
-            Err(e) => {
-                log::error!("Error uploading part: {:?}", e);
-                return Ok(false);
-            }
-        };
-        let progress = ((chunk_index + 1) as f64 / chunk_count as f64) * 100.0;
-        log::info!(
-            "S3 upload: {}/{} chunks ({:.0}%)",
-            chunk_index + 1,
-            chunk_count,
-            progress
-        );
-        upload_parts.push(
-            CompletedPart::builder()
-                .e_tag(upload_part_res.e_tag.unwrap_or_default())
-                .part_number(part_number)
-                .build(),
-        );
-    }
-
-    let completed_multipart_upload: CompletedMultipartUpload = CompletedMultipartUpload::builder()
-        .set_parts(Some(upload_parts))
-        .build();
-
-    let _complete_multipart_upload_res = match client
-        .complete_multipart_upload()
-        .bucket(bucket)
-        .key(key)
-        .multipart_upload(completed_multipart_upload)
-        .upload_id(upload_id)
-        .send()
-        .await
-    {
-        Ok(res) => res,
-        Err(err) => {
-            log::error!("Error completing multipart: {:?}", err);
-            return Ok(false);
-        }
-    };
-
-    Ok(true)
-}
-
-// Deletes old backups from the S3 bucket
-pub async fn delete_old_backups(bucket: &str, retention_days: i64) -> Result<()> {
+// Deletes old backups from the backup store
+pub async fn delete_old_backups(store: &dyn BackupStore, retention_days: i64) -> Result<()> {
     let swarm_number = getenv("SWARM_NUMBER")?;
     let prefix = format!("swarm{}", swarm_number);
-    delete_old_backups_with_prefix(bucket, retention_days, &prefix).await
-}
-
-async fn delete_old_backups_with_prefix(
-    bucket: &str,
-    retention_days: i64,
-    prefix: &str,
-) -> Result<()> {
-    // Read the custom region environment variable
-    let region = getenv("AWS_REGION")?;
-
-    // Create a region provider chain
-    let region_provider = RegionProviderChain::first_try(Some(Region::new(region)));
-
-    // Load the AWS configuration with the custom region
-    let config = aws_config::from_env().region(region_provider).load().await;
-    let client = Client::new(&config);
-
-    let object_prefix = format!("{}/", prefix);
-
-    // List objects in the bucket
-    let resp = client
-        .list_objects_v2()
-        .bucket(bucket)
-        .prefix(object_prefix)
-        .send()
-        .await?;
-
-    let objects = resp.contents();
-
-    if objects.len() > 12 {
-        // Filter objects older than retention_days
-        let retention_date = Utc::now() - Duration::days(retention_days);
-        let mut objects_to_delete = Vec::new();
-
-        for obj in objects {
-            if let Some(last_modified) = obj.last_modified {
-                let last_modified_timestamp = last_modified.secs();
-                let naive_datetime = NaiveDateTime::from_timestamp_opt(last_modified_timestamp, 0)
-                    .context("Invalid timestamp")?;
-                let last_modified_chrono: DateTime<Utc> =
-                    DateTime::from_naive_utc_and_offset(naive_datetime, Utc);
-
-                if last_modified_chrono < retention_date {
-                    if let Some(key) = &obj.key {
-                        let object_identifier_result = ObjectIdentifier::builder().key(key).build();
-                        match object_identifier_result {
-                            Ok(object_identifier) => {
-                                objects_to_delete.push(object_identifier);
-                            }
-                            Err(_) => {
-                                print!("Could not build object correctly")
-                            }
-                        }
-                    }
-                }
-            }
+    match config::stack_read(|s| s.backup_retention.clone()).await {
+        Some(retention) => {
+            prune_backups(store, &retention, false).await?;
         }
-
-        if !objects_to_delete.is_empty() {
-            // Delete old objects
-            let delete_request = client
-                .delete_objects()
-                .bucket(bucket)
-                .delete(
-                    Delete::builder()
-                        .set_objects(Some(objects_to_delete))
-                        .build()?,
-                )
-                .send()
-                .await?;
-
-            log::info!(
-                "Deleted {} old objects from bucket {}",
-                delete_request.deleted().len(),
-                bucket
-            );
-        } else {
-            log::info!("No old objects to delete in bucket {}", bucket);
+        None => {
+            // never drop the last snapshot a test restore passed on
+            let verified = last_verified(&backup_catalog::load_catalog().await);
+            let protected = |key: &str| {
+                backup_catalog::parse_key(key)
+                    .map(|(snapshot, node)| verified.contains(&SnapshotRef { node, snapshot }))
+                    .unwrap_or(false)
+            };
+            apply_retention(store, &prefix, retention_days, &protected).await?;
         }
     }
-
+    // chunks are shared between snapshots, so they go by reference not age
+    if incremental_enabled() {
+        let work_dir = format!("{}_gc", &prefix);
+        let res = backup_chunks::gc_chunks(store, &prefix, Path::new(&work_dir)).await;
+        let _ = remove_dir_all(&work_dir).await;
+        res?;
+    }
     Ok(())
 }
 
@@ -450,11 +330,20 @@ pub async fn backup_and_delete_volumes_cron(backup_services: Vec<String>) -> Res
     tokio::spawn(async move {
         loop {
             let go = BACK_AND_DELETE.load(Ordering::Relaxed);
-            if go {
-                if let Err(e) = backup_containers(backup_services.clone()).await {
+            // a BackupNow still running, try again on the next tick
+            if go && !BACKUP_RUNNING.swap(true, Ordering::SeqCst) {
+                let res = backup_containers(backup_services.clone()).await;
+                BACKUP_RUNNING.store(false, Ordering::SeqCst);
+                metrics::record_job("backup_volumes", res.is_ok());
+                if let Err(e) = res {
                     log::error!("Backup Volumes: {:?}", e);
                 }
-                if let Err(e) = delete_old_backups(&bucket_name(), backup_retention_days()).await {
+                let res = match backup_store().await {
+                    Ok(store) => delete_old_backups(store.as_ref(), backup_retention_days()).await,
+                    Err(e) => Err(e),
+                };
+                metrics::record_job("delete_old_backups", res.is_ok());
+                if let Err(e) = res {
                     log::error!("Delete Old backup volumes: {:?}", e);
                 }
 
@@ -509,7 +398,7 @@ async fn backup_single_file(entry: &BackupFileEntry) -> Result<()> {
     let mb_copied = bytes_copied / (1024 * 1024);
     log::info!("backup_file: copy complete ({}MB)", mb_copied);
 
-    // upload directly to S3
+    // upload directly to the backup store
     let current_date = Local::now().format("%Y-%m-%d").to_string();
     let parent_directory = swarm_prefix_from_host()?;
     let file_name = Path::new(&entry.file_path)
@@ -521,18 +410,18 @@ async fn backup_single_file(entry: &BackupFileEntry) -> Result<()> {
         &parent_directory, &current_date, &entry.name, file_name
     );
 
+    let store = backup_store().await?;
     log::info!(
-        "backup_file: uploading {} to s3://{}",
+        "backup_file: uploading {} to {}/{}",
         &backup_path,
+        store.describe(),
         &s3_key
     );
-    match upload_to_s3_multi(&bucket_name(), &backup_path, &s3_key).await {
-        Ok(_) => {}
-        Err(err) => log::error!("backup_file: S3 upload error: {}", err),
-    }
+    let res = store.put(Path::new(&backup_path), &s3_key).await;
 
     // rm the temp backup file
     let _ = tokio::fs::remove_file(&backup_path).await;
+    res?;
 
     log::info!(
         "backup_file: completed backup of {} from {}",
@@ -587,7 +476,9 @@ pub async fn backup_files_cron(backup_files: Vec<String>) -> Result<Vec<JobSched
             };
             loop {
                 if trigger_for_loop.load(Ordering::Relaxed) {
-                    if let Err(e) = backup_single_file(&entry).await {
+                    let res = backup_single_file(&entry).await;
+                    metrics::record_job("backup_file", res.is_ok());
+                    if let Err(e) = res {
                         log::error!(
                             "backup_file error for {} {}: {:?}",
                             &entry.name,
@@ -596,13 +487,14 @@ pub async fn backup_files_cron(backup_files: Vec<String>) -> Result<Vec<JobSched
                         );
                     }
                     if let Ok(prefix) = swarm_prefix_from_host() {
-                        if let Err(e) = delete_old_backups_with_prefix(
-                            &bucket_name(),
-                            backup_retention_days(),
-                            &prefix,
-                        )
-                        .await
-                        {
+                        let res = match backup_store().await {
+                            Ok(store) => {
+                                apply_retention(store.as_ref(), &prefix, backup_retention_days(), &|_| false)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = res {
                             log::error!("Delete old backup_file backups: {:?}", e);
                         }
                     }
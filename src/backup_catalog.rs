@@ -0,0 +1,534 @@
+use crate::backup::backup_containers;
+use crate::backup_chunks::{self, Manifest, MANIFEST_EXT};
+use crate::backup_hooks;
+use crate::backup_store::{backup_store, BackupStore, StoredObject};
+use crate::builder::find_img;
+use crate::config;
+use crate::dock;
+use crate::images::DockerHubImage;
+use crate::utils::{domain, getenv, volume_string};
+use anyhow::{anyhow, Context, Result};
+use bollard::container::Config;
+use bollard::models::{HealthStatusEnum, HostConfig};
+use bollard::Docker;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Mutex;
+
+// backups run in the stack project
+const PROJ: &str = "stack";
+const MAX_RECORDS: usize = 1000;
+const HELPER_IMAGE: &str = "alpine:3.19";
+const SAFETY_MOUNT: &str = "/safety";
+// a restored node must stay up this many checks in a row, 2s apart
+const HEALTHY_CHECKS: u32 = 5;
+const MAX_HEALTH_CHECKS: u32 = 60;
+
+pub static BACKUP_RUNNING: AtomicBool = AtomicBool::new(false);
+
+static CATALOG_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupStatus {
+    Ok,
+    Failed,
+    // in the store but not taken by this host, e.g. after a migration
+    Unverified,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupRecord {
+    pub node: String,
+    // the date folder, pass it to RestoreBackup
+    pub snapshot: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub size: u64,
+    // sha256 of the volume tar
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    // unix seconds
+    pub created: i64,
+    pub status: BackupStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // the last test restore of this snapshot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Verification {
+    // unix seconds
+    pub at: i64,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Progress of a RestoreBackup, sent on the event stream.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RestoreEvent {
+    // always "restore_backup"
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub node: String,
+    pub snapshot: String,
+    // fetching, stopping, copying, starting, done, rolled_back or failed
+    pub stage: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safety_volume: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RestoreReport {
+    pub node: String,
+    pub snapshot: String,
+    // the volume as it was before the restore, remove it once happy
+    pub safety_volume: String,
+}
+
+fn catalog_path() -> String {
+    format!("vol/{}/backups.json", PROJ)
+}
+
+pub async fn load_catalog() -> Vec<BackupRecord> {
+    crate::utils::load_json(&catalog_path(), Vec::new()).await
+}
+
+/// Remember a backup attempt, replacing an earlier one of the same snapshot.
+pub async fn record(rec: BackupRecord) {
+    let _lock = CATALOG_LOCK.lock().await;
+    let mut recs = load_catalog().await;
+    recs.retain(|r| !(r.node == rec.node && r.snapshot == rec.snapshot));
+    recs.push(rec);
+    let skip = recs.len().saturating_sub(MAX_RECORDS);
+    let recs: Vec<BackupRecord> = recs.into_iter().skip(skip).collect();
+    crate::utils::put_json(&catalog_path(), &recs).await;
+}
+
+/// Attach a verification result to a snapshot, adding it to the catalog
+/// if it was only in the store.
+pub async fn set_verification(rec: &BackupRecord, v: Verification) {
+    let _lock = CATALOG_LOCK.lock().await;
+    let mut recs = load_catalog().await;
+    match recs.iter_mut().find(|r| r.node == rec.node && r.snapshot == rec.snapshot) {
+        Some(r) => r.verification = Some(v),
+        None => recs.push(BackupRecord {
+            verification: Some(v),
+            ..rec.clone()
+        }),
+    }
+    crate::utils::put_json(&catalog_path(), &recs).await;
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn swarm_prefix() -> Result<String> {
+    Ok(format!("swarm{}", getenv("SWARM_NUMBER")?))
+}
+
+// "swarm7/2024-05-01/neo4j/neo4j.tar" -> ("2024-05-01", "neo4j")
+pub fn parse_key(key: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = key.split('/').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    let (snapshot, node, file) = (parts[1], parts[2], parts[3]);
+    if file != format!("{}.tar", node) && file != format!("{}.{}", node, MANIFEST_EXT) {
+        return None;
+    }
+    Some((snapshot.to_string(), node.to_string()))
+}
+
+/// What is in the store for `node`, joined with what this host recorded.
+/// Failed attempts have no object, so they only come from the records.
+pub fn merge_catalog(node: &str, objects: &[StoredObject], records: &[BackupRecord]) -> Vec<BackupRecord> {
+    let mut out: Vec<BackupRecord> = Vec::new();
+    for o in objects.iter() {
+        let (snapshot, n) = match parse_key(&o.key) {
+            Some(p) => p,
+            None => continue,
+        };
+        if n != node {
+            continue;
+        }
+        match records.iter().find(|r| r.node == node && r.snapshot == snapshot) {
+            Some(r) if r.status != BackupStatus::Failed => out.push(r.clone()),
+            _ => out.push(BackupRecord {
+                node: node.to_string(),
+                snapshot,
+                key: Some(o.key.clone()),
+                size: o.size,
+                checksum: None,
+                created: o.last_modified,
+                status: BackupStatus::Unverified,
+                error: None,
+                verification: None,
+            }),
+        }
+    }
+    for r in records.iter() {
+        if r.node == node && r.status == BackupStatus::Failed && !out.iter().any(|o| o.snapshot == r.snapshot) {
+            out.push(r.clone());
+        }
+    }
+    out.sort_by_key(|b| std::cmp::Reverse(b.created));
+    out
+}
+
+pub async fn list_backups(node: &str) -> Result<Vec<BackupRecord>> {
+    let store = backup_store().await?;
+    let objects = store.list(&format!("{}/", swarm_prefix()?)).await?;
+    let records = load_catalog().await;
+    Ok(merge_catalog(node, &objects, &records))
+}
+
+/// Back up `nodes` in the background. Only one backup runs at a time.
+pub fn backup_now(nodes: Vec<String>) -> Result<()> {
+    if nodes.is_empty() {
+        return Err(anyhow!("no nodes to back up"));
+    }
+    if BACKUP_RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(anyhow!("a backup is already running"));
+    }
+    tokio::spawn(async move {
+        if let Err(e) = backup_containers(nodes).await {
+            log::error!("BackupNow: {:?}", e);
+        }
+        BACKUP_RUNNING.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// The tar, or the tar rebuilt from chunks, for one snapshot, in `dir`.
+pub async fn fetch_tar(store: &dyn BackupStore, swarm: &str, node: &str, snapshot: &str, dir: &str) -> Result<String> {
+    tokio::fs::create_dir_all(dir).await?;
+    let base = format!("{}/{}/{}/{}", swarm, snapshot, node, node);
+    let tar = format!("{}/{}.tar", dir, node);
+    let objects = store.list(&format!("{}/{}/{}/", swarm, snapshot, node)).await?;
+    let tar_key = format!("{}.tar", base);
+    let manifest_key = format!("{}.{}", base, MANIFEST_EXT);
+    if objects.iter().any(|o| o.key == tar_key) {
+        store.get(&tar_key, Path::new(&tar)).await?;
+    } else if objects.iter().any(|o| o.key == manifest_key) {
+        let path = format!("{}/{}.{}", dir, node, MANIFEST_EXT);
+        store.get(&manifest_key, Path::new(&path)).await?;
+        let manifest: Manifest = serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+        backup_chunks::restore_snapshot(store, swarm, &manifest, Path::new(&tar)).await?;
+    } else {
+        return Err(anyhow!("no backup of {} at {}", node, snapshot));
+    }
+    Ok(tar)
+}
+
+/// Compare a fetched tar with the checksum recorded when it was taken.
+/// Snapshots this host didn't take have none, and pass.
+pub async fn check_checksum(node: &str, snapshot: &str, tar: &str) -> Result<()> {
+    let recorded = load_catalog()
+        .await
+        .into_iter()
+        .find(|r| r.node == node && r.snapshot == snapshot)
+        .and_then(|r| r.checksum);
+    if let Some(sum) = recorded {
+        let path = tar.to_string();
+        let got = tokio::task::spawn_blocking(move || sha256_file(Path::new(&path))).await??;
+        if got != sum {
+            return Err(anyhow!("checksum mismatch for {} at {}", node, snapshot));
+        }
+    }
+    Ok(())
+}
+
+/// A throwaway container with `binds` mounted, to exec file work into volumes.
+pub async fn start_helper(docker: &Docker, host: &str, binds: Vec<String>) -> Result<String> {
+    let host = host.to_string();
+    let c = Config {
+        image: Some(HELPER_IMAGE.to_string()),
+        hostname: Some(host.clone()),
+        cmd: Some(vec!["sleep".to_string(), "infinity".to_string()]),
+        host_config: Some(HostConfig {
+            binds: Some(binds),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    dock::create_image(docker, &c).await?;
+    // a leftover from an earlier failed restore
+    let _ = dock::stop_and_remove(docker, &host).await;
+    let id = dock::create_container(docker, c).await?;
+    dock::start_container(docker, &id).await?;
+    Ok(host)
+}
+
+// the node's volume and the safety volume
+fn restore_binds(node: &str, root_volume: &str, safety: &str) -> Vec<String> {
+    vec![
+        volume_string(node, root_volume),
+        format!("{}:{}:rw", safety, SAFETY_MOUNT),
+    ]
+}
+
+async fn wait_healthy(docker: &Docker, host: &str) -> Result<()> {
+    let mut good = 0;
+    for _ in 0..MAX_HEALTH_CHECKS {
+        dock::sleep(2000).await;
+        let state = docker.inspect_container(host, None).await?.state.unwrap_or_default();
+        let health = state.health.and_then(|h| h.status);
+        if health == Some(HealthStatusEnum::UNHEALTHY) {
+            return Err(anyhow!("{} is unhealthy", host));
+        }
+        let up = state.running == Some(true)
+            && state.restarting != Some(true)
+            && matches!(health, None | Some(HealthStatusEnum::HEALTHY) | Some(HealthStatusEnum::NONE) | Some(HealthStatusEnum::EMPTY));
+        good = if up { good + 1 } else { 0 };
+        if good >= HEALTHY_CHECKS {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("{} did not come up healthy", host))
+}
+
+// snapshots are date folders, nothing else may end up in a path
+pub fn check_snapshot(snapshot: &str) -> Result<()> {
+    let ok = chrono::NaiveDate::parse_from_str(snapshot, "%Y-%m-%d")
+        .map(|d| d.format("%Y-%m-%d").to_string() == snapshot)
+        .unwrap_or(false);
+    if !ok {
+        return Err(anyhow!("snapshot must be a YYYY-MM-DD date"));
+    }
+    Ok(())
+}
+
+async fn progress(node: &str, snapshot: &str, stage: &str, safety: Option<&str>, error: Option<String>) {
+    let ev = RestoreEvent {
+        kind: "restore_backup".to_string(),
+        node: node.to_string(),
+        snapshot: snapshot.to_string(),
+        stage: stage.to_string(),
+        safety_volume: safety.map(|s| s.to_string()),
+        error,
+    };
+    log::info!("RestoreBackup: {:?}", ev);
+    match serde_json::to_string(&ev) {
+        Ok(msg) => crate::events::send_event(msg).await,
+        Err(e) => log::error!("can't serialize restore event: {}", e),
+    }
+}
+
+/// Restore in the background, reporting progress on the event stream.
+/// Doesn't start while a backup or another restore is running.
+pub async fn restore_now(docker: Docker, node: String, snapshot: String) -> Result<()> {
+    check_snapshot(&snapshot)?;
+    config::stack_read(|s| find_img(&node, &s.nodes)).await?;
+    if BACKUP_RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(anyhow!("a backup or restore is already running"));
+    }
+    tokio::spawn(async move {
+        let res = restore_backup(&docker, &node, &snapshot).await;
+        match res {
+            Ok(r) => progress(&node, &snapshot, "done", Some(&r.safety_volume), None).await,
+            Err(e) => {
+                log::error!("RestoreBackup: {:?}", e);
+                progress(&node, &snapshot, "failed", None, Some(format!("{:#}", e))).await
+            }
+        }
+        BACKUP_RUNNING.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// Stop `node`, keep its volume in a safety volume, unpack `snapshot`
+/// over it and start it again. If it doesn't come up healthy the safety
+/// copy is put back; whatever fails, the node isn't left stopped.
+pub async fn restore_backup(docker: &Docker, node: &str, snapshot: &str) -> Result<RestoreReport> {
+    check_snapshot(snapshot)?;
+    let img = config::stack_read(|s| find_img(node, &s.nodes)).await?;
+    let root_volume = img.repo().root_volume;
+    let inner_root_path = dock::get_last_segment(&root_volume).to_string();
+    let swarm = swarm_prefix()?;
+    let store = backup_store().await?;
+
+    let dir = format!("restore_{}_{}", node, snapshot);
+    progress(node, snapshot, "fetching", None, None).await;
+    let res = fetch_tar(store.as_ref(), &swarm, node, snapshot, &dir).await;
+    let tar = match res {
+        Ok(t) => t,
+        Err(e) => {
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+            return Err(e);
+        }
+    };
+    if let Err(e) = check_checksum(node, snapshot, &tar).await {
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        return Err(e);
+    }
+
+    let host = domain(node);
+    let safety = format!("{}-safety-{}", host, Utc::now().timestamp());
+    log::info!("RestoreBackup: stopping {}, safety copy in {}", host, safety);
+    progress(node, snapshot, "stopping", Some(&safety), None).await;
+    dock::stop_container(docker, &host).await.context("stop node")?;
+
+    // from here on the node is started again whatever fails
+    let mut saved = false;
+    let res = async {
+        dock::create_volume(docker, &safety).await?;
+        progress(node, snapshot, "copying", Some(&safety), None).await;
+        let helper = start_helper(docker, &format!("{}-restore", host), restore_binds(node, &root_volume, &safety)).await?;
+        let copied = async {
+            dock::exec_checked(docker, &helper, &format!("cp -a {}/. {}/", root_volume, SAFETY_MOUNT)).await?;
+            saved = true;
+            dock::copy_data_to_volume(docker, &helper, node, &inner_root_path, &root_volume, &tar)
+                .await
+                .map_err(|e| anyhow!("copy to volume: {}", e))?;
+            backup_hooks::run_after_restore(docker, &helper, &img).await
+        }
+        .await;
+        let _ = dock::stop_and_remove(docker, &helper).await;
+        copied?;
+        progress(node, snapshot, "starting", Some(&safety), None).await;
+        dock::start_container(docker, &host).await?;
+        wait_healthy(docker, &host).await
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+
+    if let Err(e) = res {
+        log::error!("RestoreBackup {} failed: {:?}", node, e);
+        let _ = dock::stop_container(docker, &host).await;
+        // without a full safety copy the volume was never touched
+        let mut err = if !saved {
+            e.context(format!("restore of {} failed before its volume was changed", node))
+        } else {
+            match rollback(docker, node, &root_volume, &safety).await {
+                Ok(()) => {
+                    progress(node, snapshot, "rolled_back", Some(&safety), Some(e.to_string())).await;
+                    e.context(format!("restore failed, {} was rolled back", node))
+                }
+                Err(re) => e.context(format!("restore failed, and rolling {} back from {} failed too: {:#}", node, safety, re)),
+            }
+        };
+        if let Err(se) = dock::start_container(docker, &host).await {
+            err = err.context(format!("{} could not be started again: {:#}", host, se));
+        }
+        return Err(err);
+    }
+    Ok(RestoreReport {
+        node: node.to_string(),
+        snapshot: snapshot.to_string(),
+        safety_volume: safety,
+    })
+}
+
+async fn rollback(docker: &Docker, node: &str, root_volume: &str, safety: &str) -> Result<()> {
+    let host = format!("{}-restore", domain(node));
+    let helper = start_helper(docker, &host, restore_binds(node, root_volume, safety)).await?;
+    let res = dock::exec_checked(
+        docker,
+        &helper,
+        &format!(
+            "find {root} -mindepth 1 -maxdepth 1 -exec rm -rf {{}} + && cp -a {safe}/. {root}/",
+            root = root_volume,
+            safe = SAFETY_MOUNT
+        ),
+    )
+    .await;
+    let _ = dock::stop_and_remove(docker, &helper).await;
+    res.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obj(key: &str, size: u64, last_modified: i64) -> StoredObject {
+        StoredObject {
+            key: key.to_string(),
+            size,
+            last_modified,
+        }
+    }
+
+    #[test]
+    fn test_check_snapshot() {
+        assert!(check_snapshot("2024-05-01").is_ok());
+        assert!(check_snapshot("/../vol").is_err());
+        assert!(check_snapshot("2024-05-01/../..").is_err());
+        assert!(check_snapshot("2024-5-1").is_err());
+        assert!(check_snapshot("2024-13-01").is_err());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key("swarm7/2024-05-01/neo4j/neo4j.tar"),
+            Some(("2024-05-01".to_string(), "neo4j".to_string()))
+        );
+        assert!(parse_key("swarm7/2024-05-01/neo4j/neo4j.manifest.json").is_some());
+        assert_eq!(parse_key("swarm7/2024-05-01/neo4j/other.tar"), None);
+        assert_eq!(parse_key("chunks/swarm7/ab/abcd"), None);
+    }
+
+    #[test]
+    fn test_merge_catalog() {
+        let objects = vec![
+            obj("swarm7/2024-05-01/neo4j/neo4j.tar", 100, 1714521600),
+            obj("swarm7/2024-05-02/neo4j/neo4j.tar", 120, 1714608000),
+            obj("swarm7/2024-05-02/redis/redis.tar", 5, 1714608000),
+        ];
+        let records = vec![
+            BackupRecord {
+                node: "neo4j".to_string(),
+                snapshot: "2024-05-02".to_string(),
+                key: Some("swarm7/2024-05-02/neo4j/neo4j.tar".to_string()),
+                size: 120,
+                checksum: Some("abc".to_string()),
+                created: 1714608000,
+                status: BackupStatus::Ok,
+                error: None,
+                verification: None,
+            },
+            BackupRecord {
+                node: "neo4j".to_string(),
+                snapshot: "2024-05-03".to_string(),
+                key: None,
+                size: 0,
+                checksum: None,
+                created: 1714694400,
+                status: BackupStatus::Failed,
+                error: Some("pre-hook failed".to_string()),
+                verification: None,
+            },
+        ];
+        let got = merge_catalog("neo4j", &objects, &records);
+        let got: Vec<(&str, BackupStatus)> = got.iter().map(|r| (r.snapshot.as_str(), r.status)).collect();
+        assert_eq!(
+            got,
+            vec![
+                ("2024-05-03", BackupStatus::Failed),
+                ("2024-05-02", BackupStatus::Ok),
+                ("2024-05-01", BackupStatus::Unverified),
+            ]
+        );
+    }
+}
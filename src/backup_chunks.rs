@@ -0,0 +1,295 @@
+use crate::backup_store::BackupStore;
+use crate::utils::getenv;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+// content-defined chunk bounds, about 1MiB on average
+const MIN_CHUNK: usize = 256 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+// 20 high bits of the gear hash, so cut points depend on the last 64 bytes
+const CUT_MASK: u64 = ((1 << 20) - 1) << 44;
+// chunks written by a backup still running are not referenced yet
+const GC_GRACE_SECS: i64 = 24 * 60 * 60;
+pub const MANIFEST_EXT: &str = "manifest.json";
+
+// held by a backup from listing the known chunks until its manifests are
+// up, and by gc, so gc never sees a chunk a new manifest is about to use
+pub static CHUNKS_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, any fixed random table works but it must never change
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E3779B97F4A7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR: [u64; 256] = gear_table();
+
+/// Incremental backups are opt in, full tars stay the default.
+pub fn incremental_enabled() -> bool {
+    getenv("BACKUP_INCREMENTAL").map(|v| v == "true").unwrap_or(false)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub size: u64,
+}
+
+/// A snapshot of one volume: its tar is the concatenation of `chunks`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub service: String,
+    // unix seconds
+    pub created: i64,
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+/// Length of the next chunk at the start of `buf`. `buf` must hold
+/// MAX_CHUNK bytes unless the input ended.
+pub fn find_cut(buf: &[u8]) -> usize {
+    if buf.len() <= MIN_CHUNK {
+        return buf.len();
+    }
+    let end = buf.len().min(MAX_CHUNK);
+    let mut h: u64 = 0;
+    for (i, b) in buf.iter().enumerate().take(end).skip(MIN_CHUNK) {
+        h = (h << 1).wrapping_add(GEAR[*b as usize]);
+        if h & CUT_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+pub fn chunks_prefix(swarm: &str) -> String {
+    format!("chunks/{}/", swarm)
+}
+
+fn chunk_key(swarm: &str, hash: &str) -> String {
+    format!("{}{}/{}", chunks_prefix(swarm), &hash[..2], hash)
+}
+
+fn hash_from_key(key: &str) -> &str {
+    key.rsplit('/').next().unwrap_or(key)
+}
+
+/// Hashes of every chunk already stored for this swarm.
+pub async fn known_chunks(store: &dyn BackupStore, swarm: &str) -> Result<HashSet<String>> {
+    Ok(store
+        .list(&chunks_prefix(swarm))
+        .await?
+        .iter()
+        .map(|o| hash_from_key(&o.key).to_string())
+        .collect())
+}
+
+fn work_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.chunk", name))
+}
+
+/// Split `tar` into chunks, upload the ones not in `known` and then the
+/// manifest. Returns the manifest and how many bytes were uploaded.
+pub async fn put_snapshot(
+    store: &dyn BackupStore,
+    swarm: &str,
+    service: &str,
+    tar: &Path,
+    manifest_key: &str,
+    known: &mut HashSet<String>,
+) -> Result<(Manifest, u64)> {
+    let dir = tar.parent().unwrap_or(Path::new("."));
+    let mut file = tokio::fs::File::open(tar).await?;
+    let mut buf: Vec<u8> = Vec::with_capacity(MAX_CHUNK);
+    let mut eof = false;
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    let mut uploaded = 0;
+    loop {
+        while !eof && buf.len() < MAX_CHUNK {
+            let start = buf.len();
+            buf.resize(MAX_CHUNK, 0);
+            let n = file.read(&mut buf[start..]).await?;
+            buf.truncate(start + n);
+            if n == 0 {
+                eof = true;
+            }
+        }
+        if buf.is_empty() {
+            break;
+        }
+        let cut = find_cut(&buf);
+        let chunk: Vec<u8> = buf.drain(..cut).collect();
+        let hash = hex::encode(Sha256::digest(&chunk));
+        if !known.contains(&hash) {
+            let path = work_path(dir, &hash);
+            tokio::fs::write(&path, &chunk).await?;
+            let res = store.put(&path, &chunk_key(swarm, &hash)).await;
+            let _ = tokio::fs::remove_file(&path).await;
+            res?;
+            uploaded += chunk.len() as u64;
+            known.insert(hash.clone());
+        }
+        size += chunk.len() as u64;
+        chunks.push(ChunkRef {
+            hash,
+            size: chunk.len() as u64,
+        });
+    }
+    let manifest = Manifest {
+        service: service.to_string(),
+        created: Utc::now().timestamp(),
+        size,
+        chunks,
+    };
+    let path = work_path(dir, MANIFEST_EXT);
+    tokio::fs::write(&path, serde_json::to_vec(&manifest)?).await?;
+    let res = store.put(&path, manifest_key).await;
+    let _ = tokio::fs::remove_file(&path).await;
+    res?;
+    Ok((manifest, uploaded))
+}
+
+/// Rebuild a snapshot's tar at `tar`, checking every chunk's hash.
+pub async fn restore_snapshot(store: &dyn BackupStore, swarm: &str, manifest: &Manifest, tar: &Path) -> Result<()> {
+    let dir = tar.parent().unwrap_or(Path::new("."));
+    let partial = work_path(dir, "partial");
+    let res = async {
+        let mut out = tokio::fs::File::create(&partial).await?;
+        for c in manifest.chunks.iter() {
+            let path = work_path(dir, &c.hash);
+            store.get(&chunk_key(swarm, &c.hash), &path).await?;
+            let data = tokio::fs::read(&path).await?;
+            let _ = tokio::fs::remove_file(&path).await;
+            if data.len() as u64 != c.size || hex::encode(Sha256::digest(&data)) != c.hash {
+                return Err(anyhow!("chunk {} is corrupt", c.hash));
+            }
+            out.write_all(&data).await?;
+        }
+        out.flush().await?;
+        Ok(())
+    }
+    .await;
+    match res {
+        Ok(()) => Ok(tokio::fs::rename(&partial, tar).await?),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            Err(e)
+        }
+    }
+}
+
+/// Rebuild `<dir>/<name>.tar` from `<dir>/<name>.manifest.json` for every
+/// manifest under a downloaded backup directory.
+pub async fn restore_manifests(store: &dyn BackupStore, swarm: &str, dir: &str) -> Result<()> {
+    let suffix = format!(".{}", MANIFEST_EXT);
+    let manifests: Vec<PathBuf> = walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| e.into_path())
+        .filter(|p| p.to_string_lossy().ends_with(&suffix))
+        .collect();
+    for path in manifests {
+        let tar = PathBuf::from(path.to_string_lossy().replace(&suffix, ".tar"));
+        if tar.exists() {
+            continue;
+        }
+        let manifest: Manifest = serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+        log::info!("Rebuilding {} from {} chunks", tar.display(), manifest.chunks.len());
+        restore_snapshot(store, swarm, &manifest, &tar).await?;
+    }
+    Ok(())
+}
+
+/// Delete chunks that no manifest under `swarm/` references any more.
+pub async fn gc_chunks(store: &dyn BackupStore, swarm: &str, work_dir: &Path) -> Result<usize> {
+    let _lock = CHUNKS_LOCK.lock().await;
+    let suffix = format!(".{}", MANIFEST_EXT);
+    let manifests = store.list(&format!("{}/", swarm)).await?;
+    tokio::fs::create_dir_all(work_dir).await?;
+    let mut referenced = HashSet::new();
+    for m in manifests.iter().filter(|o| o.key.ends_with(&suffix)) {
+        let path = work_path(work_dir, MANIFEST_EXT);
+        store.get(&m.key, &path).await?;
+        let manifest: Manifest = serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+        let _ = tokio::fs::remove_file(&path).await;
+        referenced.extend(manifest.chunks.into_iter().map(|c| c.hash));
+    }
+    let cutoff = Utc::now().timestamp() - GC_GRACE_SECS;
+    let unreferenced: Vec<String> = store
+        .list(&chunks_prefix(swarm))
+        .await?
+        .into_iter()
+        .filter(|o| o.last_modified < cutoff && !referenced.contains(hash_from_key(&o.key)))
+        .map(|o| o.key)
+        .collect();
+    if !unreferenced.is_empty() {
+        store.delete(&unreferenced).await?;
+    }
+    log::info!("Deleted {} unreferenced chunks from {}", unreferenced.len(), store.describe());
+    Ok(unreferenced.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_all(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let cut = find_cut(&rest[..rest.len().min(MAX_CHUNK)]);
+            out.push(rest[..cut].to_vec());
+            rest = &rest[cut..];
+        }
+        out
+    }
+
+    #[test]
+    fn test_find_cut_is_content_defined() {
+        let mut data = vec![0u8; 12 * 1024 * 1024];
+        let mut x: u32 = 1;
+        for b in data.iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        let before = chunk_all(&data);
+        assert!(before.iter().all(|c| c.len() <= MAX_CHUNK));
+        assert_eq!(before.concat(), data);
+
+        // an insert near the start only changes the chunks around it
+        let mut edited = data.clone();
+        edited.splice(10..10, b"inserted".iter().cloned());
+        let after = chunk_all(&edited);
+        let old: HashSet<&Vec<u8>> = before.iter().collect();
+        let shared = after.iter().filter(|c| old.contains(c)).count();
+        assert!(shared >= after.len() - 2, "{} of {} shared", shared, after.len());
+    }
+
+    #[test]
+    fn test_chunk_key() {
+        let hash = "ab".repeat(32);
+        let key = chunk_key("swarm7", &hash);
+        assert_eq!(key, format!("chunks/swarm7/ab/{}", hash));
+        assert_eq!(hash_from_key(&key), hash);
+    }
+}
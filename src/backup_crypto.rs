@@ -0,0 +1,296 @@
+use crate::backup_store::{BackupStore, StoredObject};
+use crate::secrets;
+use crate::utils::getenv;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+// backups run in the stack project
+const PROJ: &str = "stack";
+const KEY_SECRET: &str = "backup_encryption_key";
+// first bytes of every encrypted artifact
+const MAGIC: &[u8; 8] = b"SWARMBK1";
+// XChaCha20's 24 byte nonce minus the STREAM counter and last-block flag
+const NONCE_LEN: usize = 19;
+const CHUNK: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+pub type BackupKey = [u8; 32];
+
+// the two backup crons can both find no key on their first run
+static KEY_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+// made with create_new, so there is only ever one
+fn key_path() -> String {
+    format!("vol/{}/backup_encryption.key", PROJ)
+}
+
+fn parse_key(hex_key: &str) -> Result<BackupKey> {
+    let bytes = hex::decode(hex_key.trim()).context("backup key is not hex")?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("backup key must be 32 bytes"))
+}
+
+/// BACKUP_ENCRYPTION_KEY wins, so a new host can restore with an
+/// escrowed key, then the key in the secrets store.
+pub async fn load_key() -> Result<Option<BackupKey>> {
+    if let Ok(k) = getenv("BACKUP_ENCRYPTION_KEY") {
+        return Ok(Some(parse_key(&k)?));
+    }
+    let secs = secrets::load_secrets(PROJ).await;
+    if let Some(k) = secs.get(KEY_SECRET) {
+        return Ok(Some(parse_key(k)?));
+    }
+    match tokio::fs::read_to_string(key_path()).await {
+        Ok(k) => Ok(Some(parse_key(&k)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The key is created on first use and kept in the secrets store.
+pub async fn load_or_create_key() -> Result<BackupKey> {
+    let _lock = KEY_LOCK.lock().await;
+    if let Some(k) = load_key().await? {
+        return Ok(k);
+    }
+    let hex_key = secrets::hex_secret_32();
+    let created = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(key_path())
+        .await;
+    match created {
+        Ok(mut f) => {
+            f.write_all(hex_key.as_bytes()).await?;
+            f.sync_all().await?;
+        }
+        // another process made it since load_key looked
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            return parse_key(&tokio::fs::read_to_string(key_path()).await?);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    secrets::add_to_secrets(PROJ, KEY_SECRET, &hex_key).await;
+    log::info!("generated a new backup encryption key");
+    parse_key(&hex_key)
+}
+
+/// Hex key for escrow. Without it, backups can't be restored if this
+/// host's secrets are lost.
+pub async fn export_key() -> Result<String> {
+    Ok(hex::encode(load_or_create_key().await?))
+}
+
+fn read_full(r: &mut impl Read, n: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; n];
+    let mut filled = 0;
+    while filled < n {
+        let read = r.read(&mut buf[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    let mut f = File::open(path)?;
+    Ok(read_full(&mut f, MAGIC.len())? == MAGIC)
+}
+
+/// MAGIC | nonce | 64KiB chunks sealed with the STREAM construction, so a
+/// truncated, reordered or modified file fails to decrypt.
+pub fn encrypt_file(key: &BackupKey, src: &Path, dst: &Path) -> Result<()> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let aead = XChaCha20Poly1305::new(Key::from_slice(key));
+    let mut enc = EncryptorBE32::from_aead(aead, GenericArray::from_slice(&nonce));
+
+    let mut r = BufReader::new(File::open(src)?);
+    let mut w = BufWriter::new(File::create(dst)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&nonce)?;
+    let mut cur = read_full(&mut r, CHUNK)?;
+    loop {
+        let next = read_full(&mut r, CHUNK)?;
+        if next.is_empty() {
+            let ct = enc
+                .encrypt_last(&cur[..])
+                .map_err(|_| anyhow!("encrypt failed"))?;
+            w.write_all(&ct)?;
+            break;
+        }
+        let ct = enc
+            .encrypt_next(&cur[..])
+            .map_err(|_| anyhow!("encrypt failed"))?;
+        w.write_all(&ct)?;
+        cur = next;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Decrypt into `dst` only once every chunk has verified.
+pub fn decrypt_file(key: &BackupKey, src: &Path, dst: &Path) -> Result<()> {
+    let mut r = BufReader::new(File::open(src)?);
+    if read_full(&mut r, MAGIC.len())? != MAGIC {
+        return Err(anyhow!("{} is not an encrypted backup", src.display()));
+    }
+    let nonce = read_full(&mut r, NONCE_LEN)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(anyhow!("{} is truncated", src.display()));
+    }
+    let aead = XChaCha20Poly1305::new(Key::from_slice(key));
+    let mut dec = DecryptorBE32::from_aead(aead, GenericArray::from_slice(&nonce));
+
+    let partial = partial_path(dst, "partial");
+    let res = (|| -> Result<()> {
+        let mut w = BufWriter::new(File::create(&partial)?);
+        let mut cur = read_full(&mut r, CHUNK + TAG_LEN)?;
+        loop {
+            let next = read_full(&mut r, CHUNK + TAG_LEN)?;
+            if next.is_empty() {
+                let pt = dec
+                    .decrypt_last(&cur[..])
+                    .map_err(|_| anyhow!("backup failed integrity check"))?;
+                w.write_all(&pt)?;
+                break;
+            }
+            let pt = dec
+                .decrypt_next(&cur[..])
+                .map_err(|_| anyhow!("backup failed integrity check"))?;
+            w.write_all(&pt)?;
+            cur = next;
+        }
+        w.flush()?;
+        Ok(())
+    })();
+    match res {
+        Ok(()) => Ok(std::fs::rename(&partial, dst)?),
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            Err(e.context(format!("decrypting {}", src.display())))
+        }
+    }
+}
+
+fn partial_path(p: &Path, ext: &str) -> PathBuf {
+    let mut s = p.as_os_str().to_owned();
+    s.push(".");
+    s.push(ext);
+    PathBuf::from(s)
+}
+
+/// Encrypts on put and decrypts on get, keys are unchanged. Backups taken
+/// before encryption are refused unless BACKUP_ALLOW_PLAINTEXT=true.
+pub struct EncryptedStore {
+    inner: Box<dyn BackupStore>,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Box<dyn BackupStore>) -> Self {
+        Self { inner }
+    }
+}
+
+fn allow_plaintext() -> bool {
+    getenv("BACKUP_ALLOW_PLAINTEXT").map(|v| v == "true").unwrap_or(false)
+}
+
+#[async_trait]
+impl BackupStore for EncryptedStore {
+    fn describe(&self) -> String {
+        format!("{} (encrypted)", self.inner.describe())
+    }
+
+    async fn put(&self, local: &Path, key: &str) -> Result<()> {
+        let k = load_or_create_key().await?;
+        let enc = partial_path(local, "enc");
+        let (src, dst) = (local.to_path_buf(), enc.clone());
+        tokio::task::spawn_blocking(move || encrypt_file(&k, &src, &dst)).await??;
+        let res = self.inner.put(&enc, key).await;
+        let _ = tokio::fs::remove_file(&enc).await;
+        res
+    }
+
+    async fn get(&self, key: &str, local: &Path) -> Result<()> {
+        let fetched = partial_path(local, "download");
+        self.inner.get(key, &fetched).await?;
+        let (src, dst) = (fetched.clone(), local.to_path_buf());
+        let res = match load_key().await? {
+            Some(k) => tokio::task::spawn_blocking(move || {
+                if is_encrypted(&src)? {
+                    decrypt_file(&k, &src, &dst)
+                } else if allow_plaintext() {
+                    log::warn!("{} is not encrypted, restoring it as is", src.display());
+                    Ok(std::fs::rename(&src, &dst)?)
+                } else {
+                    Err(anyhow!("{} is not encrypted, set BACKUP_ALLOW_PLAINTEXT=true to restore it", src.display()))
+                }
+            })
+            .await?,
+            None => Err(anyhow!("no backup encryption key, set BACKUP_ENCRYPTION_KEY")),
+        };
+        let _ = tokio::fs::remove_file(&fetched).await;
+        res
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        self.inner.list(prefix).await
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<()> {
+        self.inner.delete(keys).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("swarm-crypto-{}-{}", name, rand::random::<u64>()))
+    }
+
+    #[test]
+    fn test_roundtrip_and_tamper() {
+        let key = [7u8; 32];
+        let (plain, enc, out) = (tmp("plain"), tmp("enc"), tmp("out"));
+        // spans a chunk boundary
+        let data: Vec<u8> = (0..CHUNK * 2 + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&plain, &data).unwrap();
+
+        encrypt_file(&key, &plain, &enc).unwrap();
+        assert!(is_encrypted(&enc).unwrap());
+        assert!(!is_encrypted(&plain).unwrap());
+        decrypt_file(&key, &enc, &out).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+
+        assert!(decrypt_file(&[8u8; 32], &enc, &out).is_err());
+
+        // dropping the last chunk must not decrypt to a shorter file
+        let mut ct = std::fs::read(&enc).unwrap();
+        ct.truncate(MAGIC.len() + NONCE_LEN + 2 * (CHUNK + TAG_LEN));
+        std::fs::write(&enc, &ct).unwrap();
+        let _ = std::fs::remove_file(&out);
+        assert!(decrypt_file(&key, &enc, &out).is_err());
+        assert!(!out.exists());
+
+        for p in [plain, enc] {
+            let _ = std::fs::remove_file(p);
+        }
+    }
+}
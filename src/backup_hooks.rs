@@ -0,0 +1,197 @@
+use crate::dock;
+use crate::images::Image;
+use anyhow::Result;
+use bollard::Docker;
+use serde::{Deserialize, Serialize};
+
+// how long to wait for a redis BGSAVE to land, in seconds
+const REDIS_BGSAVE_TIMEOUT: u32 = 120;
+
+/// One step run against a container around its volume backup.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "data")]
+pub enum HookStep {
+    // `sh -c` inside the container, must exit 0
+    Exec(String),
+    // like Exec, but a failure is only logged and the backup goes on
+    TryExec(String),
+    Stop,
+    Start,
+}
+
+/// Steps that make a volume consistent before its tar is taken (`pre`) and
+/// put the service back afterwards (`post`). `post` always runs, even if
+/// `pre` or the tar failed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct BackupHooks {
+    pub pre: Vec<HookStep>,
+    pub post: Vec<HookStep>,
+}
+
+fn exec(cmd: &str) -> HookStep {
+    HookStep::Exec(cmd.to_string())
+}
+
+/// Default hooks for images whose data files are not safe to copy while
+/// the service is writing them. Postgres is not a swarm Image (it only
+/// runs inside other stacks), so `pg_dump` has no hook here.
+pub fn hooks_for(img: &Image) -> Option<BackupHooks> {
+    match img {
+        // neo4j community can't dump an online database, a brief stop is
+        // the only consistent copy
+        Image::Neo4j(_) => Some(BackupHooks {
+            pre: vec![HookStep::Stop],
+            post: vec![HookStep::Start],
+        }),
+        // flushed and locked, the raw files are a consistent copy
+        Image::Mongo(_) => Some(BackupHooks {
+            pre: vec![exec(&mongo_eval("db.fsyncLock()"))],
+            post: vec![exec(&mongo_eval("db.fsyncUnlock()"))],
+        }),
+        Image::Redis(_) => Some(BackupHooks {
+            pre: vec![exec(&redis_bgsave())],
+            post: vec![],
+        }),
+        Image::Elastic(e) => {
+            let url = format!("http://localhost:{}", e.http_port);
+            Some(BackupHooks {
+                pre: vec![
+                    exec(&es_put_settings(&url, "true")),
+                    exec(&format!("curl -sf -X POST {}/_flush", url)),
+                ],
+                post: vec![exec(&es_put_settings(&url, "null"))],
+            })
+        }
+        // the online backup goes in the tar next to the live db, and
+        // after_restore puts it in place
+        Image::Cln(c) => {
+            let db = cln_db(&c.network);
+            Some(BackupHooks {
+                pre: vec![HookStep::TryExec(format!(
+                    "sqlite3 {} \".backup {}.backup\"",
+                    db, db
+                ))],
+                post: vec![exec(&format!("rm -f {}.backup", db))],
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Shell run over a freshly unpacked volume, in a helper that has it
+/// mounted at the image's root volume, before the node starts on it.
+pub fn after_restore(img: &Image) -> Option<String> {
+    match img {
+        // the online backup is consistent, the raw db next to it may not be
+        Image::Cln(c) => Some(format!(
+            "if [ -f {db}.backup ]; then mv -f {db}.backup {db} && rm -f {db}-journal {db}-wal {db}-shm; fi",
+            db = cln_db(&c.network)
+        )),
+        _ => None,
+    }
+}
+
+pub async fn run_after_restore(docker: &Docker, helper: &str, img: &Image) -> Result<()> {
+    if let Some(cmd) = after_restore(img) {
+        dock::exec_checked(docker, helper, &cmd).await?;
+    }
+    Ok(())
+}
+
+fn cln_db(network: &str) -> String {
+    format!("/root/.lightning/{}/lightningd.sqlite3", network)
+}
+
+// newer images only ship mongosh, older ones only mongo
+fn mongo_eval(js: &str) -> String {
+    format!(
+        "if command -v mongosh >/dev/null; then mongosh --quiet --eval '{js}'; \
+         else mongo --quiet --eval '{js}'; fi",
+        js = js
+    )
+}
+
+// BGSAVE returns straight away, so wait for LASTSAVE to move
+fn redis_bgsave() -> String {
+    format!(
+        "before=$(redis-cli LASTSAVE) && redis-cli BGSAVE && i=0 && \
+         while [ \"$(redis-cli LASTSAVE)\" = \"$before\" ]; do \
+         i=$((i+1)); [ $i -gt {} ] && exit 1; sleep 1; done",
+        REDIS_BGSAVE_TIMEOUT
+    )
+}
+
+// block writes on every index so the flushed segments don't change under the tar
+fn es_put_settings(url: &str, write_block: &str) -> String {
+    format!(
+        "curl -sf -X PUT {}/_all/_settings -H 'Content-Type: application/json' \
+         -d '{{\"index.blocks.write\": {}}}'",
+        url, write_block
+    )
+}
+
+async fn run_step(docker: &Docker, id: &str, step: &HookStep) -> Result<()> {
+    match step {
+        HookStep::Exec(cmd) => {
+            dock::exec_checked(docker, id, cmd).await?;
+        }
+        HookStep::TryExec(cmd) => {
+            if let Err(e) = dock::exec_checked(docker, id, cmd).await {
+                log::warn!("backup hook on {} failed, going on without it: {}", id, e);
+            }
+        }
+        HookStep::Stop => dock::stop_container(docker, id).await?,
+        HookStep::Start => dock::start_container(docker, id).await?,
+    }
+    Ok(())
+}
+
+/// Run `pre` in order, stopping at the first failure.
+pub async fn run_pre(docker: &Docker, id: &str, hooks: &BackupHooks) -> Result<()> {
+    for step in hooks.pre.iter() {
+        log::info!("backup pre-hook on {}: {:?}", id, step);
+        run_step(docker, id, step).await?;
+    }
+    Ok(())
+}
+
+/// Run every `post` step, even after a failure, so the service is resumed.
+pub async fn run_post(docker: &Docker, id: &str, hooks: &BackupHooks) -> Result<()> {
+    let mut errs = Vec::new();
+    for step in hooks.post.iter() {
+        log::info!("backup post-hook on {}: {:?}", id, step);
+        if let Err(e) = run_step(docker, id, step).await {
+            log::error!("backup post-hook on {} failed: {:?}", id, e);
+            errs.push(e.to_string());
+        }
+    }
+    if !errs.is_empty() {
+        return Err(anyhow::anyhow!("post-hooks failed on {}: {}", id, errs.join("; ")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::cln::ClnImage;
+    use crate::images::neo4j::Neo4jImage;
+
+    #[test]
+    fn test_hooks_for() {
+        let neo = Image::Neo4j(Neo4jImage::new("neo4j", "5.19.0"));
+        let hooks = hooks_for(&neo).unwrap();
+        assert_eq!(hooks.pre, vec![HookStep::Stop]);
+        assert_eq!(hooks.post, vec![HookStep::Start]);
+
+        let cln = Image::Cln(ClnImage::new("cln", "v23.11", "regtest", "9735", "10009"));
+        let hooks = hooks_for(&cln).unwrap();
+        match &hooks.pre[0] {
+            HookStep::TryExec(cmd) => assert!(cmd.contains("/root/.lightning/regtest/lightningd.sqlite3")),
+            s => panic!("unexpected step {:?}", s),
+        }
+        let cmd = after_restore(&cln).unwrap();
+        assert!(cmd.contains("mv -f /root/.lightning/regtest/lightningd.sqlite3.backup /root/.lightning/regtest/lightningd.sqlite3"));
+        assert_eq!(after_restore(&neo), None);
+    }
+}
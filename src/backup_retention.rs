@@ -0,0 +1,273 @@
+use crate::backup_catalog::{self, BackupRecord};
+use crate::backup_store::{BackupStore, StoredObject};
+use crate::utils::getenv;
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// How many snapshots to keep per period: the newest of each of the last
+/// `daily` days, `weekly` ISO weeks, `monthly` months and `yearly` years.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct GfsRule {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub daily: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub weekly: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub monthly: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub yearly: Option<u32>,
+}
+
+impl GfsRule {
+    fn is_empty(&self) -> bool {
+        [self.daily, self.weekly, self.monthly, self.yearly]
+            .iter()
+            .all(|n| n.unwrap_or(0) == 0)
+    }
+}
+
+/// Replaces BACKUP_RETENTION_DAYS for volume backups when set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct BackupRetention {
+    #[serde(flatten)]
+    pub default: GfsRule,
+    // per service overrides of `default`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub services: Option<BTreeMap<String, GfsRule>>,
+}
+
+impl BackupRetention {
+    pub fn validate(&self) -> Result<()> {
+        if self.default.is_empty() {
+            return Err(anyhow!("retention must keep at least one period"));
+        }
+        for (name, rule) in self.services.iter().flatten() {
+            if rule.is_empty() {
+                return Err(anyhow!("retention for {} must keep at least one period", name));
+            }
+        }
+        Ok(())
+    }
+
+    fn rule_for(&self, node: &str) -> &GfsRule {
+        self.services
+            .as_ref()
+            .and_then(|s| s.get(node))
+            .unwrap_or(&self.default)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SnapshotRef {
+    pub node: String,
+    pub snapshot: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    pub kept: Vec<SnapshotRef>,
+    pub pruned: Vec<SnapshotRef>,
+    // store objects deleted, or that would be
+    pub keys: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PruneBackupsRequest {
+    pub dry_run: bool,
+}
+
+fn keep_newest_per<K: Ord>(dates: &[NaiveDate], n: Option<u32>, key: impl Fn(&NaiveDate) -> K, keep: &mut BTreeSet<NaiveDate>) {
+    let n = n.unwrap_or(0) as usize;
+    let mut seen = BTreeSet::new();
+    for d in dates.iter() {
+        if seen.len() >= n {
+            break;
+        }
+        if seen.insert(key(d)) {
+            keep.insert(*d);
+        }
+    }
+}
+
+/// The snapshot dates `rule` keeps. The newest one is always kept.
+pub fn select_keep(dates: &[NaiveDate], rule: &GfsRule) -> BTreeSet<NaiveDate> {
+    let mut dates = dates.to_vec();
+    dates.sort_by(|a, b| b.cmp(a));
+    dates.dedup();
+    let mut keep = BTreeSet::new();
+    if rule.is_empty() {
+        keep.extend(dates);
+        return keep;
+    }
+    keep_newest_per(&dates, rule.daily, |d| *d, &mut keep);
+    keep_newest_per(&dates, rule.weekly, |d| (d.iso_week().year(), d.iso_week().week()), &mut keep);
+    keep_newest_per(&dates, rule.monthly, |d| (d.year(), d.month()), &mut keep);
+    keep_newest_per(&dates, rule.yearly, |d| d.year(), &mut keep);
+    if let Some(newest) = dates.first() {
+        keep.insert(*newest);
+    }
+    keep
+}
+
+/// The newest snapshot of each service whose test restore passed.
+pub fn last_verified(records: &[BackupRecord]) -> HashSet<SnapshotRef> {
+    let mut newest: BTreeMap<&str, &str> = BTreeMap::new();
+    for r in records.iter() {
+        if !r.verification.as_ref().map(|v| v.passed).unwrap_or(false) {
+            continue;
+        }
+        let e = newest.entry(&r.node).or_insert(&r.snapshot);
+        if r.snapshot.as_str() > *e {
+            *e = &r.snapshot;
+        }
+    }
+    newest
+        .into_iter()
+        .map(|(node, snapshot)| SnapshotRef {
+            node: node.to_string(),
+            snapshot: snapshot.to_string(),
+        })
+        .collect()
+}
+
+/// Which snapshots under a swarm prefix to keep and which objects to delete.
+/// Keys that aren't snapshots, or have no parseable date, are never touched.
+pub fn plan_prune(
+    objects: &[StoredObject],
+    retention: &BackupRetention,
+    protected: &HashSet<SnapshotRef>,
+    dry_run: bool,
+) -> PruneReport {
+    let mut per_node: BTreeMap<String, BTreeMap<NaiveDate, Vec<String>>> = BTreeMap::new();
+    for o in objects.iter() {
+        let (snapshot, node) = match backup_catalog::parse_key(&o.key) {
+            Some(p) => p,
+            None => continue,
+        };
+        let date = match NaiveDate::parse_from_str(&snapshot, "%Y-%m-%d") {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        per_node
+            .entry(node)
+            .or_default()
+            .entry(date)
+            .or_default()
+            .push(o.key.clone());
+    }
+    let mut report = PruneReport {
+        dry_run,
+        kept: Vec::new(),
+        pruned: Vec::new(),
+        keys: Vec::new(),
+    };
+    for (node, snapshots) in per_node.into_iter() {
+        let dates: Vec<NaiveDate> = snapshots.keys().cloned().collect();
+        let keep = select_keep(&dates, retention.rule_for(&node));
+        for (date, keys) in snapshots.into_iter().rev() {
+            let s = SnapshotRef {
+                node: node.clone(),
+                snapshot: date.format("%Y-%m-%d").to_string(),
+            };
+            if keep.contains(&date) || protected.contains(&s) {
+                report.kept.push(s);
+            } else {
+                report.pruned.push(s);
+                report.keys.extend(keys);
+            }
+        }
+    }
+    report
+}
+
+/// Apply `retention` to this swarm's volume backups.
+pub async fn prune_backups(store: &dyn BackupStore, retention: &BackupRetention, dry_run: bool) -> Result<PruneReport> {
+    let prefix = format!("swarm{}/", getenv("SWARM_NUMBER")?);
+    let objects = store.list(&prefix).await?;
+    let protected = last_verified(&backup_catalog::load_catalog().await);
+    let report = plan_prune(&objects, retention, &protected, dry_run);
+    if !dry_run && !report.keys.is_empty() {
+        store.delete(&report.keys).await?;
+        log::info!(
+            "Pruned {} snapshots ({} objects) from {}",
+            report.pruned.len(),
+            report.keys.len(),
+            store.describe()
+        );
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn test_select_keep() {
+        // a year of dailies up to 2024-06-30
+        let end = d("2024-06-30");
+        let dates: Vec<NaiveDate> = (0..366).map(|i| end - chrono::Duration::days(i)).collect();
+        let rule = GfsRule {
+            daily: Some(7),
+            weekly: Some(4),
+            monthly: Some(12),
+            yearly: None,
+        };
+        let keep = select_keep(&dates, &rule);
+        assert!(keep.contains(&end));
+        assert!(keep.contains(&d("2024-06-24")));
+        // newest of the previous week
+        assert!(keep.contains(&d("2024-06-23")));
+        // newest of each month is its last day
+        assert!(keep.contains(&d("2024-05-31")));
+        assert!(keep.contains(&d("2023-07-31")));
+        assert!(!keep.contains(&d("2024-05-30")));
+        // 7 daily + 3 more weeks (the first overlaps) + 11 more months
+        assert_eq!(keep.len(), 7 + 3 + 11);
+    }
+
+    #[test]
+    fn test_plan_prune_keeps_verified() {
+        let obj = |key: &str| StoredObject {
+            key: key.to_string(),
+            size: 1,
+            last_modified: 0,
+        };
+        let objects = vec![
+            obj("swarm7/2024-06-01/neo4j/neo4j.tar"),
+            obj("swarm7/2024-06-02/neo4j/neo4j.tar"),
+            obj("swarm7/2024-06-03/neo4j/neo4j.tar"),
+            obj("swarm7/2024-06-01/redis/redis.tar"),
+            obj("swarm7/2024-06-03/redis/redis.tar"),
+        ];
+        let retention = BackupRetention {
+            default: GfsRule {
+                daily: Some(1),
+                ..Default::default()
+            },
+            services: Some(BTreeMap::from([(
+                "redis".to_string(),
+                GfsRule {
+                    daily: Some(2),
+                    ..Default::default()
+                },
+            )])),
+        };
+        let protected = HashSet::from([SnapshotRef {
+            node: "neo4j".to_string(),
+            snapshot: "2024-06-01".to_string(),
+        }]);
+        let report = plan_prune(&objects, &retention, &protected, true);
+        assert_eq!(report.keys, vec!["swarm7/2024-06-02/neo4j/neo4j.tar".to_string()]);
+        assert_eq!(report.kept.len(), 4);
+        assert!(retention.validate().is_ok());
+        assert!(BackupRetention::default().validate().is_err());
+    }
+}
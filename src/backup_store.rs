@@ -0,0 +1,533 @@
+use crate::backup_crypto::EncryptedStore;
+use crate::config;
+use crate::utils::getenv;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::Region;
+use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier};
+use aws_sdk_s3::Client;
+use aws_smithy_types::byte_stream::{ByteStream, Length};
+use aws_smithy_types::retry::RetryConfig;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+// never prune below this many objects, whatever their age
+const RETENTION_KEEP_MIN: usize = 12;
+
+/// Where backups go. Unset means AWS S3 with the AWS_S3_BUCKET_NAME and
+/// AWS_REGION env vars and ambient credentials, as before.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackupStoreConfig {
+    S3 {
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        bucket: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        region: Option<String>,
+    },
+    // MinIO, Backblaze B2, Wasabi...
+    S3Compatible {
+        endpoint: String,
+        bucket: String,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        region: Option<String>,
+        access_key_id: String,
+        secret_access_key: String,
+        // most self-hosted endpoints need path-style addressing (default true)
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        path_style: Option<bool>,
+    },
+    // a local directory or NFS mount
+    Local { path: String },
+}
+
+impl BackupStoreConfig {
+    pub fn remove_secrets(&self) -> Self {
+        match self.clone() {
+            BackupStoreConfig::S3Compatible {
+                endpoint,
+                bucket,
+                region,
+                access_key_id,
+                path_style,
+                ..
+            } => BackupStoreConfig::S3Compatible {
+                endpoint,
+                bucket,
+                region,
+                access_key_id,
+                secret_access_key: "".to_string(),
+                path_style,
+            },
+            c => c,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub key: String,
+    pub size: u64,
+    // unix seconds
+    pub last_modified: i64,
+}
+
+#[async_trait]
+pub trait BackupStore: Send + Sync {
+    // for logs, e.g. "s3://sphinx-swarm"
+    fn describe(&self) -> String;
+    async fn put(&self, local: &Path, key: &str) -> Result<()>;
+    async fn get(&self, key: &str, local: &Path) -> Result<()>;
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>>;
+    async fn delete(&self, keys: &[String]) -> Result<()>;
+}
+
+/// The store configured for this stack. Everything put through it is
+/// encrypted with the swarm's backup key.
+pub async fn backup_store() -> Result<Box<dyn BackupStore>> {
+    let conf = config::stack_read(|s| s.backup_store.clone()).await;
+    Ok(Box::new(EncryptedStore::new(from_config(conf).await?)))
+}
+
+pub async fn from_config(conf: Option<BackupStoreConfig>) -> Result<Box<dyn BackupStore>> {
+    Ok(match conf {
+        None => Box::new(S3Store::aws(None, None).await?),
+        Some(BackupStoreConfig::S3 { bucket, region }) => Box::new(S3Store::aws(bucket, region).await?),
+        Some(BackupStoreConfig::S3Compatible {
+            endpoint,
+            bucket,
+            region,
+            access_key_id,
+            secret_access_key,
+            path_style,
+        }) => Box::new(S3Store::compatible(
+            &endpoint,
+            &bucket,
+            region,
+            &access_key_id,
+            &secret_access_key,
+            path_style.unwrap_or(true),
+        )),
+        Some(BackupStoreConfig::Local { path }) => Box::new(LocalStore::new(&path)),
+    })
+}
+
+/// Delete objects under `prefix` older than `retention_days`, oldest first,
+/// but never so many that fewer than a dozen are left to choose from. Keys
+/// `protected` returns true for are kept whatever their age.
+pub async fn apply_retention(
+    store: &dyn BackupStore,
+    prefix: &str,
+    retention_days: i64,
+    protected: &(dyn Fn(&str) -> bool + Sync),
+) -> Result<usize> {
+    let objects = store.list(&format!("{}/", prefix)).await?;
+    let cutoff = (Utc::now() - Duration::days(retention_days)).timestamp();
+    let mut expired = expired_keys(&objects, cutoff, RETENTION_KEEP_MIN);
+    expired.retain(|k| !protected(k));
+    if expired.is_empty() {
+        log::info!("No old objects to delete in {}", store.describe());
+        return Ok(0);
+    }
+    store.delete(&expired).await?;
+    log::info!("Deleted {} old objects from {}", expired.len(), store.describe());
+    Ok(expired.len())
+}
+
+fn expired_keys(objects: &[StoredObject], cutoff: i64, keep_min: usize) -> Vec<String> {
+    let mut expired: Vec<&StoredObject> = objects
+        .iter()
+        .filter(|o| o.last_modified < cutoff)
+        .collect();
+    // oldest first, and only as many as still leaves keep_min behind
+    expired.sort_by_key(|o| o.last_modified);
+    expired.truncate(objects.len().saturating_sub(keep_min));
+    expired.into_iter().map(|o| o.key.clone()).collect()
+}
+
+/// Download everything under `prefix`, each object to a local path equal to its key.
+pub async fn download_prefix(store: &dyn BackupStore, prefix: &str) -> Result<()> {
+    for obj in store.list(prefix).await? {
+        log::info!("Downloading {} from {}", obj.key, store.describe());
+        store.get(&obj.key, Path::new(&obj.key)).await?;
+    }
+    Ok(())
+}
+
+pub struct S3Store {
+    client: Client,
+    bucket: String,
+}
+
+impl S3Store {
+    pub async fn aws(bucket: Option<String>, region: Option<String>) -> Result<Self> {
+        let region = match region {
+            Some(r) => r,
+            None => getenv("AWS_REGION").context("AWS_REGION is not provided in environment variable")?,
+        };
+        let region_provider = RegionProviderChain::first_try(Some(Region::new(region)));
+        let config = aws_config::from_env()
+            .region(region_provider)
+            .retry_config(RetryConfig::standard().with_max_attempts(10))
+            .load()
+            .await;
+        Ok(Self {
+            client: Client::new(&config),
+            bucket: bucket.unwrap_or(crate::backup::bucket_name()),
+        })
+    }
+
+    pub fn compatible(
+        endpoint: &str,
+        bucket: &str,
+        region: Option<String>,
+        access_key_id: &str,
+        secret_access_key: &str,
+        path_style: bool,
+    ) -> Self {
+        let creds = Credentials::new(access_key_id, secret_access_key, None, None, "swarm-backup-store");
+        let conf = aws_sdk_s3::config::Builder::new()
+            .behavior_version_latest()
+            .endpoint_url(endpoint)
+            // the region is part of the signature even when the endpoint ignores it
+            .region(Region::new(region.unwrap_or("us-east-1".to_string())))
+            .credentials_provider(creds)
+            .force_path_style(path_style)
+            .retry_config(RetryConfig::standard().with_max_attempts(10))
+            .build();
+        Self {
+            client: Client::from_conf(conf),
+            bucket: bucket.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl BackupStore for S3Store {
+    fn describe(&self) -> String {
+        format!("s3://{}", self.bucket)
+    }
+
+    async fn put(&self, local: &Path, key: &str) -> Result<()> {
+        //In bytes, minimum chunk size of 150MB.
+        const CHUNK_SIZE: u64 = 1024 * 1024 * 150;
+        const MAX_CHUNKS: u64 = 10000;
+
+        let file_size = tokio::fs::metadata(local)
+            .await
+            .with_context(|| format!("unable to find file to upload in this path: {:?}", local))?
+            .len();
+        if file_size == 0 {
+            return Err(anyhow!("Invalid file, file size is 0"));
+        }
+
+        let mut chunk_count = (file_size / CHUNK_SIZE) + 1;
+        let mut size_of_last_chunk = file_size % CHUNK_SIZE;
+        if size_of_last_chunk == 0 {
+            size_of_last_chunk = CHUNK_SIZE;
+            chunk_count -= 1;
+        }
+        if chunk_count > MAX_CHUNKS {
+            return Err(anyhow!("Too many chunks! Try increasing your chunk size."));
+        }
+
+        let multipart = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+        let upload_id = multipart.upload_id().context("Upload ID not found")?;
+
+        log::info!(
+            "S3 upload: {} ({}MB, {} chunks)",
+            key,
+            file_size / (1024 * 1024),
+            chunk_count
+        );
+
+        let mut upload_parts: Vec<CompletedPart> = Vec::new();
+        for chunk_index in 0..chunk_count {
+            let this_chunk = if chunk_count - 1 == chunk_index {
+                size_of_last_chunk
+            } else {
+                CHUNK_SIZE
+            };
+            let stream = ByteStream::read_from()
+                .path(local)
+                .offset(chunk_index * CHUNK_SIZE)
+                .length(Length::Exact(this_chunk))
+                .build()
+                .await?;
+            //Chunk index needs to start at 0, but part numbers start at 1.
+            let part_number = (chunk_index as i32) + 1;
+            let part = self
+                .client
+                .upload_part()
+                .key(key)
+                .bucket(&self.bucket)
+                .upload_id(upload_id)
+                .body(stream)
+                .part_number(part_number)
+                .send()
+                .await;
+            let part = match part {
+                Ok(p) => p,
+                Err(e) => {
+                    // don't leave the parts behind to be billed for
+                    let _ = self
+                        .client
+                        .abort_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .send()
+                        .await;
+                    return Err(e.into());
+                }
+            };
+            log::info!(
+                "S3 upload: {}/{} chunks ({:.0}%)",
+                chunk_index + 1,
+                chunk_count,
+                ((chunk_index + 1) as f64 / chunk_count as f64) * 100.0
+            );
+            upload_parts.push(
+                CompletedPart::builder()
+                    .e_tag(part.e_tag.unwrap_or_default())
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+
+        let completed = CompletedMultipartUpload::builder()
+            .set_parts(Some(upload_parts))
+            .build();
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .multipart_upload(completed)
+            .upload_id(upload_id)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, local: &Path) -> Result<()> {
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+        if let Some(parent) = local.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let body = resp.body.into_async_read();
+        tokio::pin!(body);
+        let mut file = tokio::fs::File::create(local).await?;
+        tokio::io::copy(&mut body, &mut file).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let mut out = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let resp = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_continuation_token(token.clone())
+                .send()
+                .await?;
+            for obj in resp.contents() {
+                if let Some(key) = obj.key() {
+                    out.push(StoredObject {
+                        key: key.to_string(),
+                        size: obj.size().unwrap_or(0).max(0) as u64,
+                        last_modified: obj.last_modified().map(|d| d.secs()).unwrap_or(0),
+                    });
+                }
+            }
+            token = resp.next_continuation_token().map(|t| t.to_string());
+            if !resp.is_truncated().unwrap_or(false) || token.is_none() {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<()> {
+        // DeleteObjects takes at most 1000 keys
+        for batch in keys.chunks(1000) {
+            let mut objects = Vec::new();
+            for key in batch {
+                objects.push(ObjectIdentifier::builder().key(key).build()?);
+            }
+            self.client
+                .delete_objects()
+                .bucket(&self.bucket)
+                .delete(Delete::builder().set_objects(Some(objects)).build()?)
+                .send()
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: PathBuf::from(root),
+        }
+    }
+
+    // keys are relative, "/" separated, and can't climb out of the root
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let rel = Path::new(key.trim_start_matches('/'));
+        if rel.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(anyhow!("invalid backup key {}", key));
+        }
+        Ok(self.root.join(rel))
+    }
+}
+
+#[async_trait]
+impl BackupStore for LocalStore {
+    fn describe(&self) -> String {
+        format!("file://{}", self.root.display())
+    }
+
+    async fn put(&self, local: &Path, key: &str) -> Result<()> {
+        let dest = self.path(key)?;
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // copy then rename, so a half written file never looks like a backup
+        let tmp = dest.with_extension("partial");
+        tokio::fs::copy(local, &tmp).await?;
+        tokio::fs::rename(&tmp, &dest).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, local: &Path) -> Result<()> {
+        if let Some(parent) = local.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::copy(self.path(key)?, local).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        let root = self.root.clone();
+        let prefix = prefix.trim_start_matches('/').to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut out = Vec::new();
+            if !root.exists() {
+                return Ok(out);
+            }
+            for entry in WalkDir::new(&root) {
+                let entry = entry?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let rel = entry.path().strip_prefix(&root)?;
+                let key = rel
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if !key.starts_with(&prefix) || key.ends_with(".partial") {
+                    continue;
+                }
+                let meta = entry.metadata()?;
+                let last_modified = meta
+                    .modified()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0);
+                out.push(StoredObject {
+                    key,
+                    size: meta.len(),
+                    last_modified,
+                });
+            }
+            out.sort_by(|a, b| a.key.cmp(&b.key));
+            Ok(out)
+        })
+        .await?
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<()> {
+        for key in keys {
+            match tokio::fs::remove_file(self.path(key)?).await {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obj(key: &str, last_modified: i64) -> StoredObject {
+        StoredObject {
+            key: key.to_string(),
+            size: 1,
+            last_modified,
+        }
+    }
+
+    #[test]
+    fn test_expired_keys() {
+        let objects: Vec<StoredObject> = (0..14).map(|i| obj(&format!("k{}", i), i)).collect();
+        assert_eq!(expired_keys(&objects, 2, 12), vec!["k0", "k1"]);
+        // both are expired, but deleting k1 would leave only 11
+        assert_eq!(expired_keys(&objects[..13], 2, 12), vec!["k0"]);
+        assert!(expired_keys(&objects[..12], 2, 12).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_local_store() {
+        let root = std::env::temp_dir().join(format!("swarm-store-{}", Utc::now().timestamp_nanos_opt().unwrap_or(0)));
+        let store = LocalStore::new(root.to_str().unwrap());
+        let src = root.with_extension("src");
+        tokio::fs::write(&src, b"backup").await.unwrap();
+
+        store.put(&src, "swarm1/2024-05-01/neo4j/neo4j.tar").await.unwrap();
+        store.put(&src, "swarm2/2024-05-01/neo4j/neo4j.tar").await.unwrap();
+        let listed = store.list("swarm1/").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, "swarm1/2024-05-01/neo4j/neo4j.tar");
+        assert_eq!(listed[0].size, 6);
+
+        let out = root.with_extension("out");
+        store.get(&listed[0].key, &out).await.unwrap();
+        assert_eq!(tokio::fs::read(&out).await.unwrap(), b"backup");
+
+        assert!(store.put(&src, "../escape").await.is_err());
+        store.delete(&[listed[0].key.clone()]).await.unwrap();
+        assert!(store.list("swarm1/").await.unwrap().is_empty());
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        let _ = tokio::fs::remove_file(&src).await;
+        let _ = tokio::fs::remove_file(&out).await;
+    }
+}
@@ -0,0 +1,350 @@
+use crate::backup_catalog::{self, BackupRecord, BackupStatus, Verification};
+use crate::backup_hooks;
+use crate::backup_store::backup_store;
+use crate::builder::find_img;
+use crate::config;
+use crate::dock;
+use crate::images::{DockerConfig, DockerHubImage, Image};
+use crate::metrics;
+use crate::utils::{domain, getenv};
+use anyhow::{anyhow, Result};
+use bollard::container::CreateContainerOptions;
+use bollard::network::CreateNetworkOptions;
+use bollard::Docker;
+use chrono::Utc;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio_cron_scheduler::{Job, JobScheduler};
+
+pub static VERIFY_BACKUPS: AtomicBool = AtomicBool::new(false);
+
+// internal, so a restored node can't reach peers or the live swarm
+const VERIFY_NETWORK: &str = "sphinx-swarm-verify";
+const SQLITE_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+// services can take minutes to open a restored volume
+const PROBE_ATTEMPTS: u32 = 30;
+const PROBE_INTERVAL_MS: u64 = 10_000;
+
+fn verify_cron() -> String {
+    getenv("BACKUP_VERIFY_CRON").unwrap_or("@weekly".to_string())
+}
+
+/// A command run in a throwaway container of the node's image, and what
+/// its output must contain. Credentials are passed in `env`, not in `cmd`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerProbe {
+    pub cmd: String,
+    pub env: Vec<String>,
+    pub expect: String,
+}
+
+pub fn container_probe(img: &Image) -> Option<ContainerProbe> {
+    match img {
+        Image::Neo4j(n) => Some(ContainerProbe {
+            cmd: "cypher-shell -u neo4j -p \"$PROBE_PASSWORD\" --format plain 'MATCH (n) RETURN count(n) AS nodes'"
+                .to_string(),
+            env: vec![format!("PROBE_PASSWORD={}", n.password)],
+            expect: "nodes".to_string(),
+        }),
+        Image::Btc(b) => {
+            let mut cmd = "bitcoin-cli -rpcconnect=127.0.0.1 -rpcport=18443".to_string();
+            let mut env = Vec::new();
+            match (&b.user, &b.pass) {
+                (Some(u), Some(p)) => {
+                    cmd.push_str(" -rpcuser=\"$PROBE_RPC_USER\" -rpcpassword=\"$PROBE_RPC_PASS\"");
+                    env.push(format!("PROBE_RPC_USER={}", u));
+                    env.push(format!("PROBE_RPC_PASS={}", p));
+                }
+                _ => cmd.push_str(&format!(" -datadir={}", b.repo().root_volume)),
+            }
+            if b.network != "bitcoin" {
+                cmd.push_str(&format!(" -{}=1", b.network));
+            }
+            cmd.push_str(" verifychain 3 6");
+            Some(ContainerProbe {
+                cmd,
+                env,
+                expect: "true".to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Read the whole tar, so a truncated or corrupt one fails, and run
+/// `PRAGMA integrity_check` on every sqlite database in it.
+pub fn check_tar(tar: &Path, work_dir: &Path) -> Result<Vec<String>> {
+    let mut archive = tar::Archive::new(std::fs::File::open(tar)?);
+    let mut checked = Vec::new();
+    for (i, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let mut head = Vec::new();
+        (&mut entry).take(SQLITE_MAGIC.len() as u64).read_to_end(&mut head)?;
+        if head != SQLITE_MAGIC {
+            std::io::copy(&mut entry, &mut std::io::sink())?;
+            continue;
+        }
+        let db = work_dir.join(format!("probe-{}.sqlite3", i));
+        {
+            let mut f = std::fs::File::create(&db)?;
+            f.write_all(&head)?;
+            std::io::copy(&mut entry, &mut f)?;
+        }
+        let res = sqlite_integrity(&db);
+        let _ = std::fs::remove_file(&db);
+        match res? {
+            r if r == "ok" => checked.push(name),
+            r => return Err(anyhow!("{}: {}", name, r)),
+        }
+    }
+    Ok(checked)
+}
+
+fn sqlite_integrity(db: &Path) -> Result<String> {
+    let conn = rusqlite::Connection::open_with_flags(db, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let rows: Vec<String> = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(rows.join("; "))
+}
+
+async fn ensure_network(docker: &Docker) -> Result<()> {
+    if docker.inspect_network::<String>(VERIFY_NETWORK, None).await.is_ok() {
+        return Ok(());
+    }
+    docker
+        .create_network(CreateNetworkOptions {
+            name: VERIFY_NETWORK,
+            internal: true,
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
+
+// run `probe` in a copy of the node's container on the scratch volume
+async fn run_container_probe(
+    docker: &Docker,
+    img: &Image,
+    nodes: &Vec<config::Node>,
+    scratch: &str,
+    probe: &ContainerProbe,
+) -> Result<String> {
+    let root_volume = img.repo().root_volume;
+    let mut c = img.make_config(nodes, docker).await?;
+    c.labels = None;
+    if let Some(hc) = c.host_config.as_mut() {
+        hc.binds = Some(vec![format!("{}:{}:rw", scratch, root_volume)]);
+        hc.port_bindings = None;
+        hc.network_mode = Some(VERIFY_NETWORK.to_string());
+        hc.restart_policy = None;
+        hc.extra_hosts = None;
+    }
+    ensure_network(docker).await?;
+    // keep the node's hostname, services bind to it
+    let name = format!("{}-verify", domain(&img.name()));
+    let _ = dock::stop_and_remove(docker, &name).await;
+    docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: name.clone(),
+                platform: None,
+            }),
+            c,
+        )
+        .await?;
+    let res = async {
+        dock::start_container(docker, &name).await?;
+        let mut last = anyhow!("probe never ran");
+        for _ in 0..PROBE_ATTEMPTS {
+            dock::sleep(PROBE_INTERVAL_MS).await;
+            match dock::exec_checked_env(docker, &name, &probe.cmd, probe.env.clone()).await {
+                Ok(out) if out.contains(&probe.expect) => return Ok(out.trim().to_string()),
+                Ok(out) => last = anyhow!("unexpected output: {}", out.trim()),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+    .await;
+    let _ = dock::stop_and_remove(docker, &name).await;
+    res
+}
+
+/// Test-restore one snapshot into a scratch volume and probe it.
+pub async fn verify_snapshot(docker: &Docker, node: &str, snapshot: &str) -> Result<String> {
+    let nodes = config::stack_read(|s| s.nodes.clone()).await;
+    let img = find_img(node, &nodes)?;
+    let root_volume = img.repo().root_volume;
+    let swarm = format!("swarm{}", getenv("SWARM_NUMBER")?);
+    let store = backup_store().await?;
+
+    let dir = format!("verify_{}_{}", node, snapshot);
+    let scratch = format!("{}-verify-{}", domain(node), Utc::now().timestamp());
+    let res = async {
+        let tar = backup_catalog::fetch_tar(store.as_ref(), &swarm, node, snapshot, &dir).await?;
+        backup_catalog::check_checksum(node, snapshot, &tar).await?;
+        let (tar_path, work) = (tar.clone(), dir.clone());
+        let dbs = tokio::task::spawn_blocking(move || check_tar(Path::new(&tar_path), Path::new(&work))).await??;
+        let mut detail = format!("tar ok, {} sqlite db ok", dbs.len());
+
+        if let Some(probe) = container_probe(&img) {
+            dock::create_volume(docker, &scratch).await?;
+            let helper_name = format!("{}-verify-helper", domain(node));
+            let helper = backup_catalog::start_helper(
+                docker,
+                &helper_name,
+                vec![format!("{}:{}:rw", scratch, root_volume)],
+            )
+            .await?;
+            let inner_root_path = dock::get_last_segment(&root_volume).to_string();
+            let copied = async {
+                dock::copy_data_to_volume(docker, &helper, node, &inner_root_path, &root_volume, &tar)
+                    .await
+                    .map_err(|e| anyhow!("copy to volume: {}", e))?;
+                backup_hooks::run_after_restore(docker, &helper, &img).await
+            }
+            .await;
+            let _ = dock::stop_and_remove(docker, &helper).await;
+            copied?;
+            let out = run_container_probe(docker, &img, &nodes, &scratch, &probe).await?;
+            detail = format!("{}, probe: {}", detail, out);
+        }
+        Ok(detail)
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    let _ = dock::remove_volume(docker, &scratch).await;
+    res
+}
+
+/// Verify the newest snapshot of each backed up node, if not done yet.
+pub async fn verify_recent(docker: &Docker) -> Result<bool> {
+    let services = config::stack_read(|s| s.backup_services.clone()).await.unwrap_or_default();
+    let mut all_passed = true;
+    for node in services.iter() {
+        let backups = match backup_catalog::list_backups(node).await {
+            Ok(b) => b,
+            Err(e) => {
+                log::error!("Backup verification {}: can't list backups: {:?}", node, e);
+                all_passed = false;
+                continue;
+            }
+        };
+        let latest: Option<BackupRecord> =
+            backups.into_iter().find(|r| r.status != BackupStatus::Failed);
+        let rec = match latest {
+            Some(r) if r.verification.is_none() => r,
+            _ => continue,
+        };
+        log::info!("Verifying backup {} {}", node, rec.snapshot);
+        let res = verify_snapshot(docker, node, &rec.snapshot).await;
+        let v = match res {
+            Ok(detail) => Verification {
+                at: Utc::now().timestamp(),
+                passed: true,
+                detail,
+            },
+            Err(e) => {
+                log::error!("Backup verification {} {} failed: {:?}", node, rec.snapshot, e);
+                all_passed = false;
+                Verification {
+                    at: Utc::now().timestamp(),
+                    passed: false,
+                    detail: e.to_string(),
+                }
+            }
+        };
+        backup_catalog::set_verification(&rec, v).await;
+    }
+    Ok(all_passed)
+}
+
+pub async fn backup_verify_cron(docker: Docker) -> Result<JobScheduler> {
+    log::info!(":backup verify");
+    let sched = JobScheduler::new().await?;
+
+    sched
+        .add(Job::new_async(verify_cron().as_str(), |_uuid, _l| {
+            Box::pin(async move {
+                if !VERIFY_BACKUPS.load(Ordering::Relaxed) {
+                    VERIFY_BACKUPS.store(true, Ordering::Relaxed);
+                }
+            })
+        })?)
+        .await?;
+
+    sched.start().await?;
+
+    tokio::spawn(async move {
+        loop {
+            let go = VERIFY_BACKUPS.load(Ordering::Relaxed);
+            if go {
+                let res = verify_recent(&docker).await;
+                metrics::record_job("backup_verify", matches!(res, Ok(true)));
+                if let Err(e) = res {
+                    log::error!("Backup verify: {:?}", e);
+                }
+                VERIFY_BACKUPS.store(false, Ordering::Relaxed);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
+    Ok(sched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_probe_keeps_secrets_out_of_cmd() {
+        let neo4j = Image::Neo4j(crate::images::neo4j::Neo4jImage::new("neo4j", "5.19.0"));
+        let probe = container_probe(&neo4j).unwrap();
+        let password = match &neo4j {
+            Image::Neo4j(n) => n.password.clone(),
+            _ => unreachable!(),
+        };
+        assert!(!probe.cmd.contains(&password));
+        assert_eq!(probe.env, vec![format!("PROBE_PASSWORD={}", password)]);
+
+        let mut btc = crate::images::btc::BtcImage::new("bitcoind", "23.0", "regtest");
+        btc.set_user_password("sphinx", "s3cret");
+        let probe = container_probe(&Image::Btc(btc)).unwrap();
+        assert!(!probe.cmd.contains("s3cret"));
+        assert!(probe.env.contains(&"PROBE_RPC_PASS=s3cret".to_string()));
+    }
+
+    #[test]
+    fn test_check_tar() {
+        let dir = std::env::temp_dir().join(format!("swarm-verify-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = dir.join("lightningd.sqlite3");
+        let conn = rusqlite::Connection::open(&db).unwrap();
+        conn.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);").unwrap();
+        drop(conn);
+
+        let tar_path = dir.join("cln.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&tar_path).unwrap());
+        builder.append_path_with_name(&db, ".lightning/regtest/lightningd.sqlite3").unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_cksum();
+        builder.append_data(&mut header, ".lightning/config", &b"hello"[..]).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let got = check_tar(&tar_path, &dir).unwrap();
+        assert_eq!(got, vec![".lightning/regtest/lightningd.sqlite3".to_string()]);
+
+        // chop the tar mid-database
+        let bytes = std::fs::read(&tar_path).unwrap();
+        std::fs::write(&tar_path, &bytes[..1024]).unwrap();
+        assert!(check_tar(&tar_path, &dir).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
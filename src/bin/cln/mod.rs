@@ -63,15 +63,24 @@ pub async fn main() -> Result<()> {
         }
     }
     if !skip_setup {
-        setup_cln_chans(&mut clients, &stack.nodes, CLN1, CLN2, BTC).await?;
+        setup_chans(&mut clients, &stack.nodes, CLN1, CLN2, BTC).await?;
         if do_test_proxy() {
-            setup_lnd_chans(&mut clients, &stack.nodes, CLN1, LND_1, BTC).await?;
+            setup_chans(&mut clients, &stack.nodes, CLN1, LND_1, BTC).await?;
+        }
+        // cln2's channel from cln1 is under the policy minimum
+        if do_test_liquidity() {
+            test_liquidity(&mut clients, CLN2, CLN1, "9735", BTC).await?;
         }
     }
 
     println!("hydrate clients now!");
     handler::hydrate_clients(clients).await;
 
+    // needs the channel from setup and the clients behind the rocket routes
+    if do_test_lnurl() && !skip_setup {
+        test_lightning_address(proj, CLN1, CLN2).await?;
+    }
+
     if let Some(nn) = stack.auto_update {
         let _cron_handler = builder::auto_updater(proj, docker, nn).await?;
     }
@@ -157,9 +166,28 @@ fn make_stack() -> Stack {
         auto_restart: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
 
+fn do_test_liquidity() -> bool {
+    std::env::var("TEST_LIQUIDITY").map(|v| v == "true").unwrap_or(false)
+}
+
+fn do_test_lnurl() -> bool {
+    std::env::var("TEST_LNURL").map(|v| v == "true").unwrap_or(false)
+}
+
 fn do_test_proxy() -> bool {
     if let Ok(test_proxy) = std::env::var("TEST_PROXY") {
         if test_proxy == String::from("true") {
@@ -49,6 +49,7 @@ pub async fn main() -> Result<()> {
 
     // cln setup
     let mut id_map = HashMap::new();
+    logs::init(proj).await;
     let mut log_txs = logs::new_log_chans();
     for (tag, i) in NODES.iter() {
         let name = format!("cln{}", i);
@@ -1,15 +1,24 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use rocket::tokio;
+use sphinx_swarm::alerts::alerts_cron;
 use sphinx_swarm::auto_restart_cron::auto_restart_cron;
 use sphinx_swarm::backup::{backup_and_delete_volumes_cron, backup_files_cron};
+use sphinx_swarm::backup_verify::backup_verify_cron;
 use sphinx_swarm::builder;
 use sphinx_swarm::config::{load_config_file, migrate_stack, put_config_file, Stack};
 use sphinx_swarm::cron_jobs::public_ip::check_public_ip;
+use sphinx_swarm::disk::cleanup_cron;
+use sphinx_swarm::fee_schedule::fee_schedule_cron;
 use sphinx_swarm::handler;
+use sphinx_swarm::liquidity::liquidity_cron;
+use sphinx_swarm::ln_events::watch_lightning_nodes;
 use sphinx_swarm::mount_backedup_volume::delete_zip_and_upzipped_files;
+use sphinx_swarm::rebalance::rebalance_cron;
 use sphinx_swarm::renew_ssl_cert::upload_new_ssl_cert_cron;
 use sphinx_swarm::routes;
-use sphinx_swarm::utils::is_using_port_based_ssl;
+use sphinx_swarm::stats_history::stats_history_cron;
+use sphinx_swarm::swarm_bundle::{import_bundle, HostOverrides};
+use sphinx_swarm::utils::{getenv, is_using_port_based_ssl};
 use sphinx_swarm::{dock::*, events, logs};
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -22,6 +31,18 @@ async fn main() -> Result<()> {
     sphinx_swarm::utils::setup_logs();
 
     let proj = "stack";
+
+    // migrate from another host: restore a bundle from ExportSwarm first
+    // docker-compose always sets it, empty unless a bundle was given
+    if let Ok(bundle) = getenv("IMPORT_BUNDLE") {
+        let overrides = HostOverrides::from_env();
+        // don't start on a default config over half restored volumes
+        import_bundle(&docker, &bundle, &overrides)
+            .await
+            .with_context(|| format!("import bundle {}", bundle))?;
+        log::info!("imported {}", bundle);
+    }
+
     let mut stack: Stack = load_config_file(proj).await.expect("YAML CONFIG FAIL");
 
     // auto-add new required nodes (e.g. quickwit, vector) to existing configs
@@ -37,10 +58,13 @@ async fn main() -> Result<()> {
     // hydrate the "stack" without clients
     handler::hydrate_stack(stack.clone()).await;
 
+    logs::init(proj).await;
     let log_txs = logs::new_log_chans();
     let log_txs = Arc::new(Mutex::new(log_txs));
 
     let event_txs = events::new_event_chan();
+    let ln_event_tx = event_txs.clone();
+    events::set_event_chan(event_txs.clone());
 
     println!("=> launch rocket");
     let rocket_docker = docker.clone();
@@ -63,6 +87,9 @@ async fn main() -> Result<()> {
     println!("hydrate clients now!");
     handler::hydrate_clients(clients).await;
 
+    // invoice and payment events for the event stream and webhooks
+    watch_lightning_nodes(ln_event_tx);
+
     if let Some(nn) = stack.auto_update {
         let cron_handler_res = builder::auto_updater(proj, docker.clone(), nn).await;
         if let Err(e) = cron_handler_res {
@@ -72,6 +99,9 @@ async fn main() -> Result<()> {
 
     if let Some(backup_services) = stack.backup_services {
         backup_and_delete_volumes_cron(backup_services).await?;
+        if let Err(e) = backup_verify_cron(docker.clone()).await {
+            log::error!("BACKUP VERIFY CRON failed {:?}", e);
+        }
     } else {
         log::info!("BACKUP is not set!!")
     }
@@ -108,6 +138,33 @@ async fn main() -> Result<()> {
         log::error!("CHECK PUBLIC IP CRON failed {:?}", e);
     }
 
+    if let Err(e) = stats_history_cron(proj, docker.clone()).await {
+        log::error!("STATS HISTORY CRON failed {:?}", e);
+    }
+
+    if let Err(e) = alerts_cron(docker.clone()).await {
+        log::error!("ALERTS CRON failed {:?}", e);
+    }
+
+    if let Err(e) = liquidity_cron().await {
+        log::error!("LIQUIDITY CRON failed {:?}", e);
+    }
+
+    if let Err(e) = rebalance_cron().await {
+        log::error!("REBALANCE CRON failed {:?}", e);
+    }
+
+    if let Err(e) = fee_schedule_cron().await {
+        log::error!("FEE SCHEDULE CRON failed {:?}", e);
+    }
+
+    let cleanup_schedule = stack.cleanup.as_ref().and_then(|c| c.cron.clone());
+    if let Some(cron) = cleanup_schedule {
+        if let Err(e) = cleanup_cron(docker.clone(), cron).await {
+            log::error!("DISK CLEANUP CRON failed {:?}", e);
+        }
+    }
+
     tokio::signal::ctrl_c().await?;
 
     builder::shutdown_now();
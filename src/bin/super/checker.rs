@@ -1,8 +1,12 @@
 use anyhow::anyhow;
 use anyhow::Result;
+use once_cell::sync::Lazy;
 use rocket::tokio;
 use serde::{Deserialize, Serialize};
+use sphinx_swarm::metrics;
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 use tokio_cron_scheduler::{Job, JobScheduler};
 
@@ -10,6 +14,10 @@ use crate::state::{self, BotCred};
 
 pub static SWARM_CHECKER: AtomicBool = AtomicBool::new(false);
 
+// service ("boltwall", "jarvis", "navfiber") -> number of failed checks
+pub static CHECK_FAILURES: Lazy<Mutex<BTreeMap<String, u64>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BotMsgBody {
     action: String,
@@ -47,7 +55,9 @@ pub async fn swarm_checker() -> Result<JobScheduler> {
         loop {
             let go = SWARM_CHECKER.load(Ordering::Relaxed);
             if go {
-                if let Err(e) = check_all_swarms().await {
+                let res = check_all_swarms().await;
+                metrics::record_job("swarm_checker", res.is_ok());
+                if let Err(e) = res {
                     log::error!("{:?}", e);
                 }
                 SWARM_CHECKER.store(false, Ordering::Relaxed);
@@ -90,6 +100,7 @@ pub async fn check_all_swarms() -> Result<()> {
                 let jarvis_status =
                     get_boltwall_or_jarvis_status(format!("{}stats", boltwall_url.clone())).await?;
                 let navfiber_status = get_navfiber_status(navfiber_url.clone()).await?;
+                record_check_failures(boltwall_status, jarvis_status, navfiber_status);
 
                 // if any is not responding configure error message
                 let new_message = configure_error_msg(
@@ -122,6 +133,20 @@ pub async fn check_all_swarms() -> Result<()> {
     Ok(())
 }
 
+fn record_check_failures(boltwall_status: bool, jarvis_status: bool, navfiber_status: bool) {
+    let mut failures = CHECK_FAILURES.lock().unwrap();
+    for (service, up) in [
+        ("boltwall", boltwall_status),
+        ("jarvis", jarvis_status),
+        ("navfiber", navfiber_status),
+    ] {
+        let n = failures.entry(service.to_string()).or_insert(0);
+        if !up {
+            *n += 1;
+        }
+    }
+}
+
 fn get_boltwall_and_navfiber_url(host: String, default_host: String) -> Result<(String, String)> {
     if default_host.ends_with(":8800") {
         return Ok((
@@ -0,0 +1,53 @@
+use crate::checker::CHECK_FAILURES;
+use crate::state::state_read;
+use sphinx_swarm::metrics::{render_counters, Exposition};
+
+/// Everything the super binary exposes on /metrics: its own command and
+/// job counters plus gauges describing the fleet.
+pub async fn render_super() -> String {
+    let mut e = Exposition::new();
+    render_counters(&mut e);
+
+    let (active, deleted, stopped, reserved, reserved_min) = state_read(|s| {
+        let deleted = s.stacks.iter().filter(|st| st.deleted == Some(true)).count();
+        let stopped = s.stopped_stacks.as_ref().map(|st| st.len()).unwrap_or(0);
+        let (reserved, reserved_min) = match &s.reserved_instances {
+            Some(r) => (r.available_instances.len(), r.minimum_available),
+            None => (0, 0),
+        };
+        (s.stacks.len() - deleted, deleted, stopped, reserved, reserved_min)
+    })
+    .await;
+
+    for (state, n) in [("active", active), ("stopped", stopped), ("deleted", deleted)] {
+        e.gauge(
+            "super_swarms",
+            "Number of child swarms by state",
+            &[("state", state)],
+            n as f64,
+        );
+    }
+    e.gauge(
+        "super_reserved_instances_available",
+        "Warm swarms waiting in the reserved pool",
+        &[],
+        reserved as f64,
+    );
+    e.gauge(
+        "super_reserved_instances_minimum",
+        "Configured minimum size of the reserved pool",
+        &[],
+        reserved_min as f64,
+    );
+
+    let failures = CHECK_FAILURES.lock().unwrap().clone();
+    for (service, n) in failures.iter() {
+        e.counter(
+            "super_checker_failures_total",
+            "Failed swarm checker probes, by service",
+            &[("service", service)],
+            *n as f64,
+        );
+    }
+    e.finish()
+}
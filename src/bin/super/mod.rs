@@ -5,6 +5,7 @@ pub mod cloudwatch;
 mod cmd;
 mod ec2;
 mod lightning_bots;
+mod metrics;
 mod route53;
 mod routes;
 mod service;
@@ -19,6 +19,7 @@ use rocket::*;
 use sphinx_swarm::config::{
     ApiResponse, SendSwarmDetailsBody, SendSwarmDetailsResponse, UpdateChildSwarmPublicIpBody,
 };
+use sphinx_swarm::metrics;
 use sphinx_swarm::rocket_utils::{Error, Result, CORS};
 use sphinx_swarm::routes::{all_options, events, logs, logstream, refresh_jwt};
 use std::sync::Arc;
@@ -33,6 +34,7 @@ pub async fn launch_rocket(
 ) -> Result<Rocket<Ignite>> {
     Ok(rocket::build()
         .mount("/", FileServer::from(relative!("app/dist")))
+        .mount("/", routes![super_metrics])
         .mount(
             "/api/",
             routes![
@@ -71,7 +73,11 @@ async fn cmd(
     claims: auth::AdminJwtClaims,
 ) -> Result<String> {
     if let Ok(cmd) = serde_json::from_str::<Cmd>(txt) {
-        match super_handle(&proj, cmd, tag, &Some(claims.user)).await {
+        let (typ, name) = metrics::cmd_labels(&cmd);
+        let start = std::time::Instant::now();
+        let res = super_handle(proj, cmd, tag, &Some(claims.user)).await;
+        metrics::record_cmd(&typ, &name, start.elapsed().as_secs_f64(), res.is_ok());
+        match res {
             Ok(res) => Ok(res),
             Err(err) => Ok(fmt_err(&err.to_string())),
         }
@@ -80,6 +86,11 @@ async fn cmd(
     }
 }
 
+#[get("/metrics")]
+async fn super_metrics(_auth: metrics::MetricsAuth) -> String {
+    crate::metrics::render_super().await
+}
+
 // Super-specific login route (replaces shared login that used mpsc channel)
 use crate::cmd::{ChangePasswordInfo, LoginInfo};
 use rocket::serde::Deserialize;
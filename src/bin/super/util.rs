@@ -294,6 +294,7 @@ pub async fn access_child_swarm_containers(
             cmd = Cmd::Swarm(SwarmCmd::UpdateNode(UpdateNode {
                 id: node.clone(),
                 version: "latest".to_string(),
+                experimental_offers: None,
             }));
         } else if cmd_text == "StartContainer" {
             cmd = Cmd::Swarm(SwarmCmd::StartContainer(node.clone()))
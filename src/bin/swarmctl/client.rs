@@ -0,0 +1,113 @@
+use crate::context::{CtlConfig, SwarmContext};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sphinx_swarm::utils::make_reqwest_client;
+
+#[derive(Serialize)]
+struct LoginBody<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+struct LoginRes {
+    token: String,
+}
+
+pub struct Client {
+    name: String,
+    ctx: SwarmContext,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(name: &str, ctx: SwarmContext) -> Self {
+        Self {
+            name: name.to_string(),
+            ctx,
+            http: make_reqwest_client(),
+        }
+    }
+
+    pub async fn login(&self, password: &str) -> Result<String> {
+        let route = format!("{}/login", self.ctx.url);
+        let res = self
+            .http
+            .post(&route)
+            .json(&LoginBody {
+                username: &self.ctx.username,
+                password,
+            })
+            .send()
+            .await?;
+        if res.status() != 200 {
+            return Err(anyhow!("{} status code from login", res.status()));
+        }
+        let body: LoginRes = res.json().await?;
+        Ok(body.token)
+    }
+
+    /// A cached token if one is still fresh, otherwise log in again with
+    /// SWARMCTL_PASSWORD and cache the new token.
+    async fn token(&mut self, cfg: &mut CtlConfig) -> Result<String> {
+        if let Some(t) = self.ctx.fresh_token() {
+            return Ok(t);
+        }
+        self.relogin(cfg).await
+    }
+
+    async fn relogin(&mut self, cfg: &mut CtlConfig) -> Result<String> {
+        let password = std::env::var("SWARMCTL_PASSWORD").map_err(|_| {
+            anyhow!(
+                "token for {} is missing or expired, run `swarmctl login`",
+                self.name
+            )
+        })?;
+        let token = self.login(&password).await?;
+        cfg.set_token(&self.name, &token);
+        cfg.save()?;
+        self.ctx.token = Some(token.clone());
+        Ok(token)
+    }
+
+    /// Send a serialized Cmd to /cmd. Retries once with a fresh login on 401.
+    pub async fn cmd<C: Serialize>(
+        &mut self,
+        cfg: &mut CtlConfig,
+        cmd: &C,
+        tag: &str,
+    ) -> Result<Value> {
+        let txt = serde_json::to_string(cmd)?;
+        let token = self.token(cfg).await?;
+        let mut res = self.send(&txt, tag, &token).await?;
+        if res.status() == 401 {
+            let token = self.relogin(cfg).await?;
+            res = self.send(&txt, tag, &token).await?;
+        }
+        if res.status() != 200 {
+            return Err(anyhow!("{} status code from {}", res.status(), self.name));
+        }
+        let text = res.text().await?;
+        let val: Value = match serde_json::from_str(&text) {
+            Ok(v) => v,
+            // some commands return a bare string
+            Err(_) => Value::String(text),
+        };
+        if let Some(err) = val.get("stack_error") {
+            return Err(anyhow!("{}", err.as_str().unwrap_or_default()));
+        }
+        Ok(val)
+    }
+
+    async fn send(&self, txt: &str, tag: &str, token: &str) -> Result<reqwest::Response> {
+        let route = format!("{}/cmd", self.ctx.url);
+        Ok(self
+            .http
+            .get(&route)
+            .header("x-jwt", token)
+            .query(&[("txt", txt), ("tag", tag)])
+            .send()
+            .await?)
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextKind {
+    Stack,
+    Super,
+}
+
+/// One swarm (or super admin) host the CLI can talk to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SwarmContext {
+    pub kind: ContextKind,
+    // base api url, e.g. https://app.swarm12.sphinx.chat/api
+    pub url: String,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    // unix seconds when the cached token was issued
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_issued: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CtlConfig {
+    pub current: Option<String>,
+    pub contexts: BTreeMap<String, SwarmContext>,
+}
+
+// swarm JWTs are valid for 7 days; refresh a bit before that
+const TOKEN_MAX_AGE_SECS: u64 = 6 * 24 * 60 * 60;
+
+pub fn config_path() -> Result<PathBuf> {
+    if let Ok(p) = std::env::var("SWARMCTL_CONFIG") {
+        return Ok(PathBuf::from(p));
+    }
+    let home = std::env::var("HOME").context("HOME is not set")?;
+    Ok(PathBuf::from(home).join(".swarmctl").join("config.yaml"))
+}
+
+impl CtlConfig {
+    pub fn load() -> Result<Self> {
+        let path = config_path()?;
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        let data = std::fs::read(&path)?;
+        Ok(serde_yaml::from_slice(&data)?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = config_path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // the file holds JWTs, so it's private before anything is written,
+        // including when an older version left it readable
+        use std::io::Write;
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        file.write_all(serde_yaml::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// The context named by --context, or the current one.
+    pub fn resolve(&self, name: &Option<String>) -> Result<(String, SwarmContext)> {
+        let name = match name {
+            Some(n) => n.clone(),
+            None => self
+                .current
+                .clone()
+                .ok_or(anyhow!("no context selected, run `swarmctl context use <name>`"))?,
+        };
+        let ctx = self
+            .contexts
+            .get(&name)
+            .ok_or(anyhow!("no context named {}", name))?;
+        Ok((name, ctx.clone()))
+    }
+
+    pub fn set_token(&mut self, name: &str, token: &str) {
+        if let Some(ctx) = self.contexts.get_mut(name) {
+            ctx.token = Some(token.to_string());
+            ctx.token_issued = Some(now());
+        }
+    }
+}
+
+impl SwarmContext {
+    pub fn fresh_token(&self) -> Option<String> {
+        let issued = self.token_issued.unwrap_or(0);
+        if now().saturating_sub(issued) > TOKEN_MAX_AGE_SECS {
+            return None;
+        }
+        self.token.clone()
+    }
+}
+
+pub fn now() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,553 @@
+mod client;
+mod context;
+mod output;
+
+// the super admin command types live in the super binary
+#[allow(dead_code)]
+#[path = "../super/cmd.rs"]
+mod super_cmd;
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use client::Client;
+use context::{ContextKind, CtlConfig, SwarmContext};
+use output::Output;
+use rocket::tokio;
+use serde_json::Value;
+use sphinx_swarm::cmd::{
+    ClnCmd, Cmd, ContainerLogsRequest, GetInvoice, LightningCmd, LndCmd, PayInvoice, SwarmCmd,
+    UpdateNode,
+};
+use std::collections::HashSet;
+
+#[derive(Parser)]
+#[command(name = "swarmctl", about = "Command-line client for sphinx swarms")]
+struct Cli {
+    /// Context to use instead of the current one
+    #[arg(long, global = true)]
+    context: Option<String>,
+    #[arg(long, short, global = true, value_enum, default_value_t = Output::Table)]
+    output: Output,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage named contexts, one per swarm host
+    #[command(subcommand)]
+    Context(ContextCmd),
+    /// Log in and cache the JWT for the context
+    Login {
+        /// Read from SWARMCTL_PASSWORD or prompted for if omitted
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Print the stack config (secrets removed)
+    Config,
+    /// List containers
+    Containers,
+    /// Print container logs
+    Logs {
+        name: String,
+        #[arg(long)]
+        since: Option<String>,
+        #[arg(long)]
+        before: Option<String>,
+        /// Keep polling for new lines
+        #[arg(long, short)]
+        follow: bool,
+    },
+    /// Container cpu and memory statistics
+    Stats { container: Option<String> },
+    Start { node: String },
+    Stop { node: String },
+    Restart { node: String },
+    /// Update a node to a new image version
+    Update {
+        node: String,
+        #[arg(long, default_value = "latest")]
+        version: String,
+        /// Turn BOLT12 offers on or off for a CLN node older than v24.11
+        #[arg(long)]
+        experimental_offers: Option<bool>,
+    },
+    /// LND commands against the node with this tag
+    Lnd {
+        #[arg(long, default_value = "lnd")]
+        tag: String,
+        #[command(subcommand)]
+        cmd: LightningSub,
+    },
+    /// CLN commands against the node with this tag
+    Cln {
+        #[arg(long, default_value = "cln")]
+        tag: String,
+        #[command(subcommand)]
+        cmd: LightningSub,
+    },
+    /// Lightning commands against a CLN or LND node, same output for both
+    Ln {
+        #[arg(long, default_value = "cln")]
+        tag: String,
+        #[command(subcommand)]
+        cmd: LightningSub,
+    },
+    /// Super admin commands (context must be a super context)
+    #[command(subcommand)]
+    Super(SuperSub),
+    /// Send a raw Cmd JSON, e.g. '{"type":"Swarm","data":{"cmd":"GetConfig"}}'
+    Raw {
+        json: String,
+        #[arg(long, default_value = "SWARM")]
+        tag: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ContextCmd {
+    Add {
+        name: String,
+        /// Base api url, e.g. https://app.swarm12.sphinx.chat/api
+        #[arg(long)]
+        url: String,
+        #[arg(long, default_value = "admin")]
+        username: String,
+        /// The host runs the super admin instead of a stack
+        #[arg(long = "super")]
+        is_super: bool,
+    },
+    Use { name: String },
+    List,
+    Remove { name: String },
+}
+
+#[derive(Subcommand)]
+enum LightningSub {
+    Info,
+    Peers,
+    Channels,
+    Balance,
+    NewAddress,
+    Invoices,
+    Payments,
+    Pay { payment_request: String },
+}
+
+#[derive(Subcommand)]
+enum SuperSub {
+    /// List swarms
+    Swarms,
+    /// List the containers of a child swarm
+    Containers {
+        host: String,
+        #[arg(long)]
+        reserved: bool,
+    },
+    /// Restart containers on a child swarm
+    Restart {
+        host: String,
+        nodes: Vec<String>,
+        #[arg(long)]
+        reserved: bool,
+    },
+    /// Update containers on a child swarm to latest
+    Update {
+        host: String,
+        nodes: Vec<String>,
+        #[arg(long)]
+        reserved: bool,
+    },
+}
+
+#[rocket::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let mut cfg = CtlConfig::load()?;
+    let out = cli.output;
+
+    let cmd = match cli.command {
+        Command::Context(c) => return context_cmd(&mut cfg, c, out),
+        Command::Login { password } => {
+            let (name, ctx) = cfg.resolve(&cli.context)?;
+            let password = match password {
+                Some(p) => p,
+                None => match std::env::var("SWARMCTL_PASSWORD") {
+                    Ok(p) => p,
+                    Err(_) => prompt_password(&ctx.username)?,
+                },
+            };
+            let token = Client::new(&name, ctx).login(&password).await?;
+            cfg.set_token(&name, &token);
+            cfg.save()?;
+            println!("logged in to {}", name);
+            return Ok(());
+        }
+        other => other,
+    };
+
+    let (name, ctx) = cfg.resolve(&cli.context)?;
+    let kind = ctx.kind;
+    let mut client = Client::new(&name, ctx);
+
+    let cmd = match (kind, cmd) {
+        (ContextKind::Super, Command::Super(sub)) => {
+            return super_command(&mut client, &mut cfg, sub, out).await;
+        }
+        (ContextKind::Super, Command::Raw { json, tag }) => {
+            let raw: Value = serde_json::from_str(&json)?;
+            let res = client.cmd(&mut cfg, &raw, &tag).await?;
+            output::print(out, &res, &[]);
+            return Ok(());
+        }
+        (ContextKind::Super, _) => {
+            return Err(anyhow!("{} is a super admin context, use `swarmctl super`", name));
+        }
+        (ContextKind::Stack, Command::Super(_)) => {
+            return Err(anyhow!("{} is not a super admin context", name));
+        }
+        (ContextKind::Stack, c) => c,
+    };
+
+    match cmd {
+        Command::Config => {
+            let res = client.cmd(&mut cfg, &Cmd::Swarm(SwarmCmd::GetConfig), "SWARM").await?;
+            match out {
+                Output::Json => output::print(out, &res, &[]),
+                Output::Table => {
+                    let nodes = res.get("nodes").cloned().unwrap_or(Value::Null);
+                    output::print(out, &nodes, &["place", "type", "name", "version", "host"]);
+                }
+            }
+        }
+        Command::Containers => {
+            let res = client
+                .cmd(&mut cfg, &Cmd::Swarm(SwarmCmd::ListContainers), "SWARM")
+                .await?;
+            output::print(out, &res, &["Names", "Image", "State", "Status"]);
+        }
+        Command::Logs {
+            name: node,
+            since,
+            before,
+            follow,
+        } => {
+            let mut seen: HashSet<String> = HashSet::new();
+            let mut since = since;
+            let container = if node.contains('.') {
+                node.clone()
+            } else {
+                sphinx_swarm::utils::domain(&node)
+            };
+            loop {
+                let req = ContainerLogsRequest {
+                    name: container.clone(),
+                    before_timestamp: before.clone(),
+                    since_timestamp: since.clone(),
+                };
+                let res = client
+                    .cmd(&mut cfg, &Cmd::Swarm(SwarmCmd::GetContainerLogs(req)), "SWARM")
+                    .await?;
+                let lines: Vec<String> = serde_json::from_value(res)?;
+                let mut latest: HashSet<String> = HashSet::new();
+                for line in lines.iter() {
+                    // "since" is only second-precision, so drop repeats
+                    if !seen.contains(line) {
+                        println!("{}", line);
+                    }
+                    latest.insert(line.clone());
+                    if let Some((ts, _)) = line.split_once(' ') {
+                        since = Some(ts.to_string());
+                    }
+                }
+                if !follow {
+                    break;
+                }
+                seen = latest;
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            }
+        }
+        Command::Stats { container } => {
+            let res = client
+                .cmd(&mut cfg, &Cmd::Swarm(SwarmCmd::GetStatistics(container)), "SWARM")
+                .await?;
+            output::print(out, &res, &[]);
+        }
+        Command::Start { node } => {
+            let res = client
+                .cmd(&mut cfg, &Cmd::Swarm(SwarmCmd::StartContainer(node)), "SWARM")
+                .await?;
+            output::print(out, &res, &[]);
+        }
+        Command::Stop { node } => {
+            let res = client
+                .cmd(&mut cfg, &Cmd::Swarm(SwarmCmd::StopContainer(node)), "SWARM")
+                .await?;
+            output::print(out, &res, &[]);
+        }
+        Command::Restart { node } => {
+            client
+                .cmd(&mut cfg, &Cmd::Swarm(SwarmCmd::RestartContainer(node.clone())), "SWARM")
+                .await?;
+            println!("restarted {}", node);
+        }
+        Command::Update {
+            node,
+            version,
+            experimental_offers,
+        } => {
+            let un = UpdateNode {
+                id: node.clone(),
+                version,
+                experimental_offers,
+            };
+            client
+                .cmd(&mut cfg, &Cmd::Swarm(SwarmCmd::UpdateNode(un)), "SWARM")
+                .await?;
+            println!("updated {}", node);
+        }
+        Command::Lnd { tag, cmd } => {
+            let (c, cols) = match cmd {
+                LightningSub::Info => (LndCmd::GetInfo, vec![]),
+                LightningSub::Peers => (LndCmd::ListPeers, vec![]),
+                LightningSub::Channels => (
+                    LndCmd::ListChannels,
+                    vec!["chan_id", "remote_pubkey", "capacity", "local_balance", "remote_balance", "active"],
+                ),
+                LightningSub::Balance => (LndCmd::GetBalance, vec![]),
+                LightningSub::NewAddress => (LndCmd::NewAddress, vec![]),
+                LightningSub::Invoices => (LndCmd::ListInvoices, vec![]),
+                LightningSub::Payments => (LndCmd::ListPayments, vec![]),
+                LightningSub::Pay { payment_request } => {
+                    (LndCmd::PayInvoice(PayInvoice { payment_request }), vec![])
+                }
+            };
+            let res = client.cmd(&mut cfg, &Cmd::Lnd(c), &tag).await?;
+            output::print(out, &res, &cols);
+        }
+        Command::Cln { tag, cmd } => {
+            let (c, list_key) = match cmd {
+                LightningSub::Info => (ClnCmd::GetInfo, None),
+                LightningSub::Peers => (ClnCmd::ListPeers, Some("peers")),
+                LightningSub::Channels => (ClnCmd::ListPeerChannels, Some("channels")),
+                LightningSub::Balance => (ClnCmd::ListFunds, None),
+                LightningSub::NewAddress => (ClnCmd::NewAddress, None),
+                LightningSub::Invoices => (
+                    ClnCmd::ListInvoices(Some(GetInvoice { payment_hash: None })),
+                    Some("invoices"),
+                ),
+                LightningSub::Payments => (
+                    ClnCmd::ListPays(Some(GetInvoice { payment_hash: None })),
+                    Some("pays"),
+                ),
+                LightningSub::Pay { payment_request } => {
+                    (ClnCmd::PayInvoice(PayInvoice { payment_request }), None)
+                }
+            };
+            let res = client.cmd(&mut cfg, &Cmd::Cln(c), &tag).await?;
+            // cln list responses wrap the rows in an object
+            let rows = match (out, list_key) {
+                (Output::Table, Some(k)) => res.get(k).cloned().unwrap_or(res),
+                _ => res,
+            };
+            output::print(out, &rows, &[]);
+        }
+        Command::Ln { tag, cmd } => {
+            let (c, cols) = match cmd {
+                LightningSub::Info => (LightningCmd::GetInfo, vec![]),
+                LightningSub::Peers => (LightningCmd::ListPeers, vec![]),
+                LightningSub::Channels => (
+                    LightningCmd::ListChannels,
+                    vec!["id", "peer", "capacity_sat", "local_msat", "remote_msat", "state"],
+                ),
+                LightningSub::Balance => (LightningCmd::GetBalance, vec![]),
+                LightningSub::NewAddress => (LightningCmd::NewAddress, vec![]),
+                LightningSub::Invoices => (
+                    LightningCmd::ListInvoices(None),
+                    vec!["payment_hash", "amount_msat", "status"],
+                ),
+                LightningSub::Payments => (
+                    LightningCmd::ListPayments(None),
+                    vec!["payment_hash", "amount_msat", "fee_msat", "status"],
+                ),
+                LightningSub::Pay { payment_request } => {
+                    (LightningCmd::PayInvoice(PayInvoice { payment_request }), vec![])
+                }
+            };
+            let res = client.cmd(&mut cfg, &Cmd::Lightning(c), &tag).await?;
+            output::print(out, &res, &cols);
+        }
+        Command::Raw { json, tag } => {
+            let raw: Cmd = serde_json::from_str(&json)?;
+            let res = client.cmd(&mut cfg, &raw, &tag).await?;
+            output::print(out, &res, &[]);
+        }
+        Command::Context(_) | Command::Login { .. } | Command::Super(_) => unreachable!(),
+    }
+    Ok(())
+}
+
+async fn super_command(
+    client: &mut Client,
+    cfg: &mut CtlConfig,
+    sub: SuperSub,
+    out: Output,
+) -> Result<()> {
+    use super_cmd::{AccessNodesInfo, ChildSwarmIdentifier, Cmd, SwarmCmd};
+    match sub {
+        SuperSub::Swarms => {
+            let res = client.cmd(cfg, &Cmd::Swarm(SwarmCmd::GetConfig), "SWARM").await?;
+            match out {
+                Output::Json => output::print(out, &res, &[]),
+                Output::Table => {
+                    let stacks = res.get("stacks").cloned().unwrap_or(Value::Null);
+                    output::print(
+                        out,
+                        &stacks,
+                        &["host", "default_host", "ec2", "ec2_instance_id", "public_ip_address", "note"],
+                    );
+                }
+            }
+        }
+        SuperSub::Containers { host, reserved } => {
+            let info = ChildSwarmIdentifier {
+                host,
+                is_reserved: Some(reserved),
+            };
+            let res = client
+                .cmd(cfg, &Cmd::Swarm(SwarmCmd::GetChildSwarmContainers(info)), "SWARM")
+                .await?;
+            let res = super_data(res)?;
+            output::print(out, &res, &["Names", "Image", "State", "Status"]);
+        }
+        SuperSub::Restart {
+            host,
+            nodes,
+            reserved,
+        } => {
+            let info = AccessNodesInfo {
+                host,
+                nodes,
+                is_reserved: Some(reserved),
+            };
+            let res = client
+                .cmd(cfg, &Cmd::Swarm(SwarmCmd::RestartChildSwarmContainers(info)), "SWARM")
+                .await?;
+            output::print(out, &super_data(res)?, &[]);
+        }
+        SuperSub::Update {
+            host,
+            nodes,
+            reserved,
+        } => {
+            let info = AccessNodesInfo {
+                host,
+                nodes,
+                is_reserved: Some(reserved),
+            };
+            let res = client
+                .cmd(cfg, &Cmd::Swarm(SwarmCmd::UpdateChildSwarmContainers(info)), "SWARM")
+                .await?;
+            output::print(out, &super_data(res)?, &[]);
+        }
+    }
+    Ok(())
+}
+
+// unwrap a SuperSwarmResponse, turning success: false into an error
+fn super_data(res: Value) -> Result<Value> {
+    let success = res.get("success").and_then(|s| s.as_bool()).unwrap_or(true);
+    let message = res
+        .get("message")
+        .and_then(|m| m.as_str())
+        .unwrap_or_default()
+        .to_string();
+    if !success {
+        return Err(anyhow!("{}", message));
+    }
+    match res.get("data") {
+        Some(Value::Null) | None => Ok(Value::String(message)),
+        Some(d) => Ok(d.clone()),
+    }
+}
+
+fn context_cmd(cfg: &mut CtlConfig, c: ContextCmd, out: Output) -> Result<()> {
+    match c {
+        ContextCmd::Add {
+            name,
+            url,
+            username,
+            is_super,
+        } => {
+            let kind = if is_super {
+                ContextKind::Super
+            } else {
+                ContextKind::Stack
+            };
+            cfg.contexts.insert(
+                name.clone(),
+                SwarmContext {
+                    kind,
+                    url: url.trim_end_matches('/').to_string(),
+                    username,
+                    token: None,
+                    token_issued: None,
+                },
+            );
+            if cfg.current.is_none() {
+                cfg.current = Some(name.clone());
+            }
+            cfg.save()?;
+            println!("added context {}", name);
+        }
+        ContextCmd::Use { name } => {
+            if !cfg.contexts.contains_key(&name) {
+                return Err(anyhow!("no context named {}", name));
+            }
+            cfg.current = Some(name.clone());
+            cfg.save()?;
+            println!("using context {}", name);
+        }
+        ContextCmd::List => {
+            let rows: Vec<Value> = cfg
+                .contexts
+                .iter()
+                .map(|(name, ctx)| {
+                    serde_json::json!({
+                        "current": if cfg.current.as_ref() == Some(name) { "*" } else { "" },
+                        "name": name,
+                        "kind": ctx.kind,
+                        "url": ctx.url,
+                        "username": ctx.username,
+                        "logged_in": ctx.fresh_token().is_some(),
+                    })
+                })
+                .collect();
+            output::print(
+                out,
+                &Value::Array(rows),
+                &["current", "name", "kind", "url", "username", "logged_in"],
+            );
+        }
+        ContextCmd::Remove { name } => {
+            cfg.contexts.remove(&name);
+            if cfg.current.as_ref() == Some(&name) {
+                cfg.current = None;
+            }
+            cfg.save()?;
+            println!("removed context {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn prompt_password(username: &str) -> Result<String> {
+    Ok(rpassword::prompt_password(format!("password for {}: ", username))?)
+}
@@ -0,0 +1,142 @@
+use serde_json::Value;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+pub fn print(out: Output, val: &Value, columns: &[&str]) {
+    match out {
+        Output::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(val).unwrap_or_default()
+            );
+        }
+        Output::Table => println!("{}", render_table(val, columns)),
+    }
+}
+
+/// Render a JSON value as a plain text table. Arrays of objects become one
+/// row per item (using `columns`, or every scalar key if empty). Objects
+/// become key/value rows. Anything else is printed as-is.
+pub fn render_table(val: &Value, columns: &[&str]) -> String {
+    match val {
+        Value::Array(items) => {
+            let cols: Vec<String> = if columns.is_empty() {
+                scalar_keys(items)
+            } else {
+                columns.iter().map(|c| c.to_string()).collect()
+            };
+            if cols.is_empty() {
+                let rows = items.iter().map(|i| vec![cell(i)]).collect();
+                return grid(&["value".to_string()], rows);
+            }
+            let rows = items
+                .iter()
+                .map(|item| cols.iter().map(|c| cell(lookup(item, c))).collect())
+                .collect();
+            grid(&cols, rows)
+        }
+        Value::Object(map) => {
+            let rows = map
+                .iter()
+                .map(|(k, v)| vec![k.clone(), cell(v)])
+                .collect();
+            grid(&["key".to_string(), "value".to_string()], rows)
+        }
+        other => cell(other),
+    }
+}
+
+// dotted paths reach into nested objects, e.g. "State"
+fn lookup<'a>(item: &'a Value, path: &str) -> &'a Value {
+    let mut cur = item;
+    for part in path.split('.') {
+        cur = cur.get(part).unwrap_or(&Value::Null);
+    }
+    cur
+}
+
+fn scalar_keys(items: &[Value]) -> Vec<String> {
+    let mut keys: Vec<String> = Vec::new();
+    for item in items {
+        if let Value::Object(map) = item {
+            for (k, v) in map {
+                if !v.is_object() && !v.is_array() && !keys.contains(k) {
+                    keys.push(k.clone());
+                }
+            }
+        }
+    }
+    keys
+}
+
+fn cell(v: &Value) -> String {
+    match v {
+        Value::Null => "".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(a) => a.iter().map(cell).collect::<Vec<_>>().join(","),
+        other => other.to_string(),
+    }
+}
+
+fn grid(header: &[String], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows.iter() {
+        for (i, c) in row.iter().enumerate() {
+            widths[i] = widths[i].max(c.chars().count());
+        }
+    }
+    let line = |cells: &[String]| -> String {
+        cells
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("{:width$}", c, width = widths[i]))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    let upper: Vec<String> = header.iter().map(|h| h.to_uppercase()).collect();
+    let mut out = vec![line(&upper)];
+    for row in rows.iter() {
+        out.push(line(row));
+    }
+    out.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_array_with_columns() {
+        let v = json!([
+            {"Names": ["/bitcoind.sphinx"], "State": "running", "Image": "lightninglabs/bitcoin-core"},
+            {"Names": ["/cln.sphinx"], "State": "exited", "Image": "sphinxlightning/cln-sphinx"},
+        ]);
+        let t = render_table(&v, &["Names", "State"]);
+        let lines: Vec<&str> = t.lines().collect();
+        assert_eq!(lines[0], "NAMES             STATE");
+        assert_eq!(lines[1], "/bitcoind.sphinx  running");
+        assert_eq!(lines[2], "/cln.sphinx       exited");
+    }
+
+    #[test]
+    fn test_render_object() {
+        let v = json!({"alias": "swarm1", "blockheight": 800000});
+        let t = render_table(&v, &[]);
+        assert!(t.contains("alias        swarm1"));
+        assert!(t.contains("blockheight  800000"));
+    }
+
+    #[test]
+    fn test_render_scalar_keys_skip_nested() {
+        let v = json!([{"name": "a", "nested": {"x": 1}}]);
+        let t = render_table(&v, &[]);
+        assert_eq!(t.lines().next().unwrap(), "NAME");
+    }
+}
@@ -8,7 +8,7 @@ use sphinx_swarm::images::{
     lnd::LndImage, mixer::MixerImage, tribes::TribesImage, Image,
 };
 use sphinx_swarm::rocket_utils::CmdRequest;
-use sphinx_swarm::setup::{get_pubkey_cln, mine_blocks, setup_cln_chans, setup_lnd_chans};
+use sphinx_swarm::setup::{get_pubkey, mine_blocks, setup_chans};
 use sphinx_swarm::utils::domain;
 use sphinx_swarm::{builder, events, handler, logs, routes};
 use std::sync::Arc;
@@ -97,10 +97,10 @@ pub async fn main() -> Result<()> {
         mine_blocks(&mut clients, BTC, 500).await?;
         mine_blocks(&mut clients, BTC, 500).await?;
         log::info!("setup chans");
-        setup_cln_chans(&mut clients, &stack.nodes, CLN1, CLN2, BTC).await?;
-        setup_cln_chans(&mut clients, &stack.nodes, CLN3, CLN2, BTC).await?;
+        setup_chans(&mut clients, &stack.nodes, CLN1, CLN2, BTC).await?;
+        setup_chans(&mut clients, &stack.nodes, CLN3, CLN2, BTC).await?;
         if do_test_lnd() {
-            setup_lnd_chans(&mut clients, &stack.nodes, CLN2, LND_1, BTC).await?;
+            setup_chans(&mut clients, &stack.nodes, CLN2, LND_1, BTC).await?;
         }
         try_check_2_hops(&mut clients, CLN1, CLN3).await;
     }
@@ -131,7 +131,7 @@ async fn sleep(secs: u64) {
 }
 
 async fn check_2_hops(clients: &mut Clients, node1: &str, node3: &str) -> Result<()> {
-    let cln3_pubkey = get_pubkey_cln(clients, node3).await?;
+    let cln3_pubkey = get_pubkey(clients, node3).await?;
     let cln1 = clients.cln.get_mut(node1).unwrap();
     let res = cln1.get_route(&cln3_pubkey, 1000).await?;
     if res.route.len() < 2 {
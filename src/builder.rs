@@ -143,11 +143,14 @@ pub async fn auto_updater(
         loop {
             let go = AUTO_UPDATE.load(Ordering::Relaxed);
             if go {
+                let mut ok = true;
                 for nn in &node_names_ {
                     if let Err(e) = update_node_from_state(&proj, &docker, nn).await {
                         log::error!("{:?}", e);
+                        ok = false;
                     }
                 }
+                crate::metrics::record_job("auto_update", ok);
                 // check if swarm is up to date if not update
                 let swarm_version_response = get_image_version("swarm", &docker, "").await;
                 if swarm_version_response.is_latest == false {
@@ -379,7 +382,9 @@ pub async fn fast_service_update(
         loop {
             let go = FAST_UPDATE.load(Ordering::Relaxed);
             if go {
-                if let Err(e) = handle_fast_node_update(&proj, &docker, nodes.clone()).await {
+                let res = handle_fast_node_update(&proj, &docker, nodes.clone()).await;
+                crate::metrics::record_job("fast_update", res.is_ok());
+                if let Err(e) = res {
                     log::error!("{:?}", e);
                 }
 
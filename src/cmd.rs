@@ -2,7 +2,26 @@ use anyhow::Error;
 use reqwest::Response;
 use std::collections::HashMap;
 
-use crate::{config::LightningPeer, images::Image, utils::make_reqwest_client};
+use crate::{
+    alerts::CreateSilenceRequest,
+    backup_retention::{BackupRetention, PruneBackupsRequest},
+    config::LightningPeer,
+    disk::{CleanupPolicy, RunCleanupRequest},
+    fee_schedule::{ListFeeChangesRequest, SetFeePolicy, UpdateFeeScheduleRequest},
+    images::Image,
+    liquidity::{
+        EvaluateLiquidityRequest, ListLiquidityDecisionsRequest, UpdateLiquidityPolicyRequest,
+    },
+    lnurl::{ListAddressInvoicesRequest, UpdateLightningAddressRequest},
+    log_search::SearchLogsRequest,
+    onchain::ListOnchainSpendsRequest,
+    quickwit_logs::{QueryQuickwitLogsRequest, QuickwitErrorCountsRequest, SavedLogQuery},
+    rebalance::{ListRebalancesRequest, UpdateRebalancePolicyRequest},
+    stats_history::StatisticsHistoryRequest,
+    swarm_bundle::ExportSwarmRequest,
+    utils::make_reqwest_client,
+    webhooks::{ListWebhookDeliveriesRequest, UpdateWebhookRequest},
+};
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use sphinx_auther::secp256k1::PublicKey;
@@ -17,6 +36,7 @@ pub enum Cmd {
     Cln(ClnCmd),
     Proxy(ProxyCmd),
     Hsmd(HsmdCmd),
+    Lightning(LightningCmd),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,6 +70,9 @@ pub struct ChangeAdminInfo {
 pub struct UpdateNode {
     pub id: String,
     pub version: String,
+    // cln only: BOLT12 offers for builds before v24.11, unchanged when none
+    #[serde(default)]
+    pub experimental_offers: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -198,6 +221,12 @@ pub enum SwarmCmd {
     GetConfig,
     AddNode(Image),
     GetContainerLogs(ContainerLogsRequest),
+    SearchLogs(SearchLogsRequest),
+    QueryQuickwitLogs(QueryQuickwitLogsRequest),
+    GetQuickwitErrorCounts(QuickwitErrorCountsRequest),
+    ListSavedLogQueries,
+    SaveLogQuery(SavedLogQuery),
+    DeleteSavedLogQuery(String),
     ListVersions(ImageRequest),
     Login(LoginInfo),
     ChangePassword(ChangePasswordInfo),
@@ -208,6 +237,7 @@ pub enum SwarmCmd {
     RestartContainer(String),
     UpdateNode(UpdateNode),
     GetStatistics(Option<String>),
+    GetStatisticsHistory(StatisticsHistoryRequest),
     AddBoltwallAdminPubkey(AddAdminRequest),
     GetBoltwallSuperAdmin,
     AddBoltwallUser(AddUserRequest),
@@ -258,6 +288,54 @@ pub enum SwarmCmd {
     HermesAuthStatus(String),
     HermesAuthList(HermesAuthRequest),
     HermesAuthLogout(HermesAuthRequest),
+    ListAlerts,
+    CreateAlertSilence(CreateSilenceRequest),
+    DeleteAlertSilence(String),
+    GetDiskUsage,
+    UpdateCleanupPolicy(CleanupPolicy),
+    RunCleanup(RunCleanupRequest),
+    ExportBackupKey,
+    ListBackups(ListBackupsRequest),
+    BackupNow(BackupNowRequest),
+    RestoreBackup(RestoreBackupRequest),
+    UpdateBackupRetention(BackupRetention),
+    PruneBackups(PruneBackupsRequest),
+    ExportSwarm(ExportSwarmRequest),
+    GetLiquidityPolicies,
+    UpdateLiquidityPolicy(UpdateLiquidityPolicyRequest),
+    EvaluateLiquidity(EvaluateLiquidityRequest),
+    ListLiquidityDecisions(ListLiquidityDecisionsRequest),
+    GetRebalancePolicies,
+    UpdateRebalancePolicy(UpdateRebalancePolicyRequest),
+    ListRebalances(ListRebalancesRequest),
+    GetFeeSchedules,
+    UpdateFeeSchedule(UpdateFeeScheduleRequest),
+    ListFeeChanges(ListFeeChangesRequest),
+    GetWebhooks,
+    UpdateWebhook(UpdateWebhookRequest),
+    ListWebhookDeliveries(ListWebhookDeliveriesRequest),
+    GetLightningAddresses,
+    UpdateLightningAddress(UpdateLightningAddressRequest),
+    ListLightningAddressInvoices(ListAddressInvoicesRequest),
+    GetFeeEstimates,
+    ListOnchainSpends(ListOnchainSpendsRequest),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListBackupsRequest {
+    pub node: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupNowRequest {
+    pub nodes: Vec<String>,
+}
+
+/// `snapshot` is a date from ListBackups, e.g. "2024-05-01".
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RestoreBackupRequest {
+    pub node: String,
+    pub snapshot: String,
 }
 
 /// `provider` defaults to "xai-oauth" when omitted.
@@ -343,6 +421,37 @@ pub struct CloseChannel {
     pub id: String,
     pub destination: String,
 }
+/// Pay ourselves out over `from_channel` and back in over `to_channel`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rebalance {
+    pub from_channel: String,
+    pub to_channel: String,
+    pub amount_msat: u64,
+    pub max_fee_ppm: u64,
+}
+/// A BOLT12 offer, labeled in the stack.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateOffer {
+    // any amount when none
+    pub amount_msat: Option<u64>,
+    pub description: Option<String>,
+    pub label: String,
+    pub single_use: Option<bool>,
+    // unix seconds
+    pub absolute_expiry: Option<u64>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisableOffer {
+    pub offer_id: String,
+}
+/// Fetch an invoice from someone's offer, and pay it for PayOffer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OfferPayment {
+    pub offer: String,
+    // required when the offer has no amount
+    pub amount_msat: Option<u64>,
+    pub payer_note: Option<String>,
+}
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AddChannel {
     pub pubkey: String,
@@ -378,6 +487,9 @@ pub enum LndCmd {
     ListPayments,
     ListInvoices,
     ListPendingChannels,
+    CloseChannel(CloseChannel),
+    ListFeePolicies,
+    SetFeePolicy(SetFeePolicy),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -396,6 +508,97 @@ pub enum ClnCmd {
     CloseChannel(CloseChannel),
     ListInvoices(Option<GetInvoice>),
     ListPays(Option<GetInvoice>),
+    ListPendingChannels,
+    Rebalance(Rebalance),
+    ListFeePolicies,
+    SetFeePolicy(SetFeePolicy),
+    CreateOffer(CreateOffer),
+    ListOffers,
+    DisableOffer(DisableOffer),
+    FetchInvoice(OfferPayment),
+    PayOffer(OfferPayment),
+}
+
+/// The same commands on CLN and LND, with normalized responses. Amounts
+/// are msat unless named otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "cmd", content = "content")]
+pub enum LightningCmd {
+    GetInfo,
+    ListPeers,
+    AddPeer(AddPeer),
+    ListChannels,
+    GetBalance,
+    NewAddress,
+    AddInvoice(LightningInvoice),
+    ListInvoices(Option<GetInvoice>),
+    PayInvoice(PayInvoice),
+    ListPayments(Option<GetInvoice>),
+    PayKeysend(LightningKeysend),
+    OpenChannel(LightningOpenChannel),
+    CloseChannel(LightningCloseChannel),
+    ListFeePolicies,
+    SetFeePolicy(SetFeePolicy),
+    ListUtxos,
+    FreezeUtxos(LightningUtxos),
+    UnfreezeUtxos(LightningUtxos),
+    // both only prepare the spend, ConfirmSpend sends it
+    SendOnchain(LightningSend),
+    PublishPsbt(LightningPsbt),
+    ConfirmSpend(ConfirmSpend),
+    CreatePsbt(LightningSend),
+    SignPsbt(LightningPsbt),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightningInvoice {
+    // 0 for any amount
+    pub amt_msat: u64,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightningKeysend {
+    pub dest: String,
+    pub amt_msat: u64,
+    pub tlvs: Option<HashMap<u64, Vec<u8>>>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightningOpenChannel {
+    pub pubkey: String,
+    pub amount_sat: u64,
+    pub sat_per_vbyte: Option<u64>,
+}
+/// `id` is a channel id from ListChannels.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightningCloseChannel {
+    pub id: String,
+    pub destination: Option<String>,
+}
+/// An on-chain send. The fee is sat_per_vbyte, or bitcoind's estimate for
+/// target_blocks, or the node's own estimate when neither is given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightningSend {
+    pub address: String,
+    // everything in the wallet, or in utxos, when none
+    pub amount_sat: Option<u64>,
+    pub sat_per_vbyte: Option<u64>,
+    pub target_blocks: Option<u16>,
+    // "txid:vout" from ListUtxos, the wallet picks when empty
+    #[serde(default)]
+    pub utxos: Vec<String>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightningUtxos {
+    pub utxos: Vec<String>,
+}
+/// A base64 PSBT.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightningPsbt {
+    pub psbt: String,
+}
+/// `id` of the PendingSpend that SendOnchain or PublishPsbt returned.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfirmSpend {
+    pub id: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -1,3 +1,14 @@
+use crate::alerts::AlertsConfig;
+use crate::backup_retention::BackupRetention;
+use crate::backup_store::BackupStoreConfig;
+use crate::disk::CleanupPolicy;
+use crate::fee_schedule::FeeSchedule;
+use crate::liquidity::LiquidityPolicy;
+use crate::lnurl::LightningAddress;
+use crate::offers::StoredOffer;
+use crate::quickwit_logs::SavedLogQuery;
+use crate::rebalance::RebalancePolicy;
+use crate::webhooks::Webhook;
 use crate::conn::bitcoin::bitcoinrpc::BitcoinRPC;
 use crate::conn::cln::hsmd::HsmdClient;
 use crate::conn::cln::ClnRPC;
@@ -11,7 +22,7 @@ use once_cell::sync::Lazy;
 use rocket::tokio;
 use rocket::tokio::sync::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
@@ -131,6 +142,34 @@ pub struct Stack {
     pub ssl_cert_last_modified: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub instance_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<AlertsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cleanup: Option<CleanupPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved_log_queries: Option<Vec<SavedLogQuery>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_store: Option<BackupStoreConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup_retention: Option<BackupRetention>,
+    // liquidity policies by lightning node name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub liquidity: Option<BTreeMap<String, LiquidityPolicy>>,
+    // auto-rebalance policies by cln node name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rebalance: Option<BTreeMap<String, RebalancePolicy>>,
+    // dynamic fee schedules by lightning node name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_schedules: Option<BTreeMap<String, FeeSchedule>>,
+    // lightning event webhooks by name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhooks: Option<BTreeMap<String, Webhook>>,
+    // lightning addresses by username
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lightning_addresses: Option<BTreeMap<String, LightningAddress>>,
+    // bolt12 offers made on the cln nodes, by offer id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offers: Option<BTreeMap<String, StoredOffer>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -544,7 +583,12 @@ impl Stack {
                 Image::Chrome(c) => Node::Internal(Image::Chrome(c)),
                 Image::Stakgraph(p) => Node::Internal(Image::Stakgraph(p)),
                 Image::Quickwit(q) => Node::Internal(Image::Quickwit(q)),
-                Image::Vector(v) => Node::Internal(Image::Vector(v)),
+                Image::Vector(mut v) => {
+                    v.sinks = v
+                        .sinks
+                        .map(|sinks| sinks.iter().map(|s| s.remove_secrets()).collect());
+                    Node::Internal(Image::Vector(v))
+                }
                 Image::HiveRelay(h) => Node::Internal(Image::HiveRelay(h)),
                 Image::Bifrost(b) => Node::Internal(Image::Bifrost(b)),
                 Image::Hermes(h) => Node::Internal(Image::Hermes(h)),
@@ -567,6 +611,21 @@ impl Stack {
             lightning_peers: self.lightning_peers.clone(),
             ssl_cert_last_modified: self.ssl_cert_last_modified.clone(),
             instance_id: self.instance_id.clone(),
+            alerts: self.alerts.as_ref().map(|a| a.remove_secrets()),
+            cleanup: self.cleanup.clone(),
+            saved_log_queries: self.saved_log_queries.clone(),
+            backup_store: self.backup_store.as_ref().map(|b| b.remove_secrets()),
+            backup_retention: self.backup_retention.clone(),
+            liquidity: self.liquidity.clone(),
+            rebalance: self.rebalance.clone(),
+            fee_schedules: self.fee_schedules.clone(),
+            webhooks: self.webhooks.as_ref().map(|w| {
+                w.iter()
+                    .map(|(name, h)| (name.clone(), h.remove_secrets()))
+                    .collect()
+            }),
+            lightning_addresses: self.lightning_addresses.clone(),
+            offers: self.offers.clone(),
         }
     }
 }
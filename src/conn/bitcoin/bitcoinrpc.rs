@@ -54,6 +54,13 @@ impl BitcoinRPC {
         Ok(self.0.get_balance(Some(6), None)?.as_btc())
     }
 
+    /// Smart fee estimate in sat/vbyte, none until bitcoind has enough data.
+    pub fn estimate_fee_rate(&self, blocks: u16) -> Result<Option<u64>> {
+        let est = self.0.estimate_smart_fee(blocks, None)?;
+        // fee_rate is btc per kvB, round up so it doesn't come in under
+        Ok(est.fee_rate.map(|r| r.as_sat().div_ceil(1000).max(1)))
+    }
+
     pub fn test_mine(&self, n: u64, addr: Option<String>) -> Result<Vec<BlockHash>> {
         let address = if let Some(addy) = addr {
             Address::from_str(&addy)?
@@ -306,6 +306,33 @@ impl ClnRPC {
         Ok(response.into_inner())
     }
 
+    // the invoice commits to sha256(description) instead of the description
+    pub async fn create_hashed_invoice(
+        &self,
+        amt: u64,
+        description: &str,
+    ) -> Result<pb::InvoiceResponse> {
+        let response = match self
+            .client
+            .clone()
+            .invoice(pb::InvoiceRequest {
+                amount_msat: amount_or_any(amt),
+                label: hex_secret(),
+                description: description.to_string(),
+                deschashonly: Some(true),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error creating an invoice error: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
     pub async fn pay(&self, bolt11: &str) -> Result<pb::PayResponse> {
         let response = match self
             .client
@@ -325,13 +352,14 @@ impl ClnRPC {
         Ok(response.into_inner())
     }
 
-    pub async fn close(&self, id: &str, out_addy: &str) -> Result<pb::CloseResponse> {
+    // without out_addy the funds go to the node's wallet
+    pub async fn close(&self, id: &str, out_addy: Option<&str>) -> Result<pb::CloseResponse> {
         let response = match self
             .client
             .clone()
             .close(pb::CloseRequest {
                 id: id.to_string(),
-                destination: Some(out_addy.to_string()),
+                destination: out_addy.map(|a| a.to_string()),
                 unilateraltimeout: Some(30),
                 ..Default::default()
             })
@@ -448,8 +476,381 @@ impl ClnRPC {
         };
         Ok(response.into_inner())
     }
+
+    // id is a channel id, short channel id, peer id or "all"
+    pub async fn set_channel(
+        &self,
+        id: &str,
+        feebase: Option<u64>,
+        feeppm: Option<u32>,
+        htlcmin: Option<u64>,
+        htlcmax: Option<u64>,
+    ) -> Result<pb::SetchannelResponse> {
+        let req = pb::SetchannelRequest {
+            id: id.to_string(),
+            feebase: feebase.map(amount),
+            feeppm,
+            htlcmin: htlcmin.map(amount),
+            htlcmax: htlcmax.map(amount),
+            ..Default::default()
+        };
+        let response = match self.client.clone().set_channel(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error setting channel: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // exclude takes "scid/direction" channels and node ids
+    pub async fn get_route_excluding(
+        &self,
+        dest: &str,
+        amt_msat: u64,
+        cltv: u32,
+        exclude: Vec<String>,
+    ) -> Result<pb::GetrouteResponse> {
+        let req = pb::GetrouteRequest {
+            id: hex::decode(dest)?,
+            amount_msat: Some(amount(amt_msat)),
+            riskfactor: 10,
+            cltv: Some(cltv),
+            exclude,
+            ..Default::default()
+        };
+        let response = match self.client.clone().get_route(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error getting route: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // both directions of a channel, as gossiped
+    pub async fn channel_gossip(&self, short_channel_id: &str) -> Result<pb::ListchannelsResponse> {
+        let req = pb::ListchannelsRequest {
+            short_channel_id: Some(short_channel_id.to_string()),
+            ..Default::default()
+        };
+        let response = match self.client.clone().list_channels(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error listing channels: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    pub async fn send_pay(
+        &self,
+        route: Vec<pb::SendpayRoute>,
+        payment_hash: Vec<u8>,
+        payment_secret: Vec<u8>,
+        amt_msat: u64,
+    ) -> Result<pb::SendpayResponse> {
+        let req = pb::SendpayRequest {
+            route,
+            payment_hash,
+            payment_secret: Some(payment_secret),
+            amount_msat: Some(amount(amt_msat)),
+            ..Default::default()
+        };
+        let response = match self.client.clone().send_pay(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error sending pay: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    pub async fn wait_send_pay(
+        &self,
+        payment_hash: Vec<u8>,
+        timeout: u32,
+    ) -> Result<pb::WaitsendpayResponse> {
+        let req = pb::WaitsendpayRequest {
+            payment_hash,
+            timeout: Some(timeout),
+            ..Default::default()
+        };
+        let response = match self.client.clone().wait_send_pay(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error waiting for pay: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // blocks until an invoice is paid, the next one after lastpay_index if set
+    pub async fn wait_any_invoice(
+        &self,
+        lastpay_index: Option<u64>,
+    ) -> Result<pb::WaitanyinvoiceResponse> {
+        let req = pb::WaitanyinvoiceRequest {
+            lastpay_index,
+            timeout: None,
+        };
+        let response = match self.client.clone().wait_any_invoice(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error waiting for invoice: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // amount is "any" or like "1000msat"
+    pub async fn offer(
+        &self,
+        amount: &str,
+        description: Option<String>,
+        label: Option<String>,
+        single_use: Option<bool>,
+        absolute_expiry: Option<u64>,
+    ) -> Result<pb::OfferResponse> {
+        let req = pb::OfferRequest {
+            amount: amount.to_string(),
+            description,
+            label,
+            single_use,
+            absolute_expiry,
+            ..Default::default()
+        };
+        let response = match self.client.clone().offer(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error creating offer: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    pub async fn list_offers(&self, offer_id: Option<Vec<u8>>) -> Result<pb::ListoffersResponse> {
+        let req = pb::ListoffersRequest {
+            offer_id,
+            ..Default::default()
+        };
+        let response = match self.client.clone().list_offers(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error listing offers: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    pub async fn disable_offer(&self, offer_id: Vec<u8>) -> Result<pb::DisableofferResponse> {
+        let req = pb::DisableofferRequest { offer_id };
+        let response = match self.client.clone().disable_offer(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error disabling offer: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // asks the offer's node for an invoice over onion messages
+    pub async fn fetch_invoice(
+        &self,
+        offer: &str,
+        amount_msat: Option<u64>,
+        payer_note: Option<String>,
+    ) -> Result<pb::FetchinvoiceResponse> {
+        let req = pb::FetchinvoiceRequest {
+            offer: offer.to_string(),
+            amount_msat: amount_msat.map(amount),
+            payer_note,
+            ..Default::default()
+        };
+        let response = match self.client.clone().fetch_invoice(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error fetching invoice: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // everything in the wallet (or in utxos) when amount_sat is none
+    pub async fn withdraw(
+        &self,
+        destination: &str,
+        amount_sat: Option<u64>,
+        sat_per_vbyte: Option<u64>,
+        utxos: Vec<pb::Outpoint>,
+    ) -> Result<pb::WithdrawResponse> {
+        let req = pb::WithdrawRequest {
+            destination: destination.to_string(),
+            satoshi: sat_or_all(amount_sat),
+            feerate: sat_per_vbyte.map(feerate).transpose()?,
+            utxos,
+            ..Default::default()
+        };
+        let response = match self.client.clone().withdraw(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error withdrawing: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // an unsigned psbt paying destination, its inputs are reserved until it's sent
+    pub async fn tx_prepare(
+        &self,
+        destination: &str,
+        amount_sat: u64,
+        sat_per_vbyte: Option<u64>,
+        utxos: Vec<pb::Outpoint>,
+    ) -> Result<pb::TxprepareResponse> {
+        let req = pb::TxprepareRequest {
+            outputs: vec![pb::OutputDesc {
+                address: destination.to_string(),
+                amount: Some(amount(amount_sat * 1000)),
+            }],
+            feerate: sat_per_vbyte.map(feerate).transpose()?,
+            utxos,
+            ..Default::default()
+        };
+        let response = match self.client.clone().tx_prepare(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error preparing tx: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // a psbt spending exactly these utxos, to reserve or unreserve them with
+    pub async fn utxo_psbt(&self, utxos: Vec<pb::Outpoint>) -> Result<pb::UtxopsbtResponse> {
+        let req = pb::UtxopsbtRequest {
+            satoshi: sat_or_all(None),
+            feerate: Some(pb::Feerate {
+                style: Some(pb::feerate::Style::Slow(true)),
+            }),
+            startweight: 0,
+            utxos,
+            reserve: Some(0),
+            reservedok: Some(true),
+            ..Default::default()
+        };
+        let response = match self.client.clone().utxo_psbt(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error making utxo psbt: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // reserve is a number of blocks
+    pub async fn reserve_inputs(
+        &self,
+        psbt: &str,
+        reserve: u32,
+    ) -> Result<pb::ReserveinputsResponse> {
+        let req = pb::ReserveinputsRequest {
+            psbt: psbt.to_string(),
+            exclusive: Some(false),
+            reserve: Some(reserve),
+        };
+        let response = match self.client.clone().reserve_inputs(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error reserving inputs: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    pub async fn unreserve_inputs(
+        &self,
+        psbt: &str,
+        reserve: u32,
+    ) -> Result<pb::UnreserveinputsResponse> {
+        let req = pb::UnreserveinputsRequest {
+            psbt: psbt.to_string(),
+            reserve: Some(reserve),
+        };
+        let response = match self.client.clone().unreserve_inputs(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error unreserving inputs: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    pub async fn sign_psbt(&self, psbt: &str) -> Result<pb::SignpsbtResponse> {
+        let req = pb::SignpsbtRequest {
+            psbt: psbt.to_string(),
+            ..Default::default()
+        };
+        let response = match self.client.clone().sign_psbt(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error signing psbt: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
+
+    // finalizes and broadcasts a signed psbt
+    pub async fn send_psbt(&self, psbt: &str) -> Result<pb::SendpsbtResponse> {
+        let req = pb::SendpsbtRequest {
+            psbt: psbt.to_string(),
+            ..Default::default()
+        };
+        let response = match self.client.clone().send_psbt(req).await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Error sending psbt: {:?}", err.message());
+                return Err(anyhow!(extract_cln_error_msg(err.message())));
+            }
+        };
+        Ok(response.into_inner())
+    }
 }
 
+fn sat_or_all(sat: Option<u64>) -> Option<pb::AmountOrAll> {
+    match sat {
+        Some(s) => amount_or_all(s * 1000),
+        None => Some(pb::AmountOrAll {
+            value: Some(pb::amount_or_all::Value::All(true)),
+        }),
+    }
+}
+// cln takes sat per 1000 vbytes
+fn feerate(sat_per_vbyte: u64) -> Result<pb::Feerate> {
+    let perkb = sat_per_vbyte
+        .checked_mul(1000)
+        .and_then(|r| u32::try_from(r).ok())
+        .ok_or(anyhow!("fee rate {} sat/vB is too high", sat_per_vbyte))?;
+    Ok(pb::Feerate {
+        style: Some(pb::feerate::Style::Perkb(perkb)),
+    })
+}
 fn amount_or_any(msat: u64) -> Option<pb::AmountOrAny> {
     Some(pb::AmountOrAny {
         value: Some(pb::amount_or_any::Value::Amount(amount(msat))),
@@ -0,0 +1,383 @@
+use super::*;
+use crate::conn::cln::ClnRPC;
+use cln_grpc::pb;
+
+// how long a frozen utxo stays reserved, cln reservations are in blocks
+const FREEZE_BLOCKS: u32 = 1_000_000;
+
+fn msat(a: &Option<pb::Amount>) -> u64 {
+    a.as_ref().map(|a| a.msat).unwrap_or(0)
+}
+
+fn outpoints(utxos: &[String]) -> Result<Vec<pb::Outpoint>> {
+    utxos
+        .iter()
+        .map(|u| {
+            let (txid, vout) = parse_outpoint(u)?;
+            Ok(pb::Outpoint {
+                txid: hex::decode(txid)?,
+                outnum: vout,
+            })
+        })
+        .collect()
+}
+
+// the state from lightningd's "STATE:message" status line
+pub(super) fn channel_state(status: &str, connected: bool) -> ChannelState {
+    let state = status.split(':').next().unwrap_or_default();
+    match state {
+        "CHANNELD_NORMAL" if connected => ChannelState::Active,
+        "CHANNELD_NORMAL" => ChannelState::Inactive,
+        s if s.starts_with("OPENINGD")
+            || s.starts_with("DUALOPEND")
+            || s == "CHANNELD_AWAITING_LOCKIN" =>
+        {
+            ChannelState::Pending
+        }
+        _ => ChannelState::Closing,
+    }
+}
+
+fn invoice(i: pb::ListinvoicesInvoices) -> Invoice {
+    use pb::listinvoices_invoices::ListinvoicesInvoicesStatus;
+    let status = match i.status() {
+        ListinvoicesInvoicesStatus::Paid => InvoiceStatus::Paid,
+        ListinvoicesInvoicesStatus::Expired => InvoiceStatus::Expired,
+        ListinvoicesInvoicesStatus::Unpaid => InvoiceStatus::Open,
+    };
+    Invoice {
+        payment_hash: hex::encode(&i.payment_hash),
+        bolt11: i.bolt11.unwrap_or_default(),
+        amount_msat: i.amount_msat.map(|a| a.msat),
+        received_msat: i.amount_received_msat.map(|a| a.msat),
+        status,
+        paid_at: i.paid_at,
+        bolt12: i.bolt12,
+        offer_id: i.local_offer_id.map(hex::encode),
+    }
+}
+
+fn payment(p: pb::ListsendpaysPayments) -> Payment {
+    use pb::listsendpays_payments::ListsendpaysPaymentsStatus;
+    let status = match p.status() {
+        ListsendpaysPaymentsStatus::Complete => PaymentStatus::Succeeded,
+        ListsendpaysPaymentsStatus::Failed => PaymentStatus::Failed,
+        ListsendpaysPaymentsStatus::Pending => PaymentStatus::Pending,
+    };
+    let amount = msat(&p.amount_msat);
+    let sent = msat(&p.amount_sent_msat);
+    Payment {
+        payment_hash: hex::encode(&p.payment_hash),
+        preimage: p.payment_preimage.map(hex::encode),
+        amount_msat: amount,
+        fee_msat: (sent >= amount && p.amount_sent_msat.is_some()).then(|| sent - amount),
+        bolt11: p.bolt11,
+        status,
+        created_at: p.created_at,
+    }
+}
+
+#[async_trait]
+impl LightningNode for ClnRPC {
+    async fn get_info(&self) -> Result<NodeInfo> {
+        let info = ClnRPC::get_info(self).await?;
+        Ok(NodeInfo {
+            pubkey: hex::encode(&info.id),
+            alias: info.alias.unwrap_or_default(),
+            network: info.network,
+            block_height: info.blockheight,
+            synced: info.warning_bitcoind_sync.is_none() && info.warning_lightningd_sync.is_none(),
+            num_peers: info.num_peers,
+            num_active_channels: info.num_active_channels,
+            num_pending_channels: info.num_pending_channels,
+        })
+    }
+
+    async fn list_peers(&self) -> Result<Vec<Peer>> {
+        let peers = ClnRPC::list_peers(self).await?;
+        Ok(peers
+            .peers
+            .into_iter()
+            .map(|p| Peer {
+                pubkey: hex::encode(&p.id),
+                address: p.netaddr.first().cloned(),
+                connected: p.connected,
+            })
+            .collect())
+    }
+
+    async fn connect_peer(&self, pubkey: &str, host: &str) -> Result<()> {
+        let (host, port) = split_host_port(host);
+        ClnRPC::connect_peer(self, pubkey, host, port).await?;
+        Ok(())
+    }
+
+    async fn list_channels(&self) -> Result<Vec<Channel>> {
+        let chans = self.list_peer_channels(None).await?;
+        Ok(chans
+            .channels
+            .into_iter()
+            .map(|c| {
+                let status = c.status.first().cloned().unwrap_or_default();
+                let total = msat(&c.total_msat);
+                let local = msat(&c.to_us_msat);
+                Channel {
+                    id: c.channel_id.as_ref().map(hex::encode).unwrap_or_default(),
+                    short_channel_id: c.short_channel_id,
+                    peer: hex::encode(&c.peer_id),
+                    capacity_sat: total / 1000,
+                    local_msat: local,
+                    remote_msat: total.saturating_sub(local),
+                    state: channel_state(&status, c.peer_connected),
+                    private: c.private.unwrap_or(false),
+                }
+            })
+            .collect())
+    }
+
+    async fn get_balance(&self) -> Result<Balance> {
+        use pb::listfunds_outputs::ListfundsOutputsStatus;
+        let funds = self.list_funds().await?;
+        let mut bal = Balance::default();
+        for o in funds.outputs.iter() {
+            let sat = msat(&o.amount_msat) / 1000;
+            match o.status() {
+                ListfundsOutputsStatus::Confirmed => bal.onchain_confirmed_sat += sat,
+                ListfundsOutputsStatus::Unconfirmed | ListfundsOutputsStatus::Immature => {
+                    bal.onchain_unconfirmed_sat += sat
+                }
+                ListfundsOutputsStatus::Spent => (),
+            }
+        }
+        bal.channels_local_msat = LightningNode::list_channels(self)
+            .await?
+            .iter()
+            .filter(|c| matches!(c.state, ChannelState::Active | ChannelState::Inactive))
+            .map(|c| c.local_msat)
+            .sum();
+        Ok(bal)
+    }
+
+    async fn new_address(&self) -> Result<String> {
+        self.new_addr().await?.bech32.ok_or(anyhow!("no bech32 address"))
+    }
+
+    async fn create_invoice(&self, amt_msat: u64) -> Result<Invoice> {
+        let inv = ClnRPC::create_invoice(self, amt_msat).await?;
+        Ok(Invoice {
+            payment_hash: hex::encode(&inv.payment_hash),
+            bolt11: inv.bolt11,
+            amount_msat: (amt_msat > 0).then_some(amt_msat),
+            received_msat: None,
+            status: InvoiceStatus::Open,
+            paid_at: None,
+            bolt12: None,
+            offer_id: None,
+        })
+    }
+
+    async fn create_hashed_invoice(&self, amt_msat: u64, description: &str) -> Result<Invoice> {
+        let inv = ClnRPC::create_hashed_invoice(self, amt_msat, description).await?;
+        Ok(Invoice {
+            payment_hash: hex::encode(&inv.payment_hash),
+            bolt11: inv.bolt11,
+            amount_msat: (amt_msat > 0).then_some(amt_msat),
+            received_msat: None,
+            status: InvoiceStatus::Open,
+            paid_at: None,
+            bolt12: None,
+            offer_id: None,
+        })
+    }
+
+    async fn list_invoices(&self, payment_hash: Option<String>) -> Result<Vec<Invoice>> {
+        let invs = ClnRPC::list_invoices(self, payment_hash).await?;
+        Ok(invs.invoices.into_iter().map(invoice).collect())
+    }
+
+    async fn wait_paid_invoice(&self, after: Option<u64>) -> Result<(Invoice, u64)> {
+        use pb::waitanyinvoice_response::WaitanyinvoiceStatus;
+        let i = self.wait_any_invoice(after).await?;
+        let index = i.pay_index.ok_or(anyhow!("paid invoice without pay_index"))?;
+        let status = match i.status() {
+            WaitanyinvoiceStatus::Paid => InvoiceStatus::Paid,
+            WaitanyinvoiceStatus::Expired => InvoiceStatus::Expired,
+        };
+        let inv = Invoice {
+            payment_hash: hex::encode(&i.payment_hash),
+            bolt11: i.bolt11.unwrap_or_default(),
+            amount_msat: i.amount_msat.map(|a| a.msat),
+            received_msat: i.amount_received_msat.map(|a| a.msat),
+            status,
+            paid_at: i.paid_at,
+            bolt12: i.bolt12,
+            offer_id: None,
+        };
+        Ok((inv, index))
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<Payment> {
+        use pb::pay_response::PayStatus;
+        let paid = self.pay(bolt11).await?;
+        let status = match paid.status() {
+            PayStatus::Complete => PaymentStatus::Succeeded,
+            PayStatus::Pending => PaymentStatus::Pending,
+            PayStatus::Failed => return Err(anyhow!("payment failed")),
+        };
+        let (amount, sent) = (msat(&paid.amount_msat), msat(&paid.amount_sent_msat));
+        Ok(Payment {
+            payment_hash: hex::encode(&paid.payment_hash),
+            preimage: Some(hex::encode(&paid.payment_preimage)),
+            amount_msat: amount,
+            fee_msat: Some(sent.saturating_sub(amount)),
+            bolt11: Some(bolt11.to_string()),
+            status,
+            created_at: paid.created_at as u64,
+        })
+    }
+
+    async fn list_payments(&self, payment_hash: Option<String>) -> Result<Vec<Payment>> {
+        let pays = self.list_pays(payment_hash).await?;
+        Ok(pays.payments.into_iter().map(payment).collect())
+    }
+
+    async fn keysend(
+        &self,
+        dest: &str,
+        amt_msat: u64,
+        tlvs: Option<HashMap<u64, Vec<u8>>>,
+    ) -> Result<Payment> {
+        let sent = ClnRPC::keysend(self, dest, amt_msat, None, None, None, tlvs).await?;
+        let (amount, total) = (msat(&sent.amount_msat), msat(&sent.amount_sent_msat));
+        Ok(Payment {
+            payment_hash: hex::encode(&sent.payment_hash),
+            preimage: Some(hex::encode(&sent.payment_preimage)),
+            amount_msat: amount,
+            fee_msat: Some(total.saturating_sub(amount)),
+            bolt11: None,
+            status: PaymentStatus::Succeeded,
+            created_at: sent.created_at as u64,
+        })
+    }
+
+    async fn open_channel(
+        &self,
+        pubkey: &str,
+        amount_sat: u64,
+        sat_per_vbyte: Option<u64>,
+    ) -> Result<OpenedChannel> {
+        let spvb = sat_per_vbyte.map(u32::try_from).transpose()?;
+        let funded = self.fund_channel(pubkey, amount_sat * 1000, spvb).await?;
+        Ok(OpenedChannel {
+            funding_txid: hex::encode(&funded.txid),
+            output_index: funded.outnum,
+        })
+    }
+
+    async fn close_channel(&self, id: &str, destination: Option<String>) -> Result<ClosedChannel> {
+        let closed = self.close(id, destination.as_deref()).await?;
+        Ok(ClosedChannel {
+            closing_txid: closed.txid.as_ref().map(hex::encode),
+        })
+    }
+
+    async fn list_fee_policies(&self) -> Result<Vec<FeePolicy>> {
+        let chans = self.list_peer_channels(None).await?;
+        Ok(chans
+            .channels
+            .into_iter()
+            .filter(|c| c.status.first().map(|s| s.starts_with("CHANNELD_NORMAL")).unwrap_or(false))
+            .map(|c| FeePolicy {
+                channel: c.channel_id.as_ref().map(hex::encode).unwrap_or_default(),
+                short_channel_id: c.short_channel_id,
+                peer: hex::encode(&c.peer_id),
+                base_fee_msat: msat(&c.fee_base_msat),
+                fee_ppm: c.fee_proportional_millionths.unwrap_or(0) as u64,
+                min_htlc_msat: c.minimum_htlc_out_msat.map(|a| a.msat),
+                max_htlc_msat: c.maximum_htlc_out_msat.map(|a| a.msat),
+                cltv_delta: None,
+            })
+            .collect())
+    }
+
+    async fn set_fee_policy(&self, channel: Option<&str>, update: &FeeUpdate) -> Result<()> {
+        if update.cltv_delta.is_some() {
+            return Err(anyhow!("cln takes its cltv delta from the node config"));
+        }
+        let ppm = update.fee_ppm.map(u32::try_from).transpose()?;
+        self.set_channel(
+            channel.unwrap_or("all"),
+            update.base_fee_msat,
+            ppm,
+            update.min_htlc_msat,
+            update.max_htlc_msat,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn list_utxos(&self) -> Result<Vec<Utxo>> {
+        use pb::listfunds_outputs::ListfundsOutputsStatus;
+        let height = self.get_info().await?.blockheight;
+        let funds = self.list_funds().await?;
+        Ok(funds
+            .outputs
+            .into_iter()
+            .filter(|o| o.status() != ListfundsOutputsStatus::Spent)
+            .map(|o| Utxo {
+                outpoint: format!("{}:{}", hex::encode(&o.txid), o.output),
+                amount_sat: msat(&o.amount_msat) / 1000,
+                address: o.address.clone(),
+                confirmations: o
+                    .blockheight
+                    .map(|b| height.saturating_sub(b) + 1)
+                    .unwrap_or(0),
+                locked: o.reserved,
+            })
+            .collect())
+    }
+
+    async fn lock_utxos(&self, utxos: &[String], locked: bool) -> Result<()> {
+        let psbt = self.utxo_psbt(outpoints(utxos)?).await?.psbt;
+        if locked {
+            self.reserve_inputs(&psbt, FREEZE_BLOCKS).await?;
+        } else {
+            self.unreserve_inputs(&psbt, FREEZE_BLOCKS).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_onchain(
+        &self,
+        address: &str,
+        amount_sat: Option<u64>,
+        sat_per_vbyte: Option<u64>,
+        utxos: &[String],
+    ) -> Result<String> {
+        let res = self
+            .withdraw(address, amount_sat, sat_per_vbyte, outpoints(utxos)?)
+            .await?;
+        Ok(hex::encode(&res.txid))
+    }
+
+    async fn create_psbt(
+        &self,
+        address: &str,
+        amount_sat: u64,
+        sat_per_vbyte: Option<u64>,
+        utxos: &[String],
+    ) -> Result<String> {
+        let res = self
+            .tx_prepare(address, amount_sat, sat_per_vbyte, outpoints(utxos)?)
+            .await?;
+        Ok(res.psbt)
+    }
+
+    async fn sign_psbt(&self, psbt: &str) -> Result<String> {
+        Ok(ClnRPC::sign_psbt(self, psbt).await?.signed_psbt)
+    }
+
+    async fn publish_psbt(&self, psbt: &str) -> Result<String> {
+        Ok(hex::encode(&self.send_psbt(psbt).await?.txid))
+    }
+}
@@ -0,0 +1,503 @@
+use super::*;
+use crate::cmd::{AddChannel, AddPeer, PayInvoice, PayKeysend};
+use crate::conn::lnd::lndrpc::LndRPC;
+use futures_util::StreamExt;
+use rocket::tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
+use tonic_lnd::lnrpc;
+
+// lnd keeps txids in internal byte order, reversed from how they're shown
+pub(super) fn txid_hex(bytes: &[u8]) -> String {
+    let mut b = bytes.to_vec();
+    b.reverse();
+    hex::encode(b)
+}
+
+pub(super) fn txid_bytes(txid: &str) -> Result<Vec<u8>> {
+    let mut b = hex::decode(txid)?;
+    b.reverse();
+    Ok(b)
+}
+
+// lnd's default bitcoin.timelockdelta
+const DEFAULT_CLTV_DELTA: u32 = 80;
+// frozen utxos are leased under this id, ten years at a time
+const FREEZE_LEASE_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+fn freeze_lease_id() -> Vec<u8> {
+    Sha256::digest(b"sphinx-swarm/freeze").to_vec()
+}
+
+fn outpoint(utxo: &str) -> Result<lnrpc::OutPoint> {
+    let (txid, vout) = parse_outpoint(utxo)?;
+    Ok(lnrpc::OutPoint {
+        txid_bytes: txid_bytes(&txid)?,
+        txid_str: txid,
+        output_index: vout,
+    })
+}
+
+fn outpoint_str(o: &lnrpc::OutPoint) -> String {
+    let txid = if o.txid_str.is_empty() {
+        txid_hex(&o.txid_bytes)
+    } else {
+        o.txid_str.clone()
+    };
+    format!("{}:{}", txid, o.output_index)
+}
+
+// finalize a signed psbt and broadcast it
+async fn publish(client: &mut LndRPC, psbt: Vec<u8>) -> Result<String> {
+    let tx = client.finalize_psbt(psbt).await?.raw_final_tx;
+    let txid = bitcoin::consensus::deserialize::<bitcoin::Transaction>(&tx)?.txid();
+    client.publish_transaction(tx).await?;
+    Ok(txid.to_string())
+}
+
+fn sat(v: i64) -> u64 {
+    v.max(0) as u64
+}
+
+fn pending_channel(c: lnrpc::pending_channels_response::PendingChannel, state: ChannelState) -> Channel {
+    Channel {
+        id: c.channel_point,
+        short_channel_id: None,
+        peer: c.remote_node_pub,
+        capacity_sat: sat(c.capacity),
+        local_msat: sat(c.local_balance) * 1000,
+        remote_msat: sat(c.remote_balance) * 1000,
+        state,
+        private: c.private,
+    }
+}
+
+fn invoice(i: lnrpc::Invoice) -> Invoice {
+    use lnrpc::invoice::InvoiceState;
+    let now = chrono::Utc::now().timestamp();
+    let status = match i.state() {
+        InvoiceState::Settled => InvoiceStatus::Paid,
+        InvoiceState::Canceled => InvoiceStatus::Expired,
+        _ if i.creation_date + i.expiry < now => InvoiceStatus::Expired,
+        _ => InvoiceStatus::Open,
+    };
+    Invoice {
+        payment_hash: hex::encode(&i.r_hash),
+        bolt11: i.payment_request,
+        amount_msat: (i.value_msat > 0).then_some(i.value_msat as u64),
+        received_msat: (i.amt_paid_msat > 0).then_some(i.amt_paid_msat as u64),
+        status,
+        paid_at: (i.settle_date > 0).then_some(i.settle_date as u64),
+        bolt12: None,
+        offer_id: None,
+    }
+}
+
+fn payment(p: lnrpc::Payment) -> Payment {
+    use lnrpc::payment::PaymentStatus as LndStatus;
+    let status = match p.status() {
+        LndStatus::Succeeded => PaymentStatus::Succeeded,
+        LndStatus::Failed => PaymentStatus::Failed,
+        _ => PaymentStatus::Pending,
+    };
+    Payment {
+        payment_hash: p.payment_hash,
+        preimage: Some(p.payment_preimage).filter(|p| !p.is_empty() && p.chars().any(|c| c != '0')),
+        amount_msat: sat(p.value_msat),
+        fee_msat: Some(sat(p.fee_msat)),
+        bolt11: Some(p.payment_request).filter(|r| !r.is_empty()),
+        status,
+        created_at: sat(p.creation_time_ns / 1_000_000_000),
+    }
+}
+
+// send_payment_sync reports a failed payment in the response, not as an error
+fn sent(res: lnrpc::SendResponse, bolt11: Option<String>) -> Result<Payment> {
+    if !res.payment_error.is_empty() {
+        return Err(anyhow!(res.payment_error));
+    }
+    let route = res.payment_route.unwrap_or_default();
+    Ok(Payment {
+        payment_hash: hex::encode(&res.payment_hash),
+        preimage: Some(hex::encode(&res.payment_preimage)),
+        amount_msat: sat(route.total_amt_msat - route.total_fees_msat),
+        fee_msat: Some(sat(route.total_fees_msat)),
+        bolt11,
+        status: PaymentStatus::Succeeded,
+        created_at: chrono::Utc::now().timestamp() as u64,
+    })
+}
+
+#[async_trait]
+impl LightningNode for Mutex<LndRPC> {
+    async fn get_info(&self) -> Result<NodeInfo> {
+        let info = self.lock().await.get_info().await?;
+        Ok(NodeInfo {
+            pubkey: info.identity_pubkey,
+            alias: info.alias,
+            network: info.chains.first().map(|c| c.network.clone()).unwrap_or_default(),
+            block_height: info.block_height,
+            synced: info.synced_to_chain && info.synced_to_graph,
+            num_peers: info.num_peers,
+            num_active_channels: info.num_active_channels,
+            num_pending_channels: info.num_pending_channels,
+        })
+    }
+
+    async fn list_peers(&self) -> Result<Vec<Peer>> {
+        let peers = self.lock().await.list_peers().await?;
+        // lnd only lists connected peers
+        Ok(peers
+            .peers
+            .into_iter()
+            .map(|p| Peer {
+                pubkey: p.pub_key,
+                address: Some(p.address),
+                connected: true,
+            })
+            .collect())
+    }
+
+    async fn connect_peer(&self, pubkey: &str, host: &str) -> Result<()> {
+        let (h, port) = split_host_port(host);
+        let peer = AddPeer {
+            pubkey: pubkey.to_string(),
+            host: format!("{}:{}", h, port),
+            alias: None,
+        };
+        self.lock().await.add_peer(peer).await?;
+        Ok(())
+    }
+
+    async fn list_channels(&self) -> Result<Vec<Channel>> {
+        let mut client = self.lock().await;
+        let open = client.list_channels().await?;
+        let pending = client.list_pending_channels().await?;
+        let mut chans: Vec<Channel> = open
+            .channels
+            .into_iter()
+            .map(|c| Channel {
+                id: c.channel_point,
+                short_channel_id: Some(crate::conn::cln::ShortChannelId(c.chan_id).to_string()),
+                peer: c.remote_pubkey,
+                capacity_sat: sat(c.capacity),
+                local_msat: sat(c.local_balance) * 1000,
+                remote_msat: sat(c.remote_balance) * 1000,
+                state: if c.active { ChannelState::Active } else { ChannelState::Inactive },
+                private: c.private,
+            })
+            .collect();
+        let opening = pending.pending_open_channels.into_iter().filter_map(|c| c.channel);
+        chans.extend(opening.map(|c| pending_channel(c, ChannelState::Pending)));
+        let closing = pending
+            .waiting_close_channels
+            .into_iter()
+            .filter_map(|c| c.channel)
+            .chain(pending.pending_force_closing_channels.into_iter().filter_map(|c| c.channel));
+        chans.extend(closing.map(|c| pending_channel(c, ChannelState::Closing)));
+        Ok(chans)
+    }
+
+    async fn get_balance(&self) -> Result<Balance> {
+        let mut client = self.lock().await;
+        let wallet = client.get_balance().await?;
+        let chans = client.channel_balance().await?;
+        Ok(Balance {
+            onchain_confirmed_sat: sat(wallet.confirmed_balance),
+            onchain_unconfirmed_sat: sat(wallet.unconfirmed_balance),
+            channels_local_msat: chans.local_balance.map(|a| a.msat).unwrap_or(0),
+        })
+    }
+
+    async fn new_address(&self) -> Result<String> {
+        Ok(self.lock().await.new_address().await?.address)
+    }
+
+    async fn create_invoice(&self, amt_msat: u64) -> Result<Invoice> {
+        let res = self.lock().await.add_invoice_msat(amt_msat.try_into()?).await?;
+        Ok(Invoice {
+            payment_hash: hex::encode(&res.r_hash),
+            bolt11: res.payment_request,
+            amount_msat: (amt_msat > 0).then_some(amt_msat),
+            received_msat: None,
+            status: InvoiceStatus::Open,
+            paid_at: None,
+            bolt12: None,
+            offer_id: None,
+        })
+    }
+
+    async fn create_hashed_invoice(&self, amt_msat: u64, description: &str) -> Result<Invoice> {
+        let hash = Sha256::digest(description.as_bytes()).to_vec();
+        let res = self
+            .lock()
+            .await
+            .add_hashed_invoice(amt_msat as i64, hash)
+            .await?;
+        Ok(Invoice {
+            payment_hash: hex::encode(&res.r_hash),
+            bolt11: res.payment_request,
+            amount_msat: (amt_msat > 0).then_some(amt_msat),
+            received_msat: None,
+            status: InvoiceStatus::Open,
+            paid_at: None,
+            bolt12: None,
+            offer_id: None,
+        })
+    }
+
+    async fn list_invoices(&self, payment_hash: Option<String>) -> Result<Vec<Invoice>> {
+        let mut client = self.lock().await;
+        if let Some(h) = payment_hash {
+            let found = client.lookup_invoice(hex::decode(&h)?).await?;
+            return Ok(found.into_iter().map(invoice).collect());
+        }
+        let invs = client.list_invoices().await?;
+        Ok(invs.invoices.into_iter().map(invoice).collect())
+    }
+
+    async fn wait_paid_invoice(&self, after: Option<u64>) -> Result<(Invoice, u64)> {
+        use lnrpc::invoice::InvoiceState;
+        let after = after.unwrap_or(0);
+        // only hold the lock to open the subscription
+        let mut updates = self.lock().await.subscribe_invoices(after).await?;
+        while let Some(i) = updates.next().await {
+            let i = i?;
+            if i.state() == InvoiceState::Settled && i.settle_index > after {
+                let index = i.settle_index;
+                return Ok((invoice(i), index));
+            }
+        }
+        Err(anyhow!("invoice subscription closed"))
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<Payment> {
+        let req = PayInvoice {
+            payment_request: bolt11.to_string(),
+        };
+        let res = self.lock().await.pay_invoice(req).await?;
+        sent(res, Some(bolt11.to_string()))
+    }
+
+    async fn list_payments(&self, payment_hash: Option<String>) -> Result<Vec<Payment>> {
+        // the trait lists failed and pending payments too, like cln
+        let mut client = self.lock().await;
+        if let Some(h) = payment_hash {
+            let found = client.find_payment(&h).await?;
+            return Ok(found.into_iter().map(payment).collect());
+        }
+        let pays = client.list_payments(true).await?;
+        Ok(pays.payments.into_iter().map(payment).collect())
+    }
+
+    async fn keysend(
+        &self,
+        dest: &str,
+        amt_msat: u64,
+        tlvs: Option<HashMap<u64, Vec<u8>>>,
+    ) -> Result<Payment> {
+        // lnd's keysend amount is in sats
+        if amt_msat == 0 || !amt_msat.is_multiple_of(1000) {
+            return Err(anyhow!("lnd keysends whole sats, not {} msat", amt_msat));
+        }
+        let ks = PayKeysend {
+            dest: dest.to_string(),
+            amt: (amt_msat / 1000) as i64,
+            tlvs,
+            ..Default::default()
+        };
+        let res = self.lock().await.pay_keysend(ks).await?;
+        sent(res, None)
+    }
+
+    async fn open_channel(
+        &self,
+        pubkey: &str,
+        amount_sat: u64,
+        sat_per_vbyte: Option<u64>,
+    ) -> Result<OpenedChannel> {
+        let chan = AddChannel {
+            pubkey: pubkey.to_string(),
+            amount: amount_sat.try_into()?,
+            satsperbyte: sat_per_vbyte.unwrap_or(0),
+        };
+        let point = self.lock().await.create_channel(chan).await?;
+        let funding_txid = match point.funding_txid {
+            Some(lnrpc::channel_point::FundingTxid::FundingTxidBytes(b)) => txid_hex(&b),
+            Some(lnrpc::channel_point::FundingTxid::FundingTxidStr(s)) => s,
+            None => return Err(anyhow!("no funding txid")),
+        };
+        Ok(OpenedChannel {
+            funding_txid,
+            output_index: point.output_index,
+        })
+    }
+
+    async fn close_channel(&self, id: &str, destination: Option<String>) -> Result<ClosedChannel> {
+        let (txid, index) = id
+            .split_once(':')
+            .ok_or(anyhow!("lnd channels close by channel point, txid:index"))?;
+        let point = lnrpc::ChannelPoint {
+            funding_txid: Some(lnrpc::channel_point::FundingTxid::FundingTxidBytes(txid_bytes(txid)?)),
+            output_index: index.parse()?,
+        };
+        let closing = self.lock().await.close_channel(point, destination).await?;
+        Ok(ClosedChannel {
+            closing_txid: closing.map(|t| txid_hex(&t)),
+        })
+    }
+
+    async fn list_fee_policies(&self) -> Result<Vec<FeePolicy>> {
+        let mut client = self.lock().await;
+        let chans = client.list_channels().await?;
+        let report = client.fee_report().await?;
+        let our_pubkey = client.get_info().await?.identity_pubkey;
+        let mut policies = Vec::new();
+        for c in chans.channels.into_iter() {
+            let fees = report.channel_fees.iter().find(|f| f.chan_id == c.chan_id);
+            let mut p = FeePolicy {
+                channel: c.channel_point.clone(),
+                short_channel_id: Some(crate::conn::cln::ShortChannelId(c.chan_id).to_string()),
+                peer: c.remote_pubkey.clone(),
+                base_fee_msat: fees.map(|f| sat(f.base_fee_msat)).unwrap_or(0),
+                fee_ppm: fees.map(|f| sat(f.fee_per_mil)).unwrap_or(0),
+                min_htlc_msat: None,
+                max_htlc_msat: None,
+                cltv_delta: None,
+            };
+            // the rest is in the graph, which may not have a new channel yet
+            if let Ok(edge) = client.get_chan_info(c.chan_id).await {
+                let ours = if edge.node1_pub == our_pubkey {
+                    edge.node1_policy
+                } else {
+                    edge.node2_policy
+                };
+                if let Some(rp) = ours {
+                    p.min_htlc_msat = Some(sat(rp.min_htlc));
+                    p.max_htlc_msat = Some(rp.max_htlc_msat);
+                    p.cltv_delta = Some(rp.time_lock_delta);
+                }
+            }
+            policies.push(p);
+        }
+        Ok(policies)
+    }
+
+    // lnd wants every field set, so unchanged ones are copied from the
+    // current policy of each channel
+    async fn set_fee_policy(&self, channel: Option<&str>, update: &FeeUpdate) -> Result<()> {
+        let current = self.list_fee_policies().await?;
+        let targets: Vec<&FeePolicy> = current
+            .iter()
+            .filter(|p| channel.map(|c| c == p.channel).unwrap_or(true))
+            .collect();
+        if targets.is_empty() {
+            return Err(anyhow!("no channel {}", channel.unwrap_or("")));
+        }
+        let mut client = self.lock().await;
+        for p in targets.into_iter() {
+            let (txid, index) = p
+                .channel
+                .split_once(':')
+                .ok_or(anyhow!("bad channel point {}", p.channel))?;
+            let point = lnrpc::ChannelPoint {
+                funding_txid: Some(lnrpc::channel_point::FundingTxid::FundingTxidStr(txid.to_string())),
+                output_index: index.parse()?,
+            };
+            let req = lnrpc::PolicyUpdateRequest {
+                scope: Some(lnrpc::policy_update_request::Scope::ChanPoint(point)),
+                base_fee_msat: update.base_fee_msat.unwrap_or(p.base_fee_msat).try_into()?,
+                fee_rate_ppm: update.fee_ppm.unwrap_or(p.fee_ppm).try_into()?,
+                time_lock_delta: update.cltv_delta.or(p.cltv_delta).unwrap_or(DEFAULT_CLTV_DELTA),
+                // 0 leaves it unchanged
+                max_htlc_msat: update.max_htlc_msat.unwrap_or(0),
+                min_htlc_msat: update.min_htlc_msat.unwrap_or(0),
+                min_htlc_msat_specified: update.min_htlc_msat.is_some(),
+                ..Default::default()
+            };
+            client.update_channel_policy(req).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_utxos(&self) -> Result<Vec<Utxo>> {
+        let mut client = self.lock().await;
+        let leased: Vec<String> = client
+            .list_leases()
+            .await?
+            .locked_utxos
+            .iter()
+            .filter_map(|l| l.outpoint.as_ref().map(outpoint_str))
+            .collect();
+        let utxos = client.list_unspent().await?.utxos;
+        Ok(utxos
+            .into_iter()
+            .map(|u| {
+                let outpoint = u.outpoint.as_ref().map(outpoint_str).unwrap_or_default();
+                Utxo {
+                    locked: leased.contains(&outpoint),
+                    outpoint,
+                    amount_sat: sat(u.amount_sat),
+                    address: (!u.address.is_empty()).then_some(u.address),
+                    confirmations: u.confirmations.max(0) as u32,
+                }
+            })
+            .collect())
+    }
+
+    async fn lock_utxos(&self, utxos: &[String], locked: bool) -> Result<()> {
+        let points = utxos.iter().map(|u| outpoint(u)).collect::<Result<Vec<_>>>()?;
+        let mut client = self.lock().await;
+        for p in points.into_iter() {
+            if locked {
+                client.lease_output(freeze_lease_id(), p, FREEZE_LEASE_SECS).await?;
+            } else {
+                client.release_output(freeze_lease_id(), p).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_onchain(
+        &self,
+        address: &str,
+        amount_sat: Option<u64>,
+        sat_per_vbyte: Option<u64>,
+        utxos: &[String],
+    ) -> Result<String> {
+        let mut client = self.lock().await;
+        if utxos.is_empty() {
+            let res = client.send_coins(address, amount_sat, sat_per_vbyte).await?;
+            return Ok(res.txid);
+        }
+        // sendcoins can't pick the inputs, so go through a psbt
+        let amount = amount_sat.ok_or(anyhow!("lnd needs an amount to send from chosen utxos"))?;
+        let inputs = utxos.iter().map(|u| outpoint(u)).collect::<Result<Vec<_>>>()?;
+        let outputs = HashMap::from([(address.to_string(), amount)]);
+        let funded = client.fund_psbt(inputs, outputs, sat_per_vbyte).await?.funded_psbt;
+        let signed = client.sign_psbt(funded).await?.signed_psbt;
+        publish(&mut client, signed).await
+    }
+
+    async fn create_psbt(
+        &self,
+        address: &str,
+        amount_sat: u64,
+        sat_per_vbyte: Option<u64>,
+        utxos: &[String],
+    ) -> Result<String> {
+        let inputs = utxos.iter().map(|u| outpoint(u)).collect::<Result<Vec<_>>>()?;
+        let outputs = HashMap::from([(address.to_string(), amount_sat)]);
+        let res = self.lock().await.fund_psbt(inputs, outputs, sat_per_vbyte).await?;
+        Ok(base64::encode(res.funded_psbt))
+    }
+
+    async fn sign_psbt(&self, psbt: &str) -> Result<String> {
+        let res = self.lock().await.sign_psbt(base64::decode(psbt)?).await?;
+        Ok(base64::encode(res.signed_psbt))
+    }
+
+    async fn publish_psbt(&self, psbt: &str) -> Result<String> {
+        let psbt = base64::decode(psbt)?;
+        publish(&mut *self.lock().await, psbt).await
+    }
+}
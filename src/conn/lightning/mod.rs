@@ -0,0 +1,300 @@
+mod cln;
+mod lnd;
+
+use crate::config::{self, ClientMap};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Amounts are msat unless the field says otherwise, on both backends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub pubkey: String,
+    pub alias: String,
+    pub network: String,
+    pub block_height: u32,
+    // caught up with bitcoind and the gossip
+    pub synced: bool,
+    pub num_peers: u32,
+    pub num_active_channels: u32,
+    pub num_pending_channels: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub pubkey: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    pub connected: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelState {
+    Pending,
+    Active,
+    // open, but the peer is offline
+    Inactive,
+    Closing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Channel {
+    // what CloseChannel takes: the channel point on LND, the channel id on CLN
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short_channel_id: Option<String>,
+    pub peer: String,
+    pub capacity_sat: u64,
+    pub local_msat: u64,
+    pub remote_msat: u64,
+    pub state: ChannelState,
+    pub private: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Balance {
+    pub onchain_confirmed_sat: u64,
+    pub onchain_unconfirmed_sat: u64,
+    // spendable over active and inactive channels
+    pub channels_local_msat: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvoiceStatus {
+    Open,
+    Paid,
+    Expired,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Invoice {
+    pub payment_hash: String,
+    pub bolt11: String,
+    // none for "any amount" invoices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_msat: Option<u64>,
+    pub status: InvoiceStatus,
+    // unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paid_at: Option<u64>,
+    // cln only: the BOLT12 invoice and its offer, bolt11 is empty then
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub bolt12: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub offer_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Payment {
+    pub payment_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<String>,
+    pub amount_msat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bolt11: Option<String>,
+    pub status: PaymentStatus,
+    // unix seconds
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Utxo {
+    // "txid:vout", what the utxo lists of the wallet commands take
+    pub outpoint: String,
+    pub amount_sat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    // 0 while in the mempool
+    pub confirmations: u32,
+    // frozen, or reserved by an unpublished psbt. the wallet won't spend it
+    pub locked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OpenedChannel {
+    pub funding_txid: String,
+    pub output_index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClosedChannel {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closing_txid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeePolicy {
+    // the Channel id
+    pub channel: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short_channel_id: Option<String>,
+    pub peer: String,
+    pub base_fee_msat: u64,
+    pub fee_ppm: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_htlc_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_htlc_msat: Option<u64>,
+    // none on cln, where it's one value in the node's config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cltv_delta: Option<u32>,
+}
+
+/// The fields to change, the rest stay as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FeeUpdate {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub base_fee_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fee_ppm: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_htlc_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_htlc_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub cltv_delta: Option<u32>,
+}
+
+/// The calls swarm makes against a lightning node, whichever backend it
+/// runs. Failed payments are errors on both, LND doesn't get to return Ok.
+#[async_trait]
+pub trait LightningNode: Send + Sync {
+    async fn get_info(&self) -> Result<NodeInfo>;
+    async fn list_peers(&self) -> Result<Vec<Peer>>;
+    // host is "host:port", the port defaults to 9735
+    async fn connect_peer(&self, pubkey: &str, host: &str) -> Result<()>;
+    // pending and closing channels included
+    async fn list_channels(&self) -> Result<Vec<Channel>>;
+    async fn get_balance(&self) -> Result<Balance>;
+    async fn new_address(&self) -> Result<String>;
+    async fn create_invoice(&self, amt_msat: u64) -> Result<Invoice>;
+    // commits to sha256(description) instead of the description, for lnurl-pay
+    async fn create_hashed_invoice(&self, amt_msat: u64, description: &str) -> Result<Invoice>;
+    async fn list_invoices(&self, payment_hash: Option<String>) -> Result<Vec<Invoice>>;
+    // the next invoice paid after the index, with its own index. the index is the
+    // backend's own counter, none waits for the first one paid from now on
+    async fn wait_paid_invoice(&self, after: Option<u64>) -> Result<(Invoice, u64)>;
+    async fn pay_invoice(&self, bolt11: &str) -> Result<Payment>;
+    async fn list_payments(&self, payment_hash: Option<String>) -> Result<Vec<Payment>>;
+    async fn keysend(
+        &self,
+        dest: &str,
+        amt_msat: u64,
+        tlvs: Option<HashMap<u64, Vec<u8>>>,
+    ) -> Result<Payment>;
+    async fn open_channel(
+        &self,
+        pubkey: &str,
+        amount_sat: u64,
+        sat_per_vbyte: Option<u64>,
+    ) -> Result<OpenedChannel>;
+    // the wallet gets the funds when destination is none
+    async fn close_channel(&self, id: &str, destination: Option<String>) -> Result<ClosedChannel>;
+    // our side of each open channel
+    async fn list_fee_policies(&self) -> Result<Vec<FeePolicy>>;
+    // every channel when channel is none
+    async fn set_fee_policy(&self, channel: Option<&str>, update: &FeeUpdate) -> Result<()>;
+    async fn list_utxos(&self) -> Result<Vec<Utxo>>;
+    // keeps the wallet from spending them until unlocked
+    async fn lock_utxos(&self, outpoints: &[String], locked: bool) -> Result<()>;
+    // the txid. everything in the wallet, or in utxos if given, when amount_sat is none.
+    // the backend's own fee estimate when sat_per_vbyte is none
+    async fn send_onchain(
+        &self,
+        address: &str,
+        amount_sat: Option<u64>,
+        sat_per_vbyte: Option<u64>,
+        utxos: &[String],
+    ) -> Result<String>;
+    // base64 psbts. the inputs of a new one stay locked until it's published
+    async fn create_psbt(
+        &self,
+        address: &str,
+        amount_sat: u64,
+        sat_per_vbyte: Option<u64>,
+        utxos: &[String],
+    ) -> Result<String>;
+    async fn sign_psbt(&self, psbt: &str) -> Result<String>;
+    // finalize and broadcast, returns the txid
+    async fn publish_psbt(&self, psbt: &str) -> Result<String>;
+}
+
+/// The client of the CLN or LND node named `tag`.
+pub fn lightning_node(clients: &ClientMap, tag: &str) -> Option<Arc<dyn LightningNode>> {
+    if let Some(c) = clients.cln.get(tag) {
+        let node: Arc<dyn LightningNode> = Arc::new(c.clone());
+        return Some(node);
+    }
+    if let Some(c) = clients.lnd.get(tag) {
+        let node: Arc<dyn LightningNode> = c.clone();
+        return Some(node);
+    }
+    None
+}
+
+pub async fn lightning_client(tag: &str) -> Result<Arc<dyn LightningNode>> {
+    config::clients_read(|c| lightning_node(c, tag))
+        .await
+        .ok_or(anyhow!("no lightning client {}", tag))
+}
+
+/// "txid:vout" to its parts.
+pub fn parse_outpoint(outpoint: &str) -> Result<(String, u32)> {
+    let (txid, vout) = outpoint
+        .split_once(':')
+        .ok_or(anyhow!("outpoint must be txid:vout"))?;
+    if txid.len() != 64 || hex::decode(txid).is_err() {
+        return Err(anyhow!("bad txid in outpoint {}", outpoint));
+    }
+    let vout = vout
+        .parse::<u32>()
+        .map_err(|_| anyhow!("bad vout in outpoint {}", outpoint))?;
+    Ok((txid.to_lowercase(), vout))
+}
+
+fn split_host_port(host: &str) -> (&str, &str) {
+    match host.rsplit_once(':') {
+        Some((h, p)) if !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()) => (h, p),
+        _ => (host, "9735"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("cln.sphinx:9736"), ("cln.sphinx", "9736"));
+        assert_eq!(split_host_port("cln.sphinx"), ("cln.sphinx", "9735"));
+        let txid = "ab".repeat(32);
+        assert_eq!(parse_outpoint(&format!("{}:1", txid)).unwrap(), (txid.clone(), 1));
+        assert!(parse_outpoint(&txid).is_err());
+        assert!(parse_outpoint("abcd:0").is_err());
+        assert!(parse_outpoint(&format!("{}:x", txid)).is_err());
+        assert_eq!(cln::channel_state("CHANNELD_NORMAL:Channel ready for use.", true), ChannelState::Active);
+        assert_eq!(cln::channel_state("CHANNELD_NORMAL:Reconnected", false), ChannelState::Inactive);
+        assert_eq!(cln::channel_state("CHANNELD_AWAITING_LOCKIN:Funding needs 3 more", true), ChannelState::Pending);
+        assert_eq!(cln::channel_state("ONCHAIN:All outputs resolved", false), ChannelState::Closing);
+        let txid = "0a".repeat(31) + "ff";
+        let bytes = lnd::txid_bytes(&txid).unwrap();
+        assert_eq!(bytes[0], 0xff);
+        assert_eq!(lnd::txid_hex(&bytes), txid);
+    }
+}
@@ -5,8 +5,10 @@ use crate::images::lnd::LndImage;
 use crate::secrets::hex_secret_32;
 use crate::utils::docker_domain;
 use anyhow::{anyhow, Result};
+use futures_util::stream::{BoxStream, StreamExt};
 use sha2::{Digest, Sha256};
 use tonic_lnd::lnrpc::*;
+use tonic_lnd::walletrpc;
 use tonic_lnd::Client;
 
 pub struct LndRPC(Client);
@@ -82,6 +84,12 @@ impl LndRPC {
         Ok(response.into_inner())
     }
 
+    pub async fn channel_balance(&mut self) -> Result<ChannelBalanceResponse> {
+        let lnd = self.0.lightning();
+        let response = lnd.channel_balance(ChannelBalanceRequest {}).await?;
+        Ok(response.into_inner())
+    }
+
     pub async fn try_get_balance(&mut self) -> Result<WalletBalanceResponse> {
         for _ in 0..60 {
             if let Ok(b) = self.get_balance().await {
@@ -114,6 +122,48 @@ impl LndRPC {
         Ok(response.into_inner())
     }
 
+    // amount_msat 0 is any amount
+    pub async fn add_invoice_msat(&mut self, value_msat: i64) -> Result<AddInvoiceResponse> {
+        let lnd = self.0.lightning();
+        let response = lnd
+            .add_invoice(Invoice {
+                value_msat,
+                ..Default::default()
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    // none if lnd has no invoice for the hash
+    pub async fn lookup_invoice(&mut self, r_hash: Vec<u8>) -> Result<Option<Invoice>> {
+        let lnd = self.0.lightning();
+        let req = PaymentHash {
+            r_hash,
+            ..Default::default()
+        };
+        match lnd.lookup_invoice(req).await {
+            Ok(res) => Ok(Some(res.into_inner())),
+            Err(s) if s.message().contains("unable to locate invoice") => Ok(None),
+            Err(s) => Err(s.into()),
+        }
+    }
+
+    pub async fn add_hashed_invoice(
+        &mut self,
+        value_msat: i64,
+        description_hash: Vec<u8>,
+    ) -> Result<AddInvoiceResponse> {
+        let lnd = self.0.lightning();
+        let response = lnd
+            .add_invoice(Invoice {
+                value_msat,
+                description_hash,
+                ..Default::default()
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
     pub async fn pay_invoice(&mut self, invoice: PayInvoice) -> Result<SendResponse> {
         let lnd = self.0.lightning();
         let response = lnd
@@ -158,10 +208,17 @@ impl LndRPC {
         Ok(response.into_inner())
     }
 
-    pub async fn list_payments(&mut self) -> Result<ListPaymentsResponse> {
+    // failed and in-flight payments are left out unless include_incomplete
+    // the newest page, lnd pages from the oldest otherwise
+    pub async fn list_payments(
+        &mut self,
+        include_incomplete: bool,
+    ) -> Result<ListPaymentsResponse> {
         let lnd = self.0.lightning();
         let response = lnd
             .list_payments(ListPaymentsRequest {
+                include_incomplete,
+                reversed: true,
                 ..Default::default()
             })
             .await?;
@@ -169,10 +226,38 @@ impl LndRPC {
         Ok(response.into_inner())
     }
 
+    // pages back from the newest payment until the hash turns up
+    pub async fn find_payment(&mut self, payment_hash: &str) -> Result<Option<Payment>> {
+        let lnd = self.0.lightning();
+        let mut index_offset = 0;
+        loop {
+            let page = lnd
+                .list_payments(ListPaymentsRequest {
+                    include_incomplete: true,
+                    index_offset,
+                    reversed: true,
+                    ..Default::default()
+                })
+                .await?
+                .into_inner();
+            let oldest = page.payments.is_empty() || page.first_index_offset <= 1;
+            let found = page
+                .payments
+                .into_iter()
+                .find(|p| p.payment_hash == payment_hash);
+            if found.is_some() || oldest {
+                return Ok(found);
+            }
+            index_offset = page.first_index_offset;
+        }
+    }
+
+    // the newest page, lnd pages from the oldest otherwise
     pub async fn list_invoices(&mut self) -> Result<ListInvoiceResponse> {
         let lnd = self.0.lightning();
         let response = lnd
             .list_invoices(ListInvoiceRequest {
+                reversed: true,
                 ..Default::default()
             })
             .await?;
@@ -180,6 +265,69 @@ impl LndRPC {
         Ok(response.into_inner())
     }
 
+    // returns the closing txid, in lnd byte order
+    pub async fn close_channel(
+        &mut self,
+        channel_point: ChannelPoint,
+        delivery_address: Option<String>,
+    ) -> Result<Option<Vec<u8>>> {
+        let lnd = self.0.lightning();
+        let mut updates = lnd
+            .close_channel(CloseChannelRequest {
+                channel_point: Some(channel_point),
+                delivery_address: delivery_address.unwrap_or_default(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        // the first update is close_pending, don't wait for the confirmations
+        while let Some(update) = updates.message().await? {
+            match update.update {
+                Some(close_status_update::Update::ClosePending(p)) => return Ok(Some(p.txid)),
+                Some(close_status_update::Update::ChanClose(c)) => return Ok(Some(c.closing_txid)),
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    // settled invoices after settle_index are sent first, then new updates as they come
+    pub async fn subscribe_invoices(
+        &mut self,
+        settle_index: u64,
+    ) -> Result<BoxStream<'static, Result<Invoice>>> {
+        let lnd = self.0.lightning();
+        let updates = lnd
+            .subscribe_invoices(InvoiceSubscription {
+                add_index: 0,
+                settle_index,
+            })
+            .await?
+            .into_inner();
+        Ok(updates.map(|i| i.map_err(anyhow::Error::from)).boxed())
+    }
+
+    pub async fn fee_report(&mut self) -> Result<FeeReportResponse> {
+        let lnd = self.0.lightning();
+        let response = lnd.fee_report(FeeReportRequest {}).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn get_chan_info(&mut self, chan_id: u64) -> Result<ChannelEdge> {
+        let lnd = self.0.lightning();
+        let response = lnd.get_chan_info(ChanInfoRequest { chan_id }).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn update_channel_policy(&mut self, req: PolicyUpdateRequest) -> Result<()> {
+        let lnd = self.0.lightning();
+        let res = lnd.update_channel_policy(req).await?.into_inner();
+        if let Some(f) = res.failed_updates.first() {
+            return Err(anyhow!("policy update failed: {}", f.update_error));
+        }
+        Ok(())
+    }
+
     pub async fn list_pending_channels(&mut self) -> Result<PendingChannelsResponse> {
         let lnd = self.0.lightning();
         let response = lnd
@@ -189,6 +337,136 @@ impl LndRPC {
             .await?;
         Ok(response.into_inner())
     }
+
+    pub async fn list_unspent(&mut self) -> Result<ListUnspentResponse> {
+        let lnd = self.0.lightning();
+        let response = lnd
+            .list_unspent(ListUnspentRequest {
+                min_confs: 0,
+                max_confs: i32::MAX,
+                ..Default::default()
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    // everything in the wallet when amount is none
+    pub async fn send_coins(
+        &mut self,
+        addr: &str,
+        amount: Option<u64>,
+        sat_per_vbyte: Option<u64>,
+    ) -> Result<SendCoinsResponse> {
+        let lnd = self.0.lightning();
+        let response = lnd
+            .send_coins(SendCoinsRequest {
+                addr: addr.to_string(),
+                amount: amount.unwrap_or(0) as i64,
+                send_all: amount.is_none(),
+                sat_per_vbyte: sat_per_vbyte.unwrap_or(0),
+                ..Default::default()
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn lease_output(
+        &mut self,
+        id: Vec<u8>,
+        outpoint: OutPoint,
+        expiration_seconds: u64,
+    ) -> Result<walletrpc::LeaseOutputResponse> {
+        let wallet = self.0.wallet();
+        let response = wallet
+            .lease_output(walletrpc::LeaseOutputRequest {
+                id,
+                outpoint: Some(outpoint),
+                expiration_seconds,
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn release_output(
+        &mut self,
+        id: Vec<u8>,
+        outpoint: OutPoint,
+    ) -> Result<walletrpc::ReleaseOutputResponse> {
+        let wallet = self.0.wallet();
+        let response = wallet
+            .release_output(walletrpc::ReleaseOutputRequest {
+                id,
+                outpoint: Some(outpoint),
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn list_leases(&mut self) -> Result<walletrpc::ListLeasesResponse> {
+        let wallet = self.0.wallet();
+        let response = wallet.list_leases(walletrpc::ListLeasesRequest {}).await?;
+        Ok(response.into_inner())
+    }
+
+    // lnd picks the inputs when there are none, and leases them either way
+    pub async fn fund_psbt(
+        &mut self,
+        inputs: Vec<OutPoint>,
+        outputs: HashMap<String, u64>,
+        sat_per_vbyte: Option<u64>,
+    ) -> Result<walletrpc::FundPsbtResponse> {
+        use walletrpc::fund_psbt_request::{Fees, Template};
+        let wallet = self.0.wallet();
+        let fees = match sat_per_vbyte {
+            Some(r) => Fees::SatPerVbyte(r),
+            None => Fees::TargetConf(6),
+        };
+        let response = wallet
+            .fund_psbt(walletrpc::FundPsbtRequest {
+                template: Some(Template::Raw(walletrpc::TxTemplate { inputs, outputs })),
+                fees: Some(fees),
+                ..Default::default()
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn sign_psbt(&mut self, psbt: Vec<u8>) -> Result<walletrpc::SignPsbtResponse> {
+        let wallet = self.0.wallet();
+        let response = wallet
+            .sign_psbt(walletrpc::SignPsbtRequest { funded_psbt: psbt })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn finalize_psbt(
+        &mut self,
+        psbt: Vec<u8>,
+    ) -> Result<walletrpc::FinalizePsbtResponse> {
+        let wallet = self.0.wallet();
+        let response = wallet
+            .finalize_psbt(walletrpc::FinalizePsbtRequest {
+                funded_psbt: psbt,
+                ..Default::default()
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn publish_transaction(&mut self, tx: Vec<u8>) -> Result<()> {
+        let wallet = self.0.wallet();
+        let res = wallet
+            .publish_transaction(walletrpc::Transaction {
+                tx_hex: tx,
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        if !res.publish_error.is_empty() {
+            return Err(anyhow!("publish failed: {}", res.publish_error));
+        }
+        Ok(())
+    }
 }
 
 pub async fn sleep_ms(n: u64) {
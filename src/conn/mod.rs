@@ -1,6 +1,7 @@
 pub mod bitcoin;
 pub mod boltwall;
 pub mod cln;
+pub mod lightning;
 pub mod lnd;
 pub mod proxy;
 pub mod relay;
@@ -1,3 +1,4 @@
+use crate::metrics;
 use crate::service::public_ip::handle_check_public_ip_via_cron;
 use crate::utils::getenv;
 use anyhow::Result;
@@ -31,7 +32,9 @@ pub async fn check_public_ip(proj: &str) -> Result<JobScheduler> {
         loop {
             let go = CHECK_PUBLIC_IP.load(Ordering::Relaxed);
             if go {
-                if let Err(e) = handle_check_public_ip_via_cron(&proj).await {
+                let res = handle_check_public_ip_via_cron(&proj).await;
+                metrics::record_job("check_public_ip", res.is_ok());
+                if let Err(e) = res {
                     log::error!("{:?}", e);
                 }
 
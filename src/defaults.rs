@@ -194,6 +194,17 @@ impl Default for Stack {
             lightning_peers: None,
             ssl_cert_last_modified: None,
             instance_id: None,
+            alerts: None,
+            cleanup: None,
+            saved_log_queries: None,
+            backup_store: None,
+            backup_retention: None,
+            liquidity: None,
+            rebalance: None,
+            fee_schedules: None,
+            webhooks: None,
+            lightning_addresses: None,
+            offers: None,
         }
     }
 }
@@ -369,6 +380,17 @@ pub fn llama_only(network: &str, host: Option<String>) -> Stack {
         lightning_peers: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
 
@@ -0,0 +1,474 @@
+use crate::config;
+use crate::metrics;
+use crate::utils::domain;
+use anyhow::Result;
+use bollard::image::RemoveImageOptions;
+use bollard::Docker;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_cron_scheduler::{Job, JobScheduler};
+
+pub static RUN_CLEANUP: AtomicBool = AtomicBool::new(false);
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FsUsage {
+    pub path: String,
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub available_bytes: u64,
+}
+
+impl FsUsage {
+    pub fn free_percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.available_bytes as f64 / self.total_bytes as f64 * 100.0
+    }
+}
+
+/// Usage of the filesystem holding `path`, from `df -Pk`.
+pub fn fs_usage(path: &str) -> Option<FsUsage> {
+    let out = std::process::Command::new("df")
+        .args(["-Pk", path])
+        .output()
+        .ok()?;
+    parse_df(path, &String::from_utf8_lossy(&out.stdout))
+}
+
+// the 2nd, 3rd and 4th columns of the second line are total, used and
+// available 1k blocks
+fn parse_df(path: &str, out: &str) -> Option<FsUsage> {
+    let line = out.lines().nth(1)?;
+    let cols: Vec<&str> = line.split_whitespace().collect();
+    let kb = |i: usize| -> Option<u64> { cols.get(i)?.parse::<u64>().ok().map(|n| n * 1024) };
+    Some(FsUsage {
+        path: path.to_string(),
+        total_bytes: kb(1)?,
+        used_bytes: kb(2)?,
+        available_bytes: kb(3)?,
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VolumeUsage {
+    pub name: String,
+    // -1 if docker did not compute it
+    pub size_bytes: i64,
+    // containers using the volume
+    pub ref_count: i64,
+    // the stack node this volume belongs to, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    // not used by a container and not belonging to a node
+    pub orphaned: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ImagesUsage {
+    pub count: usize,
+    pub size_bytes: i64,
+    // images with no containers
+    pub unused_count: usize,
+    pub reclaimable_bytes: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct BuildCacheUsage {
+    pub count: usize,
+    pub size_bytes: i64,
+    pub reclaimable_bytes: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiskUsage {
+    pub filesystems: Vec<FsUsage>,
+    // largest first
+    pub volumes: Vec<VolumeUsage>,
+    pub images: ImagesUsage,
+    pub build_cache: BuildCacheUsage,
+    // writable layers of all containers
+    pub containers_size_bytes: i64,
+}
+
+async fn node_names() -> Vec<String> {
+    config::stack_read(|s| s.nodes.iter().map(|n| n.name()).collect()).await
+}
+
+// node volumes are named after the node's docker domain, extra ones
+// (repo2graph's "-tests", "-sessions"...) after "{node}-{what}"
+fn volume_node(volume: &str, nodes: &[String]) -> Option<String> {
+    if let Some(n) = nodes.iter().find(|n| domain(n) == volume) {
+        return Some(n.clone());
+    }
+    let name = volume.strip_suffix(".sphinx")?;
+    nodes
+        .iter()
+        .filter(|n| name.starts_with(&format!("{}-", n)))
+        .max_by_key(|n| n.len())
+        .cloned()
+}
+
+// only volumes the swarm made for a node can be orphaned, never other
+// volumes on the host. Restore safety copies ("{node}.sphinx-safety-{ts}")
+// are the only way back from a restore and verify scratch volumes
+// ("{node}.sphinx-verify-{ts}") belong to a running check, so keep both.
+fn orphan_candidate(volume: &str) -> bool {
+    volume.ends_with(".sphinx")
+        && !volume.contains(".sphinx-safety-")
+        && !volume.contains(".sphinx-verify-")
+}
+
+pub async fn get_disk_usage(docker: &Docker) -> Result<DiskUsage> {
+    let df = docker.df().await?;
+    let nodes = node_names().await;
+
+    let mut filesystems = Vec::new();
+    if let Some(fs) = fs_usage("/") {
+        filesystems.push(fs);
+    }
+    // the docker data root is often its own EBS volume
+    if let Ok(info) = docker.info().await {
+        if let Some(root) = info.docker_root_dir {
+            if let Some(fs) = fs_usage(&root) {
+                if !filesystems
+                    .iter()
+                    .any(|f| f.total_bytes == fs.total_bytes && f.used_bytes == fs.used_bytes)
+                {
+                    filesystems.push(fs);
+                }
+            }
+        }
+    }
+
+    let mut volumes: Vec<VolumeUsage> = df
+        .volumes
+        .unwrap_or_default()
+        .into_iter()
+        .map(|v| {
+            let (size_bytes, ref_count) = v
+                .usage_data
+                .map(|u| (u.size, u.ref_count))
+                .unwrap_or((-1, 0));
+            let node = volume_node(&v.name, &nodes);
+            VolumeUsage {
+                orphaned: ref_count == 0 && node.is_none(),
+                name: v.name,
+                size_bytes,
+                ref_count,
+                node,
+            }
+        })
+        .collect();
+    volumes.sort_by_key(|v| std::cmp::Reverse(v.size_bytes));
+
+    let mut images = ImagesUsage::default();
+    for img in df.images.unwrap_or_default().iter() {
+        images.count += 1;
+        images.size_bytes += img.size;
+        if img.containers == 0 {
+            images.unused_count += 1;
+            images.reclaimable_bytes += img.size - img.shared_size.max(0);
+        }
+    }
+
+    let mut build_cache = BuildCacheUsage::default();
+    for bc in df.build_cache.unwrap_or_default().iter() {
+        let size = bc.size.unwrap_or(0);
+        build_cache.count += 1;
+        build_cache.size_bytes += size;
+        if !bc.in_use.unwrap_or(false) && !bc.shared.unwrap_or(false) {
+            build_cache.reclaimable_bytes += size;
+        }
+    }
+
+    let containers_size_bytes = df
+        .containers
+        .unwrap_or_default()
+        .iter()
+        .map(|c| c.size_rw.unwrap_or(0))
+        .sum();
+
+    Ok(DiskUsage {
+        filesystems,
+        volumes,
+        images,
+        build_cache,
+        containers_size_bytes,
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CleanupPolicy {
+    // remove images no container uses that were created more than this many days ago
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images_older_than_days: Option<u64>,
+    // remove swarm volumes no container uses and no node owns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orphaned_volumes: Option<bool>,
+    // truncate json-file container logs not written to in this many days
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_logs_older_than_days: Option<u64>,
+    // run on this schedule; manual only if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunCleanupRequest {
+    pub dry_run: bool,
+    // defaults to the policy in the stack config
+    pub policy: Option<CleanupPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CleanupAction {
+    // "image", "volume" or "container_log"
+    pub kind: String,
+    pub target: String,
+    pub bytes: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CleanupReport {
+    pub dry_run: bool,
+    pub actions: Vec<CleanupAction>,
+    pub reclaimed_bytes: i64,
+    pub errors: Vec<String>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// What the policy would remove right now. Volumes belonging to a node,
+/// restore safety copies and volumes the swarm didn't make are never
+/// candidates, whatever their ref count.
+pub async fn plan_cleanup(docker: &Docker, policy: &CleanupPolicy) -> Result<Vec<CleanupAction>> {
+    let mut actions = Vec::new();
+    let needs_df = policy.images_older_than_days.is_some() || policy.orphaned_volumes == Some(true);
+    let df = if needs_df {
+        Some(docker.df().await?)
+    } else {
+        None
+    };
+    let now = now_secs();
+
+    if let (Some(days), Some(df)) = (policy.images_older_than_days, df.as_ref()) {
+        let cutoff = now.saturating_sub(days * DAY) as i64;
+        for img in df.images.iter().flatten() {
+            if img.containers == 0 && img.created < cutoff {
+                // dangling images are tagged "<none>:<none>", remove those by id
+                let target = img
+                    .repo_tags
+                    .iter()
+                    .find(|t| !t.starts_with("<none>"))
+                    .cloned()
+                    .unwrap_or(img.id.clone());
+                actions.push(CleanupAction {
+                    kind: "image".to_string(),
+                    target,
+                    bytes: img.size - img.shared_size.max(0),
+                });
+            }
+        }
+    }
+
+    if let (Some(true), Some(df)) = (policy.orphaned_volumes, df.as_ref()) {
+        let nodes = node_names().await;
+        for v in df.volumes.iter().flatten() {
+            let (size, refs) = v
+                .usage_data
+                .as_ref()
+                .map(|u| (u.size, u.ref_count))
+                .unwrap_or((-1, 0));
+            if refs == 0 && orphan_candidate(&v.name) && volume_node(&v.name, &nodes).is_none() {
+                actions.push(CleanupAction {
+                    kind: "volume".to_string(),
+                    target: v.name.clone(),
+                    bytes: size.max(0),
+                });
+            }
+        }
+    }
+
+    if let Some(days) = policy.container_logs_older_than_days {
+        let cutoff = SystemTime::now() - Duration::from_secs(days * DAY);
+        let containers = crate::dock::list_containers(docker).await?;
+        let mut seen = HashSet::new();
+        for c in containers.iter() {
+            let id = match &c.id {
+                Some(id) => id,
+                None => continue,
+            };
+            let info = match docker.inspect_container(id, None).await {
+                Ok(i) => i,
+                Err(_) => continue,
+            };
+            // only json-file logs live on disk, and only if the path is visible to us
+            let path = match info.log_path {
+                Some(p) if !p.is_empty() && seen.insert(p.clone()) => p,
+                _ => continue,
+            };
+            let meta = match std::fs::metadata(&path) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            let stale = meta.modified().map(|m| m < cutoff).unwrap_or(false);
+            if stale && meta.len() > 0 {
+                actions.push(CleanupAction {
+                    kind: "container_log".to_string(),
+                    target: path,
+                    bytes: meta.len() as i64,
+                });
+            }
+        }
+    }
+    Ok(actions)
+}
+
+pub async fn run_cleanup(
+    docker: &Docker,
+    policy: &CleanupPolicy,
+    dry_run: bool,
+) -> Result<CleanupReport> {
+    let actions = plan_cleanup(docker, policy).await?;
+    let mut report = CleanupReport {
+        dry_run,
+        ..Default::default()
+    };
+    for a in actions {
+        if dry_run {
+            report.reclaimed_bytes += a.bytes;
+            report.actions.push(a);
+            continue;
+        }
+        let res: Result<()> = match a.kind.as_str() {
+            "image" => docker
+                .remove_image(&a.target, Some(RemoveImageOptions::default()), None)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.into()),
+            "volume" => docker
+                .remove_volume(&a.target, None)
+                .await
+                .map_err(|e| e.into()),
+            "container_log" => std::fs::OpenOptions::new()
+                .write(true)
+                .open(&a.target)
+                .and_then(|f| f.set_len(0))
+                .map_err(|e| e.into()),
+            _ => Ok(()),
+        };
+        match res {
+            Ok(()) => {
+                log::info!("cleanup: removed {} {}", a.kind, a.target);
+                report.reclaimed_bytes += a.bytes;
+                report.actions.push(a);
+            }
+            Err(e) => report
+                .errors
+                .push(format!("{} {}: {}", a.kind, a.target, e)),
+        }
+    }
+    Ok(report)
+}
+
+pub async fn cleanup_cron(docker: Docker, cron: String) -> Result<JobScheduler> {
+    log::info!(":disk cleanup");
+    let sched = JobScheduler::new().await?;
+
+    sched
+        .add(Job::new_async(cron.as_str(), |_uuid, _l| {
+            Box::pin(async move {
+                if !RUN_CLEANUP.load(Ordering::Relaxed) {
+                    RUN_CLEANUP.store(true, Ordering::Relaxed);
+                }
+            })
+        })?)
+        .await?;
+
+    sched.start().await?;
+
+    tokio::spawn(async move {
+        loop {
+            let go = RUN_CLEANUP.load(Ordering::Relaxed);
+            if go {
+                // re-read so policy edits apply without a restart
+                let policy = config::stack_read(|s| s.cleanup.clone()).await;
+                if let Some(policy) = policy {
+                    let res = run_cleanup(&docker, &policy, false).await;
+                    let ok = matches!(&res, Ok(r) if r.errors.is_empty());
+                    metrics::record_job("disk_cleanup", ok);
+                    match res {
+                        Ok(r) => {
+                            for e in r.errors.iter() {
+                                log::error!("cleanup: {}", e);
+                            }
+                        }
+                        Err(e) => log::error!("Disk cleanup: {:?}", e),
+                    }
+                }
+                RUN_CLEANUP.store(false, Ordering::Relaxed);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
+    Ok(sched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_df() {
+        let out = "Filesystem 1024-blocks Used Available Capacity Mounted on\n/dev/root 1000 900 100 90% /\n";
+        let fs = parse_df("/", out).unwrap();
+        assert_eq!(fs.total_bytes, 1000 * 1024);
+        assert_eq!(fs.used_bytes, 900 * 1024);
+        assert_eq!(fs.free_percent(), 10.0);
+        assert_eq!(parse_df("/", ""), None);
+    }
+
+    #[test]
+    fn test_volume_node() {
+        let nodes = vec!["neo4j".to_string(), "bitcoind".to_string()];
+        assert_eq!(
+            volume_node("neo4j.sphinx", &nodes),
+            Some("neo4j".to_string())
+        );
+        assert_eq!(volume_node("old-elastic.sphinx", &nodes), None);
+        let nodes = vec!["repo2graph".to_string(), "repo2graph-2".to_string()];
+        assert_eq!(
+            volume_node("repo2graph-sessions.sphinx", &nodes),
+            Some("repo2graph".to_string())
+        );
+        assert_eq!(
+            volume_node("repo2graph-2-tests.sphinx", &nodes),
+            Some("repo2graph-2".to_string())
+        );
+        assert_eq!(
+            volume_node("repo2graph-2.sphinx", &nodes),
+            Some("repo2graph-2".to_string())
+        );
+        assert_eq!(volume_node("repo2graph-tests", &nodes), None);
+    }
+
+    #[test]
+    fn test_orphan_candidate() {
+        assert!(orphan_candidate("old-elastic.sphinx"));
+        assert!(orphan_candidate("repo2graph-sessions.sphinx"));
+        assert!(!orphan_candidate("neo4j.sphinx-safety-1714608000"));
+        assert!(!orphan_candidate("neo4j.sphinx-verify-1714608000"));
+        assert!(!orphan_candidate("portainer_data"));
+    }
+}
@@ -24,17 +24,17 @@ use serde_json;
 use std::default::Default;
 use std::error::Error;
 use std::time::Duration;
-use tokio::io::AsyncReadExt;
 
-use crate::backup::bucket_name;
+use crate::backup_chunks::restore_manifests;
+use crate::backup_store::{backup_store, download_prefix};
 use crate::builder::{find_img, make_client};
 use crate::config::{Node, State};
 use crate::conn::swarm::SwarmResponse;
 use crate::images::{DockerConfig, DockerHubImage};
-use crate::mount_backedup_volume::download_from_s3;
 use crate::utils::{domain, getenv, sleep_ms};
 use bollard::models::ImageInspect;
 use tokio::fs::File;
+use tokio_util::io::ReaderStream;
 
 pub fn dockr() -> Docker {
     Docker::connect_with_unix_defaults().unwrap()
@@ -543,6 +543,49 @@ pub async fn exec_no_tty(docker: &Docker, id: &str, cmd: Vec<String>) -> Result<
     Ok(ret)
 }
 
+/// Run `cmd` with `sh -c` and fail unless it exits 0. For scripted steps
+/// like backup hooks, where a silent failure would be worse than an error.
+pub async fn exec_checked(docker: &Docker, id: &str, cmd: &str) -> Result<String> {
+    exec_checked_env(docker, id, cmd, Vec::new()).await
+}
+
+/// `exec_checked` with extra `KEY=value` env. Secrets go here rather than
+/// in `cmd`, which ends up in the error.
+pub async fn exec_checked_env(
+    docker: &Docker,
+    id: &str,
+    cmd: &str,
+    env: Vec<String>,
+) -> Result<String> {
+    let argv = vec!["sh".to_string(), "-c".to_string(), cmd.to_string()];
+    let exec = docker
+        .create_exec(
+            id,
+            CreateExecOptions {
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                tty: Some(false),
+                cmd: Some(argv),
+                env: Some(env),
+                ..Default::default()
+            },
+        )
+        .await?
+        .id;
+    let started = docker.start_exec(&exec, None).await?;
+    let mut ret = String::new();
+    if let StartExecResults::Attached { mut output, .. } = started {
+        while let Some(Ok(msg)) = output.next().await {
+            ret.push_str(&msg.to_string());
+        }
+    }
+    let code = docker.inspect_exec(&exec).await?.exit_code.unwrap_or(0);
+    if code != 0 {
+        return Err(anyhow!("`{}` in {} exited {}: {}", cmd, id, code, ret.trim()));
+    }
+    Ok(ret)
+}
+
 pub async fn sleep(millis: u64) {
     tokio::time::sleep(tokio::time::Duration::from_millis(millis)).await;
 }
@@ -1189,13 +1232,21 @@ pub async fn prune_images(docker: &Docker) {
 
 #[derive(Serialize, Clone, Debug)]
 pub struct ContainerStat {
-    container_id: String,
-    container_name: String,
-    container_image: String,
-    cpu_total_usage: u64,
-    system_cpu_usage: u64,
-    memory_usage: u64,
-    memory_max_usage: u64,
+    pub container_id: String,
+    pub container_name: String,
+    pub container_image: String,
+    pub cpu_total_usage: u64,
+    pub system_cpu_usage: u64,
+    pub memory_usage: u64,
+    pub memory_max_usage: u64,
+    // 0 if the container has no limit
+    pub memory_limit: u64,
+    // cpu usage since the previous read, as a percent of one core
+    pub cpu_percent: f64,
+    pub network_rx_bytes: u64,
+    pub network_tx_bytes: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
 }
 
 impl ContainerStat {
@@ -1205,6 +1256,40 @@ impl ContainerStat {
         container_image: Option<String>,
         stats: Stats,
     ) -> Self {
+        let cpu_delta = stats
+            .cpu_stats
+            .cpu_usage
+            .total_usage
+            .saturating_sub(stats.precpu_stats.cpu_usage.total_usage);
+        let system_delta = stats
+            .cpu_stats
+            .system_cpu_usage
+            .unwrap_or(0)
+            .saturating_sub(stats.precpu_stats.system_cpu_usage.unwrap_or(0));
+        let online_cpus = stats.cpu_stats.online_cpus.unwrap_or(1).max(1);
+        let cpu_percent = if system_delta > 0 {
+            cpu_delta as f64 / system_delta as f64 * online_cpus as f64 * 100.0
+        } else {
+            0.0
+        };
+        let (network_rx_bytes, network_tx_bytes) = stats
+            .networks
+            .as_ref()
+            .map(|nets| {
+                nets.values()
+                    .fold((0, 0), |(rx, tx), n| (rx + n.rx_bytes, tx + n.tx_bytes))
+            })
+            .unwrap_or((0, 0));
+        let (mut block_read_bytes, mut block_write_bytes) = (0, 0);
+        if let Some(entries) = &stats.blkio_stats.io_service_bytes_recursive {
+            for e in entries {
+                match e.op.to_lowercase().as_str() {
+                    "read" => block_read_bytes += e.value,
+                    "write" => block_write_bytes += e.value,
+                    _ => (),
+                }
+            }
+        }
         Self {
             container_id: container_id.to_owned(),
             container_name: container_name.to_owned(),
@@ -1213,39 +1298,48 @@ impl ContainerStat {
             system_cpu_usage: stats.cpu_stats.system_cpu_usage.unwrap_or(0),
             memory_usage: stats.memory_stats.usage.unwrap_or(0),
             memory_max_usage: stats.memory_stats.max_usage.unwrap_or(0),
+            memory_limit: stats.memory_stats.limit.unwrap_or(0),
+            cpu_percent,
+            network_rx_bytes,
+            network_tx_bytes,
+            block_read_bytes,
+            block_write_bytes,
         }
     }
 }
 
-async fn copy_data_to_volume(
+/// Unpack the tar at `data_path` over `root_volume`, inside `host`: the
+/// node's own container, or a helper with its volume mounted.
+pub async fn copy_data_to_volume(
     docker: &Docker,
+    host: &str,
     name: &str,
     inner_root_path: &str,
     root_volume: &str,
     data_path: &str,
 ) -> Result<(), Box<dyn Error>> {
-    let host = domain(name);
+    let host = host.to_string();
 
     let temp_root = format!("/temp_{}", &name);
 
     exec(docker, &host, &format!("mkdir -p {}", &temp_root)).await?;
 
-    // Open the tar file
-    let mut tar_file = File::open(data_path).await?;
-
-    // Read the tar file into a buffer
-    let mut buffer = Vec::new();
-    tar_file.read_to_end(&mut buffer).await?;
-
-    // Upload the tar file to the container
+    // stream the tar, a bitcoind or neo4j volume doesn't fit in memory
+    let tar_file = File::open(data_path).await?;
+    let path = data_path.to_string();
+    // a read error ends the upload early, and docker rejects the short tar
+    let tar = ReaderStream::new(tar_file).scan((), move |_, chunk| {
+        let chunk = chunk.map_err(|e| log::error!("reading {}: {:?}", path, e));
+        futures_util::future::ready(chunk.ok())
+    });
     docker
-        .upload_to_container(
+        .upload_to_container_streaming(
             &host,
             Some(UploadToContainerOptions {
                 path: temp_root.clone(),
                 ..Default::default()
             }),
-            buffer.into(),
+            tar,
         )
         .await?;
 
@@ -1290,7 +1384,19 @@ pub async fn restore_backup_if_exist(docker: &Docker, name: &str) -> Result<bool
 
         if let Ok(backup_link) = getenv("BACKUP_KEY") {
             if !directory_exists(&backup_link) {
-                let _ = download_from_s3(&bucket_name(), &backup_link).await;
+                match backup_store().await {
+                    Ok(store) => {
+                        if let Err(e) = download_prefix(store.as_ref(), &backup_link).await {
+                            log::error!("Error downloading backup {}: {:?}", backup_link, e);
+                        }
+                        // incremental backups only have manifests under the backup key
+                        let swarm = backup_link.split('/').next().unwrap_or_default();
+                        if let Err(e) = restore_manifests(store.as_ref(), swarm, &backup_link).await {
+                            log::error!("Error rebuilding backup {}: {:?}", backup_link, e);
+                        }
+                    }
+                    Err(e) => log::error!("No backup store: {:?}", e),
+                }
             } else {
                 log::info!("Directory exist");
             }
@@ -1307,6 +1413,7 @@ pub async fn restore_backup_if_exist(docker: &Docker, name: &str) -> Result<bool
                 //create temporary container
                 match copy_data_to_volume(
                     &docker,
+                    &domain(name),
                     &name,
                     &inner_root_path,
                     &root_volume,
@@ -1,9 +1,13 @@
+use once_cell::sync::OnceCell;
 use rocket::*;
 use std::sync::Arc;
 use tokio::sync::{broadcast, Mutex};
 
 pub type EventChan = broadcast::Sender<String>;
 
+// the channel /events serves, for background jobs that report progress on it
+static EVENT_CHAN: OnceCell<Arc<Mutex<EventChan>>> = OnceCell::new();
+
 pub fn new_event_chan() -> Arc<Mutex<EventChan>> {
     Arc::new(Mutex::new(broadcast::channel::<String>(1024).0))
 }
@@ -11,3 +15,15 @@ pub fn new_event_chan() -> Arc<Mutex<EventChan>> {
 pub async fn get_event_tx(chan: &Arc<Mutex<EventChan>>) -> broadcast::Sender<String> {
     chan.lock().await.clone()
 }
+
+pub fn set_event_chan(chan: Arc<Mutex<EventChan>>) {
+    let _ = EVENT_CHAN.set(chan);
+}
+
+/// Put a message on the /events stream, if this binary serves one.
+pub async fn send_event(msg: String) {
+    if let Some(chan) = EVENT_CHAN.get() {
+        // no one listening is fine
+        let _ = get_event_tx(chan).await.send(msg);
+    }
+}
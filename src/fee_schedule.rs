@@ -0,0 +1,379 @@
+use crate::config;
+use crate::conn::lightning::{
+    lightning_client, Channel, ChannelState, FeePolicy, FeeUpdate, LightningNode,
+};
+use crate::images::Image;
+use crate::metrics;
+use crate::utils::getenv;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Mutex;
+use tokio_cron_scheduler::{Job, JobScheduler};
+
+const PROJ: &str = "stack";
+const MAX_CHANGES: usize = 1000;
+// smaller moves aren't worth a gossip update
+const DEFAULT_MIN_CHANGE_PPM: u64 = 10;
+
+pub static APPLY_FEE_SCHEDULES: AtomicBool = AtomicBool::new(false);
+
+static CHANGES_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeStep {
+    // applies while the local share of a channel is below this
+    pub below_local_ratio: f64,
+    pub fee_ppm: u64,
+}
+
+/// Sets each channel's ppm from its local balance, so the fee goes up as
+/// the channel drains.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeSchedule {
+    pub steps: Vec<FeeStep>,
+    // above every step
+    pub default_ppm: u64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub base_fee_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_change_ppm: Option<u64>,
+}
+
+impl FeeSchedule {
+    pub fn validate(&self) -> Result<()> {
+        for s in self.steps.iter() {
+            if !(0.0..=1.0).contains(&s.below_local_ratio) {
+                return Err(anyhow!("step ratios must be between 0.0 and 1.0"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The ppm for a channel with `local_ratio` of its balance on our side.
+    pub fn ppm_for(&self, local_ratio: f64) -> u64 {
+        let mut steps: Vec<&FeeStep> = self.steps.iter().collect();
+        steps.sort_by(|a, b| a.below_local_ratio.total_cmp(&b.below_local_ratio));
+        steps
+            .into_iter()
+            .find(|s| local_ratio < s.below_local_ratio)
+            .map(|s| s.fee_ppm)
+            .unwrap_or(self.default_ppm)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeChange {
+    pub node: String,
+    // unix seconds
+    pub at: i64,
+    // "all" for a node-wide change
+    pub channel: String,
+    #[serde(flatten)]
+    pub update: FeeUpdate,
+    // set by the schedule
+    pub auto: bool,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetFeePolicy {
+    // a channel id, every channel if none
+    pub channel: Option<String>,
+    #[serde(flatten)]
+    pub update: FeeUpdate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateFeeScheduleRequest {
+    pub node: String,
+    // none stops the schedule for the node
+    pub schedule: Option<FeeSchedule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListFeeChangesRequest {
+    pub node: Option<String>,
+    // newest first, 100 by default
+    pub limit: Option<usize>,
+}
+
+/// The channels whose ppm is off the schedule by at least min_change_ppm,
+/// with the update to make and why.
+pub fn plan_updates(
+    schedule: &FeeSchedule,
+    channels: &[Channel],
+    policies: &[FeePolicy],
+) -> Vec<(String, FeeUpdate, String)> {
+    let min_change = schedule.min_change_ppm.unwrap_or(DEFAULT_MIN_CHANGE_PPM);
+    let mut out = Vec::new();
+    for c in channels.iter().filter(|c| c.state == ChannelState::Active) {
+        let total = c.local_msat + c.remote_msat;
+        let current = match policies.iter().find(|p| p.channel == c.id) {
+            Some(p) => p,
+            None => continue,
+        };
+        if total == 0 {
+            continue;
+        }
+        let ratio = c.local_msat as f64 / total as f64;
+        let ppm = schedule.ppm_for(ratio);
+        let base_changed = schedule
+            .base_fee_msat
+            .map(|b| b != current.base_fee_msat)
+            .unwrap_or(false);
+        if current.fee_ppm.abs_diff(ppm) < min_change && !base_changed {
+            continue;
+        }
+        let update = FeeUpdate {
+            fee_ppm: Some(ppm),
+            base_fee_msat: schedule.base_fee_msat,
+            ..Default::default()
+        };
+        let reason = format!(
+            "local {:.0}%, ppm {} -> {}",
+            ratio * 100.0,
+            current.fee_ppm,
+            ppm
+        );
+        out.push((c.id.clone(), update, reason));
+    }
+    out
+}
+
+fn changes_path() -> String {
+    format!("vol/{}/fee_changes.json", PROJ)
+}
+
+pub async fn load_changes() -> Vec<FeeChange> {
+    crate::utils::load_json(&changes_path(), Vec::new()).await
+}
+
+async fn record(new: &[FeeChange]) {
+    if new.is_empty() {
+        return;
+    }
+    let _lock = CHANGES_LOCK.lock().await;
+    let mut all = load_changes().await;
+    all.extend_from_slice(new);
+    let skip = all.len().saturating_sub(MAX_CHANGES);
+    let all: Vec<FeeChange> = all.into_iter().skip(skip).collect();
+    crate::utils::put_json(&changes_path(), &all).await;
+}
+
+pub async fn list_changes(node: Option<String>, limit: Option<usize>) -> Vec<FeeChange> {
+    load_changes()
+        .await
+        .into_iter()
+        .rev()
+        .filter(|c| node.as_ref().map(|n| *n == c.node).unwrap_or(true))
+        .take(limit.unwrap_or(100))
+        .collect()
+}
+
+async fn apply(
+    ln: &dyn LightningNode,
+    node: &str,
+    channel: Option<String>,
+    update: FeeUpdate,
+    auto: bool,
+    reason: Option<String>,
+) -> FeeChange {
+    let res = ln.set_fee_policy(channel.as_deref(), &update).await;
+    FeeChange {
+        node: node.to_string(),
+        at: Utc::now().timestamp(),
+        channel: channel.unwrap_or("all".to_string()),
+        update,
+        auto,
+        reason,
+        error: res.err().map(|e| e.to_string()),
+    }
+}
+
+/// The SetFeePolicy commands, logged with the schedule's changes.
+pub async fn set_fees(ln: &dyn LightningNode, node: &str, req: SetFeePolicy) -> Result<FeeChange> {
+    let change = apply(ln, node, req.channel, req.update, false, None).await;
+    record(std::slice::from_ref(&change)).await;
+    match &change.error {
+        Some(e) => Err(anyhow!(e.clone())),
+        None => Ok(change),
+    }
+}
+
+pub async fn apply_schedule(
+    ln: &dyn LightningNode,
+    node: &str,
+    schedule: &FeeSchedule,
+) -> Result<Vec<FeeChange>> {
+    let channels = ln.list_channels().await?;
+    let policies = ln.list_fee_policies().await?;
+    let mut changes = Vec::new();
+    for (channel, update, reason) in plan_updates(schedule, &channels, &policies) {
+        log::info!("fee schedule {}: {} {}", node, channel, reason);
+        changes.push(apply(ln, node, Some(channel), update, true, Some(reason)).await);
+    }
+    record(&changes).await;
+    Ok(changes)
+}
+
+/// Set or remove the fee schedule of a CLN or LND node in the stack.
+pub async fn update_schedule(proj: &str, req: &UpdateFeeScheduleRequest) -> Result<()> {
+    if let Some(s) = &req.schedule {
+        s.validate()?;
+    }
+    let img = config::stack_read(|s| crate::builder::find_img(&req.node, &s.nodes)).await?;
+    if !matches!(img, Image::Cln(_) | Image::Lnd(_)) {
+        return Err(anyhow!("{} is not a lightning node", req.node));
+    }
+    config::stack_write(proj, |s| {
+        let mut schedules: BTreeMap<String, FeeSchedule> =
+            s.fee_schedules.take().unwrap_or_default();
+        match &req.schedule {
+            Some(sch) => {
+                schedules.insert(req.node.clone(), sch.clone());
+            }
+            None => {
+                schedules.remove(&req.node);
+            }
+        }
+        s.fee_schedules = (!schedules.is_empty()).then_some(schedules);
+    })
+    .await;
+    Ok(())
+}
+
+async fn apply_all() -> Result<()> {
+    let schedules = config::stack_read(|s| s.fee_schedules.clone().unwrap_or_default()).await;
+    let mut errs = Vec::new();
+    for (node, schedule) in schedules.iter() {
+        let res = match lightning_client(node).await {
+            Ok(ln) => apply_schedule(ln.as_ref(), node, schedule).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(changes) => {
+                for c in changes.iter().filter(|c| c.error.is_some()) {
+                    errs.push(format!(
+                        "{} {}: {}",
+                        node,
+                        c.channel,
+                        c.error.clone().unwrap_or_default()
+                    ));
+                }
+            }
+            Err(e) => errs.push(format!("{}: {}", node, e)),
+        }
+    }
+    if errs.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(errs.join("\n")))
+}
+
+pub async fn fee_schedule_cron() -> Result<JobScheduler> {
+    log::info!("=> start fee schedule cron");
+    let sched = JobScheduler::new().await?;
+
+    let cron_time = getenv("FEE_SCHEDULE_CRON_TIME").unwrap_or("0 15 * * * *".to_string());
+
+    sched
+        .add(Job::new_async(cron_time.as_str(), |_uuid, _l| {
+            Box::pin(async move {
+                if !APPLY_FEE_SCHEDULES.load(Ordering::Relaxed) {
+                    APPLY_FEE_SCHEDULES.store(true, Ordering::Relaxed);
+                }
+            })
+        })?)
+        .await?;
+
+    sched.start().await?;
+
+    tokio::spawn(async move {
+        loop {
+            if APPLY_FEE_SCHEDULES.load(Ordering::Relaxed) {
+                let res = apply_all().await;
+                metrics::record_job("fee_schedule", res.is_ok());
+                if let Err(e) = res {
+                    log::error!("Error applying fee schedules: {:?}", e);
+                }
+                APPLY_FEE_SCHEDULES.store(false, Ordering::Relaxed);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
+    Ok(sched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chan(id: &str, local_sat: u64, remote_sat: u64) -> Channel {
+        Channel {
+            id: id.to_string(),
+            short_channel_id: None,
+            peer: "02aa".to_string(),
+            capacity_sat: local_sat + remote_sat,
+            local_msat: local_sat * 1000,
+            remote_msat: remote_sat * 1000,
+            state: ChannelState::Active,
+            private: false,
+        }
+    }
+
+    fn policy(id: &str, fee_ppm: u64) -> FeePolicy {
+        FeePolicy {
+            channel: id.to_string(),
+            short_channel_id: None,
+            peer: "02aa".to_string(),
+            base_fee_msat: 1000,
+            fee_ppm,
+            min_htlc_msat: None,
+            max_htlc_msat: None,
+            cltv_delta: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_updates() {
+        let schedule = FeeSchedule {
+            // out of order on purpose
+            steps: vec![
+                FeeStep {
+                    below_local_ratio: 0.3,
+                    fee_ppm: 800,
+                },
+                FeeStep {
+                    below_local_ratio: 0.1,
+                    fee_ppm: 2000,
+                },
+            ],
+            default_ppm: 100,
+            base_fee_msat: None,
+            min_change_ppm: None,
+        };
+        assert_eq!(schedule.ppm_for(0.05), 2000);
+        assert_eq!(schedule.ppm_for(0.2), 800);
+        assert_eq!(schedule.ppm_for(0.3), 100);
+        let channels = vec![chan("a", 50, 950), chan("b", 500, 500), chan("c", 200, 800)];
+        let policies = vec![policy("a", 100), policy("b", 105), policy("c", 800)];
+        let updates = plan_updates(&schedule, &channels, &policies);
+        // b is within min_change_ppm, c is already right
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].0, "a");
+        assert_eq!(updates[0].1.fee_ppm, Some(2000));
+        let with_base = FeeSchedule {
+            base_fee_msat: Some(0),
+            ..schedule
+        };
+        assert_eq!(plan_updates(&with_base, &channels, &policies).len(), 3);
+    }
+}
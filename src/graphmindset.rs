@@ -44,6 +44,17 @@ pub fn only_graph_mindset(network: &str, host: Option<String>) -> Stack {
         lightning_peers: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
 
@@ -13,6 +13,7 @@ use crate::conn::boltwall::{
     get_api_token, get_max_request_size, get_request_per_seconds,
     update_max_request_size_config, update_request_per_seconds_config,
 };
+use crate::conn::lightning::{self, ChannelState, LightningNode};
 use crate::conn::swarm::add_new_lightning_peer;
 use crate::conn::swarm::handle_assign_reserved_swarm_to_active;
 use crate::conn::swarm::update_lightning_peer;
@@ -157,6 +158,172 @@ pub async fn handle(
                 let out = hermes_auth::logout(docker, &provider).await?;
                 Some(serde_json::to_string(&out)?)
             }
+            SwarmCmd::ListAlerts => {
+                let res = crate::alerts::list_alerts().await;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::CreateAlertSilence(req) => {
+                log::info!("CreateAlertSilence -> {:?}", &req);
+                let silence = crate::alerts::create_silence(proj, req, user_id).await?;
+                Some(serde_json::to_string(&silence)?)
+            }
+            SwarmCmd::DeleteAlertSilence(id) => {
+                log::info!("DeleteAlertSilence -> {}", &id);
+                crate::alerts::delete_silence(proj, &id).await?;
+                Some(serde_json::to_string(&id)?)
+            }
+            SwarmCmd::GetDiskUsage => {
+                let res = crate::disk::get_disk_usage(docker).await?;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::UpdateCleanupPolicy(policy) => {
+                log::info!("UpdateCleanupPolicy -> {:?}", &policy);
+                // a changed schedule is picked up on the next restart
+                config::stack_write(proj, |s| s.cleanup = Some(policy.clone())).await;
+                Some(serde_json::to_string(&policy)?)
+            }
+            SwarmCmd::RunCleanup(req) => {
+                log::info!("RunCleanup -> {:?}", &req);
+                let policy = match req.policy {
+                    Some(p) => p,
+                    None => config::stack_read(|s| s.cleanup.clone())
+                        .await
+                        .unwrap_or_default(),
+                };
+                let res = crate::disk::run_cleanup(docker, &policy, req.dry_run).await?;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::ExportBackupKey => {
+                log::info!("ExportBackupKey");
+                let key = crate::backup_crypto::export_key().await?;
+                let mut hm = HashMap::new();
+                hm.insert("key", key);
+                Some(serde_json::to_string(&hm)?)
+            }
+            SwarmCmd::ListBackups(req) => {
+                let res = crate::backup_catalog::list_backups(&req.node).await?;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::BackupNow(req) => {
+                log::info!("BackupNow -> {:?}", &req.nodes);
+                crate::backup_catalog::backup_now(req.nodes.clone())?;
+                Some(serde_json::to_string(&req.nodes)?)
+            }
+            SwarmCmd::RestoreBackup(req) => {
+                log::info!("RestoreBackup -> {} {}", &req.node, &req.snapshot);
+                // progress and the result go out on the event stream
+                crate::backup_catalog::restore_now(docker.clone(), req.node.clone(), req.snapshot.clone()).await?;
+                Some(serde_json::to_string(&req)?)
+            }
+            SwarmCmd::UpdateBackupRetention(retention) => {
+                log::info!("UpdateBackupRetention -> {:?}", &retention);
+                retention.validate()?;
+                config::stack_write(proj, |s| s.backup_retention = Some(retention.clone())).await;
+                Some(serde_json::to_string(&retention)?)
+            }
+            SwarmCmd::PruneBackups(req) => {
+                log::info!("PruneBackups -> dry_run {}", req.dry_run);
+                let retention = config::stack_read(|s| s.backup_retention.clone())
+                    .await
+                    .context("no backup retention policy set")?;
+                let store = crate::backup_store::backup_store().await?;
+                let res = crate::backup_retention::prune_backups(store.as_ref(), &retention, req.dry_run).await?;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::ExportSwarm(req) => {
+                log::info!("ExportSwarm -> {:?}", &req.nodes);
+                crate::swarm_bundle::export_now(docker.clone(), req.nodes.clone())?;
+                Some(serde_json::to_string(&req)?)
+            }
+            SwarmCmd::GetLiquidityPolicies => {
+                let policies =
+                    config::stack_read(|s| s.liquidity.clone().unwrap_or_default()).await;
+                Some(serde_json::to_string(&policies)?)
+            }
+            SwarmCmd::UpdateLiquidityPolicy(req) => {
+                log::info!("UpdateLiquidityPolicy -> {} {:?}", &req.node, &req.policy);
+                crate::liquidity::update_policy(proj, &req).await?;
+                Some(serde_json::to_string(&req)?)
+            }
+            SwarmCmd::EvaluateLiquidity(req) => {
+                log::info!("EvaluateLiquidity -> {} dry_run {}", &req.node, req.dry_run);
+                if req.dry_run {
+                    let res = crate::liquidity::evaluate(&req.node, true).await?;
+                    Some(serde_json::to_string(&res)?)
+                } else {
+                    // opens run in the background, the decisions get recorded
+                    crate::liquidity::evaluate_now(req.node.clone()).await?;
+                    Some(serde_json::to_string(&req)?)
+                }
+            }
+            SwarmCmd::ListLiquidityDecisions(req) => {
+                let res = crate::liquidity::list_decisions(req.node.clone(), req.limit).await;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::GetRebalancePolicies => {
+                let policies =
+                    config::stack_read(|s| s.rebalance.clone().unwrap_or_default()).await;
+                Some(serde_json::to_string(&policies)?)
+            }
+            SwarmCmd::UpdateRebalancePolicy(req) => {
+                log::info!("UpdateRebalancePolicy -> {} {:?}", &req.node, &req.policy);
+                crate::rebalance::update_policy(proj, &req).await?;
+                Some(serde_json::to_string(&req)?)
+            }
+            SwarmCmd::ListRebalances(req) => {
+                let res = crate::rebalance::list_records(req.node.clone(), req.limit).await;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::GetFeeSchedules => {
+                let schedules =
+                    config::stack_read(|s| s.fee_schedules.clone().unwrap_or_default()).await;
+                Some(serde_json::to_string(&schedules)?)
+            }
+            SwarmCmd::UpdateFeeSchedule(req) => {
+                log::info!("UpdateFeeSchedule -> {} {:?}", &req.node, &req.schedule);
+                crate::fee_schedule::update_schedule(proj, &req).await?;
+                Some(serde_json::to_string(&req)?)
+            }
+            SwarmCmd::ListFeeChanges(req) => {
+                let res = crate::fee_schedule::list_changes(req.node.clone(), req.limit).await;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::GetWebhooks => {
+                let hooks = crate::webhooks::list_webhooks().await;
+                Some(serde_json::to_string(&hooks)?)
+            }
+            SwarmCmd::UpdateWebhook(req) => {
+                log::info!("UpdateWebhook -> {}", &req.name);
+                // the only place a generated secret is shown
+                let saved = crate::webhooks::update_webhook(proj, &req).await?;
+                Some(serde_json::to_string(&saved)?)
+            }
+            SwarmCmd::ListWebhookDeliveries(req) => {
+                let res = crate::webhooks::list_deliveries(req.webhook.clone(), req.limit).await;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::GetLightningAddresses => {
+                let addrs =
+                    config::stack_read(|s| s.lightning_addresses.clone().unwrap_or_default()).await;
+                Some(serde_json::to_string(&addrs)?)
+            }
+            SwarmCmd::UpdateLightningAddress(req) => {
+                log::info!("UpdateLightningAddress -> {} {:?}", &req.username, &req.address);
+                crate::lnurl::update_address(proj, &req).await?;
+                Some(serde_json::to_string(&req)?)
+            }
+            SwarmCmd::ListLightningAddressInvoices(req) => {
+                let res = crate::lnurl::list_invoices(req.username.clone(), req.limit).await;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::GetFeeEstimates => {
+                let ests = crate::onchain::fee_estimates().await?;
+                Some(serde_json::to_string(&ests)?)
+            }
+            SwarmCmd::ListOnchainSpends(req) => {
+                let res = crate::onchain::list_spends(req.node.clone(), req.limit).await;
+                Some(serde_json::to_string(&res)?)
+            }
             SwarmCmd::AddNode(node) => {
                 log::info!("AddNode -> {:?}", node);
                 // add a node via docker
@@ -168,6 +335,11 @@ pub async fn handle(
                     for node in s.nodes.iter_mut() {
                         if node.name() == un.id {
                             let _ = node.set_version(&un.version);
+                            if let (Some(on), Node::Internal(Image::Cln(cln))) =
+                                (un.experimental_offers, &mut *node)
+                            {
+                                cln.set_experimental_offers(on);
+                            }
                         }
                     }
                 }).await;
@@ -188,6 +360,34 @@ pub async fn handle(
                 .await;
                 Some(serde_json::to_string(&logs)?)
             }
+            SwarmCmd::SearchLogs(req) => {
+                log::info!("SearchLogs -> {:?}", &req);
+                let res = crate::log_search::search_logs(docker, req).await?;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::QueryQuickwitLogs(req) => {
+                log::info!("QueryQuickwitLogs -> {:?}", &req);
+                let res = crate::quickwit_logs::query_logs(req).await?;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::GetQuickwitErrorCounts(req) => {
+                let res = crate::quickwit_logs::error_counts(req).await?;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::ListSavedLogQueries => {
+                let res = config::stack_read(|s| s.saved_log_queries.clone().unwrap_or_default()).await;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::SaveLogQuery(sq) => {
+                log::info!("SaveLogQuery -> {}", &sq.name);
+                let res = crate::quickwit_logs::save_query(proj, sq).await?;
+                Some(serde_json::to_string(&res)?)
+            }
+            SwarmCmd::DeleteSavedLogQuery(name) => {
+                log::info!("DeleteSavedLogQuery -> {}", &name);
+                let res = crate::quickwit_logs::delete_query(proj, &name).await?;
+                Some(serde_json::to_string(&res)?)
+            }
             SwarmCmd::ListVersions(req) => {
                 #[derive(Serialize, Deserialize, Debug, Clone)]
                 struct ListVersionsResult {
@@ -292,6 +492,13 @@ pub async fn handle(
                 println!("GetStatistics Called");
                 Some(serde_json::to_string(&containers)?)
             }
+            SwarmCmd::GetStatisticsHistory(req) => {
+                log::info!("GetStatisticsHistory {:?}", &req);
+                let conn = crate::stats_history::open(proj)?;
+                let points =
+                    crate::stats_history::query(&conn, &req, crate::stats_history::now_secs())?;
+                Some(serde_json::to_string(&points)?)
+            }
             SwarmCmd::AddBoltwallAdminPubkey(admin) => {
                 log::info!("AddBoltwallAdminPubkey ->pubkey {}, name {:?}", admin.pubkey, admin.name);
                 let boltwall = config::stack_read(|s| find_boltwall(&s.nodes)).await?;
@@ -793,7 +1000,7 @@ pub async fn handle(
                     Some(serde_json::to_string(&invoice)?)
                 }
                 LndCmd::ListPayments => {
-                    let payments = client.lock().await.list_payments().await?;
+                    let payments = client.lock().await.list_payments(false).await?;
                     Some(serde_json::to_string(&payments)?)
                 }
                 LndCmd::ListInvoices => {
@@ -804,6 +1011,15 @@ pub async fn handle(
                     let pending_channel_list = client.lock().await.list_pending_channels().await?;
                     Some(serde_json::to_string(&pending_channel_list.pending_open_channels)?)
                 }
+                LndCmd::CloseChannel(i) => {
+                    let closed = client.close_channel(&i.id, Some(i.destination)).await?;
+                    Some(serde_json::to_string(&closed)?)
+                }
+                LndCmd::ListFeePolicies => Some(serde_json::to_string(&client.list_fee_policies().await?)?),
+                LndCmd::SetFeePolicy(f) => {
+                    let change = crate::fee_schedule::set_fees(client.as_ref(), tag, f).await?;
+                    Some(serde_json::to_string(&change)?)
+                }
             }
         }
         Cmd::Cln(c) => {
@@ -863,7 +1079,7 @@ pub async fn handle(
                     Some(serde_json::to_string(&paid)?)
                 }
                 ClnCmd::CloseChannel(i) => {
-                    let closed = client.close(&i.id, &i.destination).await?;
+                    let closed = client.close(&i.id, Some(&i.destination)).await?;
                     let mut hm = HashMap::new();
                     hm.insert("type", closed.item_type.to_string());
                     hm.insert("txid", hex::encode(closed.txid()));
@@ -880,6 +1096,46 @@ pub async fn handle(
                         Some(serde_json::to_string(&invoices)?)
                     }
                 },
+                ClnCmd::ListPendingChannels => {
+                    let chans = LightningNode::list_channels(&client).await?;
+                    let pending: Vec<_> = chans.into_iter().filter(|c| c.state == ChannelState::Pending).collect();
+                    Some(serde_json::to_string(&pending)?)
+                }
+                ClnCmd::Rebalance(r) => {
+                    log::info!("Rebalance -> {} {:?}", tag, r);
+                    // the payment can outlast the command, check ListRebalances
+                    let rec = crate::rebalance::rebalance(client.clone(), tag, r).await?;
+                    Some(serde_json::to_string(&rec)?)
+                }
+                ClnCmd::ListFeePolicies => {
+                    let policies = LightningNode::list_fee_policies(&client).await?;
+                    Some(serde_json::to_string(&policies)?)
+                }
+                ClnCmd::SetFeePolicy(f) => {
+                    let change = crate::fee_schedule::set_fees(&client, tag, f).await?;
+                    Some(serde_json::to_string(&change)?)
+                }
+                ClnCmd::CreateOffer(o) => {
+                    let offer = crate::offers::create_offer(&client, proj, tag, &o).await?;
+                    Some(serde_json::to_string(&offer)?)
+                }
+                ClnCmd::ListOffers => {
+                    let offers = crate::offers::list_offers(&client, tag).await?;
+                    Some(serde_json::to_string(&offers)?)
+                }
+                ClnCmd::DisableOffer(o) => {
+                    let offer = crate::offers::disable_offer(&client, &o).await?;
+                    Some(serde_json::to_string(&offer)?)
+                }
+                ClnCmd::FetchInvoice(o) => {
+                    let invoice = crate::offers::fetch_invoice(&client, &o).await?;
+                    Some(serde_json::to_string(&invoice)?)
+                }
+                ClnCmd::PayOffer(o) => {
+                    log::info!("PayOffer -> {} {}", tag, o.offer);
+                    let paid = crate::offers::pay_offer(&client, &o).await?;
+                    Some(serde_json::to_string(&paid)?)
+                }
                 ClnCmd::ListPays(i) => match i {
                     Some(hash) => {
                         let pays = client.list_pays(hash.payment_hash).await?;
@@ -892,6 +1148,79 @@ pub async fn handle(
                 },
             }
         }
+        Cmd::Lightning(c) => {
+            let client = lightning::lightning_client(tag).await?;
+            match c {
+                LightningCmd::GetInfo => Some(serde_json::to_string(&client.get_info().await?)?),
+                LightningCmd::ListPeers => Some(serde_json::to_string(&client.list_peers().await?)?),
+                LightningCmd::AddPeer(peer) => {
+                    if let Some(alias) = peer.alias.clone() {
+                        config::stack_write(proj, |s| {
+                            add_new_lightning_peer(s, LightningPeer { alias, pubkey: peer.pubkey.clone() });
+                        }).await;
+                    }
+                    client.connect_peer(&peer.pubkey, &peer.host).await?;
+                    Some(serde_json::to_string(&peer.pubkey)?)
+                }
+                LightningCmd::ListChannels => Some(serde_json::to_string(&client.list_channels().await?)?),
+                LightningCmd::GetBalance => Some(serde_json::to_string(&client.get_balance().await?)?),
+                LightningCmd::NewAddress => Some(serde_json::to_string(&client.new_address().await?)?),
+                LightningCmd::AddInvoice(i) => Some(serde_json::to_string(&client.create_invoice(i.amt_msat).await?)?),
+                LightningCmd::ListInvoices(i) => {
+                    let invoices = client.list_invoices(i.and_then(|i| i.payment_hash)).await?;
+                    Some(serde_json::to_string(&invoices)?)
+                }
+                LightningCmd::PayInvoice(i) => Some(serde_json::to_string(&client.pay_invoice(&i.payment_request).await?)?),
+                LightningCmd::ListPayments(i) => {
+                    let payments = client.list_payments(i.and_then(|i| i.payment_hash)).await?;
+                    Some(serde_json::to_string(&payments)?)
+                }
+                LightningCmd::PayKeysend(k) => {
+                    let paid = client.keysend(&k.dest, k.amt_msat, k.tlvs).await?;
+                    Some(serde_json::to_string(&paid)?)
+                }
+                LightningCmd::OpenChannel(o) => {
+                    let opened = client.open_channel(&o.pubkey, o.amount_sat, o.sat_per_vbyte).await?;
+                    Some(serde_json::to_string(&opened)?)
+                }
+                LightningCmd::CloseChannel(c) => {
+                    let closed = client.close_channel(&c.id, c.destination).await?;
+                    Some(serde_json::to_string(&closed)?)
+                }
+                LightningCmd::ListFeePolicies => Some(serde_json::to_string(&client.list_fee_policies().await?)?),
+                LightningCmd::SetFeePolicy(f) => {
+                    let change = crate::fee_schedule::set_fees(client.as_ref(), tag, f).await?;
+                    Some(serde_json::to_string(&change)?)
+                }
+                LightningCmd::ListUtxos => Some(serde_json::to_string(&client.list_utxos().await?)?),
+                LightningCmd::FreezeUtxos(u) => {
+                    let utxos = crate::onchain::freeze_utxos(client.as_ref(), &u.utxos, true).await?;
+                    Some(serde_json::to_string(&utxos)?)
+                }
+                LightningCmd::UnfreezeUtxos(u) => {
+                    let utxos = crate::onchain::freeze_utxos(client.as_ref(), &u.utxos, false).await?;
+                    Some(serde_json::to_string(&utxos)?)
+                }
+                LightningCmd::SendOnchain(s) => {
+                    let pending = crate::onchain::prepare_send(tag, &s, user_id).await?;
+                    Some(serde_json::to_string(&pending)?)
+                }
+                LightningCmd::PublishPsbt(p) => {
+                    let pending = crate::onchain::prepare_publish(tag, &p.psbt, user_id).await?;
+                    Some(serde_json::to_string(&pending)?)
+                }
+                LightningCmd::ConfirmSpend(c) => {
+                    log::info!("ConfirmSpend -> {} {}", tag, c.id);
+                    let rec = crate::onchain::confirm(client.as_ref(), tag, &c.id, user_id).await?;
+                    Some(serde_json::to_string(&rec)?)
+                }
+                LightningCmd::CreatePsbt(s) => {
+                    let psbt = crate::onchain::create_psbt(client.as_ref(), &s).await?;
+                    Some(serde_json::to_string(&psbt)?)
+                }
+                LightningCmd::SignPsbt(p) => Some(serde_json::to_string(&client.sign_psbt(&p.psbt).await?)?),
+            }
+        }
         Cmd::Proxy(c) => {
             let client = config::clients_read(|c| c.proxy.get(tag).cloned()).await.context("no proxy client")?;
             match c {
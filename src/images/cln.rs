@@ -24,6 +24,8 @@ pub struct ClnImage {
     pub seed: Option<String>,
     pub developer: Option<bool>,
     pub rescan: Option<i32>,
+    // for lightningd builds before v24.11, where BOLT12 offers are off by default
+    pub experimental_offers: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -54,6 +56,7 @@ impl ClnImage {
             seed: None,
             developer: None,
             rescan: None,
+            experimental_offers: None,
         }
     }
     pub fn host(&mut self, eh: Option<String>) {
@@ -82,6 +85,9 @@ impl ClnImage {
     pub fn set_rescan(&mut self, rescan: i32) {
         self.rescan = Some(rescan);
     }
+    pub fn set_experimental_offers(&mut self, on: bool) {
+        self.experimental_offers = Some(on);
+    }
     pub fn remove_client(&self, clients: &mut ClientMap) {
         clients.cln.remove(&self.name);
     }
@@ -301,6 +307,9 @@ fn cln(img: &ClnImage, btc: ClnBtcArgs, lss: Option<lss::LssImage>) -> Config<St
     if let Ok(eba) = getenv("ANNOUNCE_ADDRESS") {
         cmd.push(format!("--announce-addr={}", eba));
     }
+    if img.experimental_offers == Some(true) {
+        cmd.push("--experimental-offers".to_string());
+    }
     if let Some(dev) = img.developer {
         if dev {
             cmd.push(format!("--developer"));
@@ -35,6 +35,7 @@ pub mod tome;
 pub mod traefik;
 pub mod tribes;
 pub mod vector;
+pub mod vector_pipeline;
 pub mod whisker;
 pub mod whisper;
 
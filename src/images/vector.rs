@@ -1,4 +1,5 @@
 use super::traefik::traefik_labels;
+use super::vector_pipeline::{self, VectorSink, VectorTransform};
 use super::*;
 use crate::config::Node;
 use crate::dock::upload_to_container;
@@ -25,6 +26,12 @@ pub struct VectorImage {
     /// Auth token for the remote Vector instance (will be hashed with sha256_hex_24)
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub forward_token: Option<String>,
+    /// Extra outputs, fed from the end of the transform chain
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub sinks: Option<Vec<VectorSink>>,
+    /// Applied in order before every sink, the built-in one included
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub transforms: Option<Vec<VectorTransform>>,
 }
 
 impl VectorImage {
@@ -38,6 +45,8 @@ impl VectorImage {
             auth_token: secrets::random_word(32),
             forward_url: None,
             forward_token: None,
+            sinks: None,
+            transforms: None,
         }
     }
     pub fn host(&mut self, eh: Option<String>) {
@@ -53,11 +62,16 @@ impl VectorImage {
         self.forward_token = Some(token.to_string());
     }
     pub async fn pre_startup(&self, docker: &Docker, nodes: &Vec<Node>) -> Result<()> {
+        let sinks = self.sinks.clone().unwrap_or_default();
+        let transforms = self.transforms.clone().unwrap_or_default();
+        vector_pipeline::validate(&sinks, &transforms)?;
+
         let config = if let (Some(url), Some(token)) = (&self.forward_url, &self.forward_token) {
             // Forwarder mode: read Docker logs and ship to remote Vector
             let auth_token = secrets::sha256_hex_24(token);
             log::info!("=> vector forwarder mode -> {}", url);
-            vector_forwarder_toml(url, &auth_token)
+            let (custom, last) = vector_pipeline::render("normalize", &sinks, &transforms);
+            vector_forwarder_toml(url, &auth_token, &last) + &custom
         } else {
             // Receiver mode: accept logs via HTTP and send to Quickwit
             let li = LinkedImages::from_nodes(self.links.clone(), nodes);
@@ -77,8 +91,11 @@ impl VectorImage {
 
             let auth_token = secrets::sha256_hex_24(&base_token);
             log::info!("=> vector auth token (hashed): {}", auth_token);
-            vector_toml(&self.http_port, &quickwit_host, &auth_token)
+            let (custom, last) =
+                vector_pipeline::render("aggregate_by_request", &sinks, &transforms);
+            vector_toml(&self.http_port, &quickwit_host, &auth_token, &last) + &custom
         };
+        vector_pipeline::check_toml(&config)?;
 
         log::info!("=> uploading vector.toml config...");
         upload_to_container(
@@ -145,7 +162,7 @@ fn vector(node: &VectorImage) -> Config<String> {
     c
 }
 
-fn vector_toml(http_port: &str, quickwit_host: &str, auth_token: &str) -> String {
+fn vector_toml(http_port: &str, quickwit_host: &str, auth_token: &str, sink_input: &str) -> String {
     format!(
         r#"# Vector configuration for log ingestion
 # Receives logs via HTTP and forwards to Quickwit
@@ -251,7 +268,7 @@ merge_strategies.type = "discard"
 
 [sinks.quickwit]
 type = "http"
-inputs = ["aggregate_by_request"]
+inputs = ["{sink_input}"]
 uri = "http://{quickwit_host}:7280/api/v1/logs/ingest"
 encoding.codec = "json"
 framing.method = "newline_delimited"
@@ -259,7 +276,7 @@ framing.method = "newline_delimited"
     )
 }
 
-fn vector_forwarder_toml(forward_url: &str, auth_token: &str) -> String {
+fn vector_forwarder_toml(forward_url: &str, auth_token: &str, sink_input: &str) -> String {
     format!(
         r#"# Vector forwarder configuration
 # Reads Docker container logs and ships them to a remote Vector instance
@@ -301,7 +318,7 @@ del(.container_created_at)
 
 [sinks.remote_vector]
 type = "http"
-inputs = ["normalize"]
+inputs = ["{sink_input}"]
 uri = "{forward_url}/logs"
 encoding.codec = "json"
 framing.method = "newline_delimited"
@@ -310,3 +327,28 @@ headers.Authorization = "Bearer {auth_token}"
 "#
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::vector_pipeline::RedactPattern;
+
+    #[test]
+    fn test_generated_configs_wire_up() {
+        let transforms = vec![VectorTransform::Redact {
+            patterns: vec![RedactPattern::Invoice],
+        }];
+        let sinks = vec![VectorSink::File {
+            name: "archive".to_string(),
+            path: "/etc/vector/archive/%Y-%m-%d.log".to_string(),
+        }];
+        let (custom, last) = vector_pipeline::render("aggregate_by_request", &sinks, &transforms);
+        let receiver = vector_toml("9000", "quickwit.sphinx", "tok", &last) + &custom;
+        vector_pipeline::check_toml(&receiver).unwrap();
+        assert!(receiver.contains("inputs = [\"custom_transform_0\"]\nuri = \"http://quickwit.sphinx"));
+
+        let (custom, last) = vector_pipeline::render("normalize", &sinks, &transforms);
+        let forwarder = vector_forwarder_toml("https://vector.example.com", "tok", &last) + &custom;
+        vector_pipeline::check_toml(&forwarder).unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+use crate::utils::domain;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Extra outputs for the Vector node, next to the built-in quickwit or
+/// forwarder sink.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VectorSink {
+    // archive to a file; strftime patterns are expanded, e.g.
+    // "/etc/vector/archive/%Y-%m-%d.log" (on the node's volume)
+    File {
+        name: String,
+        path: String,
+    },
+    // AWS S3 or any S3-compatible bucket when endpoint is set
+    S3 {
+        name: String,
+        bucket: String,
+        region: String,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        endpoint: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        key_prefix: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        access_key_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        secret_access_key: Option<String>,
+    },
+    // POSTs newline delimited JSON
+    Http {
+        name: String,
+        uri: String,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        auth_token: Option<String>,
+    },
+    // prints events to the vector container's stdout, for testing a pipeline
+    Console {
+        name: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VectorTransform {
+    // drop logs from these containers, "jarvis" or "jarvis.sphinx"
+    DropContainers { containers: Vec<String> },
+    // keep 1 in every rate events
+    Sample { rate: u32 },
+    // merge a JSON encoded field (default "message") into the event
+    ParseJson {
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        field: Option<String>,
+    },
+    // replace matches in the message with [REDACTED]
+    Redact { patterns: Vec<RedactPattern> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactPattern {
+    Macaroon,
+    Invoice,
+    Seed,
+    Password,
+    // any regex
+    Custom(String),
+}
+
+impl RedactPattern {
+    // Rust regex syntax, which is also what VRL uses
+    pub fn regex(&self) -> String {
+        match self {
+            // hex LND macaroons start with 0201, base64 ones with AgE
+            RedactPattern::Macaroon => r"\b0201[0-9a-fA-F]{40,}\b|\bAgE[A-Za-z0-9+/_=-]{40,}".to_string(),
+            RedactPattern::Invoice => r"(?i)\bln(?:bcrt|bc|tbs|tb)[0-9a-z]{50,}\b".to_string(),
+            RedactPattern::Seed => {
+                r#"(?i)\b(?:mnemonic|seed)\b["\x27]?\s*[:=]\s*["\x27]?[a-z]+(?:[\s,]+[a-z]+){11,23}"#.to_string()
+            }
+            RedactPattern::Password => {
+                r#"(?i)\b(?:password|passwd|pass|secret|token)\b["\x27]?\s*[:=]\s*["\x27]?[^\s"\x27,}]+"#.to_string()
+            }
+            RedactPattern::Custom(r) => r.clone(),
+        }
+    }
+}
+
+impl VectorSink {
+    pub fn name(&self) -> &str {
+        match self {
+            VectorSink::File { name, .. } => name,
+            VectorSink::S3 { name, .. } => name,
+            VectorSink::Http { name, .. } => name,
+            VectorSink::Console { name } => name,
+        }
+    }
+
+    pub fn remove_secrets(&self) -> Self {
+        match self.clone() {
+            VectorSink::S3 {
+                name,
+                bucket,
+                region,
+                endpoint,
+                key_prefix,
+                access_key_id,
+                ..
+            } => VectorSink::S3 {
+                name,
+                bucket,
+                region,
+                endpoint,
+                key_prefix,
+                access_key_id,
+                secret_access_key: None,
+            },
+            VectorSink::Http { name, uri, .. } => VectorSink::Http {
+                name,
+                uri,
+                auth_token: None,
+            },
+            s => s,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            VectorSink::File { path, .. } => {
+                if !path.starts_with('/') {
+                    return Err(anyhow!("file sink path must be absolute: {}", path));
+                }
+            }
+            VectorSink::S3 { bucket, region, .. } => {
+                if bucket.is_empty() || region.is_empty() {
+                    return Err(anyhow!("s3 sink needs a bucket and region"));
+                }
+            }
+            VectorSink::Http { uri, .. } => {
+                if !uri.starts_with("http://") && !uri.starts_with("https://") {
+                    return Err(anyhow!("http sink uri must be http(s): {}", uri));
+                }
+            }
+            VectorSink::Console { .. } => (),
+        }
+        Ok(())
+    }
+
+    fn render(&self, input: &str) -> String {
+        let mut t = format!(
+            "[sinks.{}]\ninputs = [{}]\n",
+            sink_id(self.name()),
+            quote(input)
+        );
+        match self {
+            VectorSink::File { path, .. } => {
+                t.push_str("type = \"file\"\n");
+                t.push_str(&format!("path = {}\n", quote(path)));
+                t.push_str("encoding.codec = \"json\"\n");
+            }
+            VectorSink::S3 {
+                bucket,
+                region,
+                endpoint,
+                key_prefix,
+                access_key_id,
+                secret_access_key,
+                ..
+            } => {
+                t.push_str("type = \"aws_s3\"\n");
+                t.push_str(&format!("bucket = {}\n", quote(bucket)));
+                t.push_str(&format!("region = {}\n", quote(region)));
+                if let Some(e) = endpoint {
+                    t.push_str(&format!("endpoint = {}\n", quote(e)));
+                }
+                if let Some(p) = key_prefix {
+                    t.push_str(&format!("key_prefix = {}\n", quote(p)));
+                }
+                t.push_str("compression = \"gzip\"\n");
+                t.push_str("encoding.codec = \"json\"\n");
+                t.push_str("framing.method = \"newline_delimited\"\n");
+                if let (Some(id), Some(secret)) = (access_key_id, secret_access_key) {
+                    t.push_str(&format!("auth.access_key_id = {}\n", quote(id)));
+                    t.push_str(&format!("auth.secret_access_key = {}\n", quote(secret)));
+                }
+            }
+            VectorSink::Http {
+                uri, auth_token, ..
+            } => {
+                t.push_str("type = \"http\"\n");
+                t.push_str(&format!("uri = {}\n", quote(uri)));
+                t.push_str("encoding.codec = \"json\"\n");
+                t.push_str("framing.method = \"newline_delimited\"\n");
+                if let Some(token) = auth_token {
+                    t.push_str(&format!(
+                        "request.headers.Authorization = {}\n",
+                        quote(&format!("Bearer {}", token))
+                    ));
+                }
+            }
+            VectorSink::Console { .. } => {
+                t.push_str("type = \"console\"\n");
+                t.push_str("encoding.codec = \"json\"\n");
+            }
+        }
+        t
+    }
+}
+
+impl VectorTransform {
+    fn validate(&self) -> Result<()> {
+        match self {
+            VectorTransform::DropContainers { containers } => {
+                if containers.is_empty() {
+                    return Err(anyhow!("drop_containers needs at least one container"));
+                }
+            }
+            VectorTransform::Sample { rate } => {
+                if *rate == 0 {
+                    return Err(anyhow!("sample rate must be at least 1"));
+                }
+            }
+            VectorTransform::ParseJson { field } => {
+                if let Some(f) = field {
+                    if !is_ident(f) {
+                        return Err(anyhow!("bad parse_json field {}", f));
+                    }
+                }
+            }
+            VectorTransform::Redact { patterns } => {
+                if patterns.is_empty() {
+                    return Err(anyhow!("redact needs at least one pattern"));
+                }
+                for p in patterns {
+                    let r = p.regex();
+                    regex::Regex::new(&r).map_err(|e| anyhow!("bad redact pattern {}: {}", r, e))?;
+                    // the VRL below sits in a toml ''' string and an r'' literal
+                    if r.contains('\'') {
+                        return Err(anyhow!("redact pattern can't contain ': use \\x27"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn render(&self, id: &str, input: &str) -> String {
+        let head = format!("[transforms.{}]\ninputs = [{}]\n", id, quote(input));
+        match self {
+            VectorTransform::DropContainers { containers } => {
+                let mut names: Vec<String> = Vec::new();
+                for c in containers {
+                    names.push(c.clone());
+                    if !c.ends_with(".sphinx") {
+                        names.push(domain(c));
+                    }
+                }
+                let list = names.iter().map(|n| quote(n)).collect::<Vec<_>>().join(", ");
+                format!(
+                    "{}type = \"filter\"\ncondition = '''\n!includes([{}], string(.container_name) ?? \"\")\n'''\n",
+                    head, list
+                )
+            }
+            VectorTransform::Sample { rate } => {
+                format!("{}type = \"sample\"\nrate = {}\n", head, rate)
+            }
+            VectorTransform::ParseJson { field } => {
+                let f = field.clone().unwrap_or("message".to_string());
+                format!(
+                    "{}type = \"remap\"\nsource = '''\nparsed, err = parse_json(string(.{f}) ?? \"\")\nif err == null && is_object(parsed) {{\n  . = merge(., object!(parsed))\n}}\n'''\n",
+                    head,
+                    f = f
+                )
+            }
+            VectorTransform::Redact { patterns } => {
+                let mut src = String::from("if is_string(.message) {\n");
+                for p in patterns {
+                    src.push_str(&format!(
+                        "  .message = replace(string!(.message), r'{}', \"[REDACTED]\")\n",
+                        p.regex()
+                    ));
+                }
+                src.push_str("}\n");
+                format!("{}type = \"remap\"\nsource = '''\n{}'''\n", head, src)
+            }
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    // a JSON string is a valid TOML basic string
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+fn is_ident(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn sink_id(name: &str) -> String {
+    format!("custom_{}", name)
+}
+
+/// Check names and settings before anything is rendered.
+pub fn validate(sinks: &[VectorSink], transforms: &[VectorTransform]) -> Result<()> {
+    let mut names = HashSet::new();
+    for s in sinks {
+        if !is_ident(s.name()) {
+            return Err(anyhow!("sink name must be letters, digits or _: {}", s.name()));
+        }
+        if !names.insert(s.name().to_string()) {
+            return Err(anyhow!("duplicate sink name {}", s.name()));
+        }
+        s.validate()?;
+    }
+    for t in transforms {
+        t.validate()?;
+    }
+    Ok(())
+}
+
+/// Chain the transforms after `input` and attach the sinks to the end of
+/// the chain. Returns the TOML to append and the id of the last stage, which
+/// the built-in sink should read from too.
+pub fn render(input: &str, sinks: &[VectorSink], transforms: &[VectorTransform]) -> (String, String) {
+    let mut out = String::new();
+    let mut last = input.to_string();
+    for (i, t) in transforms.iter().enumerate() {
+        let id = format!("custom_transform_{}", i);
+        out.push('\n');
+        out.push_str(&t.render(&id, &last));
+        last = id;
+    }
+    for s in sinks {
+        out.push('\n');
+        out.push_str(&s.render(&last));
+    }
+    (out, last)
+}
+
+/// The generated config must at least be valid TOML with the stages wired up.
+pub fn check_toml(config: &str) -> Result<()> {
+    let parsed: toml::Table = config
+        .parse()
+        .map_err(|e| anyhow!("generated vector.toml is invalid: {}", e))?;
+    let mut ids: HashSet<String> = HashSet::new();
+    for section in ["sources", "transforms"] {
+        if let Some(t) = parsed.get(section).and_then(|s| s.as_table()) {
+            ids.extend(t.keys().cloned());
+        }
+    }
+    for section in ["transforms", "sinks"] {
+        let table = match parsed.get(section).and_then(|s| s.as_table()) {
+            Some(t) => t,
+            None => continue,
+        };
+        for (id, stage) in table.iter() {
+            let inputs = stage.get("inputs").and_then(|i| i.as_array());
+            for input in inputs.into_iter().flatten() {
+                let input = input.as_str().unwrap_or_default();
+                if !ids.contains(input) {
+                    return Err(anyhow!("{} reads from unknown stage {}", id, input));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_chain() {
+        let transforms = vec![
+            VectorTransform::DropContainers {
+                containers: vec!["traefik".to_string()],
+            },
+            VectorTransform::Redact {
+                patterns: vec![RedactPattern::Macaroon, RedactPattern::Password],
+            },
+        ];
+        let sinks = vec![VectorSink::Console {
+            name: "debug".to_string(),
+        }];
+        validate(&sinks, &transforms).unwrap();
+        let (toml, last) = render("normalize", &sinks, &transforms);
+        assert_eq!(last, "custom_transform_1");
+        let config = format!("[sources.normalize]\ntype = \"stdin\"\n{}", toml);
+        check_toml(&config).unwrap();
+        assert!(toml.contains("\"traefik.sphinx\""));
+        assert!(toml.contains("[sinks.custom_debug]\ninputs = [\"custom_transform_1\"]"));
+    }
+
+    #[test]
+    fn test_check_toml_unknown_input() {
+        let config = "[sinks.x]\ninputs = [\"nope\"]\ntype = \"console\"\n";
+        assert!(check_toml(config).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let dup = vec![
+            VectorSink::Console { name: "a".to_string() },
+            VectorSink::Console { name: "a".to_string() },
+        ];
+        assert!(validate(&dup, &[]).is_err());
+        let bad = vec![VectorTransform::Redact {
+            patterns: vec![RedactPattern::Custom("(".to_string())],
+        }];
+        assert!(validate(&[], &bad).is_err());
+    }
+
+    #[test]
+    fn test_redact_patterns() {
+        let re = |p: RedactPattern| regex::Regex::new(&p.regex()).unwrap();
+        assert!(re(RedactPattern::Invoice).is_match(&format!("pay lnbcrt10u1{}", "q".repeat(60))));
+        assert!(re(RedactPattern::Password).is_match("login password=hunter2"));
+        assert!(!re(RedactPattern::Password).is_match("password reset requested"));
+        assert!(re(RedactPattern::Macaroon).is_match(&format!("mac 0201{}", "ab".repeat(30))));
+    }
+}
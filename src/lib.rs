@@ -1,23 +1,42 @@
+pub mod alerts;
 pub mod app_login;
 pub mod auth;
 pub mod auto_restart_cron;
 pub mod backup;
+pub mod backup_catalog;
+pub mod backup_chunks;
+pub mod backup_crypto;
+pub mod backup_hooks;
+pub mod backup_retention;
+pub mod backup_store;
+pub mod backup_verify;
 pub mod builder;
 pub mod cmd;
 pub mod config;
 pub mod conn;
 pub mod cron_jobs;
 pub mod defaults;
+pub mod disk;
 pub mod dock;
 pub mod env;
 pub mod events;
 pub mod fast_service_update;
+pub mod fee_schedule;
 pub mod graphmindset;
 pub mod handler;
 pub mod hermes_auth;
 pub mod images;
+pub mod liquidity;
+pub mod ln_events;
+pub mod lnurl;
+pub mod log_search;
 pub mod logs;
+pub mod metrics;
 pub mod mount_backedup_volume;
+pub mod offers;
+pub mod onchain;
+pub mod quickwit_logs;
+pub mod rebalance;
 pub mod renew_ssl_cert;
 pub mod rocket_utils;
 pub mod routes;
@@ -27,4 +46,7 @@ pub mod secrets;
 pub mod service;
 pub mod setup;
 pub mod sphinxv2;
+pub mod stats_history;
+pub mod swarm_bundle;
 pub mod utils;
+pub mod webhooks;
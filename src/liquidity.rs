@@ -0,0 +1,736 @@
+use crate::builder::find_img;
+use crate::config::{self, LightningPeer};
+use crate::conn::lightning::{lightning_client, Balance, Channel, ChannelState, LightningNode};
+use crate::images::Image;
+use crate::metrics;
+use crate::utils::getenv;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Mutex;
+use tokio_cron_scheduler::{Job, JobScheduler};
+
+const PROJ: &str = "stack";
+const MAX_DECISIONS: usize = 1000;
+const DAY_SECS: i64 = 24 * 60 * 60;
+// confirmation target for the bitcoind fee estimate
+const FEE_TARGET_BLOCKS: u16 = 6;
+
+pub static EVALUATE_LIQUIDITY: AtomicBool = AtomicBool::new(false);
+
+static DECISIONS_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+// one evaluation at a time, so two can't both spend the same budget
+static EVALUATE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TargetPeer {
+    pub pubkey: String,
+    // "host:port", needed unless the node is already connected
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub alias: Option<String>,
+}
+
+/// What the liquidity manager keeps a CLN or LND node at. Spend limits count
+/// channel amounts, the on-chain fees of the opens come on top.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct LiquidityPolicy {
+    // open channels itself, otherwise only propose them
+    #[serde(default)]
+    pub auto_open: bool,
+    #[serde(default)]
+    pub peers: Vec<TargetPeer>,
+    // also target everyone in Stack.lightning_peers
+    #[serde(default = "default_true")]
+    pub use_lightning_peers: bool,
+    // smaller channels don't count towards a target peer
+    pub min_channel_sat: u64,
+    // size of new channels, min_channel_sat if unset
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub channel_sat: Option<u64>,
+    pub max_daily_spend_sat: u64,
+    // shares of the balance over open channels, 0.0 to 1.0
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_outbound_ratio: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_inbound_ratio: Option<f64>,
+    // opens use the bitcoind estimate if unset
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub sat_per_vbyte: Option<u64>,
+    // no opens while fees are above this
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_sat_per_vbyte: Option<u64>,
+    // left in the wallet for closes and fee bumps
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub onchain_reserve_sat: Option<u64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl LiquidityPolicy {
+    pub fn validate(&self) -> Result<()> {
+        if self.min_channel_sat == 0 {
+            return Err(anyhow!("min_channel_sat must be above 0"));
+        }
+        if self.channel_size() > self.max_daily_spend_sat {
+            return Err(anyhow!("max_daily_spend_sat is below the channel size"));
+        }
+        for r in [self.min_outbound_ratio, self.min_inbound_ratio]
+            .iter()
+            .flatten()
+        {
+            if !(0.0..=1.0).contains(r) {
+                return Err(anyhow!("ratios must be between 0.0 and 1.0"));
+            }
+        }
+        if self.min_outbound_ratio.unwrap_or(0.0) + self.min_inbound_ratio.unwrap_or(0.0) > 1.0 {
+            return Err(anyhow!(
+                "outbound and inbound ratios add up to more than 1.0"
+            ));
+        }
+        if let (Some(rate), Some(max)) = (self.sat_per_vbyte, self.max_sat_per_vbyte) {
+            if rate > max {
+                return Err(anyhow!("sat_per_vbyte is above max_sat_per_vbyte"));
+            }
+        }
+        Ok(())
+    }
+
+    fn channel_size(&self) -> u64 {
+        self.channel_sat.unwrap_or(0).max(self.min_channel_sat)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LiquidityAction {
+    // a channel was opened, or would be on a dry run
+    Open,
+    // auto_open is off, an operator should open it
+    Propose,
+    // an open is wanted but a limit stopped it
+    Hold,
+    // inbound is below target, our own opens can't fix that
+    NeedInbound,
+    // within targets, nothing to do
+    Ok,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LiquidityDecision {
+    pub node: String,
+    // unix seconds
+    pub at: i64,
+    pub action: LiquidityAction,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub peer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub alias: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub amount_sat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub sat_per_vbyte: Option<u64>,
+    pub reason: String,
+    // funding txid of an open
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub txid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateLiquidityPolicyRequest {
+    pub node: String,
+    // none removes the node's policy
+    pub policy: Option<LiquidityPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EvaluateLiquidityRequest {
+    pub node: String,
+    // report what would happen without opening anything
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListLiquidityDecisionsRequest {
+    pub node: Option<String>,
+    // newest first, 100 by default
+    pub limit: Option<usize>,
+}
+
+/// Policy peers first, then the address book, without ourselves.
+pub fn targets(
+    policy: &LiquidityPolicy,
+    book: &[LightningPeer],
+    own_pubkey: &str,
+) -> Vec<TargetPeer> {
+    let mut ts: Vec<TargetPeer> = policy.peers.clone();
+    if policy.use_lightning_peers {
+        ts.extend(book.iter().map(|p| TargetPeer {
+            pubkey: p.pubkey.clone(),
+            host: None,
+            alias: Some(p.alias.clone()),
+        }));
+    }
+    let mut out: Vec<TargetPeer> = Vec::new();
+    for t in ts.into_iter() {
+        if t.pubkey != own_pubkey && !out.iter().any(|o| o.pubkey == t.pubkey) {
+            out.push(t);
+        }
+    }
+    out
+}
+
+fn pct(r: f64) -> String {
+    format!("{:.0}%", r * 100.0)
+}
+
+/// A node as the liquidity manager sees it.
+#[derive(Debug, Clone, Default)]
+pub struct NodeSnapshot {
+    pub channels: Vec<Channel>,
+    pub balance: Balance,
+    // channel amounts opened in the last 24h
+    pub spent_today_sat: u64,
+    // sat/vbyte from bitcoind
+    pub fee_rate: Option<u64>,
+}
+
+/// Decide what `node` should do about its channels. Pending channels count,
+/// so a peer isn't opened to again while the first open confirms.
+pub fn plan(
+    node: &str,
+    policy: &LiquidityPolicy,
+    targets: &[TargetPeer],
+    snap: &NodeSnapshot,
+    now: i64,
+) -> Vec<LiquidityDecision> {
+    let decision = |action, peer: Option<&TargetPeer>, reason: String| LiquidityDecision {
+        node: node.to_string(),
+        at: now,
+        action,
+        peer: peer.map(|p| p.pubkey.clone()),
+        alias: peer.and_then(|p| p.alias.clone()),
+        amount_sat: None,
+        sat_per_vbyte: None,
+        reason,
+        txid: None,
+        error: None,
+    };
+    let live: Vec<&Channel> = snap
+        .channels
+        .iter()
+        .filter(|c| c.state != ChannelState::Closing)
+        .collect();
+    let local: u64 = live.iter().map(|c| c.local_msat).sum();
+    let remote: u64 = live.iter().map(|c| c.remote_msat).sum();
+    let total = local + remote;
+
+    let mut out = Vec::new();
+    if let Some(min) = policy.min_inbound_ratio {
+        let inbound = if total > 0 {
+            remote as f64 / total as f64
+        } else {
+            0.0
+        };
+        if inbound < min {
+            let reason = format!("inbound {} is below the {} target", pct(inbound), pct(min));
+            out.push(decision(LiquidityAction::NeedInbound, None, reason));
+        }
+    }
+
+    let missing: Vec<&TargetPeer> = targets
+        .iter()
+        .filter(|t| {
+            !live
+                .iter()
+                .any(|c| c.peer == t.pubkey && c.capacity_sat >= policy.min_channel_sat)
+        })
+        .collect();
+    let outbound_low = match policy.min_outbound_ratio {
+        Some(min) if total == 0 => min > 0.0,
+        Some(min) => (local as f64 / total as f64) < min,
+        None => false,
+    };
+    if missing.is_empty() {
+        if outbound_low {
+            let reason = "outbound is below target and every target peer has a channel";
+            out.push(decision(LiquidityAction::Hold, None, reason.to_string()));
+        } else if out.is_empty() {
+            out.push(decision(
+                LiquidityAction::Ok,
+                None,
+                "within targets".to_string(),
+            ));
+        }
+        return out;
+    }
+
+    let size = policy.channel_size();
+    let rate = policy.sat_per_vbyte.or(snap.fee_rate);
+    let too_expensive = match (rate, policy.max_sat_per_vbyte) {
+        (Some(r), Some(max)) if r > max => {
+            Some(format!("fee rate {} sat/vB is above the {} limit", r, max))
+        }
+        _ => None,
+    };
+    let mut budget = policy
+        .max_daily_spend_sat
+        .saturating_sub(snap.spent_today_sat);
+    let mut wallet = snap
+        .balance
+        .onchain_confirmed_sat
+        .saturating_sub(policy.onchain_reserve_sat.unwrap_or(0));
+    for t in missing.into_iter() {
+        let (action, reason) = if let Some(r) = &too_expensive {
+            (LiquidityAction::Hold, r.clone())
+        } else if size > budget {
+            let r = format!(
+                "{} sat is over the daily limit, {} sat left today",
+                size, budget
+            );
+            (LiquidityAction::Hold, r)
+        } else if size > wallet {
+            let r = format!("{} sat needed, {} sat spendable on-chain", size, wallet);
+            (LiquidityAction::Hold, r)
+        } else {
+            budget -= size;
+            wallet -= size;
+            let action = if policy.auto_open {
+                LiquidityAction::Open
+            } else {
+                LiquidityAction::Propose
+            };
+            (action, "no channel to target peer".to_string())
+        };
+        let mut d = decision(action, Some(t), reason);
+        d.amount_sat = Some(size);
+        d.sat_per_vbyte = rate.or(policy.max_sat_per_vbyte);
+        out.push(d);
+    }
+    out
+}
+
+/// Channel amounts opened by `node` since `since`. Opens without a txid
+/// yet count too, they're recorded before the open to reserve the budget.
+pub fn spent_since(decisions: &[LiquidityDecision], node: &str, since: i64) -> u64 {
+    decisions
+        .iter()
+        .filter(|d| d.node == node && d.at >= since)
+        .filter(|d| d.action == LiquidityAction::Open && d.error.is_none())
+        .filter_map(|d| d.amount_sat)
+        .sum()
+}
+
+fn decisions_path() -> String {
+    format!("vol/{}/liquidity.json", PROJ)
+}
+
+pub async fn load_decisions() -> Vec<LiquidityDecision> {
+    crate::utils::load_json(&decisions_path(), Vec::new()).await
+}
+
+async fn record(new: &[LiquidityDecision]) {
+    let _lock = DECISIONS_LOCK.lock().await;
+    let mut all = load_decisions().await;
+    all.extend_from_slice(new);
+    let skip = all.len().saturating_sub(MAX_DECISIONS);
+    let all: Vec<LiquidityDecision> = all.into_iter().skip(skip).collect();
+    crate::utils::put_json(&decisions_path(), &all).await;
+}
+
+// the txid or error of an open that was recorded before it was made
+async fn settle(d: &LiquidityDecision) {
+    let _lock = DECISIONS_LOCK.lock().await;
+    let mut all = load_decisions().await;
+    let found = all
+        .iter_mut()
+        .rev()
+        .find(|r| r.node == d.node && r.at == d.at && r.peer == d.peer && r.action == d.action);
+    if let Some(r) = found {
+        *r = d.clone();
+        crate::utils::put_json(&decisions_path(), &all).await;
+    }
+}
+
+pub async fn list_decisions(node: Option<String>, limit: Option<usize>) -> Vec<LiquidityDecision> {
+    load_decisions()
+        .await
+        .into_iter()
+        .rev()
+        .filter(|d| node.as_ref().map(|n| *n == d.node).unwrap_or(true))
+        .take(limit.unwrap_or(100))
+        .collect()
+}
+
+/// Plan for `node`, open the channels the plan says to unless `dry_run`,
+/// and record every decision. Dry runs aren't recorded. Decisions are
+/// recorded before the opens, so an open that is interrupted still counts
+/// against the daily limit.
+pub async fn evaluate_node(
+    ln: &dyn LightningNode,
+    node: &str,
+    policy: &LiquidityPolicy,
+    book: &[LightningPeer],
+    fee_rate: Option<u64>,
+    dry_run: bool,
+) -> Result<Vec<LiquidityDecision>> {
+    let _lock = EVALUATE_LOCK.lock().await;
+    let now = Utc::now().timestamp();
+    let info = ln.get_info().await?;
+    let snap = NodeSnapshot {
+        channels: ln.list_channels().await?,
+        balance: ln.get_balance().await?,
+        spent_today_sat: spent_since(&load_decisions().await, node, now - DAY_SECS),
+        fee_rate,
+    };
+    let targets = targets(policy, book, &info.pubkey);
+    let mut decisions = plan(node, policy, &targets, &snap, now);
+    if dry_run {
+        return Ok(decisions);
+    }
+    record(&decisions).await;
+    for d in decisions.iter_mut() {
+        if d.action != LiquidityAction::Open {
+            continue;
+        }
+        let pubkey = d.peer.clone().unwrap_or_default();
+        let host = targets
+            .iter()
+            .find(|t| t.pubkey == pubkey)
+            .and_then(|t| t.host.clone());
+        if let Some(host) = host {
+            // the open can still work if we're already connected
+            if let Err(e) = ln.connect_peer(&pubkey, &host).await {
+                log::warn!("liquidity {}: connect to {} failed: {}", node, host, e);
+            }
+        }
+        match ln
+            .open_channel(&pubkey, d.amount_sat.unwrap_or(0), d.sat_per_vbyte)
+            .await
+        {
+            Ok(opened) => d.txid = Some(opened.funding_txid),
+            Err(e) => d.error = Some(e.to_string()),
+        }
+        settle(d).await;
+    }
+    for d in decisions.iter() {
+        log::info!(
+            "liquidity {}: {:?} {} {}",
+            node,
+            d.action,
+            d.peer.as_deref().unwrap_or("-"),
+            d.error.as_deref().unwrap_or(&d.reason)
+        );
+    }
+    Ok(decisions)
+}
+
+async fn fee_estimate() -> Option<u64> {
+    let btc = config::clients_read(|c| c.bitcoind.values().next().cloned()).await?;
+    match btc.estimate_fee_rate(FEE_TARGET_BLOCKS) {
+        Ok(r) => r,
+        Err(e) => {
+            log::warn!("liquidity: no fee estimate: {}", e);
+            None
+        }
+    }
+}
+
+pub async fn evaluate(node: &str, dry_run: bool) -> Result<Vec<LiquidityDecision>> {
+    let (policy, book) = config::stack_read(|s| {
+        (
+            s.liquidity.as_ref().and_then(|l| l.get(node).cloned()),
+            s.lightning_peers.clone().unwrap_or_default(),
+        )
+    })
+    .await;
+    let policy = policy.context(format!("no liquidity policy for {}", node))?;
+    let ln = lightning_client(node).await?;
+    let fee_rate = fee_estimate().await;
+    evaluate_node(ln.as_ref(), node, &policy, &book, fee_rate, dry_run).await
+}
+
+/// Evaluate `node` in the background, opens can take longer than a command
+/// may. The decisions show up in ListLiquidityDecisions.
+pub async fn evaluate_now(node: String) -> Result<()> {
+    let has_policy = config::stack_read(|s| {
+        s.liquidity
+            .as_ref()
+            .map(|l| l.contains_key(&node))
+            .unwrap_or(false)
+    })
+    .await;
+    if !has_policy {
+        return Err(anyhow!("no liquidity policy for {}", node));
+    }
+    tokio::spawn(async move {
+        if let Err(e) = evaluate(&node, false).await {
+            log::error!("Error evaluating liquidity of {}: {:?}", node, e);
+        }
+    });
+    Ok(())
+}
+
+/// Set or remove the policy of a CLN or LND node in the stack.
+pub async fn update_policy(proj: &str, req: &UpdateLiquidityPolicyRequest) -> Result<()> {
+    if let Some(p) = &req.policy {
+        p.validate()?;
+    }
+    let img = config::stack_read(|s| find_img(&req.node, &s.nodes)).await?;
+    if !matches!(img, Image::Cln(_) | Image::Lnd(_)) {
+        return Err(anyhow!("{} is not a lightning node", req.node));
+    }
+    config::stack_write(proj, |s| {
+        let mut policies: BTreeMap<String, LiquidityPolicy> =
+            s.liquidity.take().unwrap_or_default();
+        match &req.policy {
+            Some(p) => {
+                policies.insert(req.node.clone(), p.clone());
+            }
+            None => {
+                policies.remove(&req.node);
+            }
+        }
+        s.liquidity = (!policies.is_empty()).then_some(policies);
+    })
+    .await;
+    Ok(())
+}
+
+async fn evaluate_all() -> Result<()> {
+    let nodes: Vec<String> = config::stack_read(|s| {
+        s.liquidity
+            .as_ref()
+            .map(|l| l.keys().cloned().collect())
+            .unwrap_or_default()
+    })
+    .await;
+    let mut errs = Vec::new();
+    for node in nodes.iter() {
+        if let Err(e) = evaluate(node, false).await {
+            errs.push(format!("{}: {}", node, e));
+        }
+    }
+    if errs.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(errs.join("\n")))
+}
+
+pub async fn liquidity_cron() -> Result<JobScheduler> {
+    log::info!("=> start liquidity cron");
+    let sched = JobScheduler::new().await?;
+
+    let cron_time = getenv("LIQUIDITY_CRON_TIME").unwrap_or("0 0 * * * *".to_string());
+
+    sched
+        .add(Job::new_async(cron_time.as_str(), |_uuid, _l| {
+            Box::pin(async move {
+                if !EVALUATE_LIQUIDITY.load(Ordering::Relaxed) {
+                    EVALUATE_LIQUIDITY.store(true, Ordering::Relaxed);
+                }
+            })
+        })?)
+        .await?;
+
+    sched.start().await?;
+
+    tokio::spawn(async move {
+        loop {
+            if EVALUATE_LIQUIDITY.load(Ordering::Relaxed) {
+                let res = evaluate_all().await;
+                metrics::record_job("liquidity", res.is_ok());
+                if let Err(e) = res {
+                    log::error!("Error evaluating liquidity: {:?}", e);
+                }
+                EVALUATE_LIQUIDITY.store(false, Ordering::Relaxed);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
+    Ok(sched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(pubkey: &str) -> TargetPeer {
+        TargetPeer {
+            pubkey: pubkey.to_string(),
+            host: None,
+            alias: None,
+        }
+    }
+
+    fn chan(peer: &str, capacity_sat: u64, local_sat: u64, state: ChannelState) -> Channel {
+        Channel {
+            id: format!("{}:0", peer),
+            short_channel_id: None,
+            peer: peer.to_string(),
+            capacity_sat,
+            local_msat: local_sat * 1000,
+            remote_msat: (capacity_sat - local_sat) * 1000,
+            state,
+            private: false,
+        }
+    }
+
+    fn policy() -> LiquidityPolicy {
+        LiquidityPolicy {
+            auto_open: true,
+            min_channel_sat: 100_000,
+            max_daily_spend_sat: 250_000,
+            ..Default::default()
+        }
+    }
+
+    fn snap(
+        channels: &[Channel],
+        onchain_sat: u64,
+        spent_today_sat: u64,
+        fee_rate: Option<u64>,
+    ) -> NodeSnapshot {
+        NodeSnapshot {
+            channels: channels.to_vec(),
+            balance: Balance {
+                onchain_confirmed_sat: onchain_sat,
+                ..Default::default()
+            },
+            spent_today_sat,
+            fee_rate,
+        }
+    }
+
+    #[test]
+    fn test_plan_opens_within_limits() {
+        let targets = vec![peer("a"), peer("b"), peer("c"), peer("d")];
+        let channels = vec![
+            chan("a", 500_000, 250_000, ChannelState::Active),
+            // too small to count
+            chan("b", 50_000, 50_000, ChannelState::Active),
+            chan("c", 100_000, 100_000, ChannelState::Closing),
+        ];
+        let ds = plan(
+            "cln",
+            &policy(),
+            &targets,
+            &snap(&channels, 1_000_000, 50_000, None),
+            1,
+        );
+        let actions: Vec<(Option<&str>, LiquidityAction)> =
+            ds.iter().map(|d| (d.peer.as_deref(), d.action)).collect();
+        // 200k left today, two opens fit
+        assert_eq!(
+            actions,
+            vec![
+                (Some("b"), LiquidityAction::Open),
+                (Some("c"), LiquidityAction::Open),
+                (Some("d"), LiquidityAction::Hold),
+            ]
+        );
+        let ds = plan(
+            "cln",
+            &policy(),
+            &targets,
+            &snap(&channels, 150_000, 0, None),
+            1,
+        );
+        assert_eq!(ds[1].action, LiquidityAction::Hold);
+        let p = LiquidityPolicy {
+            auto_open: false,
+            max_sat_per_vbyte: Some(20),
+            ..policy()
+        };
+        let ds = plan(
+            "cln",
+            &p,
+            &targets[..2],
+            &snap(&channels, 1_000_000, 0, Some(12)),
+            1,
+        );
+        assert_eq!(ds[0].action, LiquidityAction::Propose);
+        assert_eq!(ds[0].sat_per_vbyte, Some(12));
+        let ds = plan(
+            "cln",
+            &p,
+            &targets[..2],
+            &snap(&channels, 1_000_000, 0, Some(40)),
+            1,
+        );
+        assert_eq!(ds[0].action, LiquidityAction::Hold);
+    }
+
+    #[test]
+    fn test_plan_ratios() {
+        let targets = vec![peer("a")];
+        let channels = vec![chan("a", 1_000_000, 900_000, ChannelState::Active)];
+        let p = LiquidityPolicy {
+            min_outbound_ratio: Some(0.3),
+            min_inbound_ratio: Some(0.3),
+            ..policy()
+        };
+        let ds = plan("lnd", &p, &targets, &snap(&channels, 0, 0, None), 1);
+        assert_eq!(ds.len(), 1);
+        assert_eq!(ds[0].action, LiquidityAction::NeedInbound);
+        let channels = vec![chan("a", 1_000_000, 100_000, ChannelState::Pending)];
+        let ds = plan("lnd", &p, &targets, &snap(&channels, 0, 0, None), 1);
+        assert_eq!(ds[0].action, LiquidityAction::Hold);
+        let channels = vec![chan("a", 1_000_000, 500_000, ChannelState::Active)];
+        let ds = plan("lnd", &p, &targets, &snap(&channels, 0, 0, None), 1);
+        assert_eq!(ds[0].action, LiquidityAction::Ok);
+        assert!(p.validate().is_ok());
+        assert!(LiquidityPolicy {
+            min_inbound_ratio: Some(0.8),
+            ..p
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_targets_and_spent() {
+        let book = vec![
+            LightningPeer {
+                alias: "me".to_string(),
+                pubkey: "self".to_string(),
+            },
+            LightningPeer {
+                alias: "a".to_string(),
+                pubkey: "a".to_string(),
+            },
+        ];
+        let p = LiquidityPolicy {
+            peers: vec![peer("a"), peer("b")],
+            use_lightning_peers: true,
+            ..policy()
+        };
+        let ts = targets(&p, &book, "self");
+        assert_eq!(ts, vec![peer("a"), peer("b")]);
+        let open = |at: i64, error: Option<&str>| LiquidityDecision {
+            node: "cln".to_string(),
+            at,
+            action: LiquidityAction::Open,
+            peer: Some("a".to_string()),
+            alias: None,
+            amount_sat: Some(100_000),
+            sat_per_vbyte: None,
+            reason: String::new(),
+            txid: None,
+            error: error.map(|e| e.to_string()),
+        };
+        let ds = vec![open(10, None), open(20, Some("no funds")), open(30, None)];
+        assert_eq!(spent_since(&ds, "cln", 15), 100_000);
+        assert_eq!(spent_since(&ds, "cln", 0), 200_000);
+        assert_eq!(spent_since(&ds, "lnd", 0), 0);
+    }
+}
@@ -0,0 +1,287 @@
+use crate::config;
+use crate::conn::lightning::{lightning_client, Invoice, InvoiceStatus, Payment, PaymentStatus};
+use crate::events::{get_event_tx, EventChan};
+use crate::webhooks;
+use anyhow::Result;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+const PROJ: &str = "stack";
+// how often to look for new lightning clients
+const WATCH_NODES_SECS: u64 = 30;
+const PAYMENT_POLL_SECS: u64 = 10;
+const RETRY_SECS: u64 = 10;
+
+static CURSORS_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LightningEventKind {
+    InvoicePaid,
+    PaymentSucceeded,
+    PaymentFailed,
+}
+
+impl LightningEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LightningEventKind::InvoicePaid => "invoice_paid",
+            LightningEventKind::PaymentSucceeded => "payment_succeeded",
+            LightningEventKind::PaymentFailed => "payment_failed",
+        }
+    }
+}
+
+/// A paid invoice or a finished payment on one of the lightning nodes, the
+/// same for CLN and LND. Goes out on the event stream and to webhooks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LightningEvent {
+    // "{node}:{kind}:{payment_hash}", receivers can dedupe on it
+    pub id: String,
+    pub node: String,
+    pub kind: LightningEventKind,
+    // unix seconds
+    pub at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invoice: Option<Invoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment: Option<Payment>,
+}
+
+impl LightningEvent {
+    pub fn invoice_paid(node: &str, invoice: Invoice, at: i64) -> Self {
+        let kind = LightningEventKind::InvoicePaid;
+        Self {
+            id: format!("{}:{}:{}", node, kind.as_str(), invoice.payment_hash),
+            node: node.to_string(),
+            kind,
+            at,
+            invoice: Some(invoice),
+            payment: None,
+        }
+    }
+
+    pub fn payment(node: &str, payment: Payment, at: i64) -> Option<Self> {
+        let kind = match payment.status {
+            PaymentStatus::Succeeded => LightningEventKind::PaymentSucceeded,
+            PaymentStatus::Failed => LightningEventKind::PaymentFailed,
+            PaymentStatus::Pending => return None,
+        };
+        Some(Self {
+            id: format!("{}:{}:{}", node, kind.as_str(), payment.payment_hash),
+            node: node.to_string(),
+            kind,
+            at,
+            invoice: None,
+            payment: Some(payment),
+        })
+    }
+}
+
+/// Merge the parts and retries of each payment into one, then return events
+/// for the ones that finished since `known` was last updated. The first call
+/// for a node, with `known` still none, only fills it in.
+pub fn payment_events(
+    node: &str,
+    known: &mut Option<HashMap<String, PaymentStatus>>,
+    payments: Vec<Payment>,
+    at: i64,
+) -> Vec<LightningEvent> {
+    let mut merged: BTreeMap<String, Payment> = BTreeMap::new();
+    for p in payments.into_iter() {
+        match merged.get_mut(&p.payment_hash) {
+            Some(m) => {
+                // succeeded beats pending beats failed
+                let rank = |s: PaymentStatus| match s {
+                    PaymentStatus::Succeeded => 2,
+                    PaymentStatus::Pending => 1,
+                    PaymentStatus::Failed => 0,
+                };
+                if rank(p.status) > rank(m.status) {
+                    *m = p;
+                }
+            }
+            None => {
+                merged.insert(p.payment_hash.clone(), p);
+            }
+        }
+    }
+    let seeding = known.is_none();
+    let known = known.get_or_insert_with(HashMap::new);
+    let mut events = Vec::new();
+    for (hash, p) in merged.into_iter() {
+        let prev = known.insert(hash, p.status);
+        if seeding || prev == Some(p.status) {
+            continue;
+        }
+        if let Some(ev) = LightningEvent::payment(node, p, at) {
+            events.push(ev);
+        }
+    }
+    events
+}
+
+// last invoice index seen per node, so a restart picks up where it left off
+fn cursors_path() -> String {
+    format!("vol/{}/ln_event_cursors.json", PROJ)
+}
+
+async fn cursor(node: &str) -> Option<u64> {
+    let cursors: BTreeMap<String, u64> =
+        crate::utils::load_json(&cursors_path(), BTreeMap::new()).await;
+    cursors.get(node).copied()
+}
+
+async fn set_cursor(node: &str, index: u64) {
+    let _lock = CURSORS_LOCK.lock().await;
+    let mut cursors: BTreeMap<String, u64> =
+        crate::utils::load_json(&cursors_path(), BTreeMap::new()).await;
+    cursors.insert(node.to_string(), index);
+    crate::utils::put_json(&cursors_path(), &cursors).await;
+}
+
+async fn publish(event_tx: &Arc<Mutex<EventChan>>, ev: LightningEvent) {
+    log::info!("lightning event {}", ev.id);
+    match serde_json::to_string(&ev) {
+        // no one listening is fine
+        Ok(msg) => {
+            let _ = get_event_tx(event_tx).await.send(msg);
+        }
+        Err(e) => log::error!("can't serialize lightning event: {}", e),
+    }
+    webhooks::deliver(&ev).await;
+}
+
+async fn next_paid_invoice(node: &str, event_tx: &Arc<Mutex<EventChan>>) -> Result<()> {
+    let ln = lightning_client(node).await?;
+    let (invoice, index) = ln.wait_paid_invoice(cursor(node).await).await?;
+    set_cursor(node, index).await;
+    if invoice.status == InvoiceStatus::Paid {
+        let at = invoice
+            .paid_at
+            .map(|p| p as i64)
+            .unwrap_or_else(|| Utc::now().timestamp());
+        let ev = LightningEvent::invoice_paid(node, invoice, at);
+        publish(event_tx, ev).await;
+    }
+    Ok(())
+}
+
+async fn watch_invoices(node: String, event_tx: Arc<Mutex<EventChan>>) {
+    loop {
+        if let Err(e) = next_paid_invoice(&node, &event_tx).await {
+            log::warn!("invoice events {}: {}", node, e);
+            tokio::time::sleep(Duration::from_secs(RETRY_SECS)).await;
+        }
+    }
+}
+
+// neither backend streams payment outcomes for payments it didn't start, so poll
+async fn watch_payments(node: String, event_tx: Arc<Mutex<EventChan>>) {
+    let mut known: Option<HashMap<String, PaymentStatus>> = None;
+    loop {
+        let pays = match lightning_client(&node).await {
+            Ok(ln) => ln.list_payments(None).await,
+            Err(e) => Err(e),
+        };
+        match pays {
+            Ok(pays) => {
+                let now = Utc::now().timestamp();
+                for ev in payment_events(&node, &mut known, pays, now) {
+                    publish(&event_tx, ev).await;
+                }
+            }
+            Err(e) => log::warn!("payment events {}: {}", node, e),
+        }
+        tokio::time::sleep(Duration::from_secs(PAYMENT_POLL_SECS)).await;
+    }
+}
+
+/// Start an invoice subscriber and a payment watcher for each CLN and LND
+/// client, including ones added later. Stops them when a client goes away.
+pub fn watch_lightning_nodes(event_tx: Arc<Mutex<EventChan>>) {
+    log::info!("=> watch lightning events");
+    tokio::spawn(async move {
+        let mut watching: HashMap<String, [JoinHandle<()>; 2]> = HashMap::new();
+        loop {
+            let nodes: HashSet<String> =
+                config::clients_read(|c| c.cln.keys().chain(c.lnd.keys()).cloned().collect()).await;
+            watching.retain(|node, handles| {
+                let keep = nodes.contains(node);
+                if !keep {
+                    handles.iter().for_each(|h| h.abort());
+                }
+                keep
+            });
+            for node in nodes.into_iter() {
+                if let Entry::Vacant(e) = watching.entry(node) {
+                    let node = e.key().clone();
+                    e.insert([
+                        tokio::spawn(watch_invoices(node.clone(), event_tx.clone())),
+                        tokio::spawn(watch_payments(node, event_tx.clone())),
+                    ]);
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(WATCH_NODES_SECS)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pay(hash: &str, status: PaymentStatus) -> Payment {
+        Payment {
+            payment_hash: hash.to_string(),
+            preimage: None,
+            amount_msat: 1000,
+            fee_msat: None,
+            bolt11: None,
+            status,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_payment_events() {
+        let mut known = None;
+        let first = vec![
+            pay("aa", PaymentStatus::Succeeded),
+            pay("bb", PaymentStatus::Pending),
+        ];
+        // the first poll only remembers what's there
+        assert!(payment_events("cln1", &mut known, first, 1).is_empty());
+        assert_eq!(known.as_ref().map(|k| k.len()), Some(2));
+
+        let second = vec![
+            pay("aa", PaymentStatus::Succeeded),
+            // a failed part and a retry that went through
+            pay("bb", PaymentStatus::Failed),
+            pay("bb", PaymentStatus::Succeeded),
+            pay("cc", PaymentStatus::Failed),
+            pay("dd", PaymentStatus::Pending),
+        ];
+        let evs = payment_events("cln1", &mut known, second, 2);
+        let ids: Vec<&str> = evs.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec!["cln1:payment_succeeded:bb", "cln1:payment_failed:cc"]
+        );
+        assert_eq!(evs[0].at, 2);
+
+        // nothing changed
+        let third = vec![
+            pay("bb", PaymentStatus::Succeeded),
+            pay("cc", PaymentStatus::Failed),
+        ];
+        assert!(payment_events("cln1", &mut known, third, 3).is_empty());
+    }
+}
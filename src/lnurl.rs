@@ -0,0 +1,350 @@
+use crate::builder::find_img;
+use crate::config;
+use crate::conn::lightning::lightning_client;
+use crate::images::Image;
+use crate::utils::getenv;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::Mutex;
+
+const PROJ: &str = "stack";
+const MAX_INVOICES: usize = 1000;
+// invoices one address hands out a minute, the callback is public
+const MAX_PER_MINUTE: usize = 20;
+
+static INVOICES_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+// callback times by username, for the rate limit
+static RECENT: Lazy<Mutex<HashMap<String, Vec<i64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A Lightning Address, `username@<swarm host>`, paid into one of the
+/// stack's lightning nodes over LNURL-pay.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LightningAddress {
+    // the CLN or LND node that issues the invoices
+    pub node: String,
+    pub min_sendable_msat: u64,
+    pub max_sendable_msat: u64,
+    // the text/plain metadata wallets show the payer
+    pub description: String,
+    // longest comment a payer can attach, none are taken when 0
+    #[serde(default)]
+    pub comment_allowed: u32,
+}
+
+impl LightningAddress {
+    pub fn validate(&self) -> Result<()> {
+        if self.min_sendable_msat == 0 || self.min_sendable_msat > self.max_sendable_msat {
+            return Err(anyhow!(
+                "min_sendable_msat must be between 1 and max_sendable_msat"
+            ));
+        }
+        if self.description.trim().is_empty() {
+            return Err(anyhow!("description is required"));
+        }
+        Ok(())
+    }
+
+    /// Check a callback's amount and comment against the address.
+    pub fn check_payment(&self, amount_msat: u64, comment: Option<&str>) -> Result<()> {
+        if amount_msat < self.min_sendable_msat || amount_msat > self.max_sendable_msat {
+            return Err(anyhow!(
+                "amount must be between {} and {} msat",
+                self.min_sendable_msat,
+                self.max_sendable_msat
+            ));
+        }
+        let len = comment.map(|c| c.chars().count()).unwrap_or(0);
+        if len > self.comment_allowed as usize {
+            return Err(anyhow!(
+                "comment is limited to {} characters",
+                self.comment_allowed
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The LUD-06 payRequest served at /.well-known/lnurlp/<username>.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PayRequest {
+    pub callback: String,
+    pub min_sendable: u64,
+    pub max_sendable: u64,
+    // json array of [mime, content] pairs, the invoice commits to its sha256
+    pub metadata: String,
+    pub tag: String,
+    #[serde(skip_serializing_if = "is_zero", default)]
+    pub comment_allowed: u32,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressInvoice {
+    pub username: String,
+    pub node: String,
+    pub payment_hash: String,
+    pub amount_msat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    pub at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateLightningAddressRequest {
+    pub username: String,
+    // none removes the address
+    pub address: Option<LightningAddress>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListAddressInvoicesRequest {
+    pub username: Option<String>,
+    // newest first, 100 by default
+    pub limit: Option<usize>,
+}
+
+// LUD-16 usernames
+pub fn check_username(username: &str) -> Result<()> {
+    let ok = !username.is_empty()
+        && username
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c));
+    if !ok {
+        return Err(anyhow!("username can only have a-z, 0-9, -, _ and ."));
+    }
+    Ok(())
+}
+
+/// Where wallets reach the rocket server: the stack host through traefik,
+/// or the local port on a regtest stack without one.
+pub fn base_url(host: Option<&str>) -> String {
+    match host {
+        Some(h) => format!("https://{}", h),
+        None => {
+            let port = getenv("ROCKET_PORT").unwrap_or("8000".to_string());
+            format!("http://localhost:{}", port)
+        }
+    }
+}
+
+pub fn metadata(username: &str, addr: &LightningAddress, base: &str) -> String {
+    let domain = base.split("://").last().unwrap_or(base);
+    let identifier = format!("{}@{}", username, domain);
+    json!([
+        ["text/plain", addr.description],
+        ["text/identifier", identifier]
+    ])
+    .to_string()
+}
+
+pub fn pay_request_for(username: &str, addr: &LightningAddress, base: &str) -> PayRequest {
+    PayRequest {
+        callback: format!("{}/lnurlp/{}/callback", base, username),
+        min_sendable: addr.min_sendable_msat,
+        max_sendable: addr.max_sendable_msat,
+        metadata: metadata(username, addr, base),
+        tag: "payRequest".to_string(),
+        comment_allowed: addr.comment_allowed,
+    }
+}
+
+// LUD-06 errors are a 200 with this body
+pub fn error_json(e: anyhow::Error) -> Value {
+    json!({ "status": "ERROR", "reason": e.to_string() })
+}
+
+async fn find_address(username: &str) -> Result<(LightningAddress, String)> {
+    let username = username.to_lowercase();
+    let (addr, host) = config::stack_read(|s| {
+        let addr = s
+            .lightning_addresses
+            .as_ref()
+            .and_then(|a| a.get(&username).cloned());
+        (addr, s.host.clone())
+    })
+    .await;
+    let addr = addr.context(format!("no lightning address {}", username))?;
+    Ok((addr, base_url(host.as_deref())))
+}
+
+pub async fn pay_request(username: &str) -> Result<PayRequest> {
+    let (addr, base) = find_address(username).await?;
+    Ok(pay_request_for(&username.to_lowercase(), &addr, &base))
+}
+
+/// Count a callback for `username` at `now`, unless it already had `max`
+/// in the last minute.
+pub fn check_rate(
+    recent: &mut HashMap<String, Vec<i64>>,
+    username: &str,
+    now: i64,
+    max: usize,
+) -> Result<()> {
+    let hits = recent.entry(username.to_string()).or_default();
+    hits.retain(|t| *t > now - 60);
+    if hits.len() >= max {
+        return Err(anyhow!("too many requests, try again in a minute"));
+    }
+    hits.push(now);
+    Ok(())
+}
+
+/// The callback: an invoice from the address's node that commits to the
+/// sha256 of the same metadata the payRequest served.
+pub async fn callback(username: &str, amount_msat: u64, comment: Option<&str>) -> Result<String> {
+    let username = username.to_lowercase();
+    let (addr, base) = find_address(&username).await?;
+    addr.check_payment(amount_msat, comment)?;
+    check_rate(
+        &mut *RECENT.lock().await,
+        &username,
+        Utc::now().timestamp(),
+        MAX_PER_MINUTE,
+    )?;
+    let ln = lightning_client(&addr.node).await?;
+    let meta = metadata(&username, &addr, &base);
+    let invoice = ln.create_hashed_invoice(amount_msat, &meta).await?;
+    record(AddressInvoice {
+        username,
+        node: addr.node.clone(),
+        payment_hash: invoice.payment_hash.clone(),
+        amount_msat,
+        comment: comment.filter(|c| !c.is_empty()).map(|c| c.to_string()),
+        at: Utc::now().timestamp(),
+    })
+    .await;
+    Ok(invoice.bolt11)
+}
+
+fn invoices_path() -> String {
+    format!("vol/{}/lnurl_invoices.json", PROJ)
+}
+
+async fn load_invoices() -> Vec<AddressInvoice> {
+    crate::utils::load_json(&invoices_path(), Vec::new()).await
+}
+
+async fn record(inv: AddressInvoice) {
+    let _lock = INVOICES_LOCK.lock().await;
+    let mut all = load_invoices().await;
+    all.push(inv);
+    let skip = all.len().saturating_sub(MAX_INVOICES);
+    let all: Vec<AddressInvoice> = all.into_iter().skip(skip).collect();
+    crate::utils::put_json(&invoices_path(), &all).await;
+}
+
+pub async fn list_invoices(username: Option<String>, limit: Option<usize>) -> Vec<AddressInvoice> {
+    load_invoices()
+        .await
+        .into_iter()
+        .rev()
+        .filter(|i| username.as_ref().map(|u| *u == i.username).unwrap_or(true))
+        .take(limit.unwrap_or(100))
+        .collect()
+}
+
+/// Set or remove a Lightning Address in the stack.
+pub async fn update_address(proj: &str, req: &UpdateLightningAddressRequest) -> Result<()> {
+    check_username(&req.username)?;
+    if let Some(a) = &req.address {
+        a.validate()?;
+        let img = config::stack_read(|s| find_img(&a.node, &s.nodes)).await?;
+        if !matches!(img, Image::Cln(_) | Image::Lnd(_)) {
+            return Err(anyhow!("{} is not a lightning node", a.node));
+        }
+    }
+    config::stack_write(proj, |s| {
+        let mut addrs: BTreeMap<String, LightningAddress> =
+            s.lightning_addresses.take().unwrap_or_default();
+        match &req.address {
+            Some(a) => {
+                addrs.insert(req.username.clone(), a.clone());
+            }
+            None => {
+                addrs.remove(&req.username);
+            }
+        }
+        s.lightning_addresses = (!addrs.is_empty()).then_some(addrs);
+    })
+    .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> LightningAddress {
+        LightningAddress {
+            node: "cln_2".to_string(),
+            min_sendable_msat: 1_000,
+            max_sendable_msat: 1_000_000_000,
+            description: "Sats for \"alice\"".to_string(),
+            comment_allowed: 10,
+        }
+    }
+
+    #[test]
+    fn test_pay_request() {
+        let pr = pay_request_for("alice", &addr(), "https://swarm9.example.org");
+        assert_eq!(
+            pr.callback,
+            "https://swarm9.example.org/lnurlp/alice/callback"
+        );
+        assert_eq!(
+            pr.metadata,
+            r#"[["text/plain","Sats for \"alice\""],["text/identifier","alice@swarm9.example.org"]]"#
+        );
+        let v = serde_json::to_value(&pr).unwrap();
+        assert_eq!(v["tag"], "payRequest");
+        assert_eq!(v["minSendable"], 1_000);
+        assert_eq!(v["maxSendable"], 1_000_000_000);
+        assert_eq!(v["commentAllowed"], 10);
+        assert_eq!(
+            base_url(Some("swarm9.example.org")),
+            "https://swarm9.example.org"
+        );
+    }
+
+    #[test]
+    fn test_checks() {
+        let a = addr();
+        assert!(a.validate().is_ok());
+        assert!(a.check_payment(5_000, Some("thanks")).is_ok());
+        assert!(a.check_payment(999, None).is_err());
+        assert!(a.check_payment(1_000_000_001, None).is_err());
+        assert!(a
+            .check_payment(5_000, Some("this is far too long"))
+            .is_err());
+        let bad = LightningAddress {
+            min_sendable_msat: 2_000_000_000,
+            ..addr()
+        };
+        assert!(bad.validate().is_err());
+        assert!(check_username("alice.b-c_1").is_ok());
+        assert!(check_username("Alice").is_err());
+        assert!(check_username("").is_err());
+        assert!(check_username("a@b").is_err());
+    }
+
+    #[test]
+    fn test_check_rate() {
+        let mut recent = HashMap::new();
+        assert!(check_rate(&mut recent, "alice", 100, 2).is_ok());
+        assert!(check_rate(&mut recent, "alice", 110, 2).is_ok());
+        assert!(check_rate(&mut recent, "alice", 120, 2).is_err());
+        // someone else's limit
+        assert!(check_rate(&mut recent, "bob", 120, 2).is_ok());
+        // the first one is a minute old
+        assert!(check_rate(&mut recent, "alice", 160, 2).is_ok());
+        assert_eq!(recent["alice"], vec![110, 160]);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::utils::container_name;
+use anyhow::{anyhow, Result};
+use bollard::container::{LogOutput, LogsOptions};
+use bollard::Docker;
+use chrono::{DateTime, FixedOffset};
+use futures_util::stream::{self, Stream, StreamExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+const DEFAULT_LIMIT: usize = 500;
+const MAX_LIMIT: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+    #[default]
+    Both,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchLogsRequest {
+    // node names, "jarvis" or "jarvis.sphinx"
+    pub containers: Vec<String>,
+    // rfc3339
+    pub since: Option<String>,
+    pub until: Option<String>,
+    // substring, or a regex if regex is true
+    pub filter: Option<String>,
+    pub regex: Option<bool>,
+    pub stream: Option<LogStream>,
+    // newest lines kept after merging, default 500
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    // rfc3339 with nanoseconds, as docker reports it
+    pub ts: String,
+    pub container: String,
+    pub stream: LogStream,
+    pub line: String,
+}
+
+pub enum Matcher {
+    All,
+    Substring(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn new(filter: &Option<String>, regex: bool) -> Result<Self> {
+        Ok(match filter {
+            None => Matcher::All,
+            Some(f) if f.is_empty() => Matcher::All,
+            Some(f) if regex => Matcher::Regex(Regex::new(f).map_err(|e| anyhow!("bad regex: {}", e))?),
+            Some(f) => Matcher::Substring(f.clone()),
+        })
+    }
+
+    pub fn matches(&self, line: &str) -> bool {
+        match self {
+            Matcher::All => true,
+            Matcher::Substring(s) => line.contains(s.as_str()),
+            Matcher::Regex(r) => r.is_match(line),
+        }
+    }
+}
+
+fn parse_ts(ts: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(ts).ok()
+}
+
+fn to_unix(ts: &Option<String>) -> Result<i64> {
+    match ts {
+        None => Ok(0),
+        Some(t) => parse_ts(t)
+            .map(|d| d.timestamp())
+            .ok_or(anyhow!("bad timestamp {}", t)),
+    }
+}
+
+// with timestamps on, docker prefixes each line with "<rfc3339nano> "
+fn split_ts(msg: &str) -> Option<(&str, &str)> {
+    let (ts, rest) = msg.split_once(' ')?;
+    parse_ts(ts)?;
+    Some((ts, rest))
+}
+
+fn to_entries(container: &str, out: LogOutput, matcher: &Matcher) -> Vec<LogEntry> {
+    let (stream, message) = match out {
+        LogOutput::StdOut { message } => (LogStream::Stdout, message),
+        LogOutput::StdErr { message } => (LogStream::Stderr, message),
+        LogOutput::Console { message } => (LogStream::Stdout, message),
+        LogOutput::StdIn { .. } => return Vec::new(),
+    };
+    let text = String::from_utf8_lossy(&message);
+    let mut entries = Vec::new();
+    for raw in text.lines() {
+        let (ts, line) = match split_ts(raw) {
+            Some(s) => s,
+            None => continue,
+        };
+        let line = line.trim_end();
+        if line.is_empty() || line.contains('\u{FFFD}') || !matcher.matches(line) {
+            continue;
+        }
+        entries.push(LogEntry {
+            ts: ts.to_string(),
+            container: container.to_string(),
+            stream,
+            line: line.to_string(),
+        });
+    }
+    entries
+}
+
+/// Merge per-container results into one timeline, oldest first, keeping
+/// the newest `limit` entries. Ties keep the order the containers were given.
+pub fn merge(per_container: Vec<Vec<LogEntry>>, limit: usize) -> Vec<LogEntry> {
+    let mut all: Vec<(i64, LogEntry)> = per_container
+        .into_iter()
+        .flatten()
+        .map(|e| {
+            let key = parse_ts(&e.ts)
+                .and_then(|d| d.timestamp_nanos_opt())
+                .unwrap_or(0);
+            (key, e)
+        })
+        .collect();
+    all.sort_by_key(|(k, _)| *k);
+    let skip = all.len().saturating_sub(limit);
+    all.into_iter().skip(skip).map(|(_, e)| e).collect()
+}
+
+fn logs_options(
+    stream: LogStream,
+    follow: bool,
+    since: i64,
+    until: i64,
+    tail: Option<usize>,
+) -> LogsOptions<String> {
+    let mut opts = LogsOptions::<String> {
+        follow,
+        stdout: stream != LogStream::Stderr,
+        stderr: stream != LogStream::Stdout,
+        timestamps: true,
+        since,
+        until,
+        ..Default::default()
+    };
+    if let Some(n) = tail {
+        opts.tail = n.to_string();
+    }
+    opts
+}
+
+pub async fn search_logs(docker: &Docker, req: SearchLogsRequest) -> Result<Vec<LogEntry>> {
+    if req.containers.is_empty() {
+        return Err(anyhow!("no containers"));
+    }
+    let matcher = Matcher::new(&req.filter, req.regex.unwrap_or(false))?;
+    let since = to_unix(&req.since)?;
+    let until = to_unix(&req.until)?;
+    let limit = req.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let stream = req.stream.unwrap_or_default();
+    // every line is a match, so the last `limit` are all that's needed
+    let tail = (matches!(matcher, Matcher::All) && since == 0 && until == 0).then_some(limit);
+
+    let fetches = req.containers.iter().map(|c| {
+        let name = container_name(c);
+        let matcher = &matcher;
+        async move {
+            let opts = logs_options(stream, false, since, until, tail);
+            let mut logs = docker.logs(&name, Some(opts));
+            // only the newest `limit` matches can make it through the merge
+            let mut entries = VecDeque::new();
+            while let Some(lg) = logs.next().await {
+                match lg {
+                    Ok(out) => {
+                        for e in to_entries(&name, out, matcher) {
+                            if entries.len() == limit {
+                                entries.pop_front();
+                            }
+                            entries.push_back(e);
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("SearchLogs: {} {:?}", name, e);
+                        break;
+                    }
+                }
+            }
+            Vec::from(entries)
+        }
+    });
+    let per_container = futures::future::join_all(fetches).await;
+    Ok(merge(per_container, limit))
+}
+
+/// Matching lines from all containers as they are written.
+pub fn follow_logs(
+    docker: &Docker,
+    containers: Vec<String>,
+    filter: Option<String>,
+    regex: bool,
+    stream: LogStream,
+) -> Result<impl Stream<Item = LogEntry>> {
+    let matcher = std::sync::Arc::new(Matcher::new(&filter, regex)?);
+    let since = chrono::Utc::now().timestamp();
+    let streams = containers.into_iter().map(|c| {
+        let name = container_name(&c);
+        let matcher = matcher.clone();
+        docker
+            .logs(&name, Some(logs_options(stream, true, since, 0, None)))
+            .filter_map(|lg| async move { lg.ok() })
+            .flat_map(move |out| stream::iter(to_entries(&name, out, &matcher)))
+    });
+    Ok(stream::select_all(streams.map(Box::pin)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn entry(ts: &str, container: &str) -> LogEntry {
+        LogEntry {
+            ts: ts.to_string(),
+            container: container.to_string(),
+            stream: LogStream::Stdout,
+            line: "x".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_matcher() {
+        let m = Matcher::new(&Some("402".to_string()), false).unwrap();
+        assert!(m.matches("HTTP 402 payment required"));
+        assert!(!m.matches("HTTP 200"));
+        let m = Matcher::new(&Some("^ERR.*l402$".to_string()), true).unwrap();
+        assert!(m.matches("ERROR paying l402"));
+        assert!(Matcher::new(&Some("(".to_string()), true).is_err());
+    }
+
+    #[test]
+    fn test_to_entries() {
+        let out = LogOutput::StdErr {
+            message: Bytes::from("2024-05-01T10:00:00.123456789Z paid invoice\n2024-05-01T10:00:01Z other\n"),
+        };
+        let m = Matcher::new(&Some("paid".to_string()), false).unwrap();
+        let got = to_entries("cln.sphinx", out, &m);
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].ts, "2024-05-01T10:00:00.123456789Z");
+        assert_eq!(got[0].stream, LogStream::Stderr);
+        assert_eq!(got[0].line, "paid invoice");
+    }
+
+    #[test]
+    fn test_merge() {
+        // docker trims trailing zeros, so string order is not time order
+        let boltwall = vec![entry("2024-05-01T10:00:00.5Z", "boltwall"), entry("2024-05-01T10:00:02Z", "boltwall")];
+        let cln = vec![entry("2024-05-01T10:00:00.25Z", "cln"), entry("2024-05-01T10:00:01Z", "cln")];
+        let got = merge(vec![boltwall, cln], 3);
+        let order: Vec<&str> = got.iter().map(|e| e.container.as_str()).collect();
+        assert_eq!(order, vec!["boltwall", "cln", "boltwall"]);
+    }
+}
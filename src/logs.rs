@@ -1,13 +1,362 @@
+use crate::utils::getenv;
 use once_cell::sync::Lazy;
-use rocket::*;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::{broadcast, Mutex};
 
-// {tag: [log]}
-pub type LogStore = HashMap<String, Vec<String>>;
+pub static LOGS: Lazy<Mutex<LogStore>> =
+    Lazy::new(|| Mutex::new(LogStore::new(Limits::from_env())));
 
-pub static LOGS: Lazy<Mutex<LogStore>> = Lazy::new(|| Mutex::new(HashMap::new()));
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogLine {
+    // unix millis
+    pub ts: u64,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    // kept in memory per tag
+    pub max_lines: usize,
+    pub max_bytes: usize,
+    // on disk per tag: the live file plus max_files rotated ones
+    pub max_file_bytes: u64,
+    pub max_files: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_lines: 5000,
+            max_bytes: 1024 * 1024,
+            max_file_bytes: 5 * 1024 * 1024,
+            max_files: 3,
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        let d = Self::default();
+        fn num<T: std::str::FromStr>(name: &str, def: T) -> T {
+            getenv(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(def)
+        }
+        Self {
+            max_lines: num("LOGS_MAX_LINES", d.max_lines),
+            max_bytes: num("LOGS_MAX_BYTES", d.max_bytes),
+            max_file_bytes: num("LOGS_MAX_FILE_BYTES", d.max_file_bytes),
+            max_files: num("LOGS_MAX_FILES", d.max_files),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Ring {
+    lines: VecDeque<LogLine>,
+    bytes: usize,
+}
+
+impl Ring {
+    fn push(&mut self, line: LogLine, limits: &Limits) {
+        self.bytes += line.text.len();
+        self.lines.push_back(line);
+        while self.lines.len() > limits.max_lines
+            || (self.bytes > limits.max_bytes && self.lines.len() > 1)
+        {
+            if let Some(old) = self.lines.pop_front() {
+                self.bytes -= old.text.len();
+            }
+        }
+    }
+}
+
+struct Spill {
+    file: File,
+    size: u64,
+}
+
+/// Per-tag ring buffers, spilled as JSON lines to rotating files under
+/// `vol/<proj>/logs` once `init` is called. Without a dir it is memory only.
+pub struct LogStore {
+    limits: Limits,
+    dir: Option<PathBuf>,
+    rings: HashMap<String, Ring>,
+    spills: HashMap<String, Spill>,
+}
+
+impl LogStore {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            dir: None,
+            rings: HashMap::new(),
+            spills: HashMap::new(),
+        }
+    }
+
+    /// Persist to `dir` and reload the newest lines of every tag found there.
+    pub fn open(&mut self, dir: PathBuf) -> anyhow::Result<()> {
+        fs::create_dir_all(&dir)?;
+        self.spills.clear();
+        self.rings.clear();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(file) = name.strip_suffix(".log") {
+                let tag = unescape_tag(file);
+                let mut ring = Ring::default();
+                for line in read_files(&dir, file, self.limits.max_files) {
+                    ring.push(line, &self.limits);
+                }
+                self.rings.insert(tag, ring);
+            }
+        }
+        self.dir = Some(dir);
+        Ok(())
+    }
+
+    pub fn push(&mut self, tag: &str, line: LogLine) {
+        if let Err(e) = self.spill(tag, &line) {
+            log::warn!("logs: could not write {} to disk: {:?}", tag, e);
+        }
+        let limits = self.limits;
+        self.rings
+            .entry(tag.to_string())
+            .or_default()
+            .push(line, &limits);
+    }
+
+    fn spill(&mut self, tag: &str, line: &LogLine) -> anyhow::Result<()> {
+        let dir = match &self.dir {
+            Some(d) => d.clone(),
+            None => return Ok(()),
+        };
+        let file = escape_tag(tag);
+        let mut json = serde_json::to_string(line)?;
+        json.push('\n');
+        let needs_rotate = self
+            .spills
+            .get(tag)
+            .map(|s| s.size + json.len() as u64 > self.limits.max_file_bytes)
+            .unwrap_or(false);
+        if needs_rotate {
+            self.spills.remove(tag);
+            rotate(&dir, &file, self.limits.max_files)?;
+        }
+        if !self.spills.contains_key(tag) {
+            let path = dir.join(format!("{}.log", file));
+            let f = OpenOptions::new().create(true).append(true).open(&path)?;
+            let size = f.metadata()?.len();
+            self.spills.insert(tag.to_string(), Spill { file: f, size });
+        }
+        let spill = self.spills.get_mut(tag).unwrap();
+        spill.file.write_all(json.as_bytes())?;
+        spill.size += json.len() as u64;
+        Ok(())
+    }
+
+    /// Lines of `tag` with `from <= ts <= to`, oldest first, at most `limit`
+    /// of the newest. Falls back to the files on disk when the range starts
+    /// before what is still in memory.
+    pub fn query(
+        &self,
+        tag: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+        limit: Option<usize>,
+    ) -> Vec<LogLine> {
+        select(self.source(tag, from, to).read(), from, to, limit)
+    }
+
+    // the in-range lines still in memory, or where to read them from disk
+    fn source(&self, tag: &str, from: Option<u64>, to: Option<u64>) -> Source {
+        let ring = self.rings.get(tag);
+        let oldest_in_memory = ring.and_then(|r| r.lines.front()).map(|l| l.ts);
+        let from_disk = match (&self.dir, from, oldest_in_memory) {
+            (Some(_), Some(f), Some(oldest)) => f < oldest,
+            (Some(_), _, None) => true,
+            _ => false,
+        };
+        match &self.dir {
+            Some(dir) if from_disk => Source::Disk {
+                dir: dir.clone(),
+                file: escape_tag(tag),
+                max_files: self.limits.max_files,
+            },
+            _ => Source::Memory(
+                ring.map(|r| {
+                    r.lines
+                        .iter()
+                        .filter(|l| in_range(l, from, to))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default(),
+            ),
+        }
+    }
+
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self.rings.keys().cloned().collect();
+        tags.sort();
+        tags
+    }
+}
+
+enum Source {
+    Memory(Vec<LogLine>),
+    Disk {
+        dir: PathBuf,
+        file: String,
+        max_files: usize,
+    },
+}
+
+impl Source {
+    fn read(self) -> Vec<LogLine> {
+        match self {
+            Source::Memory(lines) => lines,
+            Source::Disk {
+                dir,
+                file,
+                max_files,
+            } => read_files(&dir, &file, max_files),
+        }
+    }
+}
+
+fn in_range(l: &LogLine, from: Option<u64>, to: Option<u64>) -> bool {
+    from.is_none_or(|f| l.ts >= f) && to.is_none_or(|t| l.ts <= t)
+}
+
+fn select(
+    lines: Vec<LogLine>,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<usize>,
+) -> Vec<LogLine> {
+    let mut out: Vec<LogLine> = lines
+        .into_iter()
+        .filter(|l| in_range(l, from, to))
+        .collect();
+    if let Some(limit) = limit {
+        if out.len() > limit {
+            out.drain(..out.len() - limit);
+        }
+    }
+    out
+}
+
+// tags are node names, but keep them from escaping the logs dir
+fn escape_tag(tag: &str) -> String {
+    tag.replace('%', "%25")
+        .replace('/', "%2F")
+        .replace('\\', "%5C")
+}
+
+fn unescape_tag(file: &str) -> String {
+    file.replace("%2F", "/")
+        .replace("%5C", "\\")
+        .replace("%25", "%")
+}
+
+fn rotated(dir: &Path, file: &str, n: usize) -> PathBuf {
+    if n == 0 {
+        dir.join(format!("{}.log", file))
+    } else {
+        dir.join(format!("{}.log.{}", file, n))
+    }
+}
+
+// <tag>.log -> <tag>.log.1 -> ... -> <tag>.log.<max_files>, dropping the last
+fn rotate(dir: &Path, file: &str, max_files: usize) -> anyhow::Result<()> {
+    if max_files == 0 {
+        let _ = fs::remove_file(rotated(dir, file, 0));
+        return Ok(());
+    }
+    let _ = fs::remove_file(rotated(dir, file, max_files));
+    for n in (0..max_files).rev() {
+        let from = rotated(dir, file, n);
+        if from.exists() {
+            fs::rename(&from, rotated(dir, file, n + 1))?;
+        }
+    }
+    Ok(())
+}
+
+// oldest rotated file first, skipping lines that don't parse
+fn read_files(dir: &Path, file: &str, max_files: usize) -> Vec<LogLine> {
+    let mut out = Vec::new();
+    for n in (0..=max_files).rev() {
+        let f = match File::open(rotated(dir, file, n)) {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+        for line in BufReader::new(f).lines().map_while(|l| l.ok()) {
+            if let Ok(l) = serde_json::from_str::<LogLine>(&line) {
+                out.push(l);
+            }
+        }
+    }
+    out
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Keep logs under `vol/<proj>/logs` so they survive a restart.
+pub async fn init(proj: &str) {
+    let dir = PathBuf::from(format!("vol/{}/logs", proj));
+    let limits = LOGS.lock().await.limits;
+    // read the files without holding LOGS
+    let opened = tokio::task::spawn_blocking(move || {
+        let mut store = LogStore::new(limits);
+        store.open(dir).map(|_| store)
+    })
+    .await;
+    let mut store = match opened {
+        Ok(Ok(store)) => store,
+        Ok(Err(e)) => return log::error!("logs: could not open store: {:?}", e),
+        Err(e) => return log::error!("logs: could not open store: {:?}", e),
+    };
+    let mut logs = LOGS.lock().await;
+    // keep whatever came in while the files were read
+    for (tag, ring) in logs.rings.drain() {
+        for line in ring.lines {
+            store.push(&tag, line);
+        }
+    }
+    *logs = store;
+}
+
+/// Like `LogStore::query`, but reads the files on disk after letting go of
+/// LOGS so a large read doesn't hold up every log line coming in.
+pub async fn query(
+    tag: &str,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<usize>,
+) -> Vec<LogLine> {
+    let source = LOGS.lock().await.source(tag, from, to);
+    let lines = match source {
+        Source::Memory(lines) => lines,
+        disk => tokio::task::spawn_blocking(move || disk.read())
+            .await
+            .unwrap_or_default(),
+    };
+    select(lines, from, to, limit)
+}
 
 pub type LogChans = HashMap<String, broadcast::Sender<String>>;
 
@@ -26,20 +375,96 @@ pub async fn get_log_tx(tag: &str, chans: &Arc<Mutex<LogChans>>) -> broadcast::S
 }
 
 async fn add_log(tag: String, text: String) {
-    let mut lgs = LOGS.lock().await;
-    if let Some(inner) = lgs.get_mut(&tag) {
-        inner.push(text);
-    } else {
-        lgs.insert(tag, vec![text]);
-    }
+    let line = LogLine {
+        ts: now_millis(),
+        text,
+    };
+    LOGS.lock().await.push(&tag, line);
 }
 
 pub fn collect_logs(tag: &str, log_tx: broadcast::Sender<String>) {
     let mut stream = log_tx.subscribe();
     let tag = tag.to_string();
     tokio::spawn(async move {
-        while let Ok(lo) = stream.recv().await {
-            add_log(tag.clone(), lo).await;
+        loop {
+            match stream.recv().await {
+                Ok(lo) => add_log(tag.clone(), lo).await,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(ts: u64, text: &str) -> LogLine {
+        LogLine {
+            ts,
+            text: text.to_string(),
+        }
+    }
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("swarm-logs-{}-{}", name, now_millis()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_ring_limits() {
+        let limits = Limits {
+            max_lines: 3,
+            max_bytes: 10,
+            ..Default::default()
+        };
+        let mut store = LogStore::new(limits);
+        for i in 0..5 {
+            store.push("cln", line(i, "ab"));
+        }
+        let got = store.query("cln", None, None, None);
+        assert_eq!(got.iter().map(|l| l.ts).collect::<Vec<_>>(), vec![2, 3, 4]);
+        store.push("cln", line(5, "0123456789"));
+        assert_eq!(store.query("cln", None, None, None).len(), 1);
+    }
+
+    #[test]
+    fn test_persist_rotate_and_query() {
+        let dir = tmp_dir("persist");
+        let limits = Limits {
+            max_lines: 2,
+            max_bytes: 1024,
+            max_file_bytes: 80,
+            max_files: 5,
+        };
+        let mut store = LogStore::new(limits);
+        store.open(dir.clone()).unwrap();
+        for i in 1..=6 {
+            store.push("jarvis.sphinx", line(i * 1000, "hello"));
+        }
+        assert!(rotated(&dir, "jarvis.sphinx", 1).exists());
+
+        // memory only holds the last two, the range reaches back to disk
+        let got = store.query("jarvis.sphinx", Some(2000), Some(4000), None);
+        assert_eq!(
+            got.iter().map(|l| l.ts).collect::<Vec<_>>(),
+            vec![2000, 3000, 4000]
+        );
+
+        // a fresh store after a restart sees the history
+        let mut reopened = LogStore::new(limits);
+        reopened.open(dir.clone()).unwrap();
+        let got = reopened.query("jarvis.sphinx", None, None, Some(1));
+        assert_eq!(got, vec![line(6000, "hello")]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_escape_tag() {
+        let tag = "a/b\\c%d";
+        assert_eq!(unescape_tag(&escape_tag(tag)), tag);
+        assert!(!escape_tag(tag).contains('/'));
+    }
+}
@@ -0,0 +1,555 @@
+use crate::config;
+use crate::dock::{get_container_statistics, list_containers, sphinx_container};
+use crate::handler::find_boltwall;
+use crate::utils::getenv;
+use bollard::Docker;
+use once_cell::sync::Lazy;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+/// Process-wide counters that are recorded as things happen (commands,
+/// scheduled jobs). Everything else is sampled at scrape time.
+pub static METRICS: Lazy<Mutex<Counters>> = Lazy::new(|| Mutex::new(Default::default()));
+
+#[derive(Default)]
+pub struct Counters {
+    // (type, cmd, result) -> count
+    cmds: BTreeMap<(String, String, String), u64>,
+    // (type, cmd) -> (count, total seconds)
+    cmd_latency: BTreeMap<(String, String), (u64, f64)>,
+    // (job, result) -> count
+    jobs: BTreeMap<(String, String), u64>,
+    // job -> unix seconds of the last run
+    job_last_run: BTreeMap<String, u64>,
+    // job -> whether the last run succeeded
+    job_last_ok: BTreeMap<String, bool>,
+}
+
+/// The Cmd type and variant name, e.g. ("Swarm", "GetConfig").
+pub fn cmd_labels<C: Serialize>(cmd: &C) -> (String, String) {
+    let v = serde_json::to_value(cmd).unwrap_or_default();
+    let typ = v["type"].as_str().unwrap_or("unknown").to_string();
+    let name = v["data"]["cmd"].as_str().unwrap_or("unknown").to_string();
+    (typ, name)
+}
+
+pub fn record_cmd(typ: &str, name: &str, secs: f64, ok: bool) {
+    let mut m = METRICS.lock().unwrap();
+    let key = (typ.to_string(), name.to_string(), result_label(ok).to_string());
+    *m.cmds.entry(key).or_insert(0) += 1;
+    let lat = m
+        .cmd_latency
+        .entry((typ.to_string(), name.to_string()))
+        .or_insert((0, 0.0));
+    lat.0 += 1;
+    lat.1 += secs;
+}
+
+/// Record the outcome of one run of a scheduled job.
+pub fn record_job(job: &str, ok: bool) {
+    let mut m = METRICS.lock().unwrap();
+    *m.jobs
+        .entry((job.to_string(), result_label(ok).to_string()))
+        .or_insert(0) += 1;
+    m.job_last_run.insert(job.to_string(), now_secs());
+    m.job_last_ok.insert(job.to_string(), ok);
+}
+
+/// Whether the last run of a job succeeded, if it has run at all.
+pub fn last_job_ok(job: &str) -> Option<bool> {
+    METRICS.lock().unwrap().job_last_ok.get(job).copied()
+}
+
+fn result_label(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "error"
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Builds the Prometheus text exposition format. Samples are grouped by
+/// metric, as the format requires, in the order metrics are first seen.
+#[derive(Default)]
+pub struct Exposition {
+    families: Vec<Family>,
+    index: HashMap<String, usize>,
+}
+
+struct Family {
+    name: String,
+    kind: String,
+    help: String,
+    lines: String,
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add one sample to its metric.
+    pub fn sample(&mut self, name: &str, kind: &str, help: &str, labels: &[(&str, &str)], val: f64) {
+        let f = self.family(name, kind, help);
+        line(&mut f.lines, name, labels, val);
+    }
+
+    /// A summary without quantiles: just the _sum and _count series.
+    pub fn summary(&mut self, name: &str, help: &str, labels: &[(&str, &str)], count: u64, sum: f64) {
+        let f = self.family(name, "summary", help);
+        line(&mut f.lines, &format!("{}_sum", name), labels, sum);
+        line(&mut f.lines, &format!("{}_count", name), labels, count as f64);
+    }
+
+    fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Family {
+        let i = match self.index.get(name) {
+            Some(i) => *i,
+            None => {
+                self.families.push(Family {
+                    name: name.to_string(),
+                    kind: kind.to_string(),
+                    help: help.to_string(),
+                    lines: String::new(),
+                });
+                self.index.insert(name.to_string(), self.families.len() - 1);
+                self.families.len() - 1
+            }
+        };
+        &mut self.families[i]
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], val: f64) {
+        self.sample(name, "gauge", help, labels, val)
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, labels: &[(&str, &str)], val: f64) {
+        self.sample(name, "counter", help, labels, val)
+    }
+
+    pub fn finish(self) -> String {
+        let mut out = String::new();
+        for f in self.families.iter() {
+            let _ = writeln!(out, "# HELP {} {}", f.name, f.help);
+            let _ = writeln!(out, "# TYPE {} {}", f.name, f.kind);
+            out.push_str(&f.lines);
+        }
+        out
+    }
+}
+
+fn line(out: &mut String, name: &str, labels: &[(&str, &str)], val: f64) {
+    let _ = write!(out, "{}", name);
+    if !labels.is_empty() {
+        let ls: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        let _ = write!(out, "{{{}}}", ls.join(","));
+    }
+    let _ = writeln!(out, " {}", val);
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Command and scheduled job counters.
+pub fn render_counters(e: &mut Exposition) {
+    let m = METRICS.lock().unwrap();
+    for ((typ, name, result), n) in m.cmds.iter() {
+        e.counter(
+            "swarm_cmd_total",
+            "Commands handled, by type, variant and result",
+            &[("type", typ), ("cmd", name), ("result", result)],
+            *n as f64,
+        );
+    }
+    for ((typ, name), (count, sum)) in m.cmd_latency.iter() {
+        e.summary(
+            "swarm_cmd_duration_seconds",
+            "Time spent handling commands",
+            &[("type", typ), ("cmd", name)],
+            *count,
+            *sum,
+        );
+    }
+    for ((job, result), n) in m.jobs.iter() {
+        e.counter(
+            "swarm_job_runs_total",
+            "Scheduled job runs, by result",
+            &[("job", job), ("result", result)],
+            *n as f64,
+        );
+    }
+    for (job, ts) in m.job_last_run.iter() {
+        e.gauge(
+            "swarm_job_last_run_timestamp_seconds",
+            "Unix time of the last run of a scheduled job",
+            &[("job", job)],
+            *ts as f64,
+        );
+    }
+}
+
+/// Everything the stack binary exposes on /metrics.
+pub async fn render_stack(docker: &Docker) -> String {
+    let mut e = Exposition::new();
+    render_counters(&mut e);
+    render_containers(&mut e, docker).await;
+    render_lightning(&mut e).await;
+    render_l402(&mut e).await;
+    e.finish()
+}
+
+async fn render_containers(e: &mut Exposition, docker: &Docker) {
+    match list_containers(docker).await {
+        Ok(containers) => {
+            for c in containers.iter() {
+                let name = match sphinx_container(&c.names) {
+                    Some(n) => n,
+                    None => continue,
+                };
+                let running = c.state.as_deref() == Some("running");
+                e.gauge(
+                    "swarm_container_up",
+                    "1 if the container is running",
+                    &[("container", &name)],
+                    if running { 1.0 } else { 0.0 },
+                );
+                let restarts = match docker.inspect_container(&name, None).await {
+                    Ok(info) => info.restart_count.unwrap_or(0),
+                    Err(_) => continue,
+                };
+                e.gauge(
+                    "swarm_container_restarts",
+                    "Docker restart count of the container",
+                    &[("container", &name)],
+                    restarts as f64,
+                );
+            }
+        }
+        Err(err) => log::warn!("metrics: list containers failed: {:?}", err),
+    }
+    // errors when nothing is running, which the up gauges already show
+    let stats = get_container_statistics(docker, None)
+        .await
+        .unwrap_or_default();
+    for s in stats.iter() {
+        let labels = [
+            ("container", s.container_name.as_str()),
+            ("image", s.container_image.as_str()),
+        ];
+        e.counter(
+            "swarm_container_cpu_usage_total",
+            "Total cpu time consumed by the container (nanoseconds)",
+            &labels,
+            s.cpu_total_usage as f64,
+        );
+        e.counter(
+            "swarm_container_system_cpu_usage_total",
+            "Host cpu time at the sample (nanoseconds)",
+            &labels,
+            s.system_cpu_usage as f64,
+        );
+        e.gauge(
+            "swarm_container_cpu_percent",
+            "Cpu usage since the previous read, as a percent of one core",
+            &labels,
+            s.cpu_percent,
+        );
+        e.gauge(
+            "swarm_container_memory_usage_bytes",
+            "Current memory usage",
+            &labels,
+            s.memory_usage as f64,
+        );
+        e.gauge(
+            "swarm_container_memory_max_usage_bytes",
+            "Peak memory usage",
+            &labels,
+            s.memory_max_usage as f64,
+        );
+        e.counter(
+            "swarm_container_network_receive_bytes_total",
+            "Bytes received on all container interfaces",
+            &labels,
+            s.network_rx_bytes as f64,
+        );
+        e.counter(
+            "swarm_container_network_transmit_bytes_total",
+            "Bytes sent on all container interfaces",
+            &labels,
+            s.network_tx_bytes as f64,
+        );
+        e.counter(
+            "swarm_container_block_read_bytes_total",
+            "Bytes read from block devices",
+            &labels,
+            s.block_read_bytes as f64,
+        );
+        e.counter(
+            "swarm_container_block_write_bytes_total",
+            "Bytes written to block devices",
+            &labels,
+            s.block_write_bytes as f64,
+        );
+    }
+}
+
+async fn render_lightning(e: &mut Exposition) {
+    let (btcs, lnds, clns) = config::clients_read(|c| {
+        (c.bitcoind.clone(), c.lnd.clone(), c.cln.clone())
+    })
+    .await;
+
+    for (tag, client) in btcs.iter() {
+        if let Ok(info) = client.get_info() {
+            e.gauge(
+                "swarm_bitcoind_block_height",
+                "Current block height of bitcoind",
+                &[("node", tag)],
+                info.blocks as f64,
+            );
+        }
+    }
+
+    for (tag, client) in lnds.iter() {
+        let mut lnd = client.lock().await;
+        if let Ok(bal) = lnd.get_balance().await {
+            e.gauge(
+                "swarm_lightning_onchain_balance_sats",
+                "On-chain wallet balance",
+                &[("node", tag), ("impl", "lnd"), ("status", "confirmed")],
+                bal.confirmed_balance as f64,
+            );
+            e.gauge(
+                "swarm_lightning_onchain_balance_sats",
+                "On-chain wallet balance",
+                &[("node", tag), ("impl", "lnd"), ("status", "unconfirmed")],
+                bal.unconfirmed_balance as f64,
+            );
+        }
+        if let Ok(chans) = lnd.list_channels().await {
+            let active = chans.channels.iter().filter(|c| c.active).count();
+            let local: i64 = chans.channels.iter().map(|c| c.local_balance).sum();
+            let remote: i64 = chans.channels.iter().map(|c| c.remote_balance).sum();
+            channel_gauges(e, tag, "lnd", chans.channels.len(), active, local, remote);
+        }
+    }
+
+    for (tag, client) in clns.iter() {
+        if let Ok(funds) = client.list_funds().await {
+            // ListfundsOutputsStatus: 0 unconfirmed, 1 confirmed
+            let mut confirmed = 0;
+            let mut unconfirmed = 0;
+            for o in funds.outputs.iter() {
+                let sats = o.amount_msat.as_ref().map(|a| a.msat).unwrap_or(0) / 1000;
+                match o.status {
+                    0 => unconfirmed += sats,
+                    1 => confirmed += sats,
+                    _ => (),
+                }
+            }
+            e.gauge(
+                "swarm_lightning_onchain_balance_sats",
+                "On-chain wallet balance",
+                &[("node", tag), ("impl", "cln"), ("status", "confirmed")],
+                confirmed as f64,
+            );
+            e.gauge(
+                "swarm_lightning_onchain_balance_sats",
+                "On-chain wallet balance",
+                &[("node", tag), ("impl", "cln"), ("status", "unconfirmed")],
+                unconfirmed as f64,
+            );
+            let active = funds.channels.iter().filter(|c| c.connected).count();
+            let mut local = 0;
+            let mut remote = 0;
+            for c in funds.channels.iter() {
+                let ours = c.our_amount_msat.as_ref().map(|a| a.msat).unwrap_or(0);
+                let total = c.amount_msat.as_ref().map(|a| a.msat).unwrap_or(0);
+                local += ours / 1000;
+                remote += total.saturating_sub(ours) / 1000;
+            }
+            channel_gauges(
+                e,
+                tag,
+                "cln",
+                funds.channels.len(),
+                active,
+                local as i64,
+                remote as i64,
+            );
+        }
+    }
+}
+
+fn channel_gauges(
+    e: &mut Exposition,
+    tag: &str,
+    imp: &str,
+    total: usize,
+    active: usize,
+    local: i64,
+    remote: i64,
+) {
+    e.gauge(
+        "swarm_lightning_channels",
+        "Number of channels",
+        &[("node", tag), ("impl", imp), ("state", "active")],
+        active as f64,
+    );
+    e.gauge(
+        "swarm_lightning_channels",
+        "Number of channels",
+        &[("node", tag), ("impl", imp), ("state", "inactive")],
+        (total - active) as f64,
+    );
+    e.gauge(
+        "swarm_lightning_channel_balance_sats",
+        "Sum of channel balances",
+        &[("node", tag), ("impl", imp), ("side", "local")],
+        local as f64,
+    );
+    e.gauge(
+        "swarm_lightning_channel_balance_sats",
+        "Sum of channel balances",
+        &[("node", tag), ("impl", imp), ("side", "remote")],
+        remote as f64,
+    );
+}
+
+async fn render_l402(e: &mut Exposition) {
+    let boltwall = match config::stack_read(|s| find_boltwall(&s.nodes)).await {
+        Ok(b) => b,
+        Err(_) => return,
+    };
+    let stats = match crate::conn::boltwall::get_l402_stats(&boltwall).await {
+        Ok(s) => s,
+        Err(err) => {
+            log::warn!("metrics: l402 stats failed: {:?}", err);
+            return;
+        }
+    };
+    let v: serde_json::Value = match serde_json::from_str(&stats) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    // boltwall may wrap the totals in "data"
+    let obj = v.get("data").unwrap_or(&v);
+    l402_numbers(e, obj);
+}
+
+fn l402_numbers(e: &mut Exposition, obj: &serde_json::Value) {
+    if let Some(map) = obj.as_object() {
+        for (k, val) in map.iter() {
+            if let Some(n) = val.as_f64() {
+                e.gauge(
+                    &format!("swarm_l402_{}", metric_name(k)),
+                    "Boltwall L402 total",
+                    &[],
+                    n,
+                );
+            }
+        }
+    }
+}
+
+// camelCase or anything else into a valid snake_case metric name
+fn metric_name(k: &str) -> String {
+    let mut out = String::new();
+    for (i, ch) in k.chars().enumerate() {
+        if ch.is_ascii_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.push(ch.to_ascii_lowercase());
+        } else if ch.is_ascii_alphanumeric() {
+            out.push(ch);
+        } else {
+            out.push('_');
+        }
+    }
+    out
+}
+
+/// Bearer auth for /metrics. The endpoint is off unless METRICS_TOKEN is set,
+/// it shows balances.
+pub struct MetricsAuth;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAuth {
+    type Error = ();
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, ()> {
+        let token = match getenv("METRICS_TOKEN") {
+            Ok(t) => t,
+            Err(_) => return Outcome::Error((Status::NotFound, ())),
+        };
+        let expected = format!("Bearer {}", token);
+        match req.headers().get_one("authorization") {
+            // constant time, so the token can't be guessed byte by byte
+            Some(h) if bool::from(h.as_bytes().ct_eq(expected.as_bytes())) => {
+                Outcome::Success(MetricsAuth)
+            }
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposition_declares_once() {
+        let mut e = Exposition::new();
+        e.gauge("swarm_x", "x help", &[("container", "a")], 1.0);
+        e.gauge("swarm_x", "x help", &[("container", "b\"c")], 2.5);
+        let out = e.finish();
+        assert_eq!(out.matches("# TYPE swarm_x gauge").count(), 1);
+        assert!(out.contains("swarm_x{container=\"a\"} 1\n"));
+        assert!(out.contains("swarm_x{container=\"b\\\"c\"} 2.5\n"));
+    }
+
+    #[test]
+    fn test_exposition_groups_samples() {
+        let mut e = Exposition::new();
+        e.gauge("swarm_a", "a", &[("node", "1")], 1.0);
+        e.gauge("swarm_b", "b", &[("node", "1")], 2.0);
+        e.gauge("swarm_a", "a", &[("node", "2")], 3.0);
+        let out = e.finish();
+        let want = "# HELP swarm_a a\n# TYPE swarm_a gauge\nswarm_a{node=\"1\"} 1\nswarm_a{node=\"2\"} 3\n# HELP swarm_b b\n";
+        assert!(out.starts_with(want));
+    }
+
+    #[test]
+    fn test_exposition_summary() {
+        let mut e = Exposition::new();
+        e.summary("swarm_cmd_duration_seconds", "h", &[("cmd", "GetConfig")], 3, 0.5);
+        let out = e.finish();
+        assert!(out.contains("# TYPE swarm_cmd_duration_seconds summary\n"));
+        assert!(out.contains("swarm_cmd_duration_seconds_sum{cmd=\"GetConfig\"} 0.5\n"));
+        assert!(out.contains("swarm_cmd_duration_seconds_count{cmd=\"GetConfig\"} 3\n"));
+    }
+
+    #[test]
+    fn test_metric_name() {
+        assert_eq!(metric_name("totalPaid"), "total_paid");
+        assert_eq!(metric_name("count-24h"), "count_24h");
+    }
+}
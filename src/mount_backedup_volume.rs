@@ -1,13 +1,6 @@
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::config::Region;
-use aws_sdk_s3::operation::get_object::GetObjectOutput;
-use aws_sdk_s3::Client;
-use aws_smithy_types::byte_stream::ByteStream;
-use aws_smithy_types::retry::RetryConfig;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
 use std::path::Path;
 use tar::Builder;
 use tokio::fs::remove_dir_all;
@@ -15,44 +8,6 @@ use zip::ZipArchive;
 
 use crate::utils::getenv;
 
-pub async fn download_from_s3(bucket: &str, key: &str) -> Result<(), Box<dyn Error>> {
-    let region = getenv("AWS_REGION")?;
-
-    // Create a region provider chain
-    let region_provider = RegionProviderChain::first_try(Some(Region::new(region)));
-
-    let config = aws_config::from_env()
-        .region(region_provider)
-        .retry_config(RetryConfig::standard().with_max_attempts(10))
-        .load()
-        .await;
-    let client = Client::new(&config);
-
-    match client
-        .list_objects_v2()
-        .bucket(bucket)
-        .prefix(key)
-        .send()
-        .await
-    {
-        Ok(list_resp) => {
-            log::info!("Lists: {:?}", &list_resp);
-            for object in list_resp.contents() {
-                let key = object.key().unwrap();
-                log::info!("This is the key: {}", key);
-
-                // Download the file
-                download_file(&client, bucket, &key, &key).await?;
-            }
-        }
-        Err(err) => {
-            log::error!("Get objects error: {:?}", err)
-        }
-    }
-
-    Ok(())
-}
-
 pub fn unzip_file(zip_path: &str, output_dir: &str) -> Result<(), Box<dyn Error>> {
     let file = File::open(zip_path)?;
     let mut archive = ZipArchive::new(file)?;
@@ -115,29 +70,3 @@ pub async fn delete_zip_and_upzipped_files() -> Result<(), Box<dyn std::error::E
 
     Ok(())
 }
-
-async fn download_file(
-    client: &Client,
-    bucket: &str,
-    key: &str,
-    output_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let resp: GetObjectOutput = match client.get_object().bucket(bucket).key(key).send().await {
-        Ok(response) => response,
-        Err(err) => {
-            log::error!("Error downloading file from s3 bucket: {:?}", err);
-            return Ok(());
-        }
-    };
-    let data: ByteStream = resp.body;
-
-    if let Some(parent_dir) = Path::new(output_path).parent() {
-        fs::create_dir_all(parent_dir)?;
-    }
-
-    let mut file = File::create(output_path)?;
-    let bytes = data.collect().await?.into_bytes();
-    file.write_all(&bytes)?;
-
-    Ok(())
-}
@@ -0,0 +1,234 @@
+use crate::cmd::{CreateOffer, DisableOffer, OfferPayment};
+use crate::config;
+use crate::conn::cln::ClnRPC;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use cln_grpc::pb;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A BOLT12 offer made on one of the stack's CLN nodes. CLN keeps the
+/// offer itself, the stack keeps who made it and what it's for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredOffer {
+    pub node: String,
+    pub label: String,
+    pub bolt12: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // any amount when none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<u64>,
+    pub created_at: i64,
+}
+
+/// An offer as CLN lists it, with the stack's label.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OfferInfo {
+    pub offer_id: String,
+    pub bolt12: String,
+    pub active: bool,
+    pub single_use: bool,
+    pub used: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<u64>,
+}
+
+impl CreateOffer {
+    pub fn validate(&self) -> Result<()> {
+        if self.label.trim().is_empty() {
+            return Err(anyhow!("label is required"));
+        }
+        if self.amount_msat == Some(0) {
+            return Err(anyhow!("amount_msat must be more than 0, or none for any"));
+        }
+        // without an amount or a description a wallet has nothing to show
+        if self.amount_msat.is_none() && self.description.is_none() {
+            return Err(anyhow!("an offer for any amount needs a description"));
+        }
+        Ok(())
+    }
+}
+
+// the amount string the offer command takes
+pub fn offer_amount(amount_msat: Option<u64>) -> String {
+    match amount_msat {
+        Some(a) => format!("{}msat", a),
+        None => "any".to_string(),
+    }
+}
+
+/// Offers listed by CLN, with the label, description and amount of the ones
+/// this stack made. Offers made outside the stack have no label.
+pub fn label_offers(
+    offers: Vec<pb::ListoffersOffers>,
+    stored: &BTreeMap<String, StoredOffer>,
+) -> Vec<OfferInfo> {
+    offers
+        .into_iter()
+        .map(|o| {
+            let offer_id = hex::encode(&o.offer_id);
+            let s = stored.get(&offer_id);
+            OfferInfo {
+                offer_id,
+                bolt12: o.bolt12,
+                active: o.active,
+                single_use: o.single_use,
+                used: o.used,
+                label: s.map(|s| s.label.clone()).or(o.label),
+                description: s.and_then(|s| s.description.clone()),
+                amount_msat: s.and_then(|s| s.amount_msat),
+            }
+        })
+        .collect()
+}
+
+/// Make an offer on the node and keep it in the stack by offer id.
+pub async fn create_offer(
+    cln: &ClnRPC,
+    proj: &str,
+    node: &str,
+    req: &CreateOffer,
+) -> Result<OfferInfo> {
+    req.validate()?;
+    let res = cln
+        .offer(
+            &offer_amount(req.amount_msat),
+            req.description.clone(),
+            Some(req.label.clone()),
+            req.single_use,
+            req.absolute_expiry,
+        )
+        .await?;
+    let offer_id = hex::encode(&res.offer_id);
+    let stored = StoredOffer {
+        node: node.to_string(),
+        label: req.label.clone(),
+        bolt12: res.bolt12.clone(),
+        description: req.description.clone(),
+        amount_msat: req.amount_msat,
+        created_at: Utc::now().timestamp(),
+    };
+    log::info!("offer {} on {}: {}", offer_id, node, req.label);
+    config::stack_write(proj, |s| {
+        let mut offers: BTreeMap<String, StoredOffer> = s.offers.take().unwrap_or_default();
+        offers.insert(offer_id.clone(), stored);
+        s.offers = Some(offers);
+    })
+    .await;
+    Ok(OfferInfo {
+        offer_id,
+        bolt12: res.bolt12,
+        active: res.active,
+        single_use: res.single_use,
+        used: res.used,
+        label: Some(req.label.clone()),
+        description: req.description.clone(),
+        amount_msat: req.amount_msat,
+    })
+}
+
+pub async fn list_offers(cln: &ClnRPC, node: &str) -> Result<Vec<OfferInfo>> {
+    let offers = cln.list_offers(None).await?.offers;
+    let stored: BTreeMap<String, StoredOffer> = config::stack_read(|s| {
+        s.offers
+            .iter()
+            .flatten()
+            .filter(|(_, o)| o.node == node)
+            .map(|(id, o)| (id.clone(), o.clone()))
+            .collect()
+    })
+    .await;
+    Ok(label_offers(offers, &stored))
+}
+
+/// Stop the node from issuing invoices for the offer. It stays listed
+/// (CLN can't delete offers) so it keeps its label too.
+pub async fn disable_offer(cln: &ClnRPC, req: &DisableOffer) -> Result<OfferInfo> {
+    let offer_id = hex::decode(&req.offer_id).map_err(|_| anyhow!("offer_id is not hex"))?;
+    let res = cln.disable_offer(offer_id).await?;
+    let offer_id = hex::encode(&res.offer_id);
+    let stored =
+        config::stack_read(|s| s.offers.as_ref().and_then(|o| o.get(&offer_id).cloned())).await;
+    Ok(OfferInfo {
+        offer_id,
+        bolt12: res.bolt12,
+        active: res.active,
+        single_use: res.single_use,
+        used: res.used,
+        label: stored.as_ref().map(|s| s.label.clone()).or(res.label),
+        description: stored.as_ref().and_then(|s| s.description.clone()),
+        amount_msat: stored.and_then(|s| s.amount_msat),
+    })
+}
+
+/// Ask the offer's node for a BOLT12 invoice.
+pub async fn fetch_invoice(cln: &ClnRPC, req: &OfferPayment) -> Result<String> {
+    let res = cln
+        .fetch_invoice(&req.offer, req.amount_msat, req.payer_note.clone())
+        .await?;
+    Ok(res.invoice)
+}
+
+/// Fetch an invoice from the offer and pay it.
+pub async fn pay_offer(cln: &ClnRPC, req: &OfferPayment) -> Result<pb::PayResponse> {
+    let invoice = fetch_invoice(cln, req).await?;
+    cln.pay(&invoice).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offers() {
+        assert_eq!(offer_amount(Some(5000)), "5000msat");
+        assert_eq!(offer_amount(None), "any");
+        let mut req = CreateOffer {
+            amount_msat: None,
+            description: None,
+            label: "coffee".to_string(),
+            single_use: None,
+            absolute_expiry: None,
+        };
+        assert!(req.validate().is_err());
+        req.description = Some("Coffee".to_string());
+        assert!(req.validate().is_ok());
+        req.amount_msat = Some(0);
+        assert!(req.validate().is_err());
+        req.label = " ".to_string();
+        req.amount_msat = Some(1000);
+        assert!(req.validate().is_err());
+
+        let offer = |id: u8, label: Option<&str>| pb::ListoffersOffers {
+            offer_id: vec![id; 32],
+            active: true,
+            single_use: false,
+            bolt12: format!("lno1{}", id),
+            used: false,
+            label: label.map(|l| l.to_string()),
+        };
+        let mut stored = BTreeMap::new();
+        stored.insert(
+            hex::encode([1u8; 32]),
+            StoredOffer {
+                node: "cln_1".to_string(),
+                label: "coffee".to_string(),
+                bolt12: "lno11".to_string(),
+                description: Some("Coffee".to_string()),
+                amount_msat: Some(5000),
+                created_at: 0,
+            },
+        );
+        let infos = label_offers(vec![offer(1, None), offer(2, Some("cli"))], &stored);
+        assert_eq!(infos[0].label.as_deref(), Some("coffee"));
+        assert_eq!(infos[0].amount_msat, Some(5000));
+        assert_eq!(infos[1].offer_id, hex::encode([2u8; 32]));
+        assert_eq!(infos[1].label.as_deref(), Some("cli"));
+        assert_eq!(infos[1].description, None);
+    }
+}
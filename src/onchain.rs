@@ -0,0 +1,552 @@
+use crate::builder::find_img;
+use crate::cmd::LightningSend;
+use crate::config;
+use crate::conn::lightning::{parse_outpoint, LightningNode, Utxo};
+use crate::images::Image;
+use crate::secrets::hex_secret;
+use anyhow::{anyhow, Context, Result};
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Address, Network};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const PROJ: &str = "stack";
+// older spends move to the archive
+const MAX_SPENDS: usize = 1000;
+// how long a prepared spend waits for its ConfirmSpend
+const CONFIRM_SECS: i64 = 10 * 60;
+const FEE_TARGETS: [u16; 5] = [1, 3, 6, 12, 144];
+// anything above is a typo, not a fee
+const MAX_SAT_PER_VBYTE: u64 = 1000;
+
+// prepared spends by id, not kept across restarts
+static PENDING: Lazy<Mutex<HashMap<String, PendingSpend>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static SPENDS_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SpendAction {
+    Send {
+        address: String,
+        // everything when none
+        #[serde(skip_serializing_if = "Option::is_none")]
+        amount_sat: Option<u64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        utxos: Vec<String>,
+    },
+    PublishPsbt {
+        psbt: String,
+        // decoded from the psbt when it was prepared
+        #[serde(default)]
+        outputs: Vec<SpendOutput>,
+        // none if an input doesn't say what it spends
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        fee_sat: Option<u64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpendOutput {
+    // the script in hex when it has no address
+    pub address: String,
+    pub amount_sat: u64,
+}
+
+/// A spend waiting for ConfirmSpend, shown to the operator first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingSpend {
+    pub id: String,
+    pub node: String,
+    pub action: SpendAction,
+    // the node's own estimate when none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sat_per_vbyte: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_by: Option<String>,
+    // unix seconds
+    pub expires_at: i64,
+}
+
+/// The audit trail entry of a confirmed spend, sent or failed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpendRecord {
+    pub id: String,
+    pub node: String,
+    pub action: SpendAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sat_per_vbyte: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirmed_by: Option<String>,
+    pub at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeEstimate {
+    pub target_blocks: u16,
+    // none until bitcoind has seen enough blocks
+    pub sat_per_vbyte: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListOnchainSpendsRequest {
+    pub node: Option<String>,
+    // newest first, 100 by default
+    pub limit: Option<usize>,
+}
+
+impl LightningSend {
+    pub fn validate(&self) -> Result<()> {
+        if self.address.trim().is_empty() {
+            return Err(anyhow!("address is required"));
+        }
+        if self.amount_sat == Some(0) {
+            return Err(anyhow!(
+                "amount_sat must be more than 0, or none for everything"
+            ));
+        }
+        if self.sat_per_vbyte.is_some() && self.target_blocks.is_some() {
+            return Err(anyhow!("give sat_per_vbyte or target_blocks, not both"));
+        }
+        if self.sat_per_vbyte == Some(0) || self.target_blocks == Some(0) {
+            return Err(anyhow!(
+                "sat_per_vbyte and target_blocks must be more than 0"
+            ));
+        }
+        if self.sat_per_vbyte.unwrap_or(0) > MAX_SAT_PER_VBYTE {
+            return Err(anyhow!(
+                "sat_per_vbyte can't be more than {}",
+                MAX_SAT_PER_VBYTE
+            ));
+        }
+        check_utxos(&self.utxos)
+    }
+}
+
+pub fn check_utxos(utxos: &[String]) -> Result<()> {
+    for u in utxos.iter() {
+        parse_outpoint(u)?;
+    }
+    Ok(())
+}
+
+fn btc_network(network: &str) -> Network {
+    match network {
+        "bitcoin" | "mainnet" => Network::Bitcoin,
+        "testnet" => Network::Testnet,
+        "signet" => Network::Signet,
+        _ => Network::Regtest,
+    }
+}
+
+/// The outputs and fee of a base64 PSBT, for the operator to check
+/// before it's published.
+pub fn decode_psbt(psbt: &str, network: Network) -> Result<(Vec<SpendOutput>, Option<u64>)> {
+    let bytes = base64::decode(psbt).map_err(|_| anyhow!("psbt must be base64"))?;
+    let psbt: PartiallySignedTransaction =
+        deserialize(&bytes).map_err(|e| anyhow!("invalid psbt: {}", e))?;
+    let tx = &psbt.unsigned_tx;
+    if tx.output.is_empty() {
+        return Err(anyhow!("psbt has no outputs"));
+    }
+    let outputs: Vec<SpendOutput> = tx
+        .output
+        .iter()
+        .map(|o| SpendOutput {
+            address: Address::from_script(&o.script_pubkey, network)
+                .map(|a| a.to_string())
+                .unwrap_or_else(|_| hex::encode(o.script_pubkey.as_bytes())),
+            amount_sat: o.value,
+        })
+        .collect();
+    let spent: Option<u64> = psbt
+        .inputs
+        .iter()
+        .zip(tx.input.iter())
+        .map(|(i, txin)| {
+            i.witness_utxo.as_ref().map(|o| o.value).or_else(|| {
+                i.non_witness_utxo
+                    .as_ref()
+                    .and_then(|t| t.output.get(txin.previous_output.vout as usize))
+                    .map(|o| o.value)
+            })
+        })
+        .sum();
+    let sent: u64 = outputs.iter().map(|o| o.amount_sat).sum();
+    if let Some(spent) = spent.filter(|s| *s < sent) {
+        return Err(anyhow!("psbt sends {} sat from {} in", sent, spent));
+    }
+    Ok((outputs, spent.map(|s| s - sent)))
+}
+
+/// Take the pending spend `id` for `node`, dropping any that expired.
+pub fn take_pending(
+    pending: &mut HashMap<String, PendingSpend>,
+    id: &str,
+    node: &str,
+    now: i64,
+) -> Result<PendingSpend> {
+    pending.retain(|_, p| p.expires_at > now);
+    match pending.get(id) {
+        Some(p) if p.node == node => Ok(pending.remove(id).expect("just found it")),
+        Some(p) => Err(anyhow!("spend {} is for {}", id, p.node)),
+        None => Err(anyhow!("no pending spend {}, it may have expired", id)),
+    }
+}
+
+/// bitcoind's smart fee estimate for confirming within `target_blocks`.
+pub async fn fee_rate(target_blocks: u16) -> Result<Option<u64>> {
+    let btc = config::clients_read(|c| c.bitcoind.values().next().cloned())
+        .await
+        .context("no bitcoind client")?;
+    btc.estimate_fee_rate(target_blocks)
+}
+
+pub async fn fee_estimates() -> Result<Vec<FeeEstimate>> {
+    let mut ests = Vec::new();
+    for target_blocks in FEE_TARGETS.iter() {
+        ests.push(FeeEstimate {
+            target_blocks: *target_blocks,
+            sat_per_vbyte: fee_rate(*target_blocks).await?,
+        });
+    }
+    Ok(ests)
+}
+
+async fn resolve_fee(req: &LightningSend) -> Result<Option<u64>> {
+    match req.target_blocks {
+        Some(t) => match fee_rate(t).await? {
+            Some(r) => Ok(Some(r)),
+            None => Err(anyhow!("bitcoind has no fee estimate for {} blocks yet", t)),
+        },
+        None => Ok(req.sat_per_vbyte),
+    }
+}
+
+pub async fn username(user_id: &Option<u32>) -> Option<String> {
+    let id = (*user_id)?;
+    config::stack_read(|s| {
+        s.users
+            .iter()
+            .find(|u| u.id == id)
+            .map(|u| u.username.clone())
+    })
+    .await
+}
+
+async fn prepare(
+    node: &str,
+    action: SpendAction,
+    sat_per_vbyte: Option<u64>,
+    user_id: &Option<u32>,
+) -> PendingSpend {
+    let now = Utc::now().timestamp();
+    let spend = PendingSpend {
+        id: hex_secret(),
+        node: node.to_string(),
+        action,
+        sat_per_vbyte,
+        requested_by: username(user_id).await,
+        expires_at: now + CONFIRM_SECS,
+    };
+    log::info!("onchain {}: prepared spend {:?}", node, spend);
+    let mut pending = PENDING.lock().await;
+    pending.retain(|_, p| p.expires_at > now);
+    pending.insert(spend.id.clone(), spend.clone());
+    spend
+}
+
+/// LightningCmd::SendOnchain. Nothing is sent until ConfirmSpend.
+pub async fn prepare_send(
+    node: &str,
+    req: &LightningSend,
+    user_id: &Option<u32>,
+) -> Result<PendingSpend> {
+    req.validate()?;
+    // lnd can only send everything from the whole wallet, catch it before the confirm
+    if req.amount_sat.is_none() && !req.utxos.is_empty() {
+        let img = config::stack_read(|s| find_img(node, &s.nodes)).await?;
+        if let Image::Lnd(_) = img {
+            return Err(anyhow!("lnd needs amount_sat to send from chosen utxos"));
+        }
+    }
+    let fee = resolve_fee(req).await?;
+    let action = SpendAction::Send {
+        address: req.address.clone(),
+        amount_sat: req.amount_sat,
+        utxos: req.utxos.clone(),
+    };
+    Ok(prepare(node, action, fee, user_id).await)
+}
+
+/// LightningCmd::PublishPsbt. Nothing is broadcast until ConfirmSpend.
+pub async fn prepare_publish(
+    node: &str,
+    psbt: &str,
+    user_id: &Option<u32>,
+) -> Result<PendingSpend> {
+    let network = match config::stack_read(|s| find_img(node, &s.nodes)).await? {
+        Image::Cln(c) => c.network,
+        Image::Lnd(l) => l.network,
+        _ => return Err(anyhow!("{} is not a lightning node", node)),
+    };
+    let (outputs, fee_sat) = decode_psbt(psbt, btc_network(&network))?;
+    let action = SpendAction::PublishPsbt {
+        psbt: psbt.to_string(),
+        outputs,
+        fee_sat,
+    };
+    Ok(prepare(node, action, None, user_id).await)
+}
+
+/// Send a prepared spend and add it to the audit trail, failed or not.
+pub async fn confirm(
+    ln: &dyn LightningNode,
+    node: &str,
+    id: &str,
+    user_id: &Option<u32>,
+) -> Result<SpendRecord> {
+    let spend = {
+        let mut pending = PENDING.lock().await;
+        take_pending(&mut pending, id, node, Utc::now().timestamp())?
+    };
+    let res = match &spend.action {
+        SpendAction::Send {
+            address,
+            amount_sat,
+            utxos,
+        } => {
+            ln.send_onchain(address, *amount_sat, spend.sat_per_vbyte, utxos)
+                .await
+        }
+        SpendAction::PublishPsbt { psbt, .. } => ln.publish_psbt(psbt).await,
+    };
+    let rec = SpendRecord {
+        id: spend.id,
+        node: node.to_string(),
+        action: spend.action,
+        sat_per_vbyte: spend.sat_per_vbyte,
+        requested_by: spend.requested_by,
+        confirmed_by: username(user_id).await,
+        at: Utc::now().timestamp(),
+        txid: res.as_ref().ok().cloned(),
+        error: res.err().map(|e| e.to_string()),
+    };
+    log::info!("onchain {}: {:?}", node, rec);
+    record(&rec).await;
+    match &rec.error {
+        Some(e) => Err(anyhow!(e.clone())),
+        None => Ok(rec),
+    }
+}
+
+/// An unsigned PSBT for external signing. Its inputs stay locked until it's
+/// published.
+pub async fn create_psbt(ln: &dyn LightningNode, req: &LightningSend) -> Result<String> {
+    req.validate()?;
+    let amount = req
+        .amount_sat
+        .context("amount_sat is required for a psbt")?;
+    let fee = resolve_fee(req).await?;
+    ln.create_psbt(&req.address, amount, fee, &req.utxos).await
+}
+
+/// Freeze or unfreeze utxos for coin control, returns the utxos after.
+pub async fn freeze_utxos(
+    ln: &dyn LightningNode,
+    utxos: &[String],
+    frozen: bool,
+) -> Result<Vec<Utxo>> {
+    if utxos.is_empty() {
+        return Err(anyhow!("no utxos given"));
+    }
+    check_utxos(utxos)?;
+    ln.lock_utxos(utxos, frozen).await?;
+    ln.list_utxos().await
+}
+
+fn spends_path() -> String {
+    format!("vol/{}/onchain_spends.json", PROJ)
+}
+
+async fn load_spends() -> Vec<SpendRecord> {
+    crate::utils::load_json(&spends_path(), Vec::new()).await
+}
+
+// one json record per line, only ever appended to
+fn archive_path() -> String {
+    format!("vol/{}/onchain_spends_archive.jsonl", PROJ)
+}
+
+async fn archive(old: &[SpendRecord]) -> Result<()> {
+    let mut lines = String::new();
+    for r in old.iter() {
+        lines.push_str(&serde_json::to_string(r)?);
+        lines.push('\n');
+    }
+    let mut f = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(archive_path())
+        .await?;
+    f.write_all(lines.as_bytes()).await?;
+    Ok(())
+}
+
+async fn record(rec: &SpendRecord) {
+    let _lock = SPENDS_LOCK.lock().await;
+    let mut all = load_spends().await;
+    all.push(rec.clone());
+    let over = all.len().saturating_sub(MAX_SPENDS);
+    if over > 0 {
+        // the log keeps them if the archive can't be written
+        match archive(&all[..over]).await {
+            Ok(()) => {
+                all.drain(..over);
+            }
+            Err(e) => log::error!("onchain: archiving spends failed: {}", e),
+        }
+    }
+    crate::utils::put_json(&spends_path(), &all).await;
+}
+
+pub async fn list_spends(node: Option<String>, limit: Option<usize>) -> Vec<SpendRecord> {
+    load_spends()
+        .await
+        .into_iter()
+        .rev()
+        .filter(|r| node.as_ref().map(|n| *n == r.node).unwrap_or(true))
+        .take(limit.unwrap_or(100))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send() -> LightningSend {
+        LightningSend {
+            address: "bcrt1qexample".to_string(),
+            amount_sat: Some(10_000),
+            sat_per_vbyte: None,
+            target_blocks: Some(6),
+            utxos: vec![format!("{}:0", "ab".repeat(32))],
+        }
+    }
+
+    #[test]
+    fn test_validate_send() {
+        assert!(send().validate().is_ok());
+        let both = LightningSend {
+            sat_per_vbyte: Some(5),
+            ..send()
+        };
+        assert!(both.validate().is_err());
+        let too_high = LightningSend {
+            sat_per_vbyte: Some(MAX_SAT_PER_VBYTE + 1),
+            target_blocks: None,
+            ..send()
+        };
+        assert!(too_high.validate().is_err());
+        let zero = LightningSend {
+            amount_sat: Some(0),
+            ..send()
+        };
+        assert!(zero.validate().is_err());
+        let bad_utxo = LightningSend {
+            utxos: vec!["abcd".to_string()],
+            ..send()
+        };
+        assert!(bad_utxo.validate().is_err());
+        let all = LightningSend {
+            amount_sat: None,
+            ..send()
+        };
+        assert!(all.validate().is_ok());
+    }
+
+    #[test]
+    fn test_take_pending() {
+        let spend = |id: &str, node: &str, expires_at: i64| PendingSpend {
+            id: id.to_string(),
+            node: node.to_string(),
+            action: SpendAction::PublishPsbt {
+                psbt: "cHNidP8B".to_string(),
+                outputs: Vec::new(),
+                fee_sat: None,
+            },
+            sat_per_vbyte: None,
+            requested_by: None,
+            expires_at,
+        };
+        let mut pending = HashMap::new();
+        pending.insert("a".to_string(), spend("a", "cln_1", 100));
+        pending.insert("b".to_string(), spend("b", "lnd_1", 200));
+        pending.insert("c".to_string(), spend("c", "cln_1", 200));
+        // expired
+        assert!(take_pending(&mut pending, "a", "cln_1", 150).is_err());
+        assert!(!pending.contains_key("a"));
+        // another node's
+        assert!(take_pending(&mut pending, "b", "cln_1", 150).is_err());
+        assert!(pending.contains_key("b"));
+        let c = take_pending(&mut pending, "c", "cln_1", 150).unwrap();
+        assert_eq!(c.node, "cln_1");
+        // only once
+        assert!(take_pending(&mut pending, "c", "cln_1", 150).is_err());
+        let v = serde_json::to_value(&c.action).unwrap();
+        assert_eq!(v["type"], "publish_psbt");
+    }
+
+    #[test]
+    fn test_decode_psbt() {
+        use bitcoin::{OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut};
+        // p2wpkh
+        let spk = Script::from([vec![0x00, 0x14], vec![1u8; 20]].concat());
+        let tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Default::default(),
+            }],
+            output: vec![TxOut {
+                value: 9_000,
+                script_pubkey: spk.clone(),
+            }],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let encode =
+            |p: &PartiallySignedTransaction| base64::encode(bitcoin::consensus::serialize(p));
+        // the inputs don't say what they spend
+        let (outputs, fee) = decode_psbt(&encode(&psbt), Network::Regtest).unwrap();
+        assert_eq!(outputs[0].amount_sat, 9_000);
+        assert!(outputs[0].address.starts_with("bcrt1q"));
+        assert_eq!(fee, None);
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: spk.clone(),
+        });
+        let (_, fee) = decode_psbt(&encode(&psbt), Network::Regtest).unwrap();
+        assert_eq!(fee, Some(1_000));
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 8_000,
+            script_pubkey: spk,
+        });
+        assert!(decode_psbt(&encode(&psbt), Network::Regtest).is_err());
+        assert!(decode_psbt("cHNidP8B", Network::Regtest).is_err());
+        assert!(decode_psbt("not base64!", Network::Regtest).is_err());
+    }
+}
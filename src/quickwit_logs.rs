@@ -0,0 +1,280 @@
+use crate::config::{self, Node};
+use crate::images::quickwit::QuickwitImage;
+use crate::images::Image;
+use crate::utils::{docker_domain, make_reqwest_client};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// the index QuickwitImage::post_startup creates
+const DEFAULT_INDEX: &str = "logs";
+const DEFAULT_MAX_HITS: u64 = 100;
+const MAX_HITS: u64 = 1000;
+// docker logs shipped by a vector forwarder carry the service here
+const DEFAULT_SERVICE_FIELD: &str = "container_name";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct QueryQuickwitLogsRequest {
+    // quickwit query language, e.g. "level:error AND message:timeout"
+    pub query: Option<String>,
+    pub service: Option<String>,
+    // unix seconds
+    pub start_timestamp: Option<i64>,
+    pub end_timestamp: Option<i64>,
+    pub max_hits: Option<u64>,
+    pub start_offset: Option<u64>,
+    // start from a saved query, fields set here override it
+    pub saved: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuickwitLogsResponse {
+    pub num_hits: u64,
+    pub hits: Vec<Value>,
+    pub elapsed_time_micros: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct QuickwitErrorCountsRequest {
+    pub start_timestamp: Option<i64>,
+    pub end_timestamp: Option<i64>,
+    // narrow to one service
+    pub service: Option<String>,
+    // field to group by, "container_name" by default
+    pub service_field: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorCount {
+    // start of the hour, unix seconds
+    pub hour: i64,
+    pub service: String,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedLogQuery {
+    pub name: String,
+    pub query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+}
+
+async fn find_quickwit() -> Result<QuickwitImage> {
+    config::stack_read(|s| {
+        s.nodes.iter().find_map(|n| match n {
+            Node::Internal(Image::Quickwit(q)) => Some(q.clone()),
+            _ => None,
+        })
+    })
+    .await
+    .ok_or(anyhow!("no quickwit node in this swarm"))
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn build_query(query: &Option<String>, service_field: &str, service: &Option<String>) -> String {
+    let q = query.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let svc = service
+        .as_deref()
+        .filter(|s| !s.is_empty())
+        .map(|s| format!("{}:{}", service_field, quote(s)));
+    match (q, svc) {
+        (Some(q), Some(s)) => format!("({}) AND {}", q, s),
+        (Some(q), None) => q.to_string(),
+        (None, Some(s)) => s,
+        (None, None) => "*".to_string(),
+    }
+}
+
+/// Fill unset fields of `req` from the saved query it names.
+pub fn apply_saved(req: QueryQuickwitLogsRequest, saved: &[SavedLogQuery]) -> Result<QueryQuickwitLogsRequest> {
+    let name = match &req.saved {
+        Some(n) => n,
+        None => return Ok(req),
+    };
+    let sq = saved
+        .iter()
+        .find(|s| &s.name == name)
+        .ok_or(anyhow!("no saved query named {}", name))?;
+    Ok(QueryQuickwitLogsRequest {
+        query: req.query.clone().or(sq.query.clone()),
+        service: req.service.clone().or(sq.service.clone()),
+        ..req
+    })
+}
+
+pub fn search_body(req: &QueryQuickwitLogsRequest) -> Value {
+    let mut body = json!({
+        "query": build_query(&req.query, DEFAULT_SERVICE_FIELD, &req.service),
+        "max_hits": req.max_hits.unwrap_or(DEFAULT_MAX_HITS).min(MAX_HITS),
+        "start_offset": req.start_offset.unwrap_or(0),
+        "sort_by": "-timestamp",
+    });
+    if let Some(s) = req.start_timestamp {
+        body["start_timestamp"] = json!(s);
+    }
+    if let Some(e) = req.end_timestamp {
+        body["end_timestamp"] = json!(e);
+    }
+    body
+}
+
+pub fn error_counts_body(req: &QuickwitErrorCountsRequest) -> Value {
+    let field = req.service_field.as_deref().unwrap_or(DEFAULT_SERVICE_FIELD);
+    let mut body = json!({
+        "query": build_query(&Some("level:error".to_string()), field, &req.service),
+        "max_hits": 0,
+        "aggs": {
+            "per_hour": {
+                "date_histogram": { "field": "timestamp", "fixed_interval": "1h" },
+                "aggs": {
+                    "per_service": { "terms": { "field": field, "size": 100 } }
+                }
+            }
+        }
+    });
+    if let Some(s) = req.start_timestamp {
+        body["start_timestamp"] = json!(s);
+    }
+    if let Some(e) = req.end_timestamp {
+        body["end_timestamp"] = json!(e);
+    }
+    body
+}
+
+// per_hour buckets are keyed by millis
+pub fn parse_error_counts(res: &Value) -> Vec<ErrorCount> {
+    let mut out = Vec::new();
+    let hours = res["aggregations"]["per_hour"]["buckets"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    for h in hours.iter() {
+        let hour = h["key"].as_f64().unwrap_or(0.0) as i64 / 1000;
+        for s in h["per_service"]["buckets"].as_array().into_iter().flatten() {
+            let service = match &s["key"] {
+                Value::String(k) => k.clone(),
+                k => k.to_string(),
+            };
+            out.push(ErrorCount {
+                hour,
+                service,
+                count: s["doc_count"].as_u64().unwrap_or(0),
+            });
+        }
+    }
+    out
+}
+
+async fn search(body: &Value) -> Result<Value> {
+    let qw = find_quickwit().await?;
+    let url = format!(
+        "http://{}:{}/api/v1/{}/search",
+        docker_domain(&qw.name),
+        qw.http_port,
+        DEFAULT_INDEX
+    );
+    let res = make_reqwest_client().post(&url).json(body).send().await?;
+    if !res.status().is_success() {
+        let status = res.status();
+        let text = res.text().await.unwrap_or_default();
+        return Err(anyhow!("quickwit search failed: {} {}", status, text));
+    }
+    Ok(res.json().await?)
+}
+
+pub async fn query_logs(req: QueryQuickwitLogsRequest) -> Result<QuickwitLogsResponse> {
+    let saved = config::stack_read(|s| s.saved_log_queries.clone().unwrap_or_default()).await;
+    let req = apply_saved(req, &saved)?;
+    let res = search(&search_body(&req)).await?;
+    Ok(QuickwitLogsResponse {
+        num_hits: res["num_hits"].as_u64().unwrap_or(0),
+        hits: res["hits"].as_array().cloned().unwrap_or_default(),
+        elapsed_time_micros: res["elapsed_time_micros"].as_u64().unwrap_or(0),
+    })
+}
+
+pub async fn error_counts(req: QuickwitErrorCountsRequest) -> Result<Vec<ErrorCount>> {
+    let res = search(&error_counts_body(&req)).await?;
+    Ok(parse_error_counts(&res))
+}
+
+pub async fn save_query(proj: &str, sq: SavedLogQuery) -> Result<Vec<SavedLogQuery>> {
+    if sq.name.is_empty() {
+        return Err(anyhow!("saved query needs a name"));
+    }
+    Ok(config::stack_write(proj, |s| {
+        let saved = s.saved_log_queries.get_or_insert(Vec::new());
+        // saving under an existing name replaces it
+        saved.retain(|q| q.name != sq.name);
+        saved.push(sq);
+        saved.clone()
+    })
+    .await)
+}
+
+pub async fn delete_query(proj: &str, name: &str) -> Result<Vec<SavedLogQuery>> {
+    Ok(config::stack_write(proj, |s| {
+        let saved = s.saved_log_queries.get_or_insert(Vec::new());
+        saved.retain(|q| q.name != name);
+        saved.clone()
+    })
+    .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_query() {
+        let svc = Some("boltwall.sphinx".to_string());
+        assert_eq!(build_query(&None, "container_name", &None), "*");
+        assert_eq!(
+            build_query(&Some("level:error".to_string()), "container_name", &svc),
+            "(level:error) AND container_name:\"boltwall.sphinx\""
+        );
+    }
+
+    #[test]
+    fn test_apply_saved() {
+        let saved = vec![SavedLogQuery {
+            name: "l402".to_string(),
+            query: Some("message:402".to_string()),
+            service: Some("boltwall".to_string()),
+        }];
+        let req = QueryQuickwitLogsRequest {
+            saved: Some("l402".to_string()),
+            service: Some("jarvis".to_string()),
+            ..Default::default()
+        };
+        let req = apply_saved(req, &saved).unwrap();
+        assert_eq!(req.query, Some("message:402".to_string()));
+        assert_eq!(req.service, Some("jarvis".to_string()));
+        let missing = QueryQuickwitLogsRequest {
+            saved: Some("nope".to_string()),
+            ..Default::default()
+        };
+        assert!(apply_saved(missing, &saved).is_err());
+    }
+
+    #[test]
+    fn test_parse_error_counts() {
+        let res = json!({
+            "aggregations": {"per_hour": {"buckets": [
+                {"key": 1714557600000.0, "doc_count": 3, "per_service": {"buckets": [
+                    {"key": "boltwall", "doc_count": 2},
+                    {"key": "jarvis", "doc_count": 1}
+                ]}}
+            ]}}
+        });
+        let got = parse_error_counts(&res);
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0].hour, 1714557600);
+        assert_eq!(got[0].service, "boltwall");
+        assert_eq!(got[1].count, 1);
+    }
+}
@@ -0,0 +1,711 @@
+use crate::cmd::Rebalance;
+use crate::config;
+use crate::conn::cln::ClnRPC;
+use crate::conn::lightning::{Channel, ChannelState, LightningNode};
+use crate::images::Image;
+use crate::metrics;
+use crate::utils::getenv;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use cln_grpc::pb;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Mutex;
+use tokio_cron_scheduler::{Job, JobScheduler};
+
+const PROJ: &str = "stack";
+const MAX_RECORDS: usize = 1000;
+const DAY_SECS: i64 = 24 * 60 * 60;
+// cltv of the last hop back to us
+const FINAL_CLTV: u32 = 18;
+// each retry avoids the middle of the routes that failed before
+const MAX_ATTEMPTS: usize = 3;
+const WAIT_SECS: u32 = 60;
+// pending ones this old aren't being waited on anymore
+const RECONCILE_AFTER_SECS: i64 = 60 * 60;
+
+pub static AUTO_REBALANCE: AtomicBool = AtomicBool::new(false);
+
+static RECORDS_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Keeps every active channel of a CLN node inside a balance band, moving
+/// it back to 50/50 when it leaves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RebalancePolicy {
+    // local share of each channel, 0.0 to 1.0
+    pub min_local_ratio: f64,
+    pub max_local_ratio: f64,
+    pub max_fee_ppm: u64,
+    // fees over all rebalances in 24h
+    pub daily_fee_budget_msat: u64,
+    // largest single rebalance
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_amount_msat: Option<u64>,
+}
+
+impl RebalancePolicy {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.min_local_ratio)
+            || !(0.0..=1.0).contains(&self.max_local_ratio)
+        {
+            return Err(anyhow!("ratios must be between 0.0 and 1.0"));
+        }
+        if self.min_local_ratio >= self.max_local_ratio {
+            return Err(anyhow!("min_local_ratio must be below max_local_ratio"));
+        }
+        if self.max_fee_ppm == 0 {
+            return Err(anyhow!("max_fee_ppm must be above 0"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RebalanceRecord {
+    pub node: String,
+    // unix seconds
+    pub at: i64,
+    pub from_channel: String,
+    pub to_channel: String,
+    pub amount_msat: u64,
+    // the most it may cost while pending, so it counts against the budget
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fee_msat: Option<u64>,
+    // started by the auto-rebalancer
+    pub auto: bool,
+    // recorded before paying, a payment can land after we stop waiting
+    #[serde(default)]
+    pub pending: bool,
+    // hex, to look the payment up again while it's pending
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub payment_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateRebalancePolicyRequest {
+    pub node: String,
+    // none turns the auto-rebalancer off for the node
+    pub policy: Option<RebalancePolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListRebalancesRequest {
+    pub node: Option<String>,
+    // newest first, 100 by default
+    pub limit: Option<usize>,
+}
+
+/// What listsendpays says about a payment: its fee once complete, or
+/// whether a part may still land.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendState {
+    Complete(u64),
+    Pending,
+    Failed,
+}
+
+pub fn send_state(payments: &[pb::ListsendpaysPayments]) -> SendState {
+    use pb::listsendpays_payments::ListsendpaysPaymentsStatus;
+    let mut state = SendState::Failed;
+    for p in payments.iter() {
+        match p.status() {
+            ListsendpaysPaymentsStatus::Complete => {
+                let amount = p.amount_msat.as_ref().map(|a| a.msat).unwrap_or(0);
+                let sent = p
+                    .amount_sent_msat
+                    .as_ref()
+                    .map(|a| a.msat)
+                    .unwrap_or(amount);
+                return SendState::Complete(sent.saturating_sub(amount));
+            }
+            ListsendpaysPaymentsStatus::Pending => state = SendState::Pending,
+            ListsendpaysPaymentsStatus::Failed => (),
+        }
+    }
+    state
+}
+
+async fn sendpay_state(cln: &ClnRPC, payment_hash: &str) -> Result<SendState> {
+    let res = cln.list_pays(Some(payment_hash.to_string())).await?;
+    Ok(send_state(&res.payments))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    pub from_channel: String,
+    pub to_channel: String,
+    pub amount_msat: u64,
+}
+
+/// The direction bit of a channel half from `from` to `to`: 0 when the
+/// sender has the lesser node id.
+pub fn direction(from: &[u8], to: &[u8]) -> u32 {
+    if from < to {
+        0
+    } else {
+        1
+    }
+}
+
+pub fn fee_ppm(fee_msat: u64, amount_msat: u64) -> u64 {
+    if amount_msat == 0 {
+        return 0;
+    }
+    fee_msat.saturating_mul(1_000_000) / amount_msat
+}
+
+/// Pair the channels over the band with the ones under it, biggest first,
+/// moving each towards 50/50.
+pub fn plan_moves(channels: &[Channel], policy: &RebalancePolicy) -> Vec<Move> {
+    let mut sources: Vec<(String, u64)> = Vec::new();
+    let mut sinks: Vec<(String, u64)> = Vec::new();
+    for c in channels.iter().filter(|c| c.state == ChannelState::Active) {
+        let scid = match &c.short_channel_id {
+            Some(s) => s.clone(),
+            None => continue,
+        };
+        let total = c.local_msat + c.remote_msat;
+        if total == 0 {
+            continue;
+        }
+        let ratio = c.local_msat as f64 / total as f64;
+        if ratio > policy.max_local_ratio {
+            sources.push((scid, c.local_msat - total / 2));
+        } else if ratio < policy.min_local_ratio {
+            sinks.push((scid, total / 2 - c.local_msat));
+        }
+    }
+    sources.sort_by_key(|s| std::cmp::Reverse(s.1));
+    sinks.sort_by_key(|s| std::cmp::Reverse(s.1));
+    let mut moves = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < sources.len() && j < sinks.len() {
+        let amt = sources[i]
+            .1
+            .min(sinks[j].1)
+            .min(policy.max_amount_msat.unwrap_or(u64::MAX));
+        if amt > 0 {
+            moves.push(Move {
+                from_channel: sources[i].0.clone(),
+                to_channel: sinks[j].0.clone(),
+                amount_msat: amt,
+            });
+        }
+        // one move per channel a run, the next run sees the new balances
+        i += 1;
+        j += 1;
+    }
+    moves
+}
+
+fn active_channel<'a>(chans: &'a [Channel], scid: &str) -> Result<&'a Channel> {
+    chans
+        .iter()
+        .find(|c| c.short_channel_id.as_deref() == Some(scid))
+        .filter(|c| c.state == ChannelState::Active)
+        .context(format!("no active channel {}", scid))
+}
+
+/// Move `amount_msat` out of `from_channel` and back in over `to_channel`
+/// by paying ourselves `inv`. Returns the fee paid.
+pub async fn circular_pay(cln: &ClnRPC, req: &Rebalance, inv: &pb::InvoiceResponse) -> Result<u64> {
+    let (from, to, amt) = (&req.from_channel, &req.to_channel, req.amount_msat);
+    if from == to {
+        return Err(anyhow!("from and to are the same channel"));
+    }
+    let us = ClnRPC::get_info(cln).await?.id;
+    let chans = LightningNode::list_channels(cln).await?;
+    let from_chan = active_channel(&chans, from)?;
+    let to_chan = active_channel(&chans, to)?;
+    if from_chan.local_msat < amt {
+        return Err(anyhow!("{} has {} msat local", from, from_chan.local_msat));
+    }
+    if to_chan.remote_msat < amt {
+        return Err(anyhow!("{} has {} msat remote", to, to_chan.remote_msat));
+    }
+
+    // the to_channel peer pays us on the last hop
+    let last_peer = hex::decode(&to_chan.peer)?;
+    let gossip = cln.channel_gossip(to).await?;
+    let last = gossip
+        .channels
+        .iter()
+        .find(|c| c.source == last_peer)
+        .context(format!("no fee policy of {} towards us", to))?;
+    let last_fee =
+        last.base_fee_millisatoshi as u64 + amt * last.fee_per_millionth as u64 / 1_000_000;
+
+    // leave only over from_channel, and never come back through us early
+    let first_peer = hex::decode(&from_chan.peer)?;
+    let mut exclude: Vec<String> = chans
+        .iter()
+        .filter_map(|c| c.short_channel_id.clone())
+        .filter(|s| s != from)
+        .flat_map(|s| [format!("{}/0", s), format!("{}/1", s)])
+        .collect();
+    exclude.push(format!("{}/{}", from, direction(&first_peer, &us)));
+
+    let mut last_err = anyhow!("no route");
+    for attempt in 0..MAX_ATTEMPTS {
+        let route = cln
+            .get_route_excluding(
+                &to_chan.peer,
+                amt + last_fee,
+                last.delay + FINAL_CLTV,
+                exclude.clone(),
+            )
+            .await?
+            .route;
+        let sent = route
+            .first()
+            .filter(|h| h.channel == *from)
+            .and_then(|h| h.amount_msat.as_ref())
+            .context("route does not start with from_channel")?
+            .msat;
+        let fee = sent.saturating_sub(amt);
+        if fee_ppm(fee, amt) > req.max_fee_ppm {
+            return Err(anyhow!(
+                "fee {} ppm is over the {} max",
+                fee_ppm(fee, amt),
+                req.max_fee_ppm
+            ));
+        }
+        let mut hops: Vec<pb::SendpayRoute> = route
+            .iter()
+            .map(|h| pb::SendpayRoute {
+                amount_msat: h.amount_msat.clone(),
+                id: h.id.clone(),
+                delay: h.delay,
+                channel: h.channel.clone(),
+            })
+            .collect();
+        hops.push(pb::SendpayRoute {
+            amount_msat: Some(pb::Amount { msat: amt }),
+            id: us.clone(),
+            delay: FINAL_CLTV,
+            channel: to.clone(),
+        });
+        log::info!(
+            "rebalance attempt {}: {} hops, fee {} msat",
+            attempt,
+            hops.len(),
+            fee
+        );
+        cln.send_pay(
+            hops,
+            inv.payment_hash.clone(),
+            inv.payment_secret.clone(),
+            amt,
+        )
+        .await?;
+        match cln.wait_send_pay(inv.payment_hash.clone(), WAIT_SECS).await {
+            Ok(_) => return Ok(fee),
+            Err(e) => last_err = e,
+        }
+        // no other route while this one may still land
+        if sendpay_state(cln, &hex::encode(&inv.payment_hash)).await? != SendState::Failed {
+            return Err(last_err);
+        }
+        if route.len() < 2 {
+            break;
+        }
+        exclude.extend(
+            route[1..]
+                .iter()
+                .map(|h| format!("{}/{}", h.channel, h.direction)),
+        );
+    }
+    Err(last_err)
+}
+
+fn records_path() -> String {
+    format!("vol/{}/rebalances.json", PROJ)
+}
+
+pub async fn load_records() -> Vec<RebalanceRecord> {
+    crate::utils::load_json(&records_path(), Vec::new()).await
+}
+
+async fn record(rec: &RebalanceRecord) {
+    let _lock = RECORDS_LOCK.lock().await;
+    let mut recs = load_records().await;
+    recs.push(rec.clone());
+    let skip = recs.len().saturating_sub(MAX_RECORDS);
+    let recs: Vec<RebalanceRecord> = recs.into_iter().skip(skip).collect();
+    crate::utils::put_json(&records_path(), &recs).await;
+}
+
+// the outcome of a rebalance that was recorded as pending
+async fn settle(rec: &RebalanceRecord) {
+    let _lock = RECORDS_LOCK.lock().await;
+    let mut recs = load_records().await;
+    let found = recs.iter_mut().rev().find(|r| {
+        r.pending && r.node == rec.node && r.at == rec.at && r.from_channel == rec.from_channel
+    });
+    if let Some(r) = found {
+        *r = rec.clone();
+        crate::utils::put_json(&records_path(), &recs).await;
+    }
+}
+
+pub async fn list_records(node: Option<String>, limit: Option<usize>) -> Vec<RebalanceRecord> {
+    load_records()
+        .await
+        .into_iter()
+        .rev()
+        .filter(|r| node.as_ref().map(|n| *n == r.node).unwrap_or(true))
+        .take(limit.unwrap_or(100))
+        .collect()
+}
+
+pub fn fees_since(recs: &[RebalanceRecord], node: &str, since: i64) -> u64 {
+    recs.iter()
+        .filter(|r| r.node == node && r.at >= since)
+        .filter_map(|r| r.fee_msat)
+        .sum()
+}
+
+async fn start(node: &str, req: &Rebalance, auto: bool) -> RebalanceRecord {
+    let rec = RebalanceRecord {
+        node: node.to_string(),
+        at: Utc::now().timestamp(),
+        from_channel: req.from_channel.clone(),
+        to_channel: req.to_channel.clone(),
+        amount_msat: req.amount_msat,
+        fee_msat: Some(req.amount_msat * req.max_fee_ppm / 1_000_000),
+        auto,
+        pending: true,
+        payment_hash: None,
+        error: None,
+    };
+    record(&rec).await;
+    rec
+}
+
+// fill in what is known of the payment, a pending one keeps its worst case fee
+fn resolve(rec: &mut RebalanceRecord, state: SendState) {
+    match state {
+        SendState::Complete(fee) => {
+            rec.pending = false;
+            rec.fee_msat = Some(fee);
+            rec.error = None;
+        }
+        SendState::Failed => {
+            rec.pending = false;
+            rec.fee_msat = None;
+        }
+        SendState::Pending => (),
+    }
+}
+
+async fn finish(cln: &ClnRPC, mut rec: RebalanceRecord, req: &Rebalance) -> RebalanceRecord {
+    let res = match ClnRPC::create_invoice(cln, req.amount_msat).await {
+        Ok(inv) => {
+            rec.payment_hash = Some(hex::encode(&inv.payment_hash));
+            circular_pay(cln, req, &inv).await
+        }
+        Err(e) => Err(e),
+    };
+    let state = match res {
+        Ok(fee) => SendState::Complete(fee),
+        Err(e) => {
+            rec.error = Some(e.to_string());
+            // a timeout or a failed lookup says nothing about the payment
+            match &rec.payment_hash {
+                Some(h) => sendpay_state(cln, h).await.unwrap_or(SendState::Pending),
+                None => SendState::Failed,
+            }
+        }
+    };
+    resolve(&mut rec, state);
+    log::info!("rebalance {}: {:?}", rec.node, rec);
+    settle(&rec).await;
+    rec
+}
+
+/// Settle the node's rebalances left pending by earlier runs with what
+/// listsendpays says about them now.
+async fn reconcile(cln: &ClnRPC, node: &str) {
+    let before = Utc::now().timestamp() - RECONCILE_AFTER_SECS;
+    let pending: Vec<RebalanceRecord> = load_records()
+        .await
+        .into_iter()
+        .filter(|r| r.pending && r.node == node && r.at < before)
+        .collect();
+    for mut rec in pending {
+        let hash = match &rec.payment_hash {
+            Some(h) => h.clone(),
+            None => continue,
+        };
+        match sendpay_state(cln, &hash).await {
+            Ok(SendState::Pending) => (),
+            Ok(state) => {
+                resolve(&mut rec, state);
+                log::info!("rebalance {}: settled {:?}", node, rec);
+                settle(&rec).await;
+            }
+            Err(e) => log::warn!("rebalance {}: can't look up {}: {}", node, hash, e),
+        }
+    }
+}
+
+async fn run(cln: &ClnRPC, node: &str, req: &Rebalance, auto: bool) -> RebalanceRecord {
+    let rec = start(node, req, auto).await;
+    finish(cln, rec, req).await
+}
+
+/// ClnCmd::Rebalance, recorded like the automatic ones. The payment runs
+/// in the background, this returns the pending record.
+pub async fn rebalance(cln: ClnRPC, node: &str, req: Rebalance) -> Result<RebalanceRecord> {
+    if req.from_channel == req.to_channel {
+        return Err(anyhow!("from and to are the same channel"));
+    }
+    let rec = start(node, &req, false).await;
+    let pending = rec.clone();
+    tokio::spawn(async move {
+        finish(&cln, pending, &req).await;
+    });
+    Ok(rec)
+}
+
+/// Run the moves `policy` calls for while the day's fee budget lasts.
+/// A move only starts if its worst case fee still fits.
+pub async fn auto_rebalance_node(
+    cln: &ClnRPC,
+    node: &str,
+    policy: &RebalancePolicy,
+) -> Result<Vec<RebalanceRecord>> {
+    reconcile(cln, node).await;
+    let now = Utc::now().timestamp();
+    let mut spent = fees_since(&load_records().await, node, now - DAY_SECS);
+    let chans = LightningNode::list_channels(cln).await?;
+    let mut recs = Vec::new();
+    for m in plan_moves(&chans, policy) {
+        let worst = m.amount_msat * policy.max_fee_ppm / 1_000_000;
+        if spent + worst > policy.daily_fee_budget_msat {
+            log::info!(
+                "rebalance {}: fee budget left {} msat, skip {:?}",
+                node,
+                policy.daily_fee_budget_msat.saturating_sub(spent),
+                m
+            );
+            continue;
+        }
+        let req = Rebalance {
+            from_channel: m.from_channel,
+            to_channel: m.to_channel,
+            amount_msat: m.amount_msat,
+            max_fee_ppm: policy.max_fee_ppm,
+        };
+        let rec = run(cln, node, &req, true).await;
+        spent += rec.fee_msat.unwrap_or(0);
+        recs.push(rec);
+    }
+    Ok(recs)
+}
+
+/// Set or remove the auto-rebalance policy of a CLN node in the stack.
+pub async fn update_policy(proj: &str, req: &UpdateRebalancePolicyRequest) -> Result<()> {
+    if let Some(p) = &req.policy {
+        p.validate()?;
+    }
+    let img = config::stack_read(|s| crate::builder::find_img(&req.node, &s.nodes)).await?;
+    if !matches!(img, Image::Cln(_)) {
+        return Err(anyhow!("{} is not a cln node", req.node));
+    }
+    config::stack_write(proj, |s| {
+        let mut policies: BTreeMap<String, RebalancePolicy> =
+            s.rebalance.take().unwrap_or_default();
+        match &req.policy {
+            Some(p) => {
+                policies.insert(req.node.clone(), p.clone());
+            }
+            None => {
+                policies.remove(&req.node);
+            }
+        }
+        s.rebalance = (!policies.is_empty()).then_some(policies);
+    })
+    .await;
+    Ok(())
+}
+
+async fn auto_rebalance_all() -> Result<()> {
+    let policies = config::stack_read(|s| s.rebalance.clone().unwrap_or_default()).await;
+    let mut errs = Vec::new();
+    for (node, policy) in policies.iter() {
+        let cln = match config::clients_read(|c| c.cln.get(node).cloned()).await {
+            Some(c) => c,
+            None => {
+                errs.push(format!("{}: no cln client", node));
+                continue;
+            }
+        };
+        if let Err(e) = auto_rebalance_node(&cln, node, policy).await {
+            errs.push(format!("{}: {}", node, e));
+        }
+    }
+    if errs.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(errs.join("\n")))
+}
+
+pub async fn rebalance_cron() -> Result<JobScheduler> {
+    log::info!("=> start rebalance cron");
+    let sched = JobScheduler::new().await?;
+
+    let cron_time = getenv("REBALANCE_CRON_TIME").unwrap_or("0 30 * * * *".to_string());
+
+    sched
+        .add(Job::new_async(cron_time.as_str(), |_uuid, _l| {
+            Box::pin(async move {
+                if !AUTO_REBALANCE.load(Ordering::Relaxed) {
+                    AUTO_REBALANCE.store(true, Ordering::Relaxed);
+                }
+            })
+        })?)
+        .await?;
+
+    sched.start().await?;
+
+    tokio::spawn(async move {
+        loop {
+            if AUTO_REBALANCE.load(Ordering::Relaxed) {
+                let res = auto_rebalance_all().await;
+                metrics::record_job("rebalance", res.is_ok());
+                if let Err(e) = res {
+                    log::error!("Error auto rebalancing: {:?}", e);
+                }
+                AUTO_REBALANCE.store(false, Ordering::Relaxed);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
+    Ok(sched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chan(scid: &str, local_sat: u64, remote_sat: u64) -> Channel {
+        Channel {
+            id: scid.to_string(),
+            short_channel_id: Some(scid.to_string()),
+            peer: "02aa".to_string(),
+            capacity_sat: local_sat + remote_sat,
+            local_msat: local_sat * 1000,
+            remote_msat: remote_sat * 1000,
+            state: ChannelState::Active,
+            private: false,
+        }
+    }
+
+    #[test]
+    fn test_plan_moves() {
+        let policy = RebalancePolicy {
+            min_local_ratio: 0.2,
+            max_local_ratio: 0.8,
+            max_fee_ppm: 500,
+            daily_fee_budget_msat: 10_000,
+            max_amount_msat: Some(300_000_000),
+        };
+        let chans = vec![
+            chan("1x1x0", 900_000, 100_000),
+            chan("2x1x0", 100_000, 900_000),
+            chan("3x1x0", 500_000, 500_000),
+            chan("4x1x0", 95_000, 5_000),
+            chan("5x1x0", 10_000, 90_000),
+        ];
+        let moves = plan_moves(&chans, &policy);
+        assert_eq!(
+            moves,
+            vec![
+                // capped at max_amount_msat
+                Move {
+                    from_channel: "1x1x0".to_string(),
+                    to_channel: "2x1x0".to_string(),
+                    amount_msat: 300_000_000,
+                },
+                Move {
+                    from_channel: "4x1x0".to_string(),
+                    to_channel: "5x1x0".to_string(),
+                    amount_msat: 40_000_000,
+                },
+            ]
+        );
+        assert!(policy.validate().is_ok());
+        let bad = RebalancePolicy {
+            min_local_ratio: 0.8,
+            ..policy
+        };
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn test_direction_and_fees() {
+        assert_eq!(direction(&[2, 1], &[3, 0]), 0);
+        assert_eq!(direction(&[3, 0], &[2, 1]), 1);
+        assert_eq!(fee_ppm(500, 1_000_000), 500);
+        assert_eq!(fee_ppm(1, 0), 0);
+        let rec = |at: i64, fee: Option<u64>| RebalanceRecord {
+            node: "cln".to_string(),
+            at,
+            from_channel: "1x1x0".to_string(),
+            to_channel: "2x1x0".to_string(),
+            amount_msat: 1000,
+            fee_msat: fee,
+            auto: true,
+            pending: false,
+            payment_hash: None,
+            error: None,
+        };
+        let recs = vec![rec(5, Some(7)), rec(20, Some(3)), rec(30, None)];
+        assert_eq!(fees_since(&recs, "cln", 10), 3);
+    }
+
+    #[test]
+    fn test_send_state() {
+        use pb::listsendpays_payments::ListsendpaysPaymentsStatus;
+        let part = |status: ListsendpaysPaymentsStatus, sent: u64| pb::ListsendpaysPayments {
+            status: status as i32,
+            amount_msat: Some(pb::Amount { msat: 1000 }),
+            amount_sent_msat: Some(pb::Amount { msat: sent }),
+            ..Default::default()
+        };
+        assert_eq!(send_state(&[]), SendState::Failed);
+        let failed = part(ListsendpaysPaymentsStatus::Failed, 1010);
+        let pending = part(ListsendpaysPaymentsStatus::Pending, 1020);
+        let complete = part(ListsendpaysPaymentsStatus::Complete, 1020);
+        assert_eq!(send_state(std::slice::from_ref(&failed)), SendState::Failed);
+        assert_eq!(send_state(&[failed.clone(), pending]), SendState::Pending);
+        assert_eq!(send_state(&[failed, complete]), SendState::Complete(20));
+
+        // a timed out payment keeps counting its worst case fee
+        let mut rec = RebalanceRecord {
+            node: "cln".to_string(),
+            at: 0,
+            from_channel: "1x1x0".to_string(),
+            to_channel: "2x1x0".to_string(),
+            amount_msat: 1000,
+            fee_msat: Some(50),
+            auto: false,
+            pending: true,
+            payment_hash: Some("00".to_string()),
+            error: Some("timed out".to_string()),
+        };
+        resolve(&mut rec, SendState::Pending);
+        assert_eq!((rec.pending, rec.fee_msat), (true, Some(50)));
+        resolve(&mut rec, SendState::Complete(20));
+        assert_eq!(
+            (rec.pending, rec.fee_msat, rec.error),
+            (false, Some(20), None)
+        );
+    }
+}
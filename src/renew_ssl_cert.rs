@@ -1,6 +1,7 @@
 use crate::{
     config,
     conn::swarm::{SwarmRestarterRes, UpdateSslCertSwarmBody},
+    metrics,
     utils::{getenv, is_using_port_based_ssl},
 };
 use anyhow::{anyhow, Error, Result};
@@ -34,7 +35,9 @@ pub async fn upload_new_ssl_cert_cron() -> Result<JobScheduler> {
         loop {
             let go = CHECK_SSL_CERT.load(Ordering::Relaxed);
             if go {
-                if let Err(e) = handle_update_ssl_cert("stack").await {
+                let res = handle_update_ssl_cert("stack").await;
+                metrics::record_job("ssl_cert", res.is_ok());
+                if let Err(e) = res {
                     log::error!("Checking for SSL CERT: {:?}", e);
                 }
 
@@ -5,13 +5,17 @@ use crate::cmd::UpdateAdminPubkeyInfo;
 use crate::cmd::{ChangeAdminInfo, ChangePasswordInfo, Cmd, LoginInfo, SwarmCmd};
 use crate::events::{get_event_tx, EventChan};
 use crate::handler;
-use crate::logs::{get_log_tx, LogChans, LOGS};
+use crate::lnurl;
+use crate::log_search::{follow_logs, LogStream};
+use crate::logs::{get_log_tx, LogChans};
+use crate::metrics;
 use crate::rocket_utils::{Error, Result, CORS};
 use bollard::Docker;
 use fs::{relative, FileServer};
+use futures_util::StreamExt;
 use response::stream::{Event, EventStream};
 use rocket::serde::{
-    json::{json, Json},
+    json::{json, Json, Value},
     Deserialize, Serialize,
 };
 use rocket::*;
@@ -31,6 +35,7 @@ pub async fn launch_rocket(
 ) -> Result<Rocket<Ignite>> {
     Ok(rocket::build()
         .mount("/", FileServer::from(relative!("app/dist")))
+        .mount("/", routes![lnurlp, lnurlp_callback])
         .mount(
             "/api/",
             routes![
@@ -68,12 +73,14 @@ pub async fn launch_rocket_direct(
 ) -> Result<Rocket<Ignite>> {
     Ok(rocket::build()
         .mount("/", FileServer::from(relative!("app/dist")))
+        .mount("/", routes![stack_metrics, lnurlp, lnurlp_callback])
         .mount(
             "/api/",
             routes![
                 cmd,
                 logs,
                 logstream,
+                logsearch_stream,
                 login,
                 refresh_jwt,
                 all_options,
@@ -111,18 +118,50 @@ fn fmt_err(err: &str) -> String {
 /// Call handle() directly with a timeout. Returns the JSON response string.
 async fn call_handle(proj: &str, docker: &Docker, tag: &str, txt: &str, user_id: Option<u32>) -> Result<String> {
     let cmd: Cmd = serde_json::from_str(txt)?;
-    match tokio::time::timeout(
+    let (typ, name) = metrics::cmd_labels(&cmd);
+    let start = std::time::Instant::now();
+    let res = tokio::time::timeout(
         Duration::from_secs(timeout_secs()),
         handler::handle(proj, cmd, tag, docker, &user_id),
     )
-    .await
-    {
-        Ok(Ok(res)) => Ok(res),
+    .await;
+    let secs = start.elapsed().as_secs_f64();
+    match res {
+        Ok(Ok(res)) => {
+            metrics::record_cmd(&typ, &name, secs, true);
+            Ok(res)
+        }
         Ok(Err(err)) => {
             ::log::warn!("handle ERR {:?}", err);
+            metrics::record_cmd(&typ, &name, secs, false);
             Ok(fmt_err(&err.to_string()))
         }
-        Err(_) => Ok(fmt_err("Handle operation timed out")),
+        Err(_) => {
+            metrics::record_cmd(&typ, &name, secs, false);
+            Ok(fmt_err("Handle operation timed out"))
+        }
+    }
+}
+
+#[get("/metrics")]
+pub async fn stack_metrics(docker: &State<Docker>, _auth: metrics::MetricsAuth) -> String {
+    metrics::render_stack(docker.inner()).await
+}
+
+// lightning address lookup, public like the callback
+#[get("/.well-known/lnurlp/<username>")]
+pub async fn lnurlp(username: &str) -> Json<Value> {
+    match lnurl::pay_request(username).await {
+        Ok(pr) => Json(json!(pr)),
+        Err(e) => Json(lnurl::error_json(e)),
+    }
+}
+
+#[get("/lnurlp/<username>/callback?<amount>&<comment>")]
+pub async fn lnurlp_callback(username: &str, amount: u64, comment: Option<&str>) -> Json<Value> {
+    match lnurl::callback(username, amount, comment).await {
+        Ok(pr) => Json(json!({ "pr": pr, "routes": [] })),
+        Err(e) => Json(lnurl::error_json(e)),
     }
 }
 
@@ -142,8 +181,13 @@ pub async fn cmd(
     call_handle(&proj.0, docker.inner(), tag, txt, Some(claims.user)).await
 }
 
+// lightning events carry preimages and invoices, so admins only
 #[get("/events")]
-pub async fn events(event_tx: &State<Arc<Mutex<EventChan>>>, mut end: Shutdown) -> EventStream![] {
+pub async fn events(
+    event_tx: &State<Arc<Mutex<EventChan>>>,
+    mut end: Shutdown,
+    _claims: auth::AdminJwtClaims,
+) -> EventStream![] {
     let event_tx = get_event_tx(event_tx).await;
     let mut rx = event_tx.subscribe();
     EventStream! {
@@ -162,10 +206,19 @@ pub async fn events(event_tx: &State<Arc<Mutex<EventChan>>>, mut end: Shutdown)
     }
 }
 
-#[get("/logs?<tag>")]
-pub async fn logs(tag: &str) -> Result<String> {
-    let lgs = LOGS.lock().await;
-    let ret = lgs.get(tag).unwrap_or(&Vec::new()).clone();
+// from and to are unix millis
+#[get("/logs?<tag>&<from>&<to>&<limit>")]
+pub async fn logs(
+    tag: &str,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<usize>,
+) -> Result<String> {
+    let ret: Vec<String> = crate::logs::query(tag, from, to, limit)
+        .await
+        .into_iter()
+        .map(|l| l.text)
+        .collect();
     Ok(json!(ret).to_string())
 }
 
@@ -193,6 +246,44 @@ pub async fn logstream(
     }
 }
 
+// live SearchLogs: containers is comma separated, stream is stdout, stderr or both
+#[get("/logsearch/stream?<containers>&<filter>&<regex>&<stream>")]
+pub async fn logsearch_stream(
+    docker: &State<Docker>,
+    mut end: Shutdown,
+    containers: &str,
+    filter: Option<String>,
+    regex: Option<bool>,
+    stream: Option<&str>,
+    _claims: auth::AdminJwtClaims,
+) -> Result<EventStream![]> {
+    let stream = match stream {
+        Some("stdout") => LogStream::Stdout,
+        Some("stderr") => LogStream::Stderr,
+        _ => LogStream::Both,
+    };
+    let containers = containers
+        .split(',')
+        .filter(|c| !c.is_empty())
+        .map(|c| c.to_string())
+        .collect();
+    let mut entries =
+        follow_logs(docker.inner(), containers, filter, regex.unwrap_or(false), stream)?;
+    Ok(EventStream! {
+        loop {
+            let entry = tokio::select! {
+                e = entries.next() => match e {
+                    Some(e) => e,
+                    None => break,
+                },
+                _ = &mut end => break,
+            };
+
+            yield Event::json(&entry);
+        }
+    })
+}
+
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct LoginData {
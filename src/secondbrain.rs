@@ -51,6 +51,17 @@ pub fn only_second_brain(network: &str, host: Option<String>, lightning_provider
         lightning_peers: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
 
@@ -232,6 +243,17 @@ pub fn default_local_stack(host: Option<String>, network: &str, nodes: Vec<Node>
         lightning_peers: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
 
@@ -269,5 +291,16 @@ pub fn only_logs(host: Option<String>) -> Stack {
         lightning_peers: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
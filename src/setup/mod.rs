@@ -1,75 +1,64 @@
-use crate::cmd::PayKeysend;
 use crate::config::{Clients, Node};
+use crate::conn::lightning::{
+    lightning_client, lightning_node, ChannelState, InvoiceStatus, LightningNode,
+};
 use crate::dock::*;
-use crate::utils::domain;
-use anyhow::Result;
-
-const LND_TLV_LEN: usize = 1944;
-
-pub async fn get_pubkey_cln(clients: &mut Clients, node_id: &str) -> Result<String> {
-    let client = clients.cln.get(node_id).unwrap();
-    let info = client.get_info().await?;
-    let pubkey = hex::encode(info.id);
-    Ok(pubkey)
-}
-
-pub async fn get_pubkey_lnd(clients: &mut Clients, node_id: &str) -> Result<String> {
-    let lnd1 = clients.lnd.get(node_id).unwrap().clone();
-    let lnd1_info = lnd1.lock().await.get_info().await?;
-    Ok(lnd1_info.identity_pubkey)
+use crate::images::Image;
+use crate::liquidity::{evaluate_node, LiquidityDecision, LiquidityPolicy, TargetPeer};
+use crate::lnurl::{
+    base_url, update_address, LightningAddress, PayRequest, UpdateLightningAddressRequest,
+};
+use crate::utils::{domain, make_reqwest_client};
+use anyhow::{anyhow, Context, Result};
+use std::sync::Arc;
+
+// largest keysend tlv each implementation accepts
+const CLN_TLV_LEN: usize = 1124; // (1207 ok, 1208 not) 603 bytes max
+const LND_TLV_LEN: usize = 1944; // (1124 ok, 1224 not)
+
+fn node(clients: &Clients, node_id: &str) -> Result<Arc<dyn LightningNode>> {
+    lightning_node(clients, node_id).context(format!("no lightning node {}", node_id))
 }
 
-pub async fn setup_cln_chans(
-    clients: &mut Clients,
-    nodes: &Vec<Node>,
-    sender: &str,
-    recip: &str,
-    btc_name: &str,
-) -> Result<()> {
-    let cln2_pubkey = get_pubkey_cln(clients, recip).await?;
-    if let Some(node) = nodes.iter().find(|n| n.name() == recip) {
-        log::info!("CLN2 pubkey {}", &cln2_pubkey);
-        let n = node.as_internal()?.as_cln()?;
-        new_chan_from_cln1(clients, sender, recip, &cln2_pubkey, &n.peer_port, btc_name).await?;
-        // keysend send
-        cln_keysend_to(clients, sender, &cln2_pubkey, 1_000_000, false).await?;
-        sleep(1000).await;
-        cln_keysend_to(clients, sender, &cln2_pubkey, 1_000_000, false).await?;
-        sleep(1000).await;
-        // keysend receive
-        let cln1_pubkey = get_pubkey_cln(clients, sender).await?;
-        cln_keysend_to(clients, recip, &cln1_pubkey, 500_000, true).await?;
-    } else {
-        log::error!("{} not found!", recip);
-    }
-    Ok(())
+pub async fn get_pubkey(clients: &Clients, node_id: &str) -> Result<String> {
+    Ok(node(clients, node_id)?.get_info().await?.pubkey)
 }
 
-pub async fn setup_lnd_chans(
+/// Open a channel from `sender` to `recip` and send keysends both ways.
+pub async fn setup_chans(
     clients: &mut Clients,
     nodes: &Vec<Node>,
     sender: &str,
     recip: &str,
     btc_name: &str,
 ) -> Result<()> {
-    let lnd1_pubkey = get_pubkey_lnd(clients, recip).await?;
-    if let Some(node) = nodes.iter().find(|n| n.name() == recip) {
-        log::info!("LND1 pubkey {}", &lnd1_pubkey);
-        let n = node.as_internal()?.as_lnd()?;
-        new_chan_from_cln1(clients, sender, recip, &lnd1_pubkey, &n.peer_port, btc_name).await?;
-        // keysend send
-        cln_keysend_to(clients, sender, &lnd1_pubkey, 1_000_000, false).await?;
-        sleep(1000).await;
-        // keysend send
-        cln_keysend_to(clients, sender, &lnd1_pubkey, 1_000_000, false).await?;
-        sleep(9000).await;
-        // keysend receive
-        let cln1_pubkey = get_pubkey_cln(clients, sender).await?;
-        log::info!("lnd send 1");
-        lnd_keysend_to(clients, recip, &cln1_pubkey, 500_000, false).await?;
-        log::info!("lnd send 2");
-        lnd_keysend_to(clients, recip, &cln1_pubkey, 500_000, true).await?;
+    let recip_pubkey = get_pubkey(clients, recip).await?;
+    let recip_img = match nodes.iter().find(|n| n.name() == recip) {
+        Some(n) => n.as_internal()?,
+        None => {
+            log::error!("{} not found!", recip);
+            return Ok(());
+        }
+    };
+    let (peer_port, recip_is_lnd) = match &recip_img {
+        Image::Cln(n) => (n.peer_port.clone(), false),
+        Image::Lnd(n) => (n.peer_port.clone(), true),
+        _ => return Err(anyhow!("{} is not a lightning node", recip)),
+    };
+    log::info!("{} pubkey {}", recip, &recip_pubkey);
+    new_chan(clients, sender, recip, &recip_pubkey, &peer_port, btc_name).await?;
+    // keysend send
+    keysend_to(clients, sender, &recip_pubkey, 1_000_000, false).await?;
+    sleep(1000).await;
+    keysend_to(clients, sender, &recip_pubkey, 1_000_000, false).await?;
+    // lnd takes a while to see the channel as usable
+    sleep(if recip_is_lnd { 9000 } else { 1000 }).await;
+    // keysend receive
+    let sender_pubkey = get_pubkey(clients, sender).await?;
+    if recip_is_lnd {
+        keysend_to(clients, recip, &sender_pubkey, 500_000, false).await?;
     }
+    keysend_to(clients, recip, &sender_pubkey, 500_000, true).await?;
     Ok(())
 }
 
@@ -80,7 +69,7 @@ pub async fn mine_blocks(clients: &mut Clients, btc_name: &str, n: u64) -> Resul
     Ok(())
 }
 
-pub async fn new_chan_from_cln1(
+pub async fn new_chan(
     clients: &mut Clients,
     sender_name: &str,
     peer_name: &str,
@@ -88,118 +77,167 @@ pub async fn new_chan_from_cln1(
     peer_port: &str,
     btc_name: &str,
 ) -> Result<()> {
-    let cln1 = clients.cln.get(sender_name).unwrap();
+    let sender = node(clients, sender_name)?;
 
     // skip if already have a chan
-    let peers = cln1.list_peers().await?;
-    for p in peers
-        .peers
+    let chans = sender.list_channels().await?;
+    if chans
         .iter()
-        .filter(|peer| hex::encode(peer.id.clone()) == peer_pubkey)
+        .any(|c| c.peer == peer_pubkey && c.state != ChannelState::Closing)
     {
-        if p.num_channels.unwrap_or(0) > 0 {
-            log::info!("skipping new channel setup");
-            return Ok(());
-        }
+        log::info!("skipping new channel setup");
+        return Ok(());
     }
 
-    let connected = cln1
-        .connect_peer(peer_pubkey, &domain(peer_name), peer_port)
-        .await?;
-    let channel = hex::encode(connected.id);
-    log::info!("CLN1 connected to {}: {}", peer_name, channel);
-    let funded = cln1.try_fund_channel(&channel, 100_000_000, None).await?;
-    log::info!("funded {:?}", hex::encode(funded.tx));
-    let addr = cln1.new_addr().await?;
+    let host = format!("{}:{}", domain(peer_name), peer_port);
+    sender.connect_peer(peer_pubkey, &host).await?;
+    log::info!("{} connected to {}: {}", sender_name, peer_name, peer_pubkey);
+    let mut funded = None;
+    for iteration in 0..100 {
+        match sender.open_channel(peer_pubkey, 100_000, None).await {
+            Ok(f) => {
+                funded = Some(f);
+                break;
+            }
+            Err(e) => log::info!("retry fund channel {}: {}", iteration, e),
+        }
+        sleep(5000).await;
+    }
+    let funded = funded.ok_or(anyhow!("could not fund channel - probably not synced"))?;
+    log::info!("funded {:?}", funded.funding_txid);
+    let address = sender.new_address().await?;
 
     let btcrpc = clients.bitcoind.get(btc_name).unwrap();
-    let address = addr.bech32.unwrap();
     btcrpc.test_mine(6, Some(address.clone()))?;
     log::info!("mined 6 blocks to {:?}", address);
 
-    let mut ok = false;
     log::info!("wait for channel to confirm...");
-    while !ok {
-        let pc = cln1
-            .list_peer_channels(Some(hex::decode(peer_pubkey)?))
-            .await?;
-        for c in pc.channels {
-            println!("=> channel status: {:?}", c.status);
-            if let Some(status) = c.status.get(0) {
-                if status.starts_with("CHANNELD_NORMAL") {
-                    log::info!("channel confirmed!!!");
-                    ok = true;
-                }
-            }
+    loop {
+        let chans = sender.list_channels().await?;
+        if chans
+            .iter()
+            .any(|c| c.peer == peer_pubkey && c.state == ChannelState::Active)
+        {
+            log::info!("channel confirmed!!!");
+            return Ok(());
         }
         sleep(1000).await;
     }
-
-    Ok(())
 }
 
-pub async fn cln_keysend_to(
+/// Keysend `amt` msat, with the biggest tlv the sender can carry if `do_tlv`.
+pub async fn keysend_to(
     clients: &mut Clients,
     sender_id: &str,
     recip_pubkey: &str,
     amt: u64,
     do_tlv: bool,
 ) -> Result<()> {
+    let tlv_len = if clients.lnd.contains_key(sender_id) {
+        LND_TLV_LEN
+    } else {
+        CLN_TLV_LEN
+    };
     let tlv_opt = if do_tlv {
         let mut tlvs = std::collections::HashMap::new();
-        tlvs.insert(133773310, [9u8; 1124].to_vec()); // (1207 ok, 1208 not) 603 bytes max
+        tlvs.insert(133773310, vec![9u8; tlv_len]);
         Some(tlvs)
     } else {
         None
     };
 
-    let cln1 = clients.cln.get(sender_id).unwrap();
-    match cln1
-        .keysend(recip_pubkey, amt, None, None, None, tlv_opt)
-        .await
-    {
+    let sender = node(clients, sender_id)?;
+    match sender.keysend(recip_pubkey, amt, tlv_opt).await {
         Ok(sent_keysend) => println!(
-            "[CLN] => sent_keysend to {} {:?}",
-            recip_pubkey, sent_keysend.status
+            "[{}] => sent_keysend to {} {:?}",
+            sender_id, recip_pubkey, sent_keysend.status
         ),
         Err(e) => {
-            println!("[CLN] keysend err {:?}", e)
+            println!("[{}] keysend err {:?}", sender_id, e)
         }
     };
     Ok(())
 }
 
-pub async fn lnd_keysend_to(
+/// Regtest run of the liquidity manager: fund `node`, then let a policy
+/// targeting `peer` open a channel bigger than any it already has.
+pub async fn test_liquidity(
     clients: &mut Clients,
-    sender_id: &str,
-    recip_pubkey: &str,
-    amt: u64,
-    do_tlv: bool,
-) -> Result<()> {
-    let tlv_opt = if do_tlv {
-        let mut tlvs = std::collections::HashMap::new();
-        tlvs.insert(133773310, [9u8; LND_TLV_LEN].to_vec()); // (1124 ok, 1224 not)
-        Some(tlvs)
-    } else {
-        None
-    };
-
-    let pk = PayKeysend {
-        dest: recip_pubkey.to_string(),
-        amt: (amt / 1000) as i64,
-        tlvs: tlv_opt,
+    node_id: &str,
+    peer: &str,
+    peer_port: &str,
+    btc_name: &str,
+) -> Result<Vec<LiquidityDecision>> {
+    let ln = node(clients, node_id)?;
+    let address = ln.new_address().await?;
+    let btcrpc = clients.bitcoind.get(btc_name).context("no bitcoind")?;
+    // coinbase outputs mature after 100 blocks
+    btcrpc.test_mine(101, Some(address.clone()))?;
+    log::info!("mined 101 blocks to {:?}", address);
+
+    let policy = LiquidityPolicy {
+        auto_open: true,
+        peers: vec![TargetPeer {
+            pubkey: get_pubkey(clients, peer).await?,
+            host: Some(format!("{}:{}", domain(peer), peer_port)),
+            alias: Some(peer.to_string()),
+        }],
+        min_channel_sat: 200_000,
+        max_daily_spend_sat: 500_000,
         ..Default::default()
     };
-    log::info!("pk {:?}", &pk);
-    let lnd1 = clients.lnd.get(sender_id).unwrap().clone();
-    match lnd1.lock().await.pay_keysend(pk).await {
-        Ok(sent_keysend) => println!(
-            "[LND] => sent_keysend to {} {:?}",
-            recip_pubkey, sent_keysend
-        ),
-        Err(e) => {
-            println!("[LND] keysend err {:?}", e)
+    for _ in 0..30 {
+        if ln.get_balance().await?.onchain_confirmed_sat >= policy.min_channel_sat {
+            break;
         }
+        sleep(1000).await;
+    }
+    let decisions = evaluate_node(ln.as_ref(), node_id, &policy, &[], None, false).await?;
+    for d in decisions.iter() {
+        log::info!("liquidity decision {:?}", d);
+    }
+    btcrpc.test_mine(6, None)?;
+    Ok(decisions)
+}
+
+/// Regtest run of a Lightning Address: serve it from `payee` and pay it from
+/// `payer` through the rocket server's LNURL-pay endpoints.
+pub async fn test_lightning_address(proj: &str, payer: &str, payee: &str) -> Result<()> {
+    let req = UpdateLightningAddressRequest {
+        username: "alice".to_string(),
+        address: Some(LightningAddress {
+            node: payee.to_string(),
+            min_sendable_msat: 1_000,
+            max_sendable_msat: 100_000_000,
+            description: "regtest lightning address".to_string(),
+            comment_allowed: 100,
+        }),
     };
-    Ok(())
+    update_address(proj, &req).await?;
+
+    let client = make_reqwest_client();
+    let url = format!("{}/.well-known/lnurlp/{}", base_url(None), req.username);
+    let pr: PayRequest = client.get(&url).send().await?.json().await?;
+    log::info!("lnurlp {:?}", pr);
+    let res: serde_json::Value = client
+        .get(&pr.callback)
+        .query(&[("amount", "21000"), ("comment", "from regtest")])
+        .send()
+        .await?
+        .json()
+        .await?;
+    let bolt11 = res["pr"]
+        .as_str()
+        .context(format!("no invoice from callback: {}", res))?;
+
+    let paid = lightning_client(payer).await?.pay_invoice(bolt11).await?;
+    log::info!("paid {} {:?}", req.username, paid.status);
+    let invoices = lightning_client(payee)
+        .await?
+        .list_invoices(Some(paid.payment_hash))
+        .await?;
+    match invoices.first() {
+        Some(i) if i.status == InvoiceStatus::Paid => Ok(()),
+        i => Err(anyhow!("lightning address invoice not paid: {:?}", i)),
+    }
 }
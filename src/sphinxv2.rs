@@ -100,6 +100,17 @@ pub fn sphinxv2_only(network: &str, host: Option<String>) -> Stack {
         lightning_peers: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
 
@@ -141,6 +152,17 @@ pub fn sphinxv1_only(network: &str, host: Option<String>) -> Stack {
         lightning_peers: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
 
@@ -164,5 +186,16 @@ pub fn config_only(host: Option<String>) -> Stack {
         lightning_peers: None,
         ssl_cert_last_modified: None,
         instance_id: None,
+        alerts: None,
+        cleanup: None,
+        saved_log_queries: None,
+        backup_store: None,
+        backup_retention: None,
+        liquidity: None,
+        rebalance: None,
+        fee_schedules: None,
+        webhooks: None,
+        lightning_addresses: None,
+        offers: None,
     }
 }
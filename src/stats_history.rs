@@ -0,0 +1,391 @@
+use crate::dock::{get_container_statistics, ContainerStat};
+use crate::metrics;
+use crate::utils::getenv;
+use anyhow::{anyhow, Result};
+use bollard::Docker;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_cron_scheduler::{Job, JobScheduler};
+
+pub static SAMPLE_STATS: AtomicBool = AtomicBool::new(false);
+pub static ROLLUP_STATS: AtomicBool = AtomicBool::new(false);
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Raw,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Hour => "hour",
+            Resolution::Day => "day",
+        }
+    }
+
+    // pick a resolution that keeps a graph to a few hundred points
+    fn for_range(from: u64, to: u64) -> Self {
+        let span = to.saturating_sub(from);
+        if span <= 2 * DAY {
+            Resolution::Raw
+        } else if span <= 60 * DAY {
+            Resolution::Hour
+        } else {
+            Resolution::Day
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatsPoint {
+    pub container: String,
+    // unix seconds (start of the bucket for rollups)
+    pub ts: u64,
+    pub cpu_percent: f64,
+    // average over the bucket
+    pub memory_usage: u64,
+    // peak over the bucket
+    pub memory_max: u64,
+    // cumulative counters as of the end of the bucket
+    pub network_rx_bytes: u64,
+    pub network_tx_bytes: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
+    // number of raw samples in the bucket
+    pub samples: u64,
+}
+
+pub fn db_path(proj: &str) -> String {
+    format!("vol/{}/stats.db", proj)
+}
+
+pub fn open(proj: &str) -> Result<Connection> {
+    let conn = Connection::open(db_path(proj))?;
+    init(&conn)?;
+    Ok(conn)
+}
+
+pub fn init(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS container_stats (
+            resolution TEXT NOT NULL,
+            container TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            cpu_percent REAL NOT NULL,
+            memory_usage INTEGER NOT NULL,
+            memory_max INTEGER NOT NULL,
+            network_rx_bytes INTEGER NOT NULL,
+            network_tx_bytes INTEGER NOT NULL,
+            block_read_bytes INTEGER NOT NULL,
+            block_write_bytes INTEGER NOT NULL,
+            samples INTEGER NOT NULL,
+            PRIMARY KEY (resolution, container, ts)
+        );",
+    )?;
+    Ok(())
+}
+
+pub fn insert_samples(conn: &Connection, ts: u64, stats: &[ContainerStat]) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT OR REPLACE INTO container_stats VALUES ('raw', ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, 1)",
+    )?;
+    for s in stats {
+        stmt.execute(params![
+            s.container_name,
+            ts as i64,
+            s.cpu_percent,
+            s.memory_usage as i64,
+            s.memory_usage as i64,
+            s.network_rx_bytes as i64,
+            s.network_tx_bytes as i64,
+            s.block_read_bytes as i64,
+            s.block_write_bytes as i64,
+        ])?;
+    }
+    Ok(())
+}
+
+/// Roll `from` into `into` for every bucket that has fully ended by `now`.
+/// Buckets are recomputed from the last one written, so a late sample is
+/// picked up on the next run.
+fn rollup(conn: &Connection, from: Resolution, into: Resolution, now: u64) -> Result<()> {
+    let size = match into {
+        Resolution::Hour => HOUR,
+        Resolution::Day => DAY,
+        Resolution::Raw => return Err(anyhow!("cannot roll up into raw")),
+    } as i64;
+    let last: Option<i64> = conn.query_row(
+        "SELECT MAX(ts) FROM container_stats WHERE resolution = ?1",
+        params![into.as_str()],
+        |row| row.get(0),
+    )?;
+    let start = last.unwrap_or(0);
+    let end = (now as i64 / size) * size;
+    conn.execute(
+        "INSERT OR REPLACE INTO container_stats
+        SELECT ?1, container, (ts / ?2) * ?2,
+            SUM(cpu_percent * samples) / SUM(samples),
+            SUM(memory_usage * samples) / SUM(samples),
+            MAX(memory_max),
+            MAX(network_rx_bytes), MAX(network_tx_bytes),
+            MAX(block_read_bytes), MAX(block_write_bytes),
+            SUM(samples)
+        FROM container_stats
+        WHERE resolution = ?3 AND ts >= ?4 AND ts < ?5
+        GROUP BY container, (ts / ?2)",
+        params![into.as_str(), size, from.as_str(), start, end],
+    )?;
+    Ok(())
+}
+
+pub struct Retention {
+    pub raw_secs: u64,
+    pub hour_secs: u64,
+    pub day_secs: u64,
+}
+
+impl Retention {
+    pub fn from_env() -> Self {
+        let num = |name: &str, default: u64| {
+            getenv(name)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            raw_secs: num("STATS_RAW_RETENTION_HOURS", 48) * HOUR,
+            hour_secs: num("STATS_HOURLY_RETENTION_DAYS", 30) * DAY,
+            day_secs: num("STATS_DAILY_RETENTION_DAYS", 365) * DAY,
+        }
+    }
+}
+
+/// Write hourly and daily rollups, then drop data older than the retention.
+pub fn rollup_and_prune(conn: &Connection, now: u64, retention: &Retention) -> Result<()> {
+    rollup(conn, Resolution::Raw, Resolution::Hour, now)?;
+    rollup(conn, Resolution::Hour, Resolution::Day, now)?;
+    for (res, keep) in [
+        (Resolution::Raw, retention.raw_secs),
+        (Resolution::Hour, retention.hour_secs),
+        (Resolution::Day, retention.day_secs),
+    ] {
+        conn.execute(
+            "DELETE FROM container_stats WHERE resolution = ?1 AND ts < ?2",
+            params![res.as_str(), now.saturating_sub(keep) as i64],
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StatisticsHistoryRequest {
+    pub container: Option<String>,
+    // unix seconds, defaults to the last 24 hours
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // picked from the range if not set
+    pub resolution: Option<Resolution>,
+}
+
+pub fn query(conn: &Connection, req: &StatisticsHistoryRequest, now: u64) -> Result<Vec<StatsPoint>> {
+    let to = req.to.unwrap_or(now);
+    let from = req.from.unwrap_or(to.saturating_sub(DAY));
+    let resolution = req
+        .resolution
+        .unwrap_or_else(|| Resolution::for_range(from, to));
+    let mut stmt = conn.prepare(
+        "SELECT container, ts, cpu_percent, memory_usage, memory_max,
+            network_rx_bytes, network_tx_bytes, block_read_bytes, block_write_bytes, samples
+        FROM container_stats
+        WHERE resolution = ?1 AND ts >= ?2 AND ts <= ?3 AND (?4 IS NULL OR container = ?4)
+        ORDER BY container, ts",
+    )?;
+    let rows = stmt
+        .query_map(
+            params![resolution.as_str(), from as i64, to as i64, req.container],
+            |row| {
+                Ok(StatsPoint {
+                    container: row.get(0)?,
+                    ts: row.get::<_, i64>(1)? as u64,
+                    cpu_percent: row.get(2)?,
+                    memory_usage: row.get::<_, i64>(3)? as u64,
+                    memory_max: row.get::<_, i64>(4)? as u64,
+                    network_rx_bytes: row.get::<_, i64>(5)? as u64,
+                    network_tx_bytes: row.get::<_, i64>(6)? as u64,
+                    block_read_bytes: row.get::<_, i64>(7)? as u64,
+                    block_write_bytes: row.get::<_, i64>(8)? as u64,
+                    samples: row.get::<_, i64>(9)? as u64,
+                })
+            },
+        )?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+async fn sample(proj: &str, docker: &Docker) -> Result<()> {
+    let stats = get_container_statistics(docker, None).await?;
+    let conn = open(proj)?;
+    insert_samples(&conn, now_secs(), &stats)
+}
+
+pub async fn stats_history_cron(proj: &str, docker: Docker) -> Result<JobScheduler> {
+    log::info!(":stats history sampler");
+    let sched = JobScheduler::new().await?;
+
+    let sample_cron = match getenv("STATS_SAMPLE_CRON") {
+        Ok(env) => env,
+        Err(_) => "0 * * * * *".to_string(),
+    };
+
+    sched
+        .add(Job::new_async(sample_cron.as_str(), |_uuid, _l| {
+            Box::pin(async move {
+                if !SAMPLE_STATS.load(Ordering::Relaxed) {
+                    SAMPLE_STATS.store(true, Ordering::Relaxed);
+                }
+            })
+        })?)
+        .await?;
+
+    // a few minutes past the hour, so the last raw samples are in
+    sched
+        .add(Job::new_async("0 5 * * * *", |_uuid, _l| {
+            Box::pin(async move {
+                if !ROLLUP_STATS.load(Ordering::Relaxed) {
+                    ROLLUP_STATS.store(true, Ordering::Relaxed);
+                }
+            })
+        })?)
+        .await?;
+
+    sched.start().await?;
+
+    let proj = proj.to_string();
+    tokio::spawn(async move {
+        loop {
+            if SAMPLE_STATS.load(Ordering::Relaxed) {
+                let res = sample(&proj, &docker).await;
+                metrics::record_job("stats_sample", res.is_ok());
+                if let Err(e) = res {
+                    log::error!("Sample container stats: {:?}", e);
+                }
+                SAMPLE_STATS.store(false, Ordering::Relaxed);
+            }
+            if ROLLUP_STATS.load(Ordering::Relaxed) {
+                let res = open(&proj)
+                    .and_then(|conn| rollup_and_prune(&conn, now_secs(), &Retention::from_env()));
+                metrics::record_job("stats_rollup", res.is_ok());
+                if let Err(e) = res {
+                    log::error!("Roll up container stats: {:?}", e);
+                }
+                ROLLUP_STATS.store(false, Ordering::Relaxed);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        }
+    });
+
+    Ok(sched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(name: &str, cpu: f64, mem: u64, rx: u64) -> ContainerStat {
+        ContainerStat {
+            container_id: name.to_string(),
+            container_name: name.to_string(),
+            container_image: "".to_string(),
+            cpu_total_usage: 0,
+            system_cpu_usage: 0,
+            memory_usage: mem,
+            memory_max_usage: mem,
+            memory_limit: 0,
+            cpu_percent: cpu,
+            network_rx_bytes: rx,
+            network_tx_bytes: 0,
+            block_read_bytes: 0,
+            block_write_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn test_rollup_and_query() {
+        let conn = Connection::open_in_memory().unwrap();
+        init(&conn).unwrap();
+        let base = 100 * DAY;
+        insert_samples(&conn, base, &[stat("neo4j.sphinx", 10.0, 100, 5)]).unwrap();
+        insert_samples(&conn, base + 60, &[stat("neo4j.sphinx", 30.0, 300, 9)]).unwrap();
+        insert_samples(&conn, base + HOUR, &[stat("neo4j.sphinx", 50.0, 500, 12)]).unwrap();
+
+        let retention = Retention {
+            raw_secs: 10 * DAY,
+            hour_secs: 10 * DAY,
+            day_secs: 10 * DAY,
+        };
+        rollup_and_prune(&conn, base + HOUR + 120, &retention).unwrap();
+
+        let req = StatisticsHistoryRequest {
+            container: Some("neo4j.sphinx".to_string()),
+            from: Some(base),
+            to: Some(base + DAY),
+            resolution: Some(Resolution::Hour),
+        };
+        let points = query(&conn, &req, base + DAY).unwrap();
+        // the second hour has not ended yet
+        assert_eq!(points.len(), 1);
+        let p = &points[0];
+        assert_eq!(p.ts, base);
+        assert_eq!(p.cpu_percent, 20.0);
+        assert_eq!(p.memory_usage, 200);
+        assert_eq!(p.memory_max, 300);
+        assert_eq!(p.network_rx_bytes, 9);
+        assert_eq!(p.samples, 2);
+    }
+
+    #[test]
+    fn test_prune_raw() {
+        let conn = Connection::open_in_memory().unwrap();
+        init(&conn).unwrap();
+        insert_samples(&conn, 0, &[stat("a.sphinx", 1.0, 1, 1)]).unwrap();
+        insert_samples(&conn, 3 * DAY, &[stat("a.sphinx", 1.0, 1, 1)]).unwrap();
+        let retention = Retention {
+            raw_secs: DAY,
+            hour_secs: 10 * DAY,
+            day_secs: 10 * DAY,
+        };
+        rollup_and_prune(&conn, 3 * DAY + 10, &retention).unwrap();
+        let req = StatisticsHistoryRequest {
+            resolution: Some(Resolution::Raw),
+            from: Some(0),
+            to: Some(4 * DAY),
+            ..Default::default()
+        };
+        let points = query(&conn, &req, 4 * DAY).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].ts, 3 * DAY);
+    }
+
+    #[test]
+    fn test_resolution_for_range() {
+        assert_eq!(Resolution::for_range(0, DAY), Resolution::Raw);
+        assert_eq!(Resolution::for_range(0, 7 * DAY), Resolution::Hour);
+        assert_eq!(Resolution::for_range(0, 90 * DAY), Resolution::Day);
+    }
+}
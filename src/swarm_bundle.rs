@@ -0,0 +1,532 @@
+use crate::backup::download_tar;
+use crate::backup_catalog::{self, sha256_file};
+use crate::backup_crypto::{self, decrypt_file, encrypt_file, BackupKey};
+use crate::backup_hooks::{self, hooks_for};
+use crate::config::{self, put_config_file, Node, Stack};
+use crate::dock;
+use crate::images::DockerHubImage;
+use crate::metrics;
+use crate::secrets::Secrets;
+use crate::utils::{domain, getenv, volume_string};
+use anyhow::{anyhow, Context, Result};
+use bollard::Docker;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// the stack binary always runs the "stack" project
+const PROJ: &str = "stack";
+const BUNDLE_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "bundle.json";
+const CONFIG_FILE: &str = "config.yaml.enc";
+const SECRETS_FILE: &str = "secrets.json.enc";
+const ENV_FILE: &str = "env.enc";
+const VOLUMES_DIR: &str = "volumes";
+
+pub static EXPORT_RUNNING: AtomicBool = AtomicBool::new(false);
+
+fn exports_dir() -> String {
+    format!("vol/{}/exports", PROJ)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageLock {
+    pub node: String,
+    // "repo:version" the container was created from
+    pub image: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub digest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BundleFile {
+    pub name: String,
+    pub size: u64,
+    // sha256 of the (encrypted) file as stored in the bundle
+    pub sha256: String,
+}
+
+/// `bundle.json`, the only plaintext entry of a bundle. Everything else
+/// is encrypted with the swarm's backup key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BundleManifest {
+    pub version: u32,
+    // unix seconds
+    pub created: i64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub source_host: Option<String>,
+    pub images: Vec<ImageLock>,
+    // nodes with a volume archive under volumes/
+    pub volumes: Vec<String>,
+    pub files: Vec<BundleFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportSwarmRequest {
+    // every internal node when empty
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub nodes: Option<Vec<String>>,
+}
+
+/// Host specific fields to set on import. `ip` is always replaced, so an
+/// unset one is found again by the public ip check.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostOverrides {
+    pub host: Option<String>,
+    pub ip: Option<String>,
+    pub custom_2b_domain: Option<String>,
+}
+
+impl HostOverrides {
+    pub fn from_env() -> Self {
+        let var = |k: &str| getenv(k).ok().filter(|v| !v.is_empty());
+        Self {
+            host: var("IMPORT_HOST").or_else(|| var("HOST")),
+            ip: var("IMPORT_IP"),
+            custom_2b_domain: var("IMPORT_CUSTOM_2B_DOMAIN"),
+        }
+    }
+}
+
+fn volume_file(node: &str) -> String {
+    format!("{}/{}.tar.enc", VOLUMES_DIR, node)
+}
+
+fn bundle_file(path: &Path, name: &str) -> Result<BundleFile> {
+    Ok(BundleFile {
+        name: name.to_string(),
+        size: std::fs::metadata(path)?.len(),
+        sha256: sha256_file(path)?,
+    })
+}
+
+/// Point the stack and every node at `o.host`, the way an UpdateEnv of
+/// HOST does.
+pub fn rewrite_hosts(stack: &mut Stack, o: &HostOverrides) {
+    if let Some(host) = &o.host {
+        stack.host = Some(host.clone());
+        for node in stack.nodes.iter_mut() {
+            if let Node::Internal(img) = node {
+                img.set_host(host);
+            }
+        }
+    }
+    stack.ip = o.ip.clone();
+    if o.custom_2b_domain.is_some() {
+        stack.custom_2b_domain = o.custom_2b_domain.clone();
+    }
+}
+
+fn write_bundle(path: &Path, work: &Path, manifest: &BundleManifest) -> Result<()> {
+    let partial = path.with_extension("partial");
+    let res = (|| -> Result<()> {
+        let mut builder = tar::Builder::new(File::create(&partial)?);
+        let json = serde_json::to_vec_pretty(manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(json.len() as u64);
+        header.set_mode(0o600);
+        header.set_cksum();
+        builder.append_data(&mut header, MANIFEST_FILE, &json[..])?;
+        for f in manifest.files.iter() {
+            builder.append_path_with_name(work.join(&f.name), &f.name)?;
+        }
+        builder.into_inner()?.sync_all()?;
+        Ok(())
+    })();
+    match res {
+        Ok(()) => Ok(std::fs::rename(&partial, path)?),
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Unpack a bundle into `dir`, refusing entries the manifest doesn't list
+/// and files whose size or sha256 doesn't match it.
+pub fn unpack_bundle(bundle: &Path, dir: &Path) -> Result<BundleManifest> {
+    std::fs::create_dir_all(dir.join(VOLUMES_DIR))?;
+    let mut archive = tar::Archive::new(File::open(bundle)?);
+    let mut unpacked = HashSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let safe = name == MANIFEST_FILE
+            || [CONFIG_FILE, SECRETS_FILE, ENV_FILE].contains(&name.as_str())
+            || name
+                .strip_prefix(&format!("{}/", VOLUMES_DIR))
+                .map(|n| !n.is_empty() && !n.contains('/') && !n.starts_with('.'))
+                .unwrap_or(false);
+        if !safe {
+            return Err(anyhow!("unexpected entry in bundle: {}", name));
+        }
+        let mut out = File::create(dir.join(&name))?;
+        std::io::copy(&mut entry, &mut out)?;
+        unpacked.insert(name);
+    }
+    let manifest: BundleManifest = serde_json::from_slice(
+        &std::fs::read(dir.join(MANIFEST_FILE)).context("bundle has no bundle.json")?,
+    )?;
+    if manifest.version != BUNDLE_VERSION {
+        return Err(anyhow!("unsupported bundle version {}", manifest.version));
+    }
+    for f in manifest.files.iter() {
+        if !unpacked.contains(&f.name) {
+            return Err(anyhow!("bundle is missing {}", f.name));
+        }
+        let got = bundle_file(&dir.join(&f.name), &f.name)?;
+        if got != *f {
+            return Err(anyhow!("{} failed its checksum", f.name));
+        }
+    }
+    let listed: HashSet<&str> = manifest.files.iter().map(|f| f.name.as_str()).collect();
+    for required in [CONFIG_FILE, SECRETS_FILE] {
+        if !listed.contains(required) {
+            return Err(anyhow!("bundle is missing {}", required));
+        }
+    }
+    for node in manifest.volumes.iter() {
+        if !listed.contains(volume_file(node).as_str()) {
+            return Err(anyhow!("bundle is missing the volume of {}", node));
+        }
+    }
+    Ok(manifest)
+}
+
+async fn encrypt_into(key: &BackupKey, src: &Path, work: &Path, name: &str) -> Result<BundleFile> {
+    let (key, src, dst) = (*key, src.to_path_buf(), work.join(name));
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || {
+        encrypt_file(&key, &src, &dst)?;
+        bundle_file(&dst, &name)
+    })
+    .await?
+}
+
+async fn decrypt_from(key: &BackupKey, work: &Path, name: &str) -> Result<PathBuf> {
+    let (key, src) = (*key, work.join(name));
+    let dst = work.join(name.trim_end_matches(".enc"));
+    let out = dst.clone();
+    tokio::task::spawn_blocking(move || decrypt_file(&key, &src, &dst)).await??;
+    Ok(out)
+}
+
+async fn image_lock(docker: &Docker, node: &str) -> Result<ImageLock> {
+    let info = docker.inspect_container(&domain(node), None).await?;
+    let image = info
+        .config
+        .and_then(|c| c.image)
+        .context("container has no image")?;
+    let digest = match info.image {
+        Some(id) => docker
+            .inspect_image(&id)
+            .await
+            .ok()
+            .and_then(|i| i.repo_digests)
+            .and_then(|d| d.first().cloned()),
+        None => None,
+    };
+    Ok(ImageLock {
+        node: node.to_string(),
+        image,
+        digest,
+    })
+}
+
+async fn export_volume(docker: &Docker, img: &crate::images::Image, work: &Path) -> Result<()> {
+    let name = img.name();
+    let host = domain(&name);
+    let tar = work.join(format!("{}.tar", name));
+    let hooks = hooks_for(img);
+    let pre = match &hooks {
+        Some(h) => backup_hooks::run_pre(docker, &host, h).await,
+        None => Ok(()),
+    };
+    let tarred = match pre {
+        Ok(()) => download_tar(docker, &host, &img.repo().root_volume, &tar.to_string_lossy()).await,
+        Err(e) => Err(e.context("pre-hook failed")),
+    };
+    if let Some(h) = &hooks {
+        if let Err(e) = backup_hooks::run_post(docker, &host, h).await {
+            log::error!("{:?}", e);
+        }
+    }
+    tarred
+}
+
+/// Write one bundle with the stack config, secrets, .env, image digests
+/// and a volume archive per node. Returns its path.
+pub async fn export_swarm(docker: &Docker, nodes: Option<Vec<String>>) -> Result<String> {
+    let key = backup_crypto::load_or_create_key().await?;
+    let stack = config::stack_read(|s| s.clone()).await;
+    let stamp = Utc::now().format("%Y-%m-%d-%H%M%S");
+    let dir = exports_dir();
+    let path = format!("{}/swarm-export-{}.tar", dir, stamp);
+    let work = PathBuf::from(format!("{}/work-{}", dir, stamp));
+    tokio::fs::create_dir_all(work.join(VOLUMES_DIR)).await?;
+
+    let res = async {
+        let mut files = Vec::new();
+        let plain = work.join("plain");
+        tokio::fs::write(&plain, serde_yaml::to_string(&stack)?).await?;
+        files.push(encrypt_into(&key, &plain, &work, CONFIG_FILE).await?);
+        let secrets_path = format!("vol/{}/secrets.json", PROJ);
+        files.push(encrypt_into(&key, Path::new(&secrets_path), &work, SECRETS_FILE).await?);
+        if Path::new(".env").exists() {
+            files.push(encrypt_into(&key, Path::new(".env"), &work, ENV_FILE).await?);
+        }
+        let _ = tokio::fs::remove_file(&plain).await;
+
+        let mut images = Vec::new();
+        let mut volumes = Vec::new();
+        for node in stack.nodes.iter() {
+            let img = match node {
+                Node::Internal(img) => img,
+                Node::External(_) => continue,
+            };
+            let name = img.name();
+            if let Some(only) = &nodes {
+                if !only.contains(&name) {
+                    continue;
+                }
+            }
+            match image_lock(docker, &name).await {
+                Ok(lock) => images.push(lock),
+                Err(e) => log::warn!("no image digest for {}: {:?}", name, e),
+            }
+            log::info!("export: archiving {}", name);
+            export_volume(docker, img, &work)
+                .await
+                .with_context(|| format!("archiving {}", name))?;
+            let tar = work.join(format!("{}.tar", name));
+            files.push(encrypt_into(&key, &tar, &work, &volume_file(&name)).await?);
+            let _ = tokio::fs::remove_file(&tar).await;
+            volumes.push(name);
+        }
+
+        let manifest = BundleManifest {
+            version: BUNDLE_VERSION,
+            created: Utc::now().timestamp(),
+            source_host: stack.host.clone(),
+            images,
+            volumes,
+            files,
+        };
+        let (bundle, work_dir) = (PathBuf::from(&path), work.clone());
+        tokio::task::spawn_blocking(move || write_bundle(&bundle, &work_dir, &manifest)).await??;
+        Ok(())
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&work).await;
+    res.map(|_| path)
+}
+
+/// Export in the background. Only one export runs at a time.
+pub fn export_now(docker: Docker, nodes: Option<Vec<String>>) -> Result<()> {
+    if EXPORT_RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(anyhow!("an export is already running"));
+    }
+    tokio::spawn(async move {
+        let res = export_swarm(&docker, nodes).await;
+        metrics::record_job("swarm_export", res.is_ok());
+        match res {
+            Ok(path) => log::info!("Swarm exported to {}", path),
+            Err(e) => log::error!("ExportSwarm: {:?}", e),
+        }
+        EXPORT_RUNNING.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+async fn restore_volume(docker: &Docker, img: &crate::images::Image, tar: &Path) -> Result<()> {
+    let name = img.name();
+    let root_volume = img.repo().root_volume;
+    let inner_root_path = dock::get_last_segment(&root_volume).to_string();
+    dock::create_volume(docker, &domain(&name)).await?;
+    let helper = backup_catalog::start_helper(
+        docker,
+        &format!("{}-import", domain(&name)),
+        vec![volume_string(&name, &root_volume)],
+    )
+    .await?;
+    let copied = dock::copy_data_to_volume(
+        docker,
+        &helper,
+        &name,
+        &inner_root_path,
+        &root_volume,
+        &tar.to_string_lossy(),
+    )
+    .await
+    .map_err(|e| anyhow!("copy to volume: {}", e));
+    let copied = match copied {
+        Ok(()) => backup_hooks::run_after_restore(docker, &helper, img).await,
+        Err(e) => Err(e),
+    };
+    let _ = dock::stop_and_remove(docker, &helper).await;
+    copied
+}
+
+// a tag that moved since the export won't run the same code
+async fn check_image_locks(docker: &Docker, locks: &[ImageLock]) {
+    for lock in locks.iter() {
+        let want = match &lock.digest {
+            Some(d) => d,
+            None => continue,
+        };
+        let c = bollard::container::Config {
+            image: Some(lock.image.clone()),
+            ..Default::default()
+        };
+        if let Err(e) = dock::create_image(docker, &c).await {
+            log::warn!("import: could not pull {}: {:?}", lock.image, e);
+            continue;
+        }
+        match dock::get_image_digest(&lock.image).await {
+            Ok(d) if d.digest == *want => (),
+            Ok(d) => log::warn!(
+                "import: {} is now {} but was exported as {}",
+                lock.image,
+                d.digest,
+                want
+            ),
+            Err(e) => log::warn!("import: no digest for {}: {:?}", lock.image, e),
+        }
+    }
+}
+
+/// Restore a bundle from `export_swarm` onto this host: volumes first,
+/// then secrets, .env and the config with `overrides` applied. An existing
+/// config or volume is only replaced with IMPORT_FORCE=true. The key is
+/// read from BACKUP_ENCRYPTION_KEY.
+pub async fn import_bundle(docker: &Docker, bundle: &str, overrides: &HostOverrides) -> Result<()> {
+    let done = format!("{}.imported", bundle);
+    if !Path::new(bundle).exists() && Path::new(&done).exists() {
+        log::info!("import: {} was already imported", bundle);
+        return Ok(());
+    }
+    let force = getenv("IMPORT_FORCE").map(|v| v == "true").unwrap_or(false);
+    let config_path = format!("vol/{}/config.yaml", PROJ);
+    if Path::new(&config_path).exists() && !force {
+        return Err(anyhow!("{} exists, set IMPORT_FORCE=true to replace it", config_path));
+    }
+    let key = backup_crypto::load_key()
+        .await?
+        .context("set BACKUP_ENCRYPTION_KEY to the exporting swarm's backup key")?;
+
+    let work = PathBuf::from(format!("vol/{}/import", PROJ));
+    let res = async {
+        let (src, dir) = (PathBuf::from(bundle), work.clone());
+        let manifest = tokio::task::spawn_blocking(move || unpack_bundle(&src, &dir)).await??;
+        log::info!(
+            "import: bundle from {:?} with {} volumes",
+            manifest.source_host,
+            manifest.volumes.len()
+        );
+        // decrypt everything small before touching the host
+        let config_plain = decrypt_from(&key, &work, CONFIG_FILE).await?;
+        let mut stack: Stack = serde_yaml::from_slice(&tokio::fs::read(&config_plain).await?)?;
+        let secrets_plain = decrypt_from(&key, &work, SECRETS_FILE).await?;
+        let secrets: Secrets = serde_json::from_slice(&tokio::fs::read(&secrets_plain).await?)?;
+        let has_env = manifest.files.iter().any(|f| f.name == ENV_FILE);
+        let env_plain = match has_env {
+            true => Some(decrypt_from(&key, &work, ENV_FILE).await?),
+            false => None,
+        };
+
+        let mut restores = Vec::new();
+        for name in manifest.volumes.iter() {
+            let img = crate::builder::find_img(name, &stack.nodes)?;
+            if docker.inspect_volume(&domain(name)).await.is_ok() && !force {
+                return Err(anyhow!("volume {} exists, set IMPORT_FORCE=true to replace it", domain(name)));
+            }
+            restores.push(img);
+        }
+        for img in restores.iter() {
+            log::info!("import: restoring {}", img.name());
+            let tar = decrypt_from(&key, &work, &volume_file(&img.name())).await?;
+            let restored = restore_volume(docker, img, &tar).await;
+            let _ = tokio::fs::remove_file(&tar).await;
+            restored.with_context(|| format!("restoring {}", img.name()))?;
+        }
+
+        tokio::fs::create_dir_all(format!("vol/{}", PROJ)).await?;
+        crate::utils::put_json(&format!("vol/{}/secrets.json", PROJ), &secrets).await;
+        if let Some(env) = env_plain {
+            // keep this host's own .env, the imported one is there to diff
+            let dst = if Path::new(".env").exists() { ".env.imported" } else { ".env" };
+            tokio::fs::copy(&env, dst).await?;
+            log::info!("import: wrote {}", dst);
+            dotenv::dotenv().ok();
+        }
+        rewrite_hosts(&mut stack, overrides);
+        put_config_file(PROJ, &stack).await;
+        check_image_locks(docker, &manifest.images).await;
+        // IMPORT_BUNDLE stays set across restarts
+        tokio::fs::rename(bundle, &done).await?;
+        Ok(())
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&work).await;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_hosts() {
+        let mut stack = crate::defaults::llama_only("regtest", Some("swarm7.sphinx.chat".to_string()));
+        let mut neo4j = crate::images::neo4j::Neo4jImage::new("neo4j", "5.19.0");
+        neo4j.host(stack.host.clone());
+        stack.nodes = vec![Node::Internal(crate::images::Image::Neo4j(neo4j))];
+        stack.ip = Some("1.2.3.4".to_string());
+        stack.custom_2b_domain = Some("graph.example.com".to_string());
+        assert_eq!(stack.nodes[0].host().as_deref(), Some("neo4j.swarm7.sphinx.chat"));
+        rewrite_hosts(
+            &mut stack,
+            &HostOverrides {
+                host: Some("swarm9.example.org".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(stack.host.as_deref(), Some("swarm9.example.org"));
+        assert_eq!(stack.nodes[0].host().as_deref(), Some("neo4j.swarm9.example.org"));
+        assert_eq!(stack.ip, None);
+        assert_eq!(stack.custom_2b_domain.as_deref(), Some("graph.example.com"));
+    }
+
+    #[test]
+    fn test_unpack_bundle() {
+        let dir = std::env::temp_dir().join(format!("swarm-bundle-{}", rand::random::<u64>()));
+        let work = dir.join("work");
+        std::fs::create_dir_all(work.join(VOLUMES_DIR)).unwrap();
+        let mut files = Vec::new();
+        for name in [CONFIG_FILE, SECRETS_FILE, &volume_file("neo4j")] {
+            std::fs::write(work.join(name), name.as_bytes()).unwrap();
+            files.push(bundle_file(&work.join(name), name).unwrap());
+        }
+        let manifest = BundleManifest {
+            version: BUNDLE_VERSION,
+            created: 0,
+            source_host: None,
+            images: vec![],
+            volumes: vec!["neo4j".to_string()],
+            files,
+        };
+        let bundle = dir.join("bundle.tar");
+        write_bundle(&bundle, &work, &manifest).unwrap();
+        assert_eq!(unpack_bundle(&bundle, &dir.join("out")).unwrap(), manifest);
+
+        // a listed file that changed after the export
+        let mut bad = manifest.clone();
+        bad.files[0].sha256 = "00".repeat(32);
+        write_bundle(&bundle, &work, &bad).unwrap();
+        assert!(unpack_bundle(&bundle, &dir.join("out2")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
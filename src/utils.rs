@@ -262,6 +262,15 @@ pub fn domain(name: &str) -> String {
     format!("{}.sphinx", name)
 }
 
+// accept "neo4j" as well as "neo4j.sphinx"
+pub fn container_name(c: &str) -> String {
+    if c.ends_with(".sphinx") {
+        c.to_string()
+    } else {
+        domain(c)
+    }
+}
+
 pub fn docker_domain(name: &str) -> String {
     if let Ok(_) = std::env::var("DOCKER_RUN") {
         domain(name)
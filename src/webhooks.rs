@@ -0,0 +1,329 @@
+use crate::config;
+use crate::ln_events::{LightningEvent, LightningEventKind};
+use crate::secrets::hex_secret_32;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const PROJ: &str = "stack";
+const MAX_DELIVERIES: usize = 1000;
+const MAX_ATTEMPTS: u32 = 5;
+// doubles after each failed attempt: 5s, 10s, 20s, 40s
+const RETRY_BASE_SECS: u64 = 5;
+const TIMEOUT_SECS: u64 = 15;
+
+static DELIVERIES_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Where lightning events get POSTed. Each body is signed with the secret,
+/// see `sign` for the header format.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Webhook {
+    pub url: String,
+    // generated when empty
+    #[serde(default)]
+    pub secret: String,
+    // every kind when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<LightningEventKind>,
+    // every lightning node when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<String>,
+}
+
+impl Webhook {
+    pub fn validate(&self) -> Result<()> {
+        let url = self.url.to_lowercase();
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err(anyhow!("webhook url must be http or https"));
+        }
+        Ok(())
+    }
+
+    pub fn wants(&self, ev: &LightningEvent) -> bool {
+        (self.events.is_empty() || self.events.contains(&ev.kind))
+            && (self.nodes.is_empty() || self.nodes.contains(&ev.node))
+    }
+
+    pub fn remove_secrets(&self) -> Self {
+        Self {
+            secret: "".to_string(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookDelivery {
+    pub webhook: String,
+    pub url: String,
+    // the LightningEvent id
+    pub event: String,
+    pub kind: LightningEventKind,
+    pub node: String,
+    // unix seconds of the last attempt
+    pub at: i64,
+    pub attempts: u32,
+    // http status of the last attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateWebhookRequest {
+    pub name: String,
+    // none removes the webhook
+    pub webhook: Option<Webhook>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListWebhookDeliveriesRequest {
+    pub webhook: Option<String>,
+    // newest first, 100 by default
+    pub limit: Option<usize>,
+}
+
+/// Hex HMAC-SHA256 of "{timestamp}.{body}", sent as
+/// `X-Swarm-Signature: sha256=<hex>` next to `X-Swarm-Timestamp`.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes any key length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// network errors, timeouts, throttling and server errors are worth another try
+pub fn should_retry(status: Option<u16>) -> bool {
+    match status {
+        None => true,
+        Some(s) => s == 408 || s == 429 || s >= 500,
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(RETRY_BASE_SECS << (attempt - 1))
+}
+
+fn deliveries_path() -> String {
+    format!("vol/{}/webhook_deliveries.json", PROJ)
+}
+
+async fn load_deliveries() -> Vec<WebhookDelivery> {
+    crate::utils::load_json(&deliveries_path(), Vec::new()).await
+}
+
+async fn record(d: WebhookDelivery) {
+    let _lock = DELIVERIES_LOCK.lock().await;
+    let mut all = load_deliveries().await;
+    all.push(d);
+    let skip = all.len().saturating_sub(MAX_DELIVERIES);
+    let all: Vec<WebhookDelivery> = all.into_iter().skip(skip).collect();
+    crate::utils::put_json(&deliveries_path(), &all).await;
+}
+
+pub async fn list_deliveries(
+    webhook: Option<String>,
+    limit: Option<usize>,
+) -> Vec<WebhookDelivery> {
+    load_deliveries()
+        .await
+        .into_iter()
+        .rev()
+        .filter(|d| webhook.as_ref().map(|w| *w == d.webhook).unwrap_or(true))
+        .take(limit.unwrap_or(100))
+        .collect()
+}
+
+async fn post(
+    client: &reqwest::Client,
+    hook: &Webhook,
+    ev: &LightningEvent,
+    body: &str,
+) -> Result<u16> {
+    let now = Utc::now().timestamp();
+    let res = client
+        .post(&hook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Swarm-Event", ev.kind.as_str())
+        .header("X-Swarm-Delivery", &ev.id)
+        .header("X-Swarm-Timestamp", now.to_string())
+        .header(
+            "X-Swarm-Signature",
+            format!("sha256={}", sign(&hook.secret, now, body)),
+        )
+        .body(body.to_string())
+        .send()
+        .await?;
+    Ok(res.status().as_u16())
+}
+
+async fn deliver_one(name: String, hook: Webhook, ev: LightningEvent) {
+    let body = match serde_json::to_string(&ev) {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!("webhook {}: can't serialize event: {}", name, e);
+            return;
+        }
+    };
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(TIMEOUT_SECS))
+        .build()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("webhook {}: no http client: {}", name, e);
+            return;
+        }
+    };
+    let mut delivery = WebhookDelivery {
+        webhook: name.clone(),
+        url: hook.url.clone(),
+        event: ev.id.clone(),
+        kind: ev.kind,
+        node: ev.node.clone(),
+        at: 0,
+        attempts: 0,
+        status: None,
+        ok: false,
+        error: None,
+    };
+    loop {
+        delivery.attempts += 1;
+        delivery.at = Utc::now().timestamp();
+        let (status, error) = match post(&client, &hook, &ev, &body).await {
+            Ok(s) if (200..300).contains(&s) => (Some(s), None),
+            Ok(s) => (Some(s), Some(format!("webhook returned {}", s))),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        delivery.status = status;
+        delivery.ok = error.is_none();
+        delivery.error = error;
+        if delivery.ok || delivery.attempts >= MAX_ATTEMPTS || !should_retry(status) {
+            break;
+        }
+        tokio::time::sleep(backoff(delivery.attempts)).await;
+    }
+    if !delivery.ok {
+        log::warn!(
+            "webhook {}: {} not delivered after {} attempts: {}",
+            name,
+            ev.id,
+            delivery.attempts,
+            delivery.error.as_deref().unwrap_or_default()
+        );
+    }
+    record(delivery).await;
+}
+
+/// Send the event to every webhook that wants it, each in the background.
+pub async fn deliver(ev: &LightningEvent) {
+    let hooks = config::stack_read(|s| s.webhooks.clone().unwrap_or_default()).await;
+    for (name, hook) in hooks.into_iter() {
+        if hook.wants(ev) {
+            tokio::spawn(deliver_one(name, hook, ev.clone()));
+        }
+    }
+}
+
+// without their secrets
+pub async fn list_webhooks() -> BTreeMap<String, Webhook> {
+    config::stack_read(|s| {
+        s.webhooks
+            .iter()
+            .flatten()
+            .map(|(name, h)| (name.clone(), h.remove_secrets()))
+            .collect()
+    })
+    .await
+}
+
+/// Add, replace or remove a webhook. An empty secret keeps the current one,
+/// or gets a new one for a new webhook. Returns the saved webhook.
+pub async fn update_webhook(proj: &str, req: &UpdateWebhookRequest) -> Result<Option<Webhook>> {
+    if let Some(h) = &req.webhook {
+        h.validate()?;
+    }
+    let saved = config::stack_write(proj, |s| {
+        let mut hooks: BTreeMap<String, Webhook> = s.webhooks.take().unwrap_or_default();
+        let saved = match &req.webhook {
+            Some(h) => {
+                let mut h = h.clone();
+                if h.secret.is_empty() {
+                    h.secret = hooks
+                        .get(&req.name)
+                        .map(|old| old.secret.clone())
+                        .unwrap_or_else(hex_secret_32);
+                }
+                hooks.insert(req.name.clone(), h.clone());
+                Some(h)
+            }
+            None => {
+                hooks.remove(&req.name);
+                None
+            }
+        };
+        s.webhooks = (!hooks.is_empty()).then_some(hooks);
+        saved
+    })
+    .await;
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // echo -n '1700000000.{"a":1}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign("secret", 1700000000, r#"{"a":1}"#),
+            "49f24e537407743fa4a0242bb63b94b9a47ee99cbbe071ccd8a22550ae411686"
+        );
+        assert_ne!(
+            sign("secret", 1700000001, r#"{"a":1}"#),
+            sign("secret", 1700000000, r#"{"a":1}"#)
+        );
+        assert!(should_retry(None));
+        assert!(should_retry(Some(503)));
+        assert!(should_retry(Some(429)));
+        assert!(!should_retry(Some(404)));
+        assert_eq!(backoff(1), Duration::from_secs(5));
+        assert_eq!(backoff(4), Duration::from_secs(40));
+    }
+
+    #[test]
+    fn test_wants() {
+        let ev = LightningEvent {
+            id: "cln1:invoice_paid:aa".to_string(),
+            node: "cln1".to_string(),
+            kind: LightningEventKind::InvoicePaid,
+            at: 0,
+            invoice: None,
+            payment: None,
+        };
+        let mut hook = Webhook {
+            url: "https://example.com/hook".to_string(),
+            ..Default::default()
+        };
+        assert!(hook.wants(&ev));
+        hook.events = vec![LightningEventKind::PaymentFailed];
+        assert!(!hook.wants(&ev));
+        hook.events.push(LightningEventKind::InvoicePaid);
+        hook.nodes = vec!["lnd1".to_string()];
+        assert!(!hook.wants(&ev));
+        hook.nodes.push("cln1".to_string());
+        assert!(hook.wants(&ev));
+        assert!(hook.validate().is_ok());
+        hook.url = "ftp://example.com".to_string();
+        assert!(hook.validate().is_err());
+    }
+}